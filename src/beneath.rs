@@ -0,0 +1,241 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Accessing the files of a tree relative to a file descriptor of its root,
+//! with openat2 and RESOLVE_BENEATH, so a symlink that is swapped into the
+//! tree during the run can not make us read or write outside of it.
+//!
+//! Path lookups that leave the root, through a symlink or `..`, fail. Only the
+//! last component is passed to the other `*at` syscalls, relative to its
+//! directory, which was opened beneath the root. Linux before 5.6 lacks
+//! openat2, then we fall back to openat, which does follow symlinks out of
+//! the root, and warn once.
+
+use std::ffi::{CStr, CString, OsString};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// The libc crate lacks openat2, its number is the same on every architecture.
+const SYS_OPENAT2: libc::c_long = 437;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// `struct open_how`, the arguments of openat2.
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// Set when the kernel turned out to lack openat2.
+static LACKS_OPENAT2: AtomicBool = AtomicBool::new(false);
+
+fn to_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Path {:?} contains a NUL byte.", path)))
+}
+
+fn check(result: isize) -> io::Result<isize> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n),
+    }
+}
+
+/// Open the path relative to the directory, without leaving the directory.
+fn open_beneath(dir: &fs::File, path: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<fs::File> {
+    let path_c = to_cstring(if path.as_os_str().is_empty() { Path::new(".") } else { path })?;
+    let flags = flags | libc::O_CLOEXEC;
+    // The kernel rejects a mode when the flags do not create a file.
+    let creates = flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE;
+    let how = OpenHow { flags: flags as u64, mode: if creates { u64::from(mode) } else { 0 }, resolve: RESOLVE_BENEATH };
+    loop {
+        let fd = if LACKS_OPENAT2.load(Ordering::Relaxed) {
+            unsafe { libc::openat(dir.as_raw_fd(), path_c.as_ptr(), flags, mode) }
+        } else {
+            let how_ptr = &how as *const OpenHow;
+            unsafe { libc::syscall(SYS_OPENAT2, dir.as_raw_fd(), path_c.as_ptr(), how_ptr, mem::size_of::<OpenHow>()) as libc::c_int }
+        };
+        if fd >= 0 {
+            return Ok(unsafe { fs::File::from_raw_fd(fd) });
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // A rename elsewhere in the filesystem raced with the lookup.
+            Some(libc::EAGAIN) => continue,
+            // Other workers may find out at the same time, only one warns,
+            // and all of them retry with openat.
+            Some(libc::ENOSYS) => {
+                if !LACKS_OPENAT2.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "The kernel lacks openat2, it needs Linux 5.6, so a symlink that appears in a tree \
+                        while we copy can lead the copies outside of it."
+                    );
+                }
+            }
+            Some(libc::EXDEV) => {
+                let msg = format!("{:?} leads outside of its tree, through a symlink or \"..\".", path);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+            }
+            _ => return Err(err),
+        }
+    }
+}
+
+/// A directory that we access the files below of, but not outside of.
+pub struct Root {
+    /// An O_PATH descriptor of the directory.
+    dir: fs::File,
+    /// The path of the directory, for messages.
+    path: PathBuf,
+}
+
+impl Root {
+    pub fn open(path: &Path) -> io::Result<Root> {
+        let dir = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("Failed to open {:?}: {}", path, err)))?;
+        Ok(Root { dir, path: path.to_path_buf() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return an error when the path no longer leads to the directory that we
+    /// opened, because it was deleted, or replaced, e.g. by a newer snapshot.
+    pub fn check_unchanged(&self) -> io::Result<()> {
+        let held = self.dir.metadata()?;
+        let deleted = || io::Error::new(io::ErrorKind::NotFound, format!("{:?} was deleted during the run.", self.path));
+        let current = match fs::metadata(&self.path) {
+            Ok(..) if held.nlink() == 0 => return Err(deleted()),
+            Ok(meta) => meta,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Err(deleted()),
+            Err(err) => return Err(io::Error::new(err.kind(), format!("Failed to stat {:?}: {}", self.path, err))),
+        };
+        // Every snapshot has its own device number, so this also catches a
+        // snapshot that was replaced by one with the same root inode.
+        if (current.dev(), current.ino()) != (held.dev(), held.ino()) {
+            let msg = format!("{:?} was replaced by another directory during the run.", self.path);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        Ok(())
+    }
+
+    /// Open the path below the root, with the open(2) flags.
+    pub fn open_file(&self, rel: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<fs::File> {
+        open_beneath(&self.dir, rel, flags, mode)
+    }
+
+    /// Like `fs::metadata`, that follows a symlink, if it stays below the root.
+    pub fn metadata(&self, rel: &Path) -> io::Result<fs::Metadata> {
+        self.open_file(rel, libc::O_PATH, 0)?.metadata()
+    }
+
+    /// Like `fs::symlink_metadata`, that does not follow a symlink.
+    pub fn symlink_metadata(&self, rel: &Path) -> io::Result<fs::Metadata> {
+        self.open_file(rel, libc::O_PATH | libc::O_NOFOLLOW, 0)?.metadata()
+    }
+
+    /// Return the directory that contains the path, opened beneath the root,
+    /// or none for the root itself, and the last component of the path.
+    fn split(&self, rel: &Path) -> io::Result<(Option<fs::File>, CString)> {
+        let name = rel.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Path {:?} does not end in a file name.", rel))
+        })?;
+        let dir = match rel.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                Some(self.open_file(parent, libc::O_PATH | libc::O_DIRECTORY, 0)?)
+            }
+            _ => None,
+        };
+        Ok((dir, to_cstring(Path::new(name))?))
+    }
+
+    /// Call the `*at` syscall with the directory and the name of the path.
+    fn at<F: FnOnce(libc::c_int, &CStr) -> isize>(&self, rel: &Path, syscall: F) -> io::Result<isize> {
+        let (dir, name) = self.split(rel)?;
+        check(syscall(dir.as_ref().unwrap_or(&self.dir).as_raw_fd(), &name))
+    }
+
+    /// Like `fs::create_dir_all`, return the directory as a new root.
+    pub fn create_dir_all(&self, rel: &Path) -> io::Result<Root> {
+        let path = self.path.join(rel);
+        match self.open_file(rel, libc::O_PATH | libc::O_DIRECTORY, 0) {
+            Ok(dir) => return Ok(Root { dir, path }),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        if let Some(parent) = rel.parent() {
+            self.create_dir_all(parent)?;
+        }
+        match self.at(rel, |dir, name| unsafe { libc::mkdirat(dir, name.as_ptr(), 0o777) } as isize) {
+            Ok(..) => {}
+            // Another worker may have created it in the meantime.
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+        let dir = self.open_file(rel, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        Ok(Root { dir, path })
+    }
+
+    /// Like `fs::remove_file`.
+    pub fn remove_file(&self, rel: &Path) -> io::Result<()> {
+        self.at(rel, |dir, name| unsafe { libc::unlinkat(dir, name.as_ptr(), 0) } as isize).map(drop)
+    }
+
+    /// Like `fs::read_link`.
+    pub fn read_link(&self, rel: &Path) -> io::Result<PathBuf> {
+        // Symlink targets are shorter than PATH_MAX.
+        let mut buffer = vec![0u8; libc::PATH_MAX as usize];
+        let len = self.at(rel, |dir, name| unsafe {
+            libc::readlinkat(dir, name.as_ptr(), buffer.as_mut_ptr() as *mut libc::c_char, buffer.len())
+        })?;
+        buffer.truncate(len as usize);
+        Ok(PathBuf::from(OsString::from_vec(buffer)))
+    }
+
+    /// Like `std::os::unix::fs::symlink`, create a symlink to target at the path.
+    pub fn symlink(&self, target: &Path, rel: &Path) -> io::Result<()> {
+        let target_c = to_cstring(target)?;
+        self.at(rel, |dir, name| unsafe { libc::symlinkat(target_c.as_ptr(), dir, name.as_ptr()) } as isize).map(drop)
+    }
+
+    /// Like `fs::rename`.
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (dir_from, name_from) = self.split(from)?;
+        let fd_from = dir_from.as_ref().unwrap_or(&self.dir).as_raw_fd();
+        self.at(to, |dir, name| unsafe { libc::renameat(fd_from, name_from.as_ptr(), dir, name.as_ptr()) } as isize)
+            .map(drop)
+    }
+
+    /// Give the file, that was opened with O_TMPFILE, a name at the path.
+    pub fn link_unnamed(&self, file: &fs::File, rel: &Path) -> io::Result<()> {
+        let empty = CString::default();
+        // Linking the fd itself needs CAP_DAC_READ_SEARCH, linking its path
+        // in /proc does not, but needs /proc.
+        let result = self.at(rel, |dir, name| unsafe {
+            libc::linkat(file.as_raw_fd(), empty.as_ptr(), dir, name.as_ptr(), libc::AT_EMPTY_PATH) as isize
+        });
+        if result.is_ok() {
+            return Ok(());
+        }
+        let proc_path = to_cstring(Path::new(&format!("/proc/self/fd/{}", file.as_raw_fd())))?;
+        self.at(rel, |dir, name| unsafe {
+            libc::linkat(libc::AT_FDCWD, proc_path.as_ptr(), dir, name.as_ptr(), libc::AT_SYMLINK_FOLLOW) as isize
+        })
+        .map(drop)
+    }
+}
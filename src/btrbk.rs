@@ -0,0 +1,153 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Finding the snapshots that btrbk made and transferred, for --btrbk.
+//!
+//! A btrbk config lists volumes, the subvolumes in them to snapshot, and the
+//! targets to send the snapshots to. The snapshots are named
+//! `<snapshot_name>.<timestamp>`, e.g. `home.20200601T1200`, both in the
+//! snapshot_dir of the volume and in the target.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// One subvolume of the config, with the options that apply to it.
+pub struct Subvolume {
+    /// The directory that contains the snapshots on the source side.
+    pub snapshot_dir: PathBuf,
+    /// The prefix of the snapshot names, before the timestamp.
+    pub snapshot_name: String,
+    /// The directories that the snapshots are transferred to.
+    pub targets: Vec<PathBuf>,
+}
+
+/// The options of one level of the config: global, volume, or subvolume.
+#[derive(Clone, Default)]
+struct Scope {
+    snapshot_dir: Option<String>,
+    snapshot_name: Option<String>,
+    targets: Vec<PathBuf>,
+}
+
+fn error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+fn is_remote(path: &str) -> bool {
+    path.starts_with("ssh://")
+}
+
+/// Turn the entry that was being parsed into a subvolume, if it is one.
+fn finish(subvolumes: &mut Vec<Subvolume>, volume: Option<&str>, entry: Option<(String, Scope)>) {
+    let (volume, name, scope) = match (volume, entry) {
+        (Some(volume), Some((name, scope))) => (volume, name, scope),
+        _ => return,
+    };
+    let snapshot_dir = Path::new(volume).join(scope.snapshot_dir.unwrap_or_default());
+    let snapshot_name = scope.snapshot_name.unwrap_or_else(|| {
+        Path::new(&name).file_name().map_or(name.clone(), |n| n.to_string_lossy().into_owned())
+    });
+    subvolumes.push(Subvolume { snapshot_dir, snapshot_name, targets: scope.targets });
+}
+
+/// Parse the subvolumes out of a btrbk config.
+///
+/// Only the options that determine where snapshots are, are interpreted.
+/// Volumes and targets on remote hosts are skipped with a warning.
+pub fn parse_config(contents: &str) -> Vec<Subvolume> {
+    let mut subvolumes = Vec::new();
+    let mut global = Scope::default();
+    let mut volume: Option<(String, Scope)> = None;
+    let mut subvolume: Option<(String, Scope)> = None;
+
+    for line in contents.lines() {
+        let line = line.find('#').map_or(line, |i| &line[..i]);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (key, value) = match words[..] {
+            [key, .., value] => (key, value.to_string()),
+            _ => continue,
+        };
+        if key == "volume" || key == "subvolume" {
+            // A new section ends the previous subvolume, and inherits the
+            // options of the scope around it.
+            finish(&mut subvolumes, volume.as_ref().map(|v| &v.0[..]), subvolume.take());
+            if key == "volume" {
+                if is_remote(&value) {
+                    warn!("Skipping btrbk volume {:?}, it is on a remote host.", value);
+                }
+                volume = Some((value, global.clone()));
+            } else {
+                let parent = volume.as_ref().map_or_else(|| global.clone(), |v| v.1.clone());
+                subvolume = Some((value, parent));
+            }
+            continue;
+        }
+        let scope = match (&mut subvolume, &mut volume) {
+            (Some((_, scope)), _) => scope,
+            (None, Some((_, scope))) => scope,
+            (None, None) => &mut global,
+        };
+        match key {
+            "snapshot_dir" => scope.snapshot_dir = Some(value),
+            "snapshot_name" => scope.snapshot_name = Some(value),
+            "target" if is_remote(&value) => warn!("Skipping btrbk target {:?}, it is on a remote host.", value),
+            "target" => scope.targets.push(PathBuf::from(value)),
+            _ => {}
+        }
+    }
+    finish(&mut subvolumes, volume.as_ref().map(|v| &v.0[..]), subvolume.take());
+    subvolumes.retain(|s| !is_remote(&s.snapshot_dir.to_string_lossy()));
+    subvolumes
+}
+
+/// Read the btrbk config at the path.
+pub fn load_config(path: &Path) -> io::Result<Vec<Subvolume>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| error(format!("Failed to read btrbk config {:?}: {}", path, err)))?;
+    Ok(parse_config(&contents))
+}
+
+/// Return the timestamps of the snapshots of the subvolume in the directory.
+///
+/// A timestamp starts with the date, as eight digits, and may be followed by
+/// a time and a `_N` suffix, so sorting them as strings sorts them by age.
+fn list_timestamps(dir: &Path, snapshot_name: &str) -> io::Result<Vec<String>> {
+    let prefix = format!("{}.", snapshot_name);
+    let mut timestamps = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) {
+            let timestamp = &name[prefix.len()..];
+            if timestamp.len() >= 8 && timestamp.bytes().take(8).all(|b| b.is_ascii_digit()) {
+                timestamps.push(timestamp.to_string());
+            }
+        }
+    }
+    timestamps.sort();
+    Ok(timestamps)
+}
+
+/// Return the four paths to repair sharing for the newest snapshot that is on
+/// both sides, with the newest one before it that is also on both sides as
+/// base, or None if there are fewer than two such snapshots.
+pub fn find_pair(subvolume: &Subvolume, target: &Path) -> io::Result<Option<[PathBuf; 4]>> {
+    let src_timestamps = list_timestamps(&subvolume.snapshot_dir, &subvolume.snapshot_name)?;
+    let dst_timestamps = list_timestamps(target, &subvolume.snapshot_name)?;
+    let mut common = src_timestamps.iter().filter(|t| dst_timestamps.contains(t)).rev();
+    let (target_ts, base_ts) = match (common.next(), common.next()) {
+        (Some(target_ts), Some(base_ts)) => (target_ts, base_ts),
+        _ => return Ok(None),
+    };
+    let name = |ts: &str| format!("{}.{}", subvolume.snapshot_name, ts);
+    Ok(Some([
+        subvolume.snapshot_dir.join(name(base_ts)),
+        subvolume.snapshot_dir.join(name(target_ts)),
+        target.join(name(base_ts)),
+        target.join(name(target_ts)),
+    ]))
+}
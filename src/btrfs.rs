@@ -0,0 +1,502 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Listing subvolumes with the btrfs ioctls, like `btrfs subvolume list`.
+//!
+//! The subvolumes are the ROOT_ITEM items in the root tree, which we read
+//! with BTRFS_IOC_TREE_SEARCH. Their names and the subvolume they are in are
+//! in the ROOT_BACKREF items, and the directory inside that subvolume we get
+//! with BTRFS_IOC_INO_LOOKUP. Both ioctls need CAP_SYS_ADMIN.
+//!
+//! For the daemon, there are also the ioctls to check whether a subvolume is
+//! read-only, and to create a snapshot, and for the pre-flight check of apply
+//! the one to tell which filesystem a subvolume is on, and the one to make a
+//! read-only subvolume writable.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// _IOWR(0x94, 17, struct btrfs_ioctl_search_args), obtained like FICLONE in main.rs.
+const BTRFS_IOC_TREE_SEARCH: libc::c_ulong = 0xd0009411;
+// _IOWR(0x94, 18, struct btrfs_ioctl_ino_lookup_args).
+const BTRFS_IOC_INO_LOOKUP: libc::c_ulong = 0xd0009412;
+// _IOW(0x94, 23, struct btrfs_ioctl_vol_args_v2).
+const BTRFS_IOC_SNAP_CREATE_V2: libc::c_ulong = 0x50009417;
+// _IOR(0x94, 25, __u64).
+const BTRFS_IOC_SUBVOL_GETFLAGS: libc::c_ulong = 0x80089419;
+// _IOW(0x94, 26, __u64).
+const BTRFS_IOC_SUBVOL_SETFLAGS: libc::c_ulong = 0x4008941a;
+// _IOR(0x94, 31, struct btrfs_ioctl_fs_info_args).
+const BTRFS_IOC_FS_INFO: libc::c_ulong = 0x8400941f;
+// _IOR(0x94, 60, struct btrfs_ioctl_get_subvol_info_args).
+const BTRFS_IOC_GET_SUBVOL_INFO: libc::c_ulong = 0x81f8943c;
+
+/// The f_type that statfs reports for btrfs.
+const BTRFS_SUPER_MAGIC: u64 = 0x9123683e;
+
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+const BTRFS_LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+const BTRFS_ROOT_ITEM_KEY: u32 = 132;
+const BTRFS_ROOT_BACKREF_KEY: u32 = 144;
+const BTRFS_ROOT_SUBVOL_RDONLY: u64 = 0x1;
+/// The read-only flag as BTRFS_IOC_SUBVOL_GETFLAGS reports it, which is not
+/// the same bit as in the root item.
+const BTRFS_SUBVOL_RDONLY: u64 = 0x2;
+
+/// `struct btrfs_ioctl_search_key`.
+#[repr(C)]
+#[derive(Default)]
+struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+/// `struct btrfs_ioctl_search_args`, the key followed by a buffer that the
+/// kernel fills with items, each preceded by a `struct btrfs_ioctl_search_header`.
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; 4096 - 104],
+}
+
+/// `struct btrfs_ioctl_ino_lookup_args`.
+#[repr(C)]
+struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; 4080],
+}
+
+/// `struct btrfs_ioctl_vol_args_v2`, with the unions as their largest member.
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; 4040],
+}
+
+/// `struct btrfs_ioctl_fs_info_args`.
+#[repr(C)]
+struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+/// `struct btrfs_ioctl_get_subvol_info_args`, with the fields after the uuids
+/// as one buffer.
+#[repr(C)]
+struct GetSubvolInfoArgs {
+    treeid: u64,
+    name: [u8; 256],
+    parent_id: u64,
+    dirid: u64,
+    generation: u64,
+    flags: u64,
+    uuid: [u8; 16],
+    parent_uuid: [u8; 16],
+    rest: [u8; 176],
+}
+
+/// The fields of a ROOT_ITEM that we need, at their offsets in the packed
+/// `struct btrfs_root_item`.
+const ROOT_ITEM_GENERATION: usize = 160;
+const ROOT_ITEM_FLAGS: usize = 208;
+const ROOT_ITEM_UUID: usize = 247;
+const ROOT_ITEM_PARENT_UUID: usize = 263;
+const ROOT_ITEM_OTIME: usize = 339;
+/// Root items written by kernels before 3.5 end before the uuids.
+const ROOT_ITEM_LEN_WITH_OTIME: usize = 351;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&buf[offset..offset + 2]);
+    u16::from_le_bytes(bytes)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A subvolume or snapshot.
+pub struct SubvolumeInfo {
+    pub id: u64,
+    pub generation: u64,
+    /// When the subvolume was created, if the filesystem recorded it.
+    pub otime: Option<SystemTime>,
+    pub uuid: [u8; 16],
+    /// For a snapshot, the uuid of the subvolume it is a snapshot of, or zero.
+    pub parent_uuid: [u8; 16],
+    pub read_only: bool,
+    /// The path, under the path that was listed.
+    pub path: PathBuf,
+}
+
+/// Where a subvolume is, from its ROOT_BACKREF.
+struct Backref {
+    /// The subvolume that contains it.
+    parent_id: u64,
+    /// The inode of the directory in the parent that contains it.
+    dirid: u64,
+    name: Vec<u8>,
+}
+
+fn ioctl<T>(file: &fs::File, request: libc::c_ulong, args: &mut T) -> io::Result<()> {
+    match unsafe { libc::ioctl(file.as_raw_fd(), request, args as *mut T) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Return the subvolume that contains the inode, and the path of the
+/// inode inside it, with a trailing slash, or empty for its root.
+fn ino_lookup(file: &fs::File, tree_id: u64, inode: u64) -> io::Result<(u64, Vec<u8>)> {
+    let mut args = InoLookupArgs { treeid: tree_id, objectid: inode, name: [0; 4080] };
+    ioctl(file, BTRFS_IOC_INO_LOOKUP, &mut args)?;
+    let len = args.name.iter().position(|&b| b == 0).unwrap_or_else(|| args.name.len());
+    Ok((args.treeid, args.name[..len].to_vec()))
+}
+
+/// Read all ROOT_ITEM and ROOT_BACKREF items of subvolumes from the root tree.
+fn search_root_tree(
+    file: &fs::File,
+    items: &mut HashMap<u64, SubvolumeInfo>,
+    backrefs: &mut HashMap<u64, Backref>,
+) -> io::Result<()> {
+    let mut args = SearchArgs { key: SearchKey::default(), buf: [0; 4096 - 104] };
+    args.key.tree_id = BTRFS_ROOT_TREE_OBJECTID;
+    args.key.min_objectid = BTRFS_FIRST_FREE_OBJECTID;
+    args.key.max_objectid = BTRFS_LAST_FREE_OBJECTID;
+    args.key.min_type = BTRFS_ROOT_ITEM_KEY;
+    args.key.max_type = BTRFS_ROOT_BACKREF_KEY;
+    args.key.max_offset = u64::max_value();
+    args.key.max_transid = u64::max_value();
+
+    loop {
+        args.key.nr_items = 4096;
+        ioctl(file, BTRFS_IOC_TREE_SEARCH, &mut args)?;
+        if args.key.nr_items == 0 {
+            return Ok(());
+        }
+        let mut pos = 0;
+        let mut last_key = (0, 0, 0);
+        for _ in 0..args.key.nr_items {
+            // The header is transid, objectid, offset, type, and len.
+            let objectid = read_u64(&args.buf, pos + 8);
+            let offset = read_u64(&args.buf, pos + 16);
+            let item_type = read_u32(&args.buf, pos + 24);
+            let len = read_u32(&args.buf, pos + 28) as usize;
+            let item = &args.buf[pos + 32..pos + 32 + len];
+            pos += 32 + len;
+            last_key = (objectid, item_type, offset);
+
+            // The search range is a range of keys, so it includes items of
+            // other types for objectids in the middle of the range.
+            match item_type {
+                BTRFS_ROOT_ITEM_KEY => {
+                    let has_otime = len >= ROOT_ITEM_LEN_WITH_OTIME;
+                    let uuid_at = |offset: usize| {
+                        let mut uuid = [0; 16];
+                        if has_otime { uuid.copy_from_slice(&item[offset..offset + 16]); }
+                        uuid
+                    };
+                    let otime = if has_otime {
+                        let secs = read_u64(item, ROOT_ITEM_OTIME);
+                        let nanos = read_u32(item, ROOT_ITEM_OTIME + 8);
+                        Some(UNIX_EPOCH + Duration::new(secs, nanos))
+                    } else {
+                        None
+                    };
+                    items.insert(objectid, SubvolumeInfo {
+                        id: objectid,
+                        generation: read_u64(item, ROOT_ITEM_GENERATION),
+                        otime,
+                        uuid: uuid_at(ROOT_ITEM_UUID),
+                        parent_uuid: uuid_at(ROOT_ITEM_PARENT_UUID),
+                        read_only: read_u64(item, ROOT_ITEM_FLAGS) & BTRFS_ROOT_SUBVOL_RDONLY != 0,
+                        path: PathBuf::new(),
+                    });
+                }
+                BTRFS_ROOT_BACKREF_KEY => {
+                    // `struct btrfs_root_ref` is dirid, sequence, and name_len, then the name.
+                    let name_len = read_u16(item, 16) as usize;
+                    backrefs.insert(objectid, Backref {
+                        parent_id: offset,
+                        dirid: read_u64(item, 0),
+                        name: item[18..18 + name_len].to_vec(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // Continue the search after the last key we got.
+        let (objectid, item_type, offset) = last_key;
+        args.key.min_objectid = objectid;
+        args.key.min_type = item_type;
+        args.key.min_offset = offset.wrapping_add(1);
+        if offset == u64::max_value() {
+            args.key.min_type = item_type + 1;
+        }
+        if args.key.min_type > BTRFS_ROOT_BACKREF_KEY {
+            if objectid == BTRFS_LAST_FREE_OBJECTID {
+                return Ok(());
+            }
+            args.key.min_objectid = objectid + 1;
+            args.key.min_type = BTRFS_ROOT_ITEM_KEY;
+            args.key.min_offset = 0;
+        }
+    }
+}
+
+/// Return the path of the subvolume relative to the top-level subvolume.
+fn subvolume_path(
+    file: &fs::File,
+    id: u64,
+    backrefs: &HashMap<u64, Backref>,
+    paths: &mut HashMap<u64, Option<Vec<u8>>>,
+) -> io::Result<Option<Vec<u8>>> {
+    if id == BTRFS_FS_TREE_OBJECTID {
+        return Ok(Some(Vec::new()));
+    }
+    if let Some(path) = paths.get(&id) {
+        return Ok(path.clone());
+    }
+    // Mark it first, so a cycle in a corrupted tree cannot recurse forever.
+    paths.insert(id, None);
+    let path = match backrefs.get(&id) {
+        // A subvolume without backref is deleted, but not cleaned up yet.
+        None => None,
+        Some(backref) => match subvolume_path(file, backref.parent_id, backrefs, paths)? {
+            None => None,
+            Some(mut path) => {
+                if !path.is_empty() { path.push(b'/'); }
+                path.extend_from_slice(&ino_lookup(file, backref.parent_id, backref.dirid)?.1);
+                path.extend_from_slice(&backref.name);
+                Some(path)
+            }
+        },
+    };
+    paths.insert(id, path.clone());
+    Ok(path)
+}
+
+/// Add context to the errors that the ioctls fail with, when they are not
+/// available, rather than just "Inappropriate ioctl for device".
+fn explain(err: io::Error, path: &Path) -> io::Error {
+    match err.raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EINVAL) => {
+            io::Error::new(err.kind(), format!("{:?} is not on a btrfs filesystem: {}", path, err))
+        }
+        Some(libc::EPERM) => {
+            io::Error::new(err.kind(), format!("This btrfs operation on {:?} needs root (CAP_SYS_ADMIN): {}", path, err))
+        }
+        _ => err,
+    }
+}
+
+/// List the subvolumes below the given directory, ordered by path.
+pub fn list_subvolumes(dir: &Path) -> io::Result<Vec<SubvolumeInfo>> {
+    let file = fs::File::open(dir)?;
+    let mut items = HashMap::new();
+    let mut backrefs = HashMap::new();
+    search_root_tree(&file, &mut items, &mut backrefs).map_err(|err| explain(err, dir))?;
+
+    // Find where the directory itself is, relative to the top-level subvolume.
+    let mut paths = HashMap::new();
+    let (dir_tree, _) = ino_lookup(&file, 0, BTRFS_FIRST_FREE_OBJECTID).map_err(|err| explain(err, dir))?;
+    let (_, dir_in_tree) = ino_lookup(&file, dir_tree, file.metadata()?.ino())?;
+    let mut dir_path = subvolume_path(&file, dir_tree, &backrefs, &mut paths)?.unwrap_or_default();
+    if !dir_path.is_empty() && !dir_in_tree.is_empty() { dir_path.push(b'/'); }
+    dir_path.extend_from_slice(&dir_in_tree);
+    let dir_path = PathBuf::from(OsStr::from_bytes(&dir_path));
+
+    let mut result = Vec::new();
+    for (id, mut info) in items {
+        let path = match subvolume_path(&file, id, &backrefs, &mut paths)? {
+            Some(path) => PathBuf::from(OsStr::from_bytes(&path)),
+            None => continue,
+        };
+        match path.strip_prefix(&dir_path) {
+            Ok(rel_path) if !rel_path.as_os_str().is_empty() => {
+                info.path = dir.join(rel_path);
+                result.push(info);
+            }
+            _ => continue,
+        }
+    }
+    result.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(result)
+}
+
+/// Return whether the subvolume at the path is read-only.
+pub fn is_read_only(path: &Path) -> io::Result<bool> {
+    let file = fs::File::open(path)?;
+    let mut flags: u64 = 0;
+    ioctl(&file, BTRFS_IOC_SUBVOL_GETFLAGS, &mut flags).map_err(|err| explain(err, path))?;
+    Ok(flags & BTRFS_SUBVOL_RDONLY != 0)
+}
+
+/// Make the subvolume at the path read-only or writable, like
+/// `btrfs property set <path> ro <read_only>`.
+///
+/// The path must be the root of the subvolume.
+pub fn set_read_only(path: &Path, read_only: bool) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let mut flags: u64 = 0;
+    ioctl(&file, BTRFS_IOC_SUBVOL_GETFLAGS, &mut flags).map_err(|err| explain(err, path))?;
+    if read_only {
+        flags |= BTRFS_SUBVOL_RDONLY;
+    } else {
+        flags &= !BTRFS_SUBVOL_RDONLY;
+    }
+    ioctl(&file, BTRFS_IOC_SUBVOL_SETFLAGS, &mut flags).map_err(|err| explain(err, path))
+}
+
+/// Return whether the path is on a btrfs filesystem.
+pub fn is_btrfs(path: &Path) -> io::Result<bool> {
+    let file = fs::File::open(path)?;
+    Ok(statfs(&file)?.f_type as u64 == BTRFS_SUPER_MAGIC)
+}
+
+/// Return whether the path is the root of a btrfs subvolume.
+pub fn is_subvolume(path: &Path) -> io::Result<bool> {
+    // The root directory of every subvolume has the same inode number.
+    Ok(is_btrfs(path)? && fs::metadata(path)?.ino() == BTRFS_FIRST_FREE_OBJECTID)
+}
+
+fn statfs(file: &fs::File) -> io::Result<libc::statfs> {
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Create a writable snapshot of the subvolume `src` at `dst`, like
+/// `btrfs subvolume snapshot <src> <dst>`.
+pub fn create_snapshot(src: &Path, dst: &Path) -> io::Result<()> {
+    let name = dst.file_name().map(|n| n.as_bytes()).unwrap_or_default();
+    let dir = dst.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let mut args = VolArgsV2 { fd: 0, transid: 0, flags: 0, unused: [0; 4], name: [0; 4040] };
+    // The name must be NUL-terminated, so it can fill all but the last byte.
+    if name.is_empty() || name.len() >= args.name.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid snapshot path {:?}.", dst)));
+    }
+    args.name[..name.len()].copy_from_slice(name);
+    let src_file = fs::File::open(src)?;
+    args.fd = src_file.as_raw_fd() as i64;
+    let dir_file = fs::File::open(dir)?;
+    ioctl(&dir_file, BTRFS_IOC_SNAP_CREATE_V2, &mut args).map_err(|err| explain(err, dir))
+}
+
+/// The filesystem that a path is on, to tell whether reflinks between two
+/// paths are possible.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FilesystemId {
+    /// The fsid of a btrfs filesystem. Every subvolume has its own device
+    /// number, but they all share the fsid.
+    Btrfs([u8; 16]),
+    /// The device number of a filesystem that is not btrfs.
+    Device(u64),
+}
+
+/// Return the filesystem that the path is on.
+pub fn filesystem_id(path: &Path) -> io::Result<FilesystemId> {
+    let file = fs::File::open(path)?;
+    if statfs(&file)?.f_type as u64 != BTRFS_SUPER_MAGIC {
+        return Ok(FilesystemId::Device(file.metadata()?.dev()));
+    }
+    let mut args: FsInfoArgs = unsafe { std::mem::zeroed() };
+    ioctl(&file, BTRFS_IOC_FS_INFO, &mut args).map_err(|err| explain(err, path))?;
+    Ok(FilesystemId::Btrfs(args.fsid))
+}
+
+/// Return the uuid of the subvolume that contains the path, and the uuid of
+/// the subvolume that it is a snapshot of, or zero. Needs Linux 4.18, but not
+/// root, unlike the tree search.
+fn subvolume_uuids(path: &Path) -> io::Result<([u8; 16], [u8; 16])> {
+    let file = fs::File::open(path)?;
+    let mut args: GetSubvolInfoArgs = unsafe { std::mem::zeroed() };
+    ioctl(&file, BTRFS_IOC_GET_SUBVOL_INFO, &mut args).map_err(|err| explain(err, path))?;
+    Ok((args.uuid, args.parent_uuid))
+}
+
+/// Return whether the two paths are in the same subvolume, or in snapshots
+/// of one subvolume, or one in a snapshot of the other. Snapshots keep the
+/// inode numbers, so then the same inode number is the same file.
+pub fn share_inode_numbers(a: &Path, b: &Path) -> io::Result<bool> {
+    let fs_a = filesystem_id(a)?;
+    if !matches!(fs_a, FilesystemId::Btrfs(..)) || fs_a != filesystem_id(b)? {
+        return Ok(false);
+    }
+    let (uuid_a, parent_a) = subvolume_uuids(a)?;
+    let (uuid_b, parent_b) = subvolume_uuids(b)?;
+    Ok(uuid_a == uuid_b || uuid_a == parent_b || uuid_b == parent_a || (parent_a != [0; 16] && parent_a == parent_b))
+}
+
+/// Suggest a base and target for every subvolume that has at least two
+/// snapshots in the list: its two newest snapshots.
+pub fn suggest_pairs(subvolumes: &[SubvolumeInfo]) -> Vec<(&SubvolumeInfo, &SubvolumeInfo)> {
+    let mut snapshots_of: HashMap<[u8; 16], Vec<&SubvolumeInfo>> = HashMap::new();
+    for info in subvolumes.iter().filter(|info| info.parent_uuid != [0; 16]) {
+        snapshots_of.entry(info.parent_uuid).or_insert_with(Vec::new).push(info);
+    }
+    let mut pairs: Vec<(&SubvolumeInfo, &SubvolumeInfo)> = snapshots_of
+        .into_iter()
+        .filter_map(|(_, mut snapshots)| {
+            snapshots.sort_by_key(|info| (info.otime, info.generation, info.id));
+            match snapshots[..] {
+                [.., base, target] => Some((base, target)),
+                _ => None,
+            }
+        })
+        .collect();
+    pairs.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    pairs
+}
@@ -0,0 +1,1020 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Command-line parsing.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use crate::config::find_option;
+
+pub const USAGE: &str = r#"btrfs-snapsync: Replay likely moves as reflink copies.
+
+Usage:
+    reflink-diff [options] apply   <src-base> <src-target> <dst-base> <dst-target>
+    reflink-diff [options] dry-run <src-base> <src-target> <dst-base> <dst-target>
+    reflink-diff [options] --snapper <config> (apply | dry-run) <dst-base> <dst-target>
+    reflink-diff [options] --btrbk <path> (apply | dry-run)
+    reflink-diff [options] --job <name> (apply | dry-run)
+    reflink-diff [options] run (<job>... | --all)
+    reflink-diff [options] chain <src-snapshots> <dst-snapshots>
+    reflink-diff [options] chain (<src-snapshot> <dst-snapshot>)...
+    reflink-diff [options] discover <src-snapshots> <dst-snapshots>
+    reflink-diff [options] daemon <src-snapshots> <dst-snapshots>
+    reflink-diff list <path>
+    reflink-diff rollback <undo-log>
+    reflink-diff [options] doctor [<path>...]
+    reflink-diff [options] --show-config
+    reflink-diff [options] install-systemd <job> [<unit-dir>]
+    reflink-diff completions <shell>
+    reflink-diff --help
+    reflink-diff --version
+
+Options:
+    --at <times>         In daemon mode, sync at these times of the day, in
+                         UTC, separated by commas, e.g. 03:00 or
+                         03:00,15:00, instead of watching for new
+                         snapshots. Can be combined with --every.
+                         Environment variable: BTRFS_SNAPSYNC_AT.
+    --backend <name>     How to create the copy of a move in apply mode:
+                           reflink          share extents with the source
+                                            using the FICLONE ioctl
+                           reflink-range    like reflink, but clone large
+                                            files in chunks of 1 GiB with
+                                            FICLONERANGE
+                           copy-file-range  copy the data in the kernel
+                                            with copy_file_range(2),
+                                            e.g. to get an independent
+                                            copy; some filesystems may
+                                            still share extents
+                         Defaults to reflink.
+                         Environment variable: BTRFS_SNAPSYNC_BACKEND.
+    --btrbk <path>       Take the paths from this btrbk config, to repair
+                         sharing after btrbk transferred its snapshots. For
+                         every subvolume and local target in the config,
+                         the newest snapshot that is on both sides is the
+                         target, and the newest one before it is the base.
+                         They are synced one after another, like "run".
+                         Snapshots are found by btrbk's naming scheme,
+                         <snapshot_name>.<timestamp>, in the snapshot_dir
+                         of the volume, and in the target directory.
+                         Environment variable: BTRFS_SNAPSYNC_BTRBK.
+    --color <when>       Whether to color diagnostics: auto, always, or
+                         never. Auto colors when stderr is a terminal and
+                         the NO_COLOR environment variable is not set.
+                         Defaults to auto.
+                         Environment variable: BTRFS_SNAPSYNC_COLOR.
+    --config <path>      Read default options from this file instead of
+                         ~/.config/btrfs-snapsync/config.toml.
+                         Environment variable: BTRFS_SNAPSYNC_CONFIG.
+    --copy-missing-from-source
+                         When a move has no source to reflink from, copy
+                         the file from src-target instead. This reads the
+                         data from the source trees, and the copy does not
+                         share extents. Without this, such moves are left
+                         for rsync.
+                         Environment variable:
+                         BTRFS_SNAPSYNC_COPY_MISSING_FROM_SOURCE.
+    --copy-symlink-targets
+                         For symlinks in the source trees that point to a
+                         file inside the same tree, treat the symlink as if
+                         it were that file. A move onto such a symlink
+                         replaces it in dst-target with a reflinked file.
+                         Symlinks that point outside the tree, or that
+                         loop, are reported and skipped. Without this,
+                         symlinks are ignored.
+                         Environment variable:
+                         BTRFS_SNAPSYNC_COPY_SYMLINK_TARGETS.
+    --csv <path>         Write every detected move to this file as CSV, with
+                         columns src, dst, size, mtime (in UTC), and the
+                         heuristic that matched the files: inode,
+                         size-mtime, size-extension, or name. Paths that
+                         are not valid UTF-8 are written lossily. E.g. to
+                         check the heuristic on a new dataset before
+                         applying.
+                         Environment variable: BTRFS_SNAPSYNC_CSV.
+    --every <duration>   In daemon mode, sync at every multiple of this
+                         interval, e.g. 1h syncs on the hour, instead of
+                         watching for new snapshots. Takes a unit s, m, h,
+                         d, or w. Syncs run one at a time, and scheduled
+                         times that pass while a sync runs are skipped.
+                         Environment variable: BTRFS_SNAPSYNC_EVERY.
+    --exclude <glob>     Skip paths that match this pattern when scanning
+                         the source trees, e.g. '*.tmp' or 'cache/'. A
+                         pattern without a slash matches the file name at
+                         any depth, a pattern with a slash matches the path
+                         relative to the tree root, and a trailing slash
+                         matches only directories, which are then skipped
+                         entirely. Supports *, ?, [...], and **. Can be
+                         passed multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_EXCLUDE, with
+                         patterns separated by colons.
+    --exclude-from <path>
+                         Read exclude patterns from this file, one per
+                         line. Blank lines and lines that start with '#'
+                         are ignored. Can be passed multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_EXCLUDE_FROM,
+                         with paths separated by colons.
+    --files-from <path>  Scan only the paths listed in this file, one per
+                         line, relative to the tree root. A listed
+                         directory includes everything inside it. Blank
+                         lines and lines that start with '#' are ignored.
+                         Can be passed multiple times, and combined with
+                         --include.
+                         Environment variable: BTRFS_SNAPSYNC_FILES_FROM, with
+                         paths separated by colons.
+    --exclude-regex <regex>
+                         Skip paths that match this regular expression when
+                         scanning the source trees. The regex is matched
+                         against the path relative to the tree root, with a
+                         trailing slash for directories, which are then
+                         skipped entirely, e.g. '^home/[^/]+/\.cache/$'.
+                         Supports ., [...], \d, \w, \s, ^, $, (...), |,
+                         *, +, ?, and {m,n}. Can be passed multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_EXCLUDE_REGEX,
+                         with regexes separated by colons.
+    --fail-on-missing    Exit with status 5 if any move was skipped because
+                         its source is not present in dst-base, after
+                         applying all other moves. Unlike --strict, this
+                         does not abort the run.
+                         Environment variable: BTRFS_SNAPSYNC_FAIL_ON_MISSING.
+    --fallback <list>    Where to look for the source of a move when it is
+                         not at its own path in dst-base, as a comma-
+                         separated list of:
+                           alternatives  other matching files in dst-base
+                           dst-target    the same paths in dst-target
+                           other-bases   the same paths in the snapshots
+                                         of --other-base
+                         Defaults to alternatives,other-bases. Pass '' to
+                         disable.
+                         Environment variable: BTRFS_SNAPSYNC_FALLBACK.
+    --fallback-copy      When the filesystem refuses to reflink a file,
+                         e.g. because the destination is nodatacow, the
+                         filesystem has no reflinks, or dst-base is on
+                         another filesystem, copy the data with
+                         copy_file_range, or with a buffered copy when that
+                         is not supported either, instead of failing. The
+                         copy shares nothing, but rsync has less to do.
+                         Environment variable: BTRFS_SNAPSYNC_FALLBACK_COPY.
+    --job <name>         Take the paths, and options that are not set on
+                         the command line or in the environment, from the
+                         [job.<name>] table in the config file, see below.
+                         The paths must not be passed as well.
+    -h, --help           Print this help, then exit.
+    --filter-regex <regex>
+                         Scan only files whose path matches this regular
+                         expression, in the syntax of --exclude-regex, or
+                         that are included by --include or --files-from.
+                         All directories are still entered. Can be passed
+                         multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_FILTER_REGEX,
+                         with regexes separated by colons.
+    --force              Overwrite destination files that already have
+                         data. Without this, such files are skipped, and
+                         reported, because rsync may have put them there.
+                         Environment variable: BTRFS_SNAPSYNC_FORCE.
+    --ignore-file <path> Skip paths that match the rules in this file, in
+                         gitignore format, relative to the tree roots.
+                         Independently of this, a .snapsyncignore file in
+                         any directory of the source trees applies to the
+                         paths inside that directory: blank lines and lines
+                         that start with '#' are ignored, every other line
+                         is a pattern as for --exclude, and a pattern that
+                         starts with '!' includes what it matches again.
+                         The last matching pattern wins, and files in
+                         deeper directories override those above them.
+                         Can be passed multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_IGNORE_FILE,
+                         with paths separated by colons.
+    --include <glob>     Scan only paths that match this pattern, or that
+                         are inside a directory that matches it. Patterns
+                         are as for --exclude, and --exclude takes
+                         precedence. Directories that cannot contain a
+                         match of a pattern with a slash, e.g. '/photos/',
+                         are not scanned at all. Because the filter applies
+                         to both trees, a move into an included path from
+                         outside it is not detected. Can be passed multiple
+                         times.
+                         Environment variable: BTRFS_SNAPSYNC_INCLUDE, with
+                         patterns separated by colons.
+    --include-cache-dirs Also scan directories that contain a CACHEDIR.TAG
+                         file. Without this, such directories are skipped,
+                         as their contents churn and are not worth linking.
+                         Environment variable:
+                         BTRFS_SNAPSYNC_INCLUDE_CACHE_DIRS.
+    --interactive        In apply mode, ask for every reflink, copy, and
+                         symlink whether to apply it: y to apply it, n to
+                         skip it, a to apply it and all remaining ones, q
+                         to skip it and all remaining ones. Needs a
+                         terminal on stdin. E.g. for a first run on a
+                         precious mirror.
+                         Environment variable: BTRFS_SNAPSYNC_INTERACTIVE.
+    --jitter <duration>  Delay every scheduled sync by a random time up to
+                         this, in the format of --every, so many machines
+                         do not sync at once. Defaults to 0s.
+                         Environment variable: BTRFS_SNAPSYNC_JITTER.
+    --jobs <n>           Create up to this many reflinks in parallel in
+                         apply mode. Defaults to 1.
+                         Environment variable: BTRFS_SNAPSYNC_JOBS.
+    --journal-dir <dir>  In apply mode, record every completed copy in a
+                         journal in this directory, one per dst-target,
+                         so --resume can continue an interrupted run. The
+                         journal is removed when the run completes.
+                         Environment variable: BTRFS_SNAPSYNC_JOURNAL_DIR.
+    --keep-going         In apply mode, continue with the remaining copies
+                         after a copy failed, instead of stopping, and list
+                         every failed copy with its error at the end. The
+                         exit status is still 4 when a copy failed.
+                         Environment variable: BTRFS_SNAPSYNC_KEEP_GOING.
+    --limit <n>          Stop after reflinking or copying this many files.
+                         Files that already share their data with the
+                         source do not count, so the next run continues
+                         where this one stopped. With --jobs, the copies
+                         that are in progress when the limit is reached
+                         still complete, so it can be exceeded slightly.
+                         Environment variable: BTRFS_SNAPSYNC_LIMIT.
+    --limit-bytes <bytes>
+                         Stop after reflinking or copying this many bytes,
+                         like --limit. Takes a suffix like --min-size. The
+                         file that crosses the limit is still completed.
+                         Environment variable: BTRFS_SNAPSYNC_LIMIT_BYTES.
+    --lock-wait <duration>
+                         In apply mode, wait this long for another run that
+                         applies to the same dst-target to finish, in the
+                         format of --every, instead of failing right away.
+                         Runs lock dst-target with flock(2), so two runs,
+                         e.g. overlapping cron jobs, never apply to the
+                         same tree at once. Defaults to 0s.
+                         Environment variable: BTRFS_SNAPSYNC_LOCK_WAIT.
+    --log-level <level>  Which diagnostics to print: off, error, warn, info,
+                         debug, or trace. Overrides RUST_LOG, and is in
+                         turn overridden by --verbose and --quiet.
+                         Environment variable: BTRFS_SNAPSYNC_LOG_LEVEL.
+    --make-writable      In apply mode, when dst-target is a read-only
+                         subvolume, make it writable for the run, and
+                         read-only again afterwards. Without this, apply
+                         refuses to start. Do not use this on a subvolume
+                         that a later "btrfs receive" uses as parent, its
+                         data would no longer match the sender's.
+                         Environment variable:
+                         BTRFS_SNAPSYNC_MAKE_WRITABLE.
+    --map <src-prefix>=<dst-prefix>
+                         Put files that are under src-prefix in the source
+                         trees under dst-prefix in the destination trees,
+                         e.g. --map home/alice=users/alice. Both prefixes
+                         are relative paths. When several rules match, the
+                         longest src-prefix wins. Filters still match the
+                         source paths. Can be passed multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_MAP, with
+                         rules separated by colons.
+    --metrics-file <path>
+                         After the run, write metrics in the Prometheus text
+                         format to this file, for the node_exporter textfile
+                         collector: files scanned, moves detected, files and
+                         bytes reflinked, failures, and phase durations.
+                         Environment variable: BTRFS_SNAPSYNC_METRICS_FILE.
+    --max-missing <n>    Refuse to apply when more than this many moves have
+                         no source in dst-base, which suggests that the
+                         wrong snapshot was passed, or that a filter is
+                         off. Takes a count, or a percentage of all moves,
+                         like 10%. Dry-run warns instead. Defaults to no
+                         limit.
+                         Environment variable: BTRFS_SNAPSYNC_MAX_MISSING.
+    --max-size <bytes>   Ignore files larger than this. Sizes take an
+                         optional suffix K, M, G, or T, for powers of 1024.
+                         Defaults to no limit.
+                         Environment variable: BTRFS_SNAPSYNC_MAX_SIZE.
+    --min-size <bytes>   Ignore files smaller than this, as small files
+                         save little space when reflinked. Defaults to 8K.
+                         Environment variable: BTRFS_SNAPSYNC_MIN_SIZE.
+    --newer-than <time>  Consider only files that were modified at or after
+                         this time: either a duration before the start of
+                         the run, with unit s, m, h, d, or w, e.g. 7d, or a
+                         date and time in UTC, e.g. 2020-05-01 or
+                         2020-05-01T12:00:00. Files are matched by mtime,
+                         which moves preserve, so this applies to both
+                         trees in the same way.
+                         Environment variable: BTRFS_SNAPSYNC_NEWER_THAN.
+    --no-color           Same as --color=never.
+    --no-hidden          Skip files and directories whose name starts with
+                         a dot, and everything inside such directories,
+                         e.g. .cache and .local.
+                         Environment variable: BTRFS_SNAPSYNC_NO_HIDDEN.
+    --no-space-check     Apply even when the free space of dst-target looks
+                         too small for the metadata of the clones and the
+                         data of the copies. Before applying, we abort on
+                         that, so the run does not fail halfway with ENOSPC.
+                         Environment variable: BTRFS_SNAPSYNC_NO_SPACE_CHECK.
+    --notify-cmd <cmd>   When a run ends, also when it failed or was
+                         aborted, run this command with "sh -c", with a
+                         JSON object on stdin, e.g. to post to a webhook.
+                         It has the status (nothing-to-do, applied,
+                         missing, failed, or aborted), the exit status,
+                         the error if the run was aborted, the command,
+                         job, host, start time, duration, and paths. If
+                         the command fails, that is reported, but does not
+                         change the exit status.
+                         Environment variable: BTRFS_SNAPSYNC_NOTIFY_CMD.
+    --older-than <time>  Consider only files that were modified before this
+                         time, in the format of --newer-than.
+                         Environment variable: BTRFS_SNAPSYNC_OLDER_THAN.
+    --only-ext <list>    Scan only files with one of these extensions, as a
+                         comma-separated list, e.g. 'mkv,mp4,jpg'. Matching
+                         ignores case. Can be passed multiple times, and
+                         combined with --min-size to consider only large
+                         media files.
+                         Environment variable: BTRFS_SNAPSYNC_ONLY_EXT, with
+                         lists separated by colons.
+    --other-base <dir>   Another snapshot on the filesystem of dst-target,
+                         e.g. an older one, to reflink a source from when
+                         dst-base lacks it. Can be passed multiple times,
+                         the first snapshot that has the file is used. The
+                         chain command adds the earlier snapshots of the
+                         chain, newest first.
+                         Environment variable: BTRFS_SNAPSYNC_OTHER_BASE,
+                         with paths separated by colons.
+    --output <format>    How to print the plan and the applied moves:
+                           plain  one "src" -> "dst" line per move, with
+                                  paths quoted and escaped
+                           human  one line per operation, tagged CLONE,
+                                  COPY, SKIP, MISSING, ERROR, or LINK,
+                                  colored by the rules of --color but
+                                  for stdout, with aligned columns
+                           json   one JSON object per line: a "scan"
+                                  event per source tree with its file
+                                  count, then a "plan" (dry-run) or
+                                  "apply" event per operation, with
+                                  "op", "src", "dst", and "note" or
+                                  "error"; paths that are not valid
+                                  UTF-8 have their invalid bytes
+                                  escaped as \udc80 to \udcff
+                           nul    for every operation that creates a
+                                  file or symlink, the source (empty if
+                                  there is none, the target for a
+                                  symlink) and the destination, each
+                                  terminated by a NUL byte, as raw
+                                  bytes, e.g. for xargs -0
+                         Defaults to plain.
+                         Environment variable: BTRFS_SNAPSYNC_OUTPUT.
+    --post-hook <cmd>    Run this command with "sh -c" after applying, or
+                         after a dry run, also when the run failed. It gets
+                         the variables of --pre-hook, and the exit status
+                         of the run in BTRFS_SNAPSYNC_EXIT_STATUS. When
+                         the run was aborted, BTRFS_SNAPSYNC_ERROR has the
+                         error. If the hook fails, the exit status is at
+                         least 4. Its output goes to stderr.
+                         Environment variable: BTRFS_SNAPSYNC_POST_HOOK.
+    --pre-hook <cmd>     Run this command with "sh -c" before scanning, e.g.
+                         to mount the destination. The paths are in
+                         BTRFS_SNAPSYNC_SRC_BASE, BTRFS_SNAPSYNC_SRC_TARGET,
+                         BTRFS_SNAPSYNC_DST_BASE, and
+                         BTRFS_SNAPSYNC_DST_TARGET, of the first pair if
+                         there are several. BTRFS_SNAPSYNC_COMMAND is
+                         apply or dry-run, BTRFS_SNAPSYNC_HOOK is pre or
+                         post, and BTRFS_SNAPSYNC_JOB is the job, if any.
+                         If the hook fails, the run is aborted, and the
+                         post-hook does not run. Its output goes to stderr.
+                         Environment variable: BTRFS_SNAPSYNC_PRE_HOOK.
+    --preserve-symlinks  Recreate symlinks that are new or changed in
+                         src-target in dst-target, with the same target.
+                         Cannot be combined with --copy-symlink-targets.
+                         Environment variable:
+                         BTRFS_SNAPSYNC_PRESERVE_SYMLINKS.
+    -0, --print0         Same as --output=nul.
+    --progress           Every few seconds, print how many files were
+                         scanned so far, and in apply mode how many moves
+                         are done, the bytes reflinked, and an estimate of
+                         the time remaining. Also without this, the process
+                         prints a progress line when it receives SIGUSR1,
+                         with the path that it is scanning or copying.
+                         Environment variable: BTRFS_SNAPSYNC_PROGRESS.
+    -q, --quiet          Print only warnings and errors, e.g. for cron
+                         jobs. Pass twice to print only errors. Like
+                         --verbose, this overrides RUST_LOG, and the two
+                         cannot be combined.
+    --report <path>      At the end of the run, write a report to this file
+                         as a JSON object: the command, paths, and resolved
+                         options, the duration of every phase, the counts,
+                         and in apply mode every failed move, with its
+                         reason and errno. E.g. to archive with snapshots.
+                         Environment variable: BTRFS_SNAPSYNC_REPORT.
+    --resume             Skip the copies that the journal of --journal-dir
+                         records as completed, to continue a run that was
+                         interrupted, e.g. by a reboot. Without this, the
+                         journal of an earlier run is discarded.
+                         Environment variable: BTRFS_SNAPSYNC_RESUME.
+    --retries <n>        Retry a reflink or directory creation this many
+                         times when it fails with an error that is often
+                         transient on a loaded system: EBUSY, ENOMEM, or
+                         EAGAIN. Defaults to 2. Pass 0 to not retry.
+                         Environment variable: BTRFS_SNAPSYNC_RETRIES.
+    --retry-backoff <duration>
+                         Wait this long before the first retry, in the
+                         format of --every, and twice as long before every
+                         next one. Defaults to 1s.
+                         Environment variable: BTRFS_SNAPSYNC_RETRY_BACKOFF.
+    --sandbox            Before the scan, restrict the process with Landlock
+                         to reading the source trees and dst-base, and in
+                         apply mode to writing dst-target and the files of
+                         --report, --journal-dir, etc. Needs Linux 5.13 or
+                         later. Cannot be combined with --post-hook and
+                         --notify-cmd, or with chain and daemon.
+                         Environment variable: BTRFS_SNAPSYNC_SANDBOX.
+    --show-config        Print the resolved options and where each value
+                         came from, then exit.
+    --skip-ext <list>    Skip files with one of these extensions, in the
+                         format of --only-ext.
+                         Environment variable: BTRFS_SNAPSYNC_SKIP_EXT, with
+                         lists separated by colons.
+    --snapper <config>   Use the two newest snapshots of this snapper config
+                         as src-base and src-target, so only dst-base and
+                         dst-target are passed. Takes the name of a config
+                         in /etc/snapper/configs, or the path to a config
+                         file if it contains a slash. Snapshots are ordered
+                         by their number, and must be in the .snapshots
+                         directory of the config's SUBVOLUME.
+                         Environment variable: BTRFS_SNAPSYNC_SNAPPER.
+    --stats-json <path>  In apply mode, write counts of the completed moves,
+                         and of moves that could not be completed, grouped
+                         by reason, to this file as a JSON object.
+                         Environment variable: BTRFS_SNAPSYNC_STATS_JSON.
+    --strict             Abort before applying anything if the source of a
+                         move is not present in dst-base. Without this,
+                         such moves are reported and skipped. Has no
+                         effect with --copy-missing-from-source. Also
+                         abort if a sample of the sources in dst-base do
+                         not have the size that they have in src-base,
+                         which we otherwise only warn about.
+                         Environment variable: BTRFS_SNAPSYNC_STRICT.
+    --subvolume <dir>=<src-base>,<src-target>
+                         Also diff the trees src-base and src-target, as if
+                         they were at <dir> in the main source trees, and
+                         replay the moves under <dir> in the destinations.
+                         All moves go into one plan and summary. Filters
+                         match relative to the root of each tree. Can be
+                         passed multiple times.
+                         Environment variable: BTRFS_SNAPSYNC_SUBVOLUME,
+                         with values separated by colons.
+    --sync               In apply mode, flush the filesystem of dst-target
+                         to disk with syncfs at the end, so that after a
+                         power loss the mirror still has the reflinks that
+                         the run reported. A failing sync aborts the run.
+                         Environment variable: BTRFS_SNAPSYNC_SYNC.
+    --timestamps         Prefix diagnostics with the time in UTC, in ISO
+                         8601 format, e.g. for logs of scheduled runs.
+                         Environment variable: BTRFS_SNAPSYNC_TIMESTAMPS.
+    --undo-log <path>    In apply mode, append every file, symlink, and
+                         directory that the run creates to this file, so
+                         "rollback" can remove them again.
+                         Environment variable: BTRFS_SNAPSYNC_UNDO_LOG.
+    -V, --version        Print the version, then exit.
+    -v, --verbose        Print more diagnostics, pass twice for even more.
+                         This overrides the RUST_LOG environment variable,
+                         which can be set to error, warn, info (the
+                         default), debug, or trace.
+    --xattrs             Copy the extended attributes of the source to the
+                         destination: user, security, and system ones, so
+                         also POSIX ACLs, file capabilities, and SELinux
+                         labels, which rsync would otherwise fix later.
+                         Environment variable: BTRFS_SNAPSYNC_XATTRS.
+
+Options may appear before or after the command. Everything after '--' is
+taken as a path, even if it starts with a dash. Paths need not be valid
+UTF-8, but option values other than --config must be.
+
+Options can be set in the TOML config file, with the flag name as key, e.g.
+min-size = 65536, and options that can be passed multiple times as an array,
+e.g. exclude = ["*.tmp", "cache/"]. Every option can also be set with an
+environment variable, named after the flag with a BTRFS_SNAPSYNC_ prefix,
+e.g. BTRFS_SNAPSYNC_JOBS=4, as listed above. An option passed on the
+command line takes precedence over the environment, which takes precedence
+over the config file, which in turn takes precedence over the built-in
+default.
+
+A config file can define mirror jobs, one [job.<name>] table each, with
+the four paths as keys, and any options. The options of the job override
+those at the top level of the file, and for list options, add to them:
+
+    exclude = ["*.tmp"]
+
+    [job.photos]
+    src-base = "/snapshots/photos/2020-06-01"
+    src-target = "/snapshots/photos/2020-06-02"
+    dst-base = "/mirror/photos/2020-06-01"
+    dst-target = "/mirror/photos/2020-06-02"
+    exclude = ["cache/"]
+
+The "run" command applies the named jobs, or all jobs in the config file
+with --all, one after another, with a summary for every job. A job that
+fails does not stop the jobs after it, and the exit status is the most
+severe one of all jobs. To preview a job, use "--job <name> dry-run".
+
+Diffs the file hierarchy from src-base to src-target, and detects
+potential moves, based on files having the same mtime and size.
+
+For every detected move, create a reflink:
+  * With as source, the base file, but in the destination tree.
+  * With as target, the target file, but in the destination tree.
+
+In other words, this diffs src-base..src-target and replays that diff on
+top of dst-base.
+
+The "chain" command catches up a mirror over a sequence of snapshots. It
+takes either two directories that contain snapshots as subdirectories, or
+an ordered list of source and destination snapshot pairs. For every two
+consecutive snapshots n and n+1, it applies the diff of the sources onto
+the destinations, as "apply <src-n> <src-n+1> <dst-n> <dst-n+1>" would.
+With directories, the snapshots are ordered by name, and only snapshots
+that exist in both directories are used. The destination snapshots must
+exist already. Because each step uses the result of the previous one as
+its base, the chain stops at the first step that fails, and
+--copy-missing-from-source is recommended.
+
+The "discover" command finds the four paths for the next sync, from two
+directories that contain snapshots as subdirectories, and prints them one
+per line, or terminated by NUL bytes with --output=nul, for example:
+
+    set -- $(reflink-diff discover /snapshots /mirror)
+    btrfs subvolume snapshot "$3" "$4"
+    reflink-diff apply "$@"
+
+Snapshots are ordered by the digits in their name, so timestamps and
+numbers order correctly, and by name otherwise. A source snapshot matches
+a destination snapshot with the same name, or else the one destination
+snapshot with the same digits, so "home.2020-06-01" can match
+"mirror.20200601". The target is the newest source snapshot, and the base
+is the newest one before it that has a match. A destination snapshot
+counts as synced once it exists, so the destination target does not
+exist yet. It gets the name of the source target, and must be created
+before applying, as above. When the newest snapshot is in the destination
+already, nothing is printed.
+
+The "daemon" command keeps running, and watches the source snapshot
+directory with inotify. Whenever a new read-only snapshot appears there,
+it finds the pair to sync like "discover", creates the destination target
+as a writable snapshot of the destination base, and applies the diff. A
+snapshot that is still writable is checked again until it is read-only. A
+failed sync is not retried, because the destination target exists then.
+With --every or --at, the daemon does not watch, but checks for a new
+snapshot on that schedule instead.
+
+The "list" command lists the btrfs subvolumes and snapshots below the path,
+with their id, generation, creation time, and whether they are read-only,
+like "btrfs subvolume list". Then for every subvolume with at least two
+snapshots below the path, it suggests the two newest as a base and target.
+Listing uses the btrfs ioctls that need root.
+
+The "rollback" command removes what the runs with --undo-log created,
+newest first, and then the log, e.g. when the heuristic went wrong on a
+new dataset. Files that apply replaced, with --force or because they were
+empty, and symlinks that it changed, are not restored. Paths that changed
+since the run, and directories with files that the run did not create,
+are kept. When a removal fails, the log is kept too, to retry.
+
+The "doctor" command checks what would stop reflinks or sharing: the kernel
+version, whether the paths are btrfs subvolumes, mount options such as
+autodefrag and nodatacow, and whether FICLONE and FIDEDUPERANGE work in
+the writable directories. Without paths it checks those of --job. It
+prints one line per finding, and exits with 4 when something would fail.
+
+More <dst-base> <dst-target> pairs can follow the first one, to replay the
+same diff on several mirrors, with a summary for every pair, while the
+source trees are scanned only once. The exit status is the most severe one
+of all pairs. The files of --metrics-file, --report, and --stats-json are
+written for every pair, so they describe the last one.
+
+In "apply" mode the reflinks are created. In "dry-run" mode, we print
+which reflinks would be created, with the same full paths in dst-base and
+dst-target that apply would use, and how many bytes they would share.
+
+Only the plan, as one "src -> dst" line per move, is printed to stdout.
+Progress, summaries, warnings, and errors are printed to stderr, so
+"reflink-diff dry-run ... > plan.txt" captures exactly the plan.
+
+When src-base and src-target are snapshots of the same btrfs subvolume,
+they share inode numbers, so files are matched by inode first, which is
+exact. Other files are matched on size, mtime, extension, and name.
+
+This is only a heuristic, but it sets up reflink sharing where possible,
+and rsync can later fix everything up (metadata, changed files, new and
+deleted files, etc.). When using rsync by itself, it would try to copy
+the file, destroying potential sharing.
+
+The "install-systemd" command writes a service and a timer unit that run
+the job every night, to /etc/systemd/system, or to the given directory.
+The service runs "run <job>" with the config file and the options that are
+passed to install-systemd, and it may write only to the dst-target trees
+and to the directories of the job's output files. Enable it with:
+
+    systemctl daemon-reload
+    systemctl enable --now reflink-diff-<job>.timer
+
+The "completions" command prints a completion script for bash, zsh, or
+fish, e.g. "reflink-diff completions bash > /etc/bash_completion.d/reflink-diff",
+or "reflink-diff completions fish > ~/.config/fish/completions/reflink-diff.fish".
+
+Exit status:
+  0  Nothing to do, no files were reflinked or copied.
+  1  Files were reflinked or copied, or would be in dry-run mode.
+  2  The arguments or the config are invalid.
+  3  The run was aborted by an error, e.g. while scanning, or by --strict.
+  4  Some operations failed, see the errors on stderr.
+  5  Some moves were skipped for lack of a source, with --fail-on-missing.
+  130, 143
+     Apply was interrupted by SIGINT or SIGTERM. The copies in progress
+     were finished, and the summary, journal, and reports were written.
+     A second signal kills the process immediately.
+"#;
+
+/// What the program should do.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    Apply,
+    DryRun,
+    Run,
+    Chain,
+    Discover,
+    Daemon,
+    List,
+    InstallSystemd,
+    Completions,
+    Rollback,
+    Doctor,
+}
+
+pub const COMMANDS: &[(&str, Command)] = &[
+    ("apply", Command::Apply),
+    ("dry-run", Command::DryRun),
+    ("run", Command::Run),
+    ("chain", Command::Chain),
+    ("discover", Command::Discover),
+    ("daemon", Command::Daemon),
+    ("list", Command::List),
+    ("install-systemd", Command::InstallSystemd),
+    ("completions", Command::Completions),
+    ("rollback", Command::Rollback),
+    ("doctor", Command::Doctor),
+];
+
+/// Program arguments, split into flags, the command, and its paths.
+#[derive(Clone)]
+pub struct Args {
+    /// `--flag value` or `--flag=value` pairs, in order, without the dashes.
+    pub flags: Vec<(String, String)>,
+    /// The path passed with `--config`, if any.
+    pub config: Option<PathBuf>,
+    /// The job passed with `--job`, if any.
+    pub job: Option<String>,
+    /// Whether `--all` was passed, to run all jobs.
+    pub all: bool,
+    /// Whether `--show-config` was passed.
+    pub show_config: bool,
+    /// Whether `-h` or `--help` was passed.
+    pub help: bool,
+    /// Whether `-V` or `--version` was passed.
+    pub version: bool,
+    /// The number of times `-v` or `--verbose` was passed.
+    pub verbose: usize,
+    /// The number of times `-q` or `--quiet` was passed.
+    pub quiet: usize,
+    /// The first positional argument, if there was one.
+    pub command: Option<Command>,
+    /// The positional arguments after the command.
+    pub paths: Vec<PathBuf>,
+}
+
+/// Return the number of single-character edits to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = prev[j] + if ca == cb { 0 } else { 1 };
+            row.push(substitute.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+fn parse_command(name: &str) -> Result<Command, String> {
+    if let Some(&(_, command)) = COMMANDS.iter().find(|&&(n, _)| n == name) {
+        return Ok(command);
+    }
+    let names: Vec<&str> = COMMANDS.iter().map(|&(n, _)| n).collect();
+    let mut msg = format!("Unknown command '{}', expected one of: {}.", name, names.join(", "));
+    if let Some(&(closest, _)) = COMMANDS.iter().min_by_key(|&&(n, _)| edit_distance(name, n)) {
+        if edit_distance(name, closest) <= 2 {
+            msg.push_str(&format!(" Did you mean '{}'?", closest));
+        }
+    }
+    Err(msg)
+}
+
+fn push_positional(result: &mut Args, arg: OsString) -> Result<(), String> {
+    match result.command {
+        None => result.command = Some(parse_command(&arg.to_string_lossy())?),
+        Some(..) => result.paths.push(PathBuf::from(arg)),
+    }
+    Ok(())
+}
+
+/// Handle an argument that is not valid UTF-8. Those can only be paths.
+fn push_non_utf8(result: &mut Args, arg: OsString) -> Result<(), String> {
+    let bytes = arg.as_bytes();
+    if bytes.starts_with(b"--config=") {
+        result.config = Some(PathBuf::from(OsStr::from_bytes(&bytes[b"--config=".len()..])));
+        Ok(())
+    } else if bytes.starts_with(b"-") && bytes.len() > 1 {
+        Err(format!("Option {} is not valid UTF-8.", arg.to_string_lossy()))
+    } else {
+        push_positional(result, arg)
+    }
+}
+
+pub fn parse_args<I: Iterator<Item = OsString>>(mut args: I) -> Result<Args, String> {
+    let mut result = Args {
+        flags: Vec::new(),
+        config: None,
+        job: None,
+        all: false,
+        show_config: false,
+        help: false,
+        version: false,
+        verbose: 0,
+        quiet: 0,
+        command: None,
+        paths: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        let arg = match arg.into_string() {
+            Ok(arg) => arg,
+            Err(arg) => {
+                push_non_utf8(&mut result, arg)?;
+                continue
+            }
+        };
+        match &arg[..] {
+            "--" => {
+                for arg in args.by_ref() {
+                    push_positional(&mut result, arg)?;
+                }
+                break
+            }
+            "-h" | "--help" => { result.help = true; continue }
+            "-V" | "--version" => { result.version = true; continue }
+            "--verbose" => { result.verbose += 1; continue }
+            "--quiet" => { result.quiet += 1; continue }
+            "--show-config" => { result.show_config = true; continue }
+            "--all" => { result.all = true; continue }
+            "-0" | "--print0" => {
+                result.flags.push(("output".to_string(), "nul".to_string()));
+                continue
+            }
+            "--no-color" => {
+                result.flags.push(("color".to_string(), "never".to_string()));
+                continue
+            }
+            _ => {}
+        }
+        // A lone dash is a positional argument by convention.
+        if arg.len() > 1 && arg.starts_with('-') && !arg.starts_with("--") {
+            if arg[1..].chars().all(|c| c == 'v') {
+                result.verbose += arg.len() - 1;
+                continue
+            }
+            if arg[1..].chars().all(|c| c == 'q') {
+                result.quiet += arg.len() - 1;
+                continue
+            }
+            return Err(format!("Unknown option {}.", arg));
+        }
+        if !arg.starts_with("--") {
+            push_positional(&mut result, arg.into())?;
+            continue
+        }
+        let (flag, value) = match arg[2..].find('=') {
+            Some(i) => (arg[2..2 + i].to_string(), arg[3 + i..].to_string()),
+            None => {
+                let flag = arg[2..].to_string();
+                if find_option(&flag).map(|spec| spec.is_switch) == Some(true) {
+                    result.flags.push((flag, "true".to_string()));
+                    continue
+                }
+                match args.next() {
+                    Some(value) if flag == "config" => {
+                        result.config = Some(PathBuf::from(value));
+                        continue
+                    }
+                    Some(value) => match value.into_string() {
+                        Ok(value) => (flag, value),
+                        Err(..) => return Err(format!("The value for --{} is not valid UTF-8.", flag)),
+                    },
+                    None => return Err(format!("Option --{} needs a value.", flag)),
+                }
+            }
+        };
+        match &flag[..] {
+            "config" => result.config = Some(PathBuf::from(value)),
+            "job" => result.job = Some(value),
+            _ => result.flags.push((flag, value)),
+        }
+    }
+
+    if result.verbose > 0 && result.quiet > 0 {
+        return Err("Options --verbose and --quiet cannot be combined.".to_string());
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    /// Parse arguments that must be valid.
+    fn parse_ok(args: &[&str]) -> Args {
+        parse(args).unwrap_or_else(|err| panic!("Failed to parse {:?}: {}", args, err))
+    }
+
+    /// Parse arguments that must be invalid, and return the error.
+    fn parse_err(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("Parsed invalid arguments {:?}.", args),
+            Err(err) => err,
+        }
+    }
+
+    fn paths(args: &Args) -> Vec<&str> {
+        args.paths.iter().map(|p| p.to_str().unwrap()).collect()
+    }
+
+    fn flags(args: &Args) -> Vec<(&str, &str)> {
+        args.flags.iter().map(|(k, v)| (&k[..], &v[..])).collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("apply", "apply"), 0);
+        assert_eq!(edit_distance("aply", "apply"), 1);
+        assert_eq!(edit_distance("applz", "apply"), 1);
+        assert_eq!(edit_distance("applyy", "apply"), 1);
+        assert_eq!(edit_distance("", "run"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn every_command_takes_the_positional_arguments_after_it() {
+        for &(name, command) in COMMANDS {
+            let args = parse_ok(&[name]);
+            assert_eq!(args.command, Some(command));
+            assert!(args.paths.is_empty());
+
+            let args = parse_ok(&[name, "a"]);
+            assert_eq!(paths(&args), vec!["a"], "Paths of {}", name);
+
+            let args = parse_ok(&[name, "a", "b", "c", "d", "e", "f"]);
+            assert_eq!(args.command, Some(command));
+            assert_eq!(paths(&args), vec!["a", "b", "c", "d", "e", "f"], "Paths of {}", name);
+        }
+    }
+
+    #[test]
+    fn options_may_appear_between_paths() {
+        let args = parse_ok(&["--jobs", "2", "apply", "a", "--exclude=*.tmp", "b", "-", "c", "d"]);
+        assert_eq!(args.command, Some(Command::Apply));
+        assert_eq!(paths(&args), vec!["a", "b", "-", "c", "d"]);
+        assert_eq!(flags(&args), vec![("jobs", "2"), ("exclude", "*.tmp")]);
+    }
+
+    #[test]
+    fn without_arguments_there_is_no_command() {
+        let args = parse_ok(&[]);
+        assert_eq!(args.command, None);
+        assert!(args.paths.is_empty());
+        assert!(args.flags.is_empty());
+        assert!(!args.help);
+    }
+
+    #[test]
+    fn double_dash_ends_the_options() {
+        let args = parse_ok(&["dry-run", "--", "--jobs", "-v", "--"]);
+        assert_eq!(args.command, Some(Command::DryRun));
+        assert_eq!(paths(&args), vec!["--jobs", "-v", "--"]);
+        assert!(args.flags.is_empty());
+        assert_eq!(args.verbose, 0);
+
+        // The command itself may come after the double dash.
+        let args = parse_ok(&["--", "list", "-a"]);
+        assert_eq!(args.command, Some(Command::List));
+        assert_eq!(paths(&args), vec!["-a"]);
+        assert_eq!(parse_err(&["--", "-a"]).split(',').next(), Some("Unknown command '-a'"));
+    }
+
+    #[test]
+    fn values_can_be_attached_or_separate() {
+        let args = parse_ok(&["--jobs=3", "--min-size", "1M", "--exclude=a=b", "--exclude=", "--color", "always"]);
+        assert_eq!(
+            flags(&args),
+            vec![("jobs", "3"), ("min-size", "1M"), ("exclude", "a=b"), ("exclude", ""), ("color", "always")],
+        );
+        // A separate value is taken as is, even when it looks like an option.
+        let args = parse_ok(&["--exclude", "--jobs"]);
+        assert_eq!(flags(&args), vec![("exclude", "--jobs")]);
+    }
+
+    #[test]
+    fn switches_do_not_take_a_value() {
+        let args = parse_ok(&["--force", "apply", "--fail-on-missing=false"]);
+        assert_eq!(flags(&args), vec![("force", "true"), ("fail-on-missing", "false")]);
+        assert_eq!(args.command, Some(Command::Apply));
+        let args = parse_ok(&["-0", "--no-color"]);
+        assert_eq!(flags(&args), vec![("output", "nul"), ("color", "never")]);
+    }
+
+    #[test]
+    fn config_and_job_are_kept_apart_from_the_flags() {
+        let args = parse_ok(&["--config", "a.toml", "--job=home", "--all", "--show-config", "run"]);
+        assert_eq!(args.config, Some(PathBuf::from("a.toml")));
+        assert_eq!(args.job, Some("home".to_string()));
+        assert!(args.all);
+        assert!(args.show_config);
+        assert!(args.flags.is_empty());
+        let args = parse_ok(&["--config=b.toml"]);
+        assert_eq!(args.config, Some(PathBuf::from("b.toml")));
+    }
+
+    #[test]
+    fn short_flags_and_counts() {
+        let args = parse_ok(&["-h", "-V", "-v", "-vv", "--verbose"]);
+        assert!(args.help);
+        assert!(args.version);
+        assert_eq!(args.verbose, 4);
+        let args = parse_ok(&["--help", "--version", "-qq", "--quiet"]);
+        assert!(args.help);
+        assert!(args.version);
+        assert_eq!(args.quiet, 3);
+    }
+
+    #[test]
+    fn unknown_commands_suggest_the_closest_one() {
+        let expected_names = "expected one of: apply, dry-run, run, chain, discover, daemon, list, \
+            install-systemd, completions, rollback, doctor.";
+        assert_eq!(
+            parse_err(&["aply"]),
+            format!("Unknown command 'aply', {} Did you mean 'apply'?", expected_names),
+        );
+        assert!(parse_err(&["dryrun"]).ends_with(" Did you mean 'dry-run'?"));
+        assert!(parse_err(&["lst", "a"]).ends_with(" Did you mean 'list'?"));
+        assert!(parse_err(&["doctr"]).ends_with(" Did you mean 'doctor'?"));
+        assert!(parse_err(&["install-system"]).ends_with(" Did you mean 'install-systemd'?"));
+        // Too far from every command for a suggestion.
+        assert_eq!(parse_err(&["frobnicate"]), format!("Unknown command 'frobnicate', {}", expected_names));
+    }
+
+    #[test]
+    fn errors_describe_the_problem() {
+        assert_eq!(parse_err(&["-x"]), "Unknown option -x.");
+        assert_eq!(parse_err(&["-vq"]), "Unknown option -vq.");
+        assert_eq!(parse_err(&["apply", "--jobs"]), "Option --jobs needs a value.");
+        assert_eq!(parse_err(&["--config"]), "Option --config needs a value.");
+        assert_eq!(parse_err(&["-v", "--quiet"]), "Options --verbose and --quiet cannot be combined.");
+        assert_eq!(parse_err(&["-q", "apply", "-vv"]), "Options --verbose and --quiet cannot be combined.");
+    }
+
+    #[test]
+    fn non_utf8_arguments_are_paths_only() {
+        let non_utf8 = || OsString::from_vec(vec![b'a', 0xff]);
+        let os_args = |args: Vec<OsString>| parse_args(args.into_iter());
+
+        let args = os_args(vec!["list".into(), non_utf8()]).unwrap();
+        assert_eq!(args.paths, vec![PathBuf::from(non_utf8())]);
+
+        let args = os_args(vec!["--config".into(), non_utf8()]).unwrap();
+        assert_eq!(args.config, Some(PathBuf::from(non_utf8())));
+
+        let mut config_arg = b"--config=".to_vec();
+        config_arg.extend_from_slice(non_utf8().as_bytes());
+        let args = os_args(vec![OsString::from_vec(config_arg)]).unwrap();
+        assert_eq!(args.config, Some(PathBuf::from(non_utf8())));
+
+        let err = os_args(vec!["--exclude".into(), non_utf8()]).err().unwrap();
+        assert_eq!(err, "The value for --exclude is not valid UTF-8.");
+        let err = os_args(vec![OsString::from_vec(b"--a\xff".to_vec())]).err().unwrap();
+        assert_eq!(err, "Option --a\u{fffd} is not valid UTF-8.");
+        let err = os_args(vec![non_utf8()]).err().unwrap();
+        assert!(err.starts_with("Unknown command 'a\u{fffd}'"));
+    }
+}
@@ -0,0 +1,208 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Shell completion scripts, generated from the known commands and options.
+
+use crate::cli::COMMANDS;
+use crate::config::OPTIONS;
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+/// Options that `cli::parse_args` handles itself, with their short form and
+/// whether they take a value.
+const EXTRA_FLAGS: &[(&str, Option<char>, bool)] = &[
+    ("all", None, false),
+    ("config", None, true),
+    ("help", Some('h'), false),
+    ("job", None, true),
+    ("no-color", None, false),
+    ("print0", Some('0'), false),
+    ("quiet", Some('q'), false),
+    ("show-config", None, false),
+    ("verbose", Some('v'), false),
+    ("version", Some('V'), false),
+];
+
+/// A flag, as far as completion is concerned.
+struct Flag {
+    long: &'static str,
+    short: Option<char>,
+    takes_value: bool,
+    is_list: bool,
+    /// For flags that take one of a fixed set of values, those values.
+    values: &'static [&'static str],
+}
+
+/// The fixed values a flag takes, must match the parsers in `config`.
+fn flag_values(flag: &str) -> &'static [&'static str] {
+    match flag {
+        "backend" => &["reflink", "reflink-range", "copy-file-range"],
+        "color" => &["auto", "always", "never"],
+        "fallback" => &["alternatives", "dst-target", "other-bases"],
+        "log-level" => &["off", "error", "warn", "info", "debug", "trace"],
+        "output" => &["plain", "human", "json", "nul"],
+        _ => &[],
+    }
+}
+
+fn flags() -> Vec<Flag> {
+    let mut flags: Vec<Flag> = OPTIONS
+        .iter()
+        .map(|spec| Flag {
+            long: spec.flag,
+            short: None,
+            takes_value: !spec.is_switch,
+            is_list: spec.is_list,
+            values: flag_values(spec.flag),
+        })
+        .chain(EXTRA_FLAGS.iter().map(|&(long, short, takes_value)| Flag {
+            long,
+            short,
+            takes_value,
+            is_list: long == "verbose" || long == "quiet",
+            values: &[],
+        }))
+        .collect();
+    flags.sort_by_key(|flag| flag.long);
+    flags
+}
+
+fn command_names() -> Vec<&'static str> {
+    COMMANDS.iter().map(|&(name, _)| name).collect()
+}
+
+fn bash() -> String {
+    let flags = flags();
+    let mut words: Vec<String> = flags.iter().map(|f| format!("--{}", f.long)).collect();
+    words.extend(flags.iter().filter_map(|f| f.short).map(|c| format!("-{}", c)));
+
+    let mut cases = String::new();
+    for flag in flags.iter().filter(|f| !f.values.is_empty()) {
+        cases.push_str(&format!(
+            "        --{})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return\n            ;;\n",
+            flag.long, flag.values.join(" "),
+        ));
+    }
+    let file_flags: Vec<String> = flags
+        .iter()
+        .filter(|f| f.takes_value && f.values.is_empty())
+        .map(|f| format!("--{}", f.long))
+        .collect();
+    cases.push_str(&format!(
+        "        {})\n            COMPREPLY=($(compgen -f -- \"$cur\"))\n            return\n            ;;\n",
+        file_flags.join("|"),
+    ));
+
+    format!(
+        r#"# bash completion for reflink-diff
+
+_reflink_diff() {{
+    local cur prev command i
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    case "$prev" in
+{cases}    esac
+
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{words}" -- "$cur"))
+        return
+    fi
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${{COMP_WORDS[i]}}" in
+            {commands_alt}) command="${{COMP_WORDS[i]}}"; break ;;
+        esac
+    done
+    case "$command" in
+        "") COMPREPLY=($(compgen -W "{commands}" -- "$cur")) ;;
+        completions) COMPREPLY=($(compgen -W "{shells}" -- "$cur")) ;;
+        *) COMPREPLY=($(compgen -d -- "$cur")) ;;
+    esac
+}}
+
+complete -F _reflink_diff reflink-diff
+"#,
+        cases = cases,
+        words = words.join(" "),
+        commands_alt = command_names().join("|"),
+        commands = command_names().join(" "),
+        shells = SHELLS.join(" "),
+    )
+}
+
+fn zsh() -> String {
+    let mut specs = String::new();
+    for flag in flags() {
+        // Flags that can be repeated are prefixed with a star.
+        let repeat = if flag.is_list { "*" } else { "" };
+        let action = match (flag.takes_value, flag.values) {
+            (false, _) => String::new(),
+            (true, &[]) => "=:value:_files".to_string(),
+            (true, values) => format!("=:value:({})", values.join(" ")),
+        };
+        match flag.short {
+            // The short and long form exclude each other, unless they can be repeated.
+            Some(c) if flag.is_list => specs.push_str(&format!(
+                "    '*'{{-{c},--{l}}}'{a}' \\\n", c = c, l = flag.long, a = action,
+            )),
+            Some(c) => specs.push_str(&format!(
+                "    '(-{c} --{l})'{{-{c},--{l}}}'{a}' \\\n", c = c, l = flag.long, a = action,
+            )),
+            None => specs.push_str(&format!("    '{}--{}{}' \\\n", repeat, flag.long, action)),
+        }
+    }
+    format!(
+        r#"#compdef reflink-diff
+
+_arguments -s \
+{specs}    '1:command:({commands})' \
+    '*::path:_files -/'
+"#,
+        specs = specs,
+        commands = command_names().join(" "),
+    )
+}
+
+fn fish() -> String {
+    let mut script = String::from("# fish completion for reflink-diff\n\n");
+    script.push_str("complete -c reflink-diff -f\n");
+    script.push_str(&format!(
+        "complete -c reflink-diff -n '__fish_use_subcommand' -a '{}'\n",
+        command_names().join(" "),
+    ));
+    script.push_str(&format!(
+        "complete -c reflink-diff -n '__fish_seen_subcommand_from completions' -a '{}'\n",
+        SHELLS.join(" "),
+    ));
+    script.push_str("complete -c reflink-diff -n '__fish_seen_subcommand_from apply dry-run chain discover daemon list' -a '(__fish_complete_directories)'\n");
+    for flag in flags() {
+        let mut line = String::from("complete -c reflink-diff");
+        if let Some(c) = flag.short {
+            line.push_str(&format!(" -s {}", c));
+        }
+        line.push_str(&format!(" -l {}", flag.long));
+        match (flag.takes_value, flag.values) {
+            (false, _) => {}
+            (true, &[]) => line.push_str(" -r -F"),
+            (true, values) => line.push_str(&format!(" -x -a '{}'", values.join(" "))),
+        }
+        script.push_str(&line);
+        script.push('\n');
+    }
+    script
+}
+
+/// Return the completion script for the shell, or an error for an unknown shell.
+pub fn generate(shell: &str) -> Result<String, String> {
+    match shell {
+        "bash" => Ok(bash()),
+        "zsh" => Ok(zsh()),
+        "fish" => Ok(fish()),
+        _ => Err(format!("Unknown shell '{}', expected one of: {}.", shell, SHELLS.join(", "))),
+    }
+}
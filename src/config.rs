@@ -0,0 +1,1388 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use log::LevelFilter;
+
+use crate::cli::Args;
+use crate::json::Json;
+use crate::toml;
+
+/// Options that tune scanning and applying.
+///
+/// Every option has a built-in default, which can be overridden by the config
+/// file, which can be overridden by an environment variable, which can in turn
+/// be overridden by a command-line flag. See `OPTIONS` for the names, and
+/// `Config::resolve` for where the precedence is implemented.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Times of the day at which the daemon syncs, in UTC.
+    pub at: Vec<TimeOfDay>,
+
+    /// How to create the copy of a move.
+    pub backend: Backend,
+
+    /// A btrbk config to take the snapshot pairs from.
+    pub btrbk: Option<PathBuf>,
+
+    /// The interval at which the daemon syncs.
+    pub every: Option<Duration>,
+
+    /// The maximum random delay of a scheduled sync.
+    pub jitter: Duration,
+
+    /// File to write Prometheus metrics about the run to.
+    pub metrics_file: Option<PathBuf>,
+
+    /// Refuse to apply when more moves than this have no source in dst-base.
+    pub max_missing: Option<Threshold>,
+
+    /// Files larger than this many bytes are not considered for linking up.
+    pub max_size: Option<u64>,
+
+    /// Files smaller than this many bytes are not considered for linking up.
+    pub min_size: u64,
+
+    /// Consider only files modified at or after this time, as passed.
+    pub newer_than: Option<String>,
+
+    /// Skip files and directories whose name starts with a dot.
+    pub no_hidden: bool,
+
+    /// Apply even when dst-target looks too full for the plan.
+    pub no_space_check: bool,
+
+    /// Consider only files modified before this time, as passed.
+    pub older_than: Option<String>,
+
+    /// If not empty, scan only files with one of these extensions, in lowercase.
+    pub only_ext: Vec<String>,
+
+    /// Other snapshots on the destination side, for the other-bases fallback.
+    pub other_bases: Vec<PathBuf>,
+
+    /// How to print the plan and the applied moves to stdout.
+    pub output: OutputFormat,
+
+    /// Whether to color the output.
+    pub color: ColorChoice,
+
+    /// Copy files whose source is missing in dst-base from src-target.
+    pub copy_missing_from_source: bool,
+
+    /// File to write the detected moves to, as CSV.
+    pub csv: Option<PathBuf>,
+
+    /// Treat symlinks to files inside the tree as the file they point to.
+    pub copy_symlink_targets: bool,
+
+    /// Glob patterns of paths to skip when scanning the trees.
+    pub exclude: Vec<String>,
+
+    /// Files to read more exclude patterns from, one per line.
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Regular expressions of paths to skip when scanning the trees.
+    pub exclude_regex: Vec<String>,
+
+    /// Files that list the paths to scan, one per line, relative to the root.
+    pub files_from: Vec<PathBuf>,
+
+    /// If not empty, scan only files whose path matches one of these regexes.
+    pub filter_regex: Vec<String>,
+
+    /// Ignore files in gitignore format that apply to the root of both trees.
+    pub ignore_file: Vec<PathBuf>,
+
+    /// Scan directories that are marked as cache with a `CACHEDIR.TAG` file.
+    pub include_cache_dirs: bool,
+
+    /// If not empty, scan only paths that match one of these glob patterns.
+    pub include: Vec<String>,
+
+    /// Exit with `EXIT_MISSING` when a move was skipped for lack of a source.
+    pub fail_on_missing: bool,
+
+    /// Where to look for a source that is missing in dst-base, in order.
+    pub fallback: Vec<Fallback>,
+
+    /// Copy the data when the filesystem refuses to reflink a file.
+    pub fallback_copy: bool,
+
+    /// Overwrite destination files that already have data.
+    pub force: bool,
+
+    /// Ask for confirmation of every operation before applying it.
+    pub interactive: bool,
+
+    /// Directory to keep the journals of completed copies in, for --resume.
+    pub journal_dir: Option<PathBuf>,
+
+    /// Number of reflinks to create in parallel.
+    pub jobs: usize,
+
+    /// Continue with the remaining copies after a copy failed.
+    pub keep_going: bool,
+
+    /// How long to wait for another run to release the lock of dst-target.
+    pub lock_wait: Duration,
+
+    /// Stop after reflinking or copying this many files.
+    pub limit: Option<usize>,
+
+    /// Stop after reflinking or copying this many bytes.
+    pub limit_bytes: Option<u64>,
+
+    /// The level of diagnostics to print, overriding `RUST_LOG` when set.
+    pub log_level: Option<LevelFilter>,
+
+    /// Make a read-only dst-target subvolume writable for the run.
+    pub make_writable: bool,
+
+    /// Rules to rewrite the relative paths of the diff for the destination.
+    pub maps: Vec<PathMap>,
+
+    /// File to write the stats of an apply run to, as JSON.
+    pub stats_json: Option<PathBuf>,
+
+    /// Shell command to run with a JSON summary on stdin when a run ends.
+    pub notify_cmd: Option<String>,
+
+    /// Shell command to run after applying, also when the run failed.
+    pub post_hook: Option<String>,
+
+    /// Shell command to run before scanning, the run aborts if it fails.
+    pub pre_hook: Option<String>,
+
+    /// Recreate symlinks in dst-target.
+    pub preserve_symlinks: bool,
+
+    /// Print progress lines while scanning and applying.
+    pub progress: bool,
+
+    /// Skip files with one of these extensions, in lowercase.
+    pub skip_ext: Vec<String>,
+
+    /// File to write a JSON report of the whole run to.
+    pub report: Option<PathBuf>,
+
+    /// Skip the copies that the journal records as completed by an earlier run.
+    pub resume: bool,
+
+    /// How often to retry a clone or directory creation after a transient error.
+    pub retries: u32,
+
+    /// The delay before the first retry, it doubles for every next one.
+    pub retry_backoff: Duration,
+
+    /// Restrict the process to the trees and the output files with Landlock.
+    pub sandbox: bool,
+
+    /// Abort before applying anything when a source is missing in dst-base,
+    /// or when sampled sources differ in size from src-base.
+    pub strict: bool,
+
+    /// The snapper config whose two newest snapshots are src-base and src-target.
+    pub snapper: Option<String>,
+
+    /// More source trees to diff, mapped to subdirectories of the destination.
+    pub subvolumes: Vec<Subvolume>,
+
+    /// Flush the destination filesystem to disk at the end of applying.
+    pub sync: bool,
+
+    /// Prefix diagnostics with the time.
+    pub timestamps: bool,
+
+    /// File to record the paths that apply creates in, for rollback.
+    pub undo_log: Option<PathBuf>,
+
+    /// Copy the extended attributes of the source to the destination.
+    pub xattrs: bool,
+
+    /// The config file that was read, if any.
+    pub file: Option<PathBuf>,
+
+    /// The job selected with `--job`, if any.
+    pub job: Option<String>,
+
+    /// The src-base, src-target, dst-base, and dst-target of the selected job.
+    pub job_paths: Option<Vec<PathBuf>>,
+
+    /// The names of all jobs in the config file, in order.
+    pub job_names: Vec<String>,
+
+    /// For every option that was set, where it was set, keyed by flag name.
+    sources: HashMap<&'static str, Source>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            at: Vec::new(),
+            backend: Backend::Reflink,
+            btrbk: None,
+            every: None,
+            jitter: Duration::from_secs(0),
+            metrics_file: None,
+            max_missing: None,
+            max_size: None,
+            min_size: 8192,
+            newer_than: None,
+            no_hidden: false,
+            no_space_check: false,
+            older_than: None,
+            only_ext: Vec::new(),
+            other_bases: Vec::new(),
+            output: OutputFormat::Plain,
+            color: ColorChoice::Auto,
+            copy_missing_from_source: false,
+            copy_symlink_targets: false,
+            csv: None,
+            exclude: Vec::new(),
+            exclude_from: Vec::new(),
+            exclude_regex: Vec::new(),
+            fail_on_missing: false,
+            fallback: vec![Fallback::Alternatives, Fallback::OtherBases],
+            fallback_copy: false,
+            force: false,
+            files_from: Vec::new(),
+            filter_regex: Vec::new(),
+            ignore_file: Vec::new(),
+            include: Vec::new(),
+            include_cache_dirs: false,
+            interactive: false,
+            journal_dir: None,
+            jobs: 1,
+            keep_going: false,
+            limit: None,
+            lock_wait: Duration::from_secs(0),
+            limit_bytes: None,
+            log_level: None,
+            make_writable: false,
+            maps: Vec::new(),
+            notify_cmd: None,
+            post_hook: None,
+            pre_hook: None,
+            preserve_symlinks: false,
+            progress: false,
+            stats_json: None,
+            report: None,
+            resume: false,
+            retries: 2,
+            retry_backoff: Duration::from_secs(1),
+            sandbox: false,
+            skip_ext: Vec::new(),
+            strict: false,
+            snapper: None,
+            subvolumes: Vec::new(),
+            sync: false,
+            timestamps: false,
+            undo_log: None,
+            xattrs: false,
+            file: None,
+            job: None,
+            job_paths: None,
+            job_names: Vec::new(),
+            sources: HashMap::new(),
+        }
+    }
+}
+
+/// An option that can be set from the config file, environment, or command line.
+pub struct OptionSpec {
+    /// Name of the long flag, without leading dashes. Also the config file key.
+    pub flag: &'static str,
+    /// Name of the environment variable that sets the default.
+    pub env: &'static str,
+    /// Whether the flag is a switch that takes no value on the command line.
+    pub is_switch: bool,
+    /// Whether the flag can be repeated to build up a list of values.
+    pub is_list: bool,
+}
+
+pub const OPTIONS: &[OptionSpec] = &[
+    OptionSpec { flag: "at", env: "BTRFS_SNAPSYNC_AT", is_switch: false, is_list: false },
+    OptionSpec { flag: "backend", env: "BTRFS_SNAPSYNC_BACKEND", is_switch: false, is_list: false },
+    OptionSpec { flag: "btrbk", env: "BTRFS_SNAPSYNC_BTRBK", is_switch: false, is_list: false },
+    OptionSpec { flag: "color", env: "BTRFS_SNAPSYNC_COLOR", is_switch: false, is_list: false },
+    OptionSpec { flag: "copy-missing-from-source", env: "BTRFS_SNAPSYNC_COPY_MISSING_FROM_SOURCE", is_switch: true, is_list: false },
+    OptionSpec { flag: "copy-symlink-targets", env: "BTRFS_SNAPSYNC_COPY_SYMLINK_TARGETS", is_switch: true, is_list: false },
+    OptionSpec { flag: "csv", env: "BTRFS_SNAPSYNC_CSV", is_switch: false, is_list: false },
+    OptionSpec { flag: "every", env: "BTRFS_SNAPSYNC_EVERY", is_switch: false, is_list: false },
+    OptionSpec { flag: "exclude", env: "BTRFS_SNAPSYNC_EXCLUDE", is_switch: false, is_list: true },
+    OptionSpec { flag: "exclude-from", env: "BTRFS_SNAPSYNC_EXCLUDE_FROM", is_switch: false, is_list: true },
+    OptionSpec { flag: "exclude-regex", env: "BTRFS_SNAPSYNC_EXCLUDE_REGEX", is_switch: false, is_list: true },
+    OptionSpec { flag: "fail-on-missing", env: "BTRFS_SNAPSYNC_FAIL_ON_MISSING", is_switch: true, is_list: false },
+    OptionSpec { flag: "fallback", env: "BTRFS_SNAPSYNC_FALLBACK", is_switch: false, is_list: false },
+    OptionSpec { flag: "fallback-copy", env: "BTRFS_SNAPSYNC_FALLBACK_COPY", is_switch: true, is_list: false },
+    OptionSpec { flag: "files-from", env: "BTRFS_SNAPSYNC_FILES_FROM", is_switch: false, is_list: true },
+    OptionSpec { flag: "filter-regex", env: "BTRFS_SNAPSYNC_FILTER_REGEX", is_switch: false, is_list: true },
+    OptionSpec { flag: "force", env: "BTRFS_SNAPSYNC_FORCE", is_switch: true, is_list: false },
+    OptionSpec { flag: "ignore-file", env: "BTRFS_SNAPSYNC_IGNORE_FILE", is_switch: false, is_list: true },
+    OptionSpec { flag: "include", env: "BTRFS_SNAPSYNC_INCLUDE", is_switch: false, is_list: true },
+    OptionSpec { flag: "include-cache-dirs", env: "BTRFS_SNAPSYNC_INCLUDE_CACHE_DIRS", is_switch: true, is_list: false },
+    OptionSpec { flag: "interactive", env: "BTRFS_SNAPSYNC_INTERACTIVE", is_switch: true, is_list: false },
+    OptionSpec { flag: "jitter", env: "BTRFS_SNAPSYNC_JITTER", is_switch: false, is_list: false },
+    OptionSpec { flag: "jobs", env: "BTRFS_SNAPSYNC_JOBS", is_switch: false, is_list: false },
+    OptionSpec { flag: "journal-dir", env: "BTRFS_SNAPSYNC_JOURNAL_DIR", is_switch: false, is_list: false },
+    OptionSpec { flag: "keep-going", env: "BTRFS_SNAPSYNC_KEEP_GOING", is_switch: true, is_list: false },
+    OptionSpec { flag: "limit", env: "BTRFS_SNAPSYNC_LIMIT", is_switch: false, is_list: false },
+    OptionSpec { flag: "limit-bytes", env: "BTRFS_SNAPSYNC_LIMIT_BYTES", is_switch: false, is_list: false },
+    OptionSpec { flag: "lock-wait", env: "BTRFS_SNAPSYNC_LOCK_WAIT", is_switch: false, is_list: false },
+    OptionSpec { flag: "log-level", env: "BTRFS_SNAPSYNC_LOG_LEVEL", is_switch: false, is_list: false },
+    OptionSpec { flag: "make-writable", env: "BTRFS_SNAPSYNC_MAKE_WRITABLE", is_switch: true, is_list: false },
+    OptionSpec { flag: "map", env: "BTRFS_SNAPSYNC_MAP", is_switch: false, is_list: true },
+    OptionSpec { flag: "max-missing", env: "BTRFS_SNAPSYNC_MAX_MISSING", is_switch: false, is_list: false },
+    OptionSpec { flag: "max-size", env: "BTRFS_SNAPSYNC_MAX_SIZE", is_switch: false, is_list: false },
+    OptionSpec { flag: "metrics-file", env: "BTRFS_SNAPSYNC_METRICS_FILE", is_switch: false, is_list: false },
+    OptionSpec { flag: "min-size", env: "BTRFS_SNAPSYNC_MIN_SIZE", is_switch: false, is_list: false },
+    OptionSpec { flag: "newer-than", env: "BTRFS_SNAPSYNC_NEWER_THAN", is_switch: false, is_list: false },
+    OptionSpec { flag: "no-hidden", env: "BTRFS_SNAPSYNC_NO_HIDDEN", is_switch: true, is_list: false },
+    OptionSpec { flag: "no-space-check", env: "BTRFS_SNAPSYNC_NO_SPACE_CHECK", is_switch: true, is_list: false },
+    OptionSpec { flag: "notify-cmd", env: "BTRFS_SNAPSYNC_NOTIFY_CMD", is_switch: false, is_list: false },
+    OptionSpec { flag: "older-than", env: "BTRFS_SNAPSYNC_OLDER_THAN", is_switch: false, is_list: false },
+    OptionSpec { flag: "only-ext", env: "BTRFS_SNAPSYNC_ONLY_EXT", is_switch: false, is_list: true },
+    OptionSpec { flag: "other-base", env: "BTRFS_SNAPSYNC_OTHER_BASE", is_switch: false, is_list: true },
+    OptionSpec { flag: "output", env: "BTRFS_SNAPSYNC_OUTPUT", is_switch: false, is_list: false },
+    OptionSpec { flag: "post-hook", env: "BTRFS_SNAPSYNC_POST_HOOK", is_switch: false, is_list: false },
+    OptionSpec { flag: "pre-hook", env: "BTRFS_SNAPSYNC_PRE_HOOK", is_switch: false, is_list: false },
+    OptionSpec { flag: "preserve-symlinks", env: "BTRFS_SNAPSYNC_PRESERVE_SYMLINKS", is_switch: true, is_list: false },
+    OptionSpec { flag: "progress", env: "BTRFS_SNAPSYNC_PROGRESS", is_switch: true, is_list: false },
+    OptionSpec { flag: "report", env: "BTRFS_SNAPSYNC_REPORT", is_switch: false, is_list: false },
+    OptionSpec { flag: "resume", env: "BTRFS_SNAPSYNC_RESUME", is_switch: true, is_list: false },
+    OptionSpec { flag: "retries", env: "BTRFS_SNAPSYNC_RETRIES", is_switch: false, is_list: false },
+    OptionSpec { flag: "retry-backoff", env: "BTRFS_SNAPSYNC_RETRY_BACKOFF", is_switch: false, is_list: false },
+    OptionSpec { flag: "sandbox", env: "BTRFS_SNAPSYNC_SANDBOX", is_switch: true, is_list: false },
+    OptionSpec { flag: "skip-ext", env: "BTRFS_SNAPSYNC_SKIP_EXT", is_switch: false, is_list: true },
+    OptionSpec { flag: "snapper", env: "BTRFS_SNAPSYNC_SNAPPER", is_switch: false, is_list: false },
+    OptionSpec { flag: "stats-json", env: "BTRFS_SNAPSYNC_STATS_JSON", is_switch: false, is_list: false },
+    OptionSpec { flag: "strict", env: "BTRFS_SNAPSYNC_STRICT", is_switch: true, is_list: false },
+    OptionSpec { flag: "subvolume", env: "BTRFS_SNAPSYNC_SUBVOLUME", is_switch: false, is_list: true },
+    OptionSpec { flag: "sync", env: "BTRFS_SNAPSYNC_SYNC", is_switch: true, is_list: false },
+    OptionSpec { flag: "timestamps", env: "BTRFS_SNAPSYNC_TIMESTAMPS", is_switch: true, is_list: false },
+    OptionSpec { flag: "undo-log", env: "BTRFS_SNAPSYNC_UNDO_LOG", is_switch: false, is_list: false },
+    OptionSpec { flag: "xattrs", env: "BTRFS_SNAPSYNC_XATTRS", is_switch: true, is_list: false },
+];
+
+/// The operation that creates the copy for a move.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backend {
+    /// Share extents with the source, with the FICLONE ioctl.
+    Reflink,
+    /// Share extents with the source, in chunks, with the FICLONERANGE ioctl.
+    ReflinkRange,
+    /// Copy the data in the kernel, with copy_file_range(2).
+    CopyFileRange,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Reflink => "reflink",
+            Backend::ReflinkRange => "reflink-range",
+            Backend::CopyFileRange => "copy-file-range",
+        }
+    }
+}
+
+/// When to color the output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorChoice {
+    /// Color if the output is a terminal, and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        }
+    }
+
+    /// Decide whether to use color.
+    ///
+    /// From low to high precedence: whether the output is a terminal, the
+    /// `NO_COLOR` environment variable, and an explicit `always` or `never`.
+    /// Following https://no-color.org, `NO_COLOR` counts if it is non-empty.
+    pub fn resolve(self, no_color: Option<OsString>, is_tty: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => is_tty && no_color.map_or(true, |v| v.is_empty()),
+        }
+    }
+}
+
+/// How to print the plan and the applied moves to stdout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// One line per move, with paths formatted as Rust string literals.
+    Plain,
+    /// Colored, tagged lines with aligned columns, for reviewing by eye.
+    Human,
+    /// One JSON object per line for every event, for other tools to consume.
+    Json,
+    /// The source and destination of every operation, each terminated by a
+    /// NUL byte, for `xargs -0`.
+    Nul,
+}
+
+impl OutputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Plain => "plain",
+            OutputFormat::Human => "human",
+            OutputFormat::Json => "json",
+            OutputFormat::Nul => "nul",
+        }
+    }
+}
+
+/// A place to look for the source of a move, when it is missing in dst-base.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fallback {
+    /// Other base files that matched the target file, in dst-base.
+    Alternatives,
+    /// The source path and its alternatives in dst-target, e.g. when a
+    /// previous run or rsync already put the file there.
+    DstTarget,
+    /// The source path and its alternatives in the other snapshots of
+    /// --other-base, and in chain mode in the earlier snapshots.
+    OtherBases,
+}
+
+impl Fallback {
+    pub fn name(self) -> &'static str {
+        match self {
+            Fallback::Alternatives => "alternatives",
+            Fallback::DstTarget => "dst-target",
+            Fallback::OtherBases => "other-bases",
+        }
+    }
+}
+
+/// A time of the day in UTC, as the number of seconds since midnight.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct TimeOfDay(pub u32);
+
+impl fmt::Display for TimeOfDay {
+    /// Format the time like --at takes it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 3600, self.0 / 60 % 60)
+    }
+}
+
+/// A number of files, or a percentage of all files, like --max-missing takes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    Count(usize),
+    Percent(f64),
+}
+
+impl Threshold {
+    /// Return whether `n` of `total` files is more than the threshold.
+    pub fn is_exceeded(self, n: usize, total: usize) -> bool {
+        match self {
+            Threshold::Count(max) => n > max,
+            Threshold::Percent(max) => total > 0 && n as f64 * 100.0 / total as f64 > max,
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Threshold::Count(n) => write!(f, "{}", n),
+            Threshold::Percent(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+/// A pair of source trees whose diff maps to a subdirectory of the destination.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Subvolume {
+    /// The subdirectory, relative to dst-base and dst-target.
+    pub dir: PathBuf,
+    pub src_base: PathBuf,
+    pub src_target: PathBuf,
+}
+
+impl fmt::Display for Subvolume {
+    /// Format the subvolume like --subvolume takes it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={},{}", self.dir.display(), self.src_base.display(), self.src_target.display())
+    }
+}
+
+/// A rule to put the files under a directory of the source trees under a
+/// different directory in the destination trees.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathMap {
+    pub src_prefix: PathBuf,
+    pub dst_prefix: PathBuf,
+}
+
+impl PathMap {
+    /// Rewrite the path if it starts with the source prefix, or is equal to it.
+    pub fn apply(&self, path: &Path) -> Option<PathBuf> {
+        match path.strip_prefix(&self.src_prefix) {
+            // Joining an empty path would add a trailing slash.
+            Ok(rest) if rest.as_os_str().is_empty() => Some(self.dst_prefix.clone()),
+            Ok(rest) => Some(self.dst_prefix.join(rest)),
+            Err(..) => None,
+        }
+    }
+}
+
+impl fmt::Display for PathMap {
+    /// Format the rule like --map takes it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.src_prefix.display(), self.dst_prefix.display())
+    }
+}
+
+/// Where the value of an option came from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf, usize),
+    Env(&'static str),
+    Flag,
+}
+
+impl Source {
+    /// Return the rank of the layer, higher layers override lower ones.
+    fn layer(&self) -> u8 {
+        match *self {
+            Source::Default => 0,
+            Source::File(..) => 1,
+            Source::Env(..) => 2,
+            Source::Flag => 3,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "built-in default"),
+            Source::File(ref path, line) => write!(f, "config file {}, line {}", path.display(), line),
+            Source::Env(var) => write!(f, "environment variable {}", var),
+            Source::Flag => write!(f, "command line"),
+        }
+    }
+}
+
+/// The inputs that make up a configuration.
+pub struct Layers<'a> {
+    /// Path and contents of the config file, if there is one.
+    pub file: Option<(&'a Path, &'a str)>,
+    /// Lookup of environment variables.
+    pub env: &'a dyn Fn(&str) -> Option<OsString>,
+    /// Flags from the command line, as (name, value) pairs, in order.
+    pub flags: &'a [(String, String)],
+    /// The job to take the paths and options from, a table in the config file.
+    pub job: Option<&'a str>,
+}
+
+/// The keys in a `[job.<name>]` table for the paths, in the order of the arguments.
+pub const JOB_PATHS: &[&str] = &["src-base", "src-target", "dst-base", "dst-target"];
+
+pub fn find_option(flag: &str) -> Option<&'static OptionSpec> {
+    OPTIONS.iter().find(|spec| spec.flag == flag)
+}
+
+/// Parse a number of bytes, with an optional binary suffix K, M, G, or T.
+fn parse_size(flag: &str, value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        Some('T') => (&value[..value.len() - 1], 1 << 40),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid value for --{}: '{}', expected a size like 8192 or 64K.", flag, value))
+}
+
+/// Parse a size, where the empty string means no limit.
+fn parse_optional_size(flag: &str, value: &str) -> Result<Option<u64>, String> {
+    if value.is_empty() { Ok(None) } else { parse_size(flag, value).map(Some) }
+}
+
+fn parse_jobs(flag: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid value for --{}: '{}', expected a positive number.", flag, value)),
+    }
+}
+
+/// Parse a count like `50`, or a percentage like `10%`, where the empty
+/// string means no threshold.
+fn parse_threshold(flag: &str, value: &str) -> Result<Option<Threshold>, String> {
+    let error = || format!("Invalid value for --{}: '{}', expected a number, or a percentage like 10%.", flag, value);
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if value.ends_with('%') {
+        match value[..value.len() - 1].parse::<f64>() {
+            Ok(p) if p >= 0.0 && p <= 100.0 => Ok(Some(Threshold::Percent(p))),
+            _ => Err(error()),
+        }
+    } else {
+        value.parse().map(|n| Some(Threshold::Count(n))).map_err(|_| error())
+    }
+}
+
+fn parse_count(flag: &str, value: &str) -> Result<u32, String> {
+    value.parse().map_err(|_| format!("Invalid value for --{}: '{}', expected a number.", flag, value))
+}
+
+fn parse_optional_count(flag: &str, value: &str) -> Result<Option<usize>, String> {
+    if value.is_empty() { Ok(None) } else { parse_jobs(flag, value).map(Some) }
+}
+
+/// Parse a comma-separated list of `HH:MM` times, in UTC.
+fn parse_times_of_day(flag: &str, value: &str) -> Result<Vec<TimeOfDay>, String> {
+    let error = || format!("Invalid value for --{}: '{}', expected times like 03:00 or 03:00,15:00.", flag, value);
+    let mut times = Vec::new();
+    for time in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let colon = time.find(':').ok_or_else(error)?;
+        let hour: u32 = time[..colon].parse().map_err(|_| error())?;
+        let minute: u32 = time[colon + 1..].parse().map_err(|_| error())?;
+        if hour > 23 || minute > 59 {
+            return Err(error());
+        }
+        times.push(TimeOfDay(hour * 3600 + minute * 60));
+    }
+    Ok(times)
+}
+
+/// The units of durations, largest first, with their length in seconds.
+const DURATION_UNITS: &[(char, u64)] = &[('w', 7 * 86_400), ('d', 86_400), ('h', 3600), ('m', 60), ('s', 1)];
+
+/// Parse a duration like `90s`, `15m`, `1h`, `1d`, or `1w`.
+fn parse_duration(flag: &str, value: &str) -> Result<Duration, String> {
+    let unit = value.chars().last().and_then(|c| DURATION_UNITS.iter().find(|&&(u, _)| u == c));
+    let secs = unit.and_then(|&(_, unit_secs)| {
+        let n: u64 = value[..value.len() - 1].parse().ok()?;
+        n.checked_mul(unit_secs)
+    });
+    match secs {
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!(
+            "Invalid value for --{}: '{}', expected a duration with unit s, m, h, d, or w, like 1h.", flag, value,
+        )),
+    }
+}
+
+/// Format a duration like --every takes it, in the largest unit that fits exactly.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let &(unit, unit_secs) = DURATION_UNITS
+        .iter()
+        .find(|&&(_, unit_secs)| secs % unit_secs == 0 && secs > 0)
+        .unwrap_or(&('s', 1));
+    format!("{}{}", secs / unit_secs, unit)
+}
+
+fn parse_log_level(flag: &str, value: &str) -> Result<Option<LevelFilter>, String> {
+    match value {
+        "" => Ok(None),
+        "off" => Ok(Some(LevelFilter::Off)),
+        "error" => Ok(Some(LevelFilter::Error)),
+        "warn" => Ok(Some(LevelFilter::Warn)),
+        "info" => Ok(Some(LevelFilter::Info)),
+        "debug" => Ok(Some(LevelFilter::Debug)),
+        "trace" => Ok(Some(LevelFilter::Trace)),
+        _ => Err(format!(
+            "Invalid value for --{}: '{}', expected 'off', 'error', 'warn', 'info', 'debug', or 'trace'.", flag, value,
+        )),
+    }
+}
+
+fn log_level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+/// Return whether the path is a non-empty relative path that does not leave the tree.
+fn is_inside_tree(path: &Path) -> bool {
+    !path.as_os_str().is_empty() && path.components().all(|c| match c {
+        Component::Normal(..) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+/// Parse `<src-prefix>=<dst-prefix>`, where both are relative paths inside the tree.
+fn parse_map(flag: &str, value: &str) -> Result<PathMap, String> {
+    let eq = value
+        .find('=')
+        .ok_or_else(|| format!("Invalid value for --{}: '{}', expected <src-prefix>=<dst-prefix>.", flag, value))?;
+    let (src_prefix, dst_prefix) = (Path::new(&value[..eq]), Path::new(&value[eq + 1..]));
+    if !is_inside_tree(src_prefix) || !is_inside_tree(dst_prefix) {
+        return Err(format!(
+            "Invalid value for --{}: '{}', both prefixes must be relative paths inside the trees.",
+            flag, value,
+        ));
+    }
+    Ok(PathMap {
+        src_prefix: src_prefix.to_path_buf(),
+        dst_prefix: dst_prefix.to_path_buf(),
+    })
+}
+
+/// Parse `<dir>=<src-base>,<src-target>`, where dir is a relative path that stays inside the tree.
+fn parse_subvolume(flag: &str, value: &str) -> Result<Subvolume, String> {
+    let error = || format!("Invalid value for --{}: '{}', expected <dir>=<src-base>,<src-target>.", flag, value);
+    let eq = value.find('=').ok_or_else(error)?;
+    let (dir, paths) = (Path::new(&value[..eq]), &value[eq + 1..]);
+    let comma = paths.find(',').ok_or_else(error)?;
+    let (src_base, src_target) = (&paths[..comma], &paths[comma + 1..]);
+    if src_base.is_empty() || src_target.is_empty() {
+        return Err(error());
+    }
+    if !is_inside_tree(dir) {
+        return Err(format!(
+            "Invalid value for --{}: '{}', the directory must be a relative path inside the destination trees.",
+            flag, value,
+        ));
+    }
+    Ok(Subvolume {
+        dir: dir.to_path_buf(),
+        src_base: PathBuf::from(src_base),
+        src_target: PathBuf::from(src_target),
+    })
+}
+
+fn parse_backend(flag: &str, value: &str) -> Result<Backend, String> {
+    match value {
+        "reflink" => Ok(Backend::Reflink),
+        "reflink-range" => Ok(Backend::ReflinkRange),
+        "copy-file-range" => Ok(Backend::CopyFileRange),
+        _ => Err(format!(
+            "Invalid value for --{}: '{}', expected 'reflink', 'reflink-range', or 'copy-file-range'.", flag, value,
+        )),
+    }
+}
+
+fn parse_color(flag: &str, value: &str) -> Result<ColorChoice, String> {
+    match value {
+        "auto" => Ok(ColorChoice::Auto),
+        "always" => Ok(ColorChoice::Always),
+        "never" => Ok(ColorChoice::Never),
+        _ => Err(format!("Invalid value for --{}: '{}', expected 'auto', 'always', or 'never'.", flag, value)),
+    }
+}
+
+fn parse_output(flag: &str, value: &str) -> Result<OutputFormat, String> {
+    match value {
+        "plain" => Ok(OutputFormat::Plain),
+        "human" => Ok(OutputFormat::Human),
+        "json" => Ok(OutputFormat::Json),
+        "nul" => Ok(OutputFormat::Nul),
+        _ => Err(format!(
+            "Invalid value for --{}: '{}', expected 'plain', 'human', 'json', or 'nul'.", flag, value,
+        )),
+    }
+}
+
+fn parse_fallbacks(flag: &str, value: &str) -> Result<Vec<Fallback>, String> {
+    let mut result = Vec::new();
+    for name in value.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        match name {
+            "alternatives" => result.push(Fallback::Alternatives),
+            "dst-target" => result.push(Fallback::DstTarget),
+            "other-bases" => result.push(Fallback::OtherBases),
+            _ => return Err(format!(
+                "Invalid value for --{}: '{}', expected 'alternatives', 'dst-target', or 'other-bases'.", flag, name,
+            )),
+        }
+    }
+    Ok(result)
+}
+
+/// Parse a path, where the empty string means no path.
+fn parse_optional_path(value: &str) -> Option<PathBuf> {
+    if value.is_empty() { None } else { Some(PathBuf::from(value)) }
+}
+
+/// Parse a string, where the empty string means no value.
+fn parse_optional_string(value: &str) -> Option<String> {
+    if value.is_empty() { None } else { Some(value.to_string()) }
+}
+
+/// Parse a comma-separated list of file extensions, with or without dot.
+fn parse_extensions(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+fn string_list_value<T: fmt::Display>(items: &[T]) -> toml::Value {
+    toml::Value::Array(items.iter().map(|item| toml::Value::String(item.to_string())).collect())
+}
+
+fn optional_path_value(path: &Option<PathBuf>) -> toml::Value {
+    toml::Value::String(path.as_ref().map(|p| p.display().to_string()).unwrap_or_default())
+}
+
+fn parse_bool(flag: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("Invalid value for --{}: '{}', expected true or false.", flag, value)),
+    }
+}
+
+impl Config {
+    /// Set the option with the given flag name from its string value.
+    fn set(&mut self, flag: &str, value: &str) -> Result<(), String> {
+        match flag {
+            "at" => self.at = parse_times_of_day(flag, value)?,
+            "backend" => self.backend = parse_backend(flag, value)?,
+            "btrbk" => self.btrbk = parse_optional_path(value),
+            "color" => self.color = parse_color(flag, value)?,
+            "copy-missing-from-source" => self.copy_missing_from_source = parse_bool(flag, value)?,
+            "copy-symlink-targets" => self.copy_symlink_targets = parse_bool(flag, value)?,
+            "csv" => self.csv = parse_optional_path(value),
+            "every" => self.every = if value.is_empty() { None } else { Some(parse_duration(flag, value)?) },
+            "exclude" => self.exclude.push(value.to_string()),
+            "exclude-from" => self.exclude_from.push(PathBuf::from(value)),
+            "exclude-regex" => self.exclude_regex.push(value.to_string()),
+            "fail-on-missing" => self.fail_on_missing = parse_bool(flag, value)?,
+            "fallback" => self.fallback = parse_fallbacks(flag, value)?,
+            "fallback-copy" => self.fallback_copy = parse_bool(flag, value)?,
+            "files-from" => self.files_from.push(PathBuf::from(value)),
+            "force" => self.force = parse_bool(flag, value)?,
+            "filter-regex" => self.filter_regex.push(value.to_string()),
+            "ignore-file" => self.ignore_file.push(PathBuf::from(value)),
+            "include" => self.include.push(value.to_string()),
+            "include-cache-dirs" => self.include_cache_dirs = parse_bool(flag, value)?,
+            "interactive" => self.interactive = parse_bool(flag, value)?,
+            "jitter" => self.jitter = parse_duration(flag, value)?,
+            "jobs" => self.jobs = parse_jobs(flag, value)?,
+            "journal-dir" => self.journal_dir = parse_optional_path(value),
+            "keep-going" => self.keep_going = parse_bool(flag, value)?,
+            "limit" => self.limit = parse_optional_count(flag, value)?,
+            "limit-bytes" => self.limit_bytes = parse_optional_size(flag, value)?,
+            "lock-wait" => self.lock_wait = parse_duration(flag, value)?,
+            "log-level" => self.log_level = parse_log_level(flag, value)?,
+            "make-writable" => self.make_writable = parse_bool(flag, value)?,
+            "map" => self.maps.push(parse_map(flag, value)?),
+            "metrics-file" => self.metrics_file = parse_optional_path(value),
+            "max-missing" => self.max_missing = parse_threshold(flag, value)?,
+            "max-size" => self.max_size = parse_optional_size(flag, value)?,
+            "min-size" => self.min_size = parse_size(flag, value)?,
+            "newer-than" => self.newer_than = parse_optional_string(value),
+            "no-hidden" => self.no_hidden = parse_bool(flag, value)?,
+            "no-space-check" => self.no_space_check = parse_bool(flag, value)?,
+            "notify-cmd" => self.notify_cmd = parse_optional_string(value),
+            "older-than" => self.older_than = parse_optional_string(value),
+            "only-ext" => self.only_ext.extend(parse_extensions(value)),
+            "other-base" => self.other_bases.push(PathBuf::from(value)),
+            "output" => self.output = parse_output(flag, value)?,
+            "post-hook" => self.post_hook = parse_optional_string(value),
+            "pre-hook" => self.pre_hook = parse_optional_string(value),
+            "preserve-symlinks" => self.preserve_symlinks = parse_bool(flag, value)?,
+            "progress" => self.progress = parse_bool(flag, value)?,
+            "report" => self.report = parse_optional_path(value),
+            "resume" => self.resume = parse_bool(flag, value)?,
+            "retries" => self.retries = parse_count(flag, value)?,
+            "retry-backoff" => self.retry_backoff = parse_duration(flag, value)?,
+            "sandbox" => self.sandbox = parse_bool(flag, value)?,
+            "skip-ext" => self.skip_ext.extend(parse_extensions(value)),
+            "snapper" => self.snapper = parse_optional_string(value),
+            "subvolume" => self.subvolumes.push(parse_subvolume(flag, value)?),
+            "sync" => self.sync = parse_bool(flag, value)?,
+            "stats-json" => self.stats_json = parse_optional_path(value),
+            "strict" => self.strict = parse_bool(flag, value)?,
+            "timestamps" => self.timestamps = parse_bool(flag, value)?,
+            "undo-log" => self.undo_log = parse_optional_path(value),
+            "xattrs" => self.xattrs = parse_bool(flag, value)?,
+            _ => unreachable!("Option --{} is in OPTIONS but not handled.", flag),
+        }
+        Ok(())
+    }
+
+    /// Return the current value of the option, as it would be written in the config file.
+    fn get(&self, flag: &str) -> toml::Value {
+        match flag {
+            "at" => toml::Value::String(self.at.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(",")),
+            "backend" => toml::Value::String(self.backend.name().to_string()),
+            "btrbk" => optional_path_value(&self.btrbk),
+            "color" => toml::Value::String(self.color.name().to_string()),
+            "copy-missing-from-source" => toml::Value::Boolean(self.copy_missing_from_source),
+            "copy-symlink-targets" => toml::Value::Boolean(self.copy_symlink_targets),
+            "csv" => optional_path_value(&self.csv),
+            "every" => toml::Value::String(self.every.map_or(String::new(), format_duration)),
+            "exclude" => string_list_value(&self.exclude),
+            "exclude-from" => {
+                let paths: Vec<_> = self.exclude_from.iter().map(|p| p.display()).collect();
+                string_list_value(&paths)
+            }
+            "exclude-regex" => string_list_value(&self.exclude_regex),
+            "fail-on-missing" => toml::Value::Boolean(self.fail_on_missing),
+            "fallback" => {
+                let names: Vec<&str> = self.fallback.iter().map(|f| f.name()).collect();
+                toml::Value::String(names.join(","))
+            }
+            "fallback-copy" => toml::Value::Boolean(self.fallback_copy),
+            "force" => toml::Value::Boolean(self.force),
+            "files-from" => {
+                let paths: Vec<_> = self.files_from.iter().map(|p| p.display()).collect();
+                string_list_value(&paths)
+            }
+            "filter-regex" => string_list_value(&self.filter_regex),
+            "ignore-file" => {
+                let paths: Vec<_> = self.ignore_file.iter().map(|p| p.display()).collect();
+                string_list_value(&paths)
+            }
+            "include" => string_list_value(&self.include),
+            "include-cache-dirs" => toml::Value::Boolean(self.include_cache_dirs),
+            "interactive" => toml::Value::Boolean(self.interactive),
+            "jitter" => toml::Value::String(format_duration(self.jitter)),
+            "jobs" => toml::Value::Integer(self.jobs as i64),
+            "journal-dir" => optional_path_value(&self.journal_dir),
+            "keep-going" => toml::Value::Boolean(self.keep_going),
+            "limit" => match self.limit {
+                Some(n) => toml::Value::Integer(n as i64),
+                None => toml::Value::String(String::new()),
+            },
+            "limit-bytes" => match self.limit_bytes {
+                Some(n) => toml::Value::Integer(n as i64),
+                None => toml::Value::String(String::new()),
+            },
+            "lock-wait" => toml::Value::String(format_duration(self.lock_wait)),
+            "log-level" => toml::Value::String(self.log_level.map_or("", log_level_name).to_string()),
+            "make-writable" => toml::Value::Boolean(self.make_writable),
+            "map" => string_list_value(&self.maps),
+            "metrics-file" => optional_path_value(&self.metrics_file),
+            "max-missing" => toml::Value::String(self.max_missing.map_or(String::new(), |t| t.to_string())),
+            "max-size" => match self.max_size {
+                Some(n) => toml::Value::Integer(n as i64),
+                None => toml::Value::String(String::new()),
+            },
+            "min-size" => toml::Value::Integer(self.min_size as i64),
+            "newer-than" => toml::Value::String(self.newer_than.clone().unwrap_or_default()),
+            "no-hidden" => toml::Value::Boolean(self.no_hidden),
+            "no-space-check" => toml::Value::Boolean(self.no_space_check),
+            "notify-cmd" => toml::Value::String(self.notify_cmd.clone().unwrap_or_default()),
+            "older-than" => toml::Value::String(self.older_than.clone().unwrap_or_default()),
+            "only-ext" => string_list_value(&self.only_ext),
+            "other-base" => {
+                let paths: Vec<_> = self.other_bases.iter().map(|p| p.display()).collect();
+                string_list_value(&paths)
+            }
+            "output" => toml::Value::String(self.output.name().to_string()),
+            "post-hook" => toml::Value::String(self.post_hook.clone().unwrap_or_default()),
+            "pre-hook" => toml::Value::String(self.pre_hook.clone().unwrap_or_default()),
+            "preserve-symlinks" => toml::Value::Boolean(self.preserve_symlinks),
+            "progress" => toml::Value::Boolean(self.progress),
+            "report" => optional_path_value(&self.report),
+            "resume" => toml::Value::Boolean(self.resume),
+            "retries" => toml::Value::Integer(i64::from(self.retries)),
+            "retry-backoff" => toml::Value::String(format_duration(self.retry_backoff)),
+            "sandbox" => toml::Value::Boolean(self.sandbox),
+            "skip-ext" => string_list_value(&self.skip_ext),
+            "snapper" => toml::Value::String(self.snapper.clone().unwrap_or_default()),
+            "subvolume" => string_list_value(&self.subvolumes),
+            "sync" => toml::Value::Boolean(self.sync),
+            "stats-json" => optional_path_value(&self.stats_json),
+            "strict" => toml::Value::Boolean(self.strict),
+            "timestamps" => toml::Value::Boolean(self.timestamps),
+            "undo-log" => optional_path_value(&self.undo_log),
+            "xattrs" => toml::Value::Boolean(self.xattrs),
+            _ => unreachable!("Option --{} is in OPTIONS but not handled.", flag),
+        }
+    }
+
+    /// Clear a list option, so a higher layer can replace it.
+    fn clear(&mut self, flag: &str) {
+        match flag {
+            "exclude" => self.exclude.clear(),
+            "exclude-from" => self.exclude_from.clear(),
+            "exclude-regex" => self.exclude_regex.clear(),
+            "files-from" => self.files_from.clear(),
+            "filter-regex" => self.filter_regex.clear(),
+            "ignore-file" => self.ignore_file.clear(),
+            "include" => self.include.clear(),
+            "map" => self.maps.clear(),
+            "only-ext" => self.only_ext.clear(),
+            "other-base" => self.other_bases.clear(),
+            "skip-ext" => self.skip_ext.clear(),
+            "subvolume" => self.subvolumes.clear(),
+            _ => unreachable!("Option --{} is not a list.", flag),
+        }
+    }
+
+    /// Set the option from a string, and record where the value came from.
+    ///
+    /// For list options, values from the same layer accumulate, and the first
+    /// value from a higher layer replaces those of the layers below it.
+    fn set_from(&mut self, spec: &'static OptionSpec, value: &str, source: Source) -> Result<(), String> {
+        if spec.is_list && self.source(spec.flag).layer() < source.layer() {
+            self.clear(spec.flag);
+        }
+        self.set(spec.flag, value)?;
+        self.sources.insert(spec.flag, source);
+        Ok(())
+    }
+
+    /// Return where the value of the option with the given flag name came from.
+    pub fn source(&self, flag: &str) -> &Source {
+        self.sources.get(flag).unwrap_or(&Source::Default)
+    }
+
+    /// Build the configuration from its layers.
+    ///
+    /// This is the one place that defines precedence. From low to high:
+    /// built-in defaults, config file, environment, command line. A layer that
+    /// sets an option replaces the value from all layers below it.
+    pub fn resolve(layers: &Layers) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        match (layers.file, layers.job) {
+            (Some((path, contents)), job) => config.apply_file(path, contents, job)?,
+            (None, Some(job)) => return Err(format!("No config file found to read job '{}' from.", job)),
+            (None, None) => {}
+        }
+
+        for spec in OPTIONS {
+            let env = spec.env;
+            let value = match (layers.env)(env) {
+                Some(value) => value,
+                None => continue,
+            };
+            let value = value
+                .into_string()
+                .map_err(|_| format!("Environment variable {} is not valid UTF-8.", env))?;
+            // List options take multiple values separated by colons, like PATH.
+            let values: Vec<&str> = if spec.is_list {
+                value.split(':').filter(|v| !v.is_empty()).collect()
+            } else {
+                vec![&value[..]]
+            };
+            for v in values {
+                config
+                    .set_from(spec, v, Source::Env(env))
+                    .map_err(|e| format!("In environment variable {}: {}", env, e))?;
+            }
+        }
+
+        for &(ref flag, ref value) in layers.flags {
+            match find_option(flag) {
+                Some(spec) => config.set_from(spec, value, Source::Flag)?,
+                None => return Err(format!("Unknown option --{}.", flag)),
+            }
+        }
+
+        if config.copy_symlink_targets && config.preserve_symlinks {
+            return Err("Options --copy-symlink-targets and --preserve-symlinks cannot be combined.".to_string());
+        }
+
+        Ok(config)
+    }
+
+    /// Override defaults with the options in a config file, and with those of
+    /// the `[job.<name>]` table for the selected job, if any.
+    ///
+    /// Unknown keys are reported as a warning, but do not fail the load.
+    fn apply_file(&mut self, path: &Path, contents: &str, job: Option<&str>) -> Result<(), String> {
+        let tables = toml::parse(contents)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))?;
+
+        let mut job_table = None;
+        for table in tables {
+            let job_name = match &table.name[..] {
+                [kind, name] if kind == "job" => Some(name.clone()),
+                _ => None,
+            };
+            match job_name {
+                _ if table.name.is_empty() => {
+                    for entry in table.entries {
+                        self.apply_entry(path, entry)?;
+                    }
+                }
+                Some(name) => {
+                    if Some(&name[..]) == job {
+                        job_table = Some(table);
+                    }
+                    self.job_names.push(name);
+                }
+                None => warn!(
+                    "Ignoring unknown table [{}] in {}, line {}.",
+                    table.name.join("."), path.display(), table.line,
+                ),
+            }
+        }
+
+        // The job table comes after the top level, so its options override
+        // those, and its list options add to the ones at the top level.
+        if let Some(job) = job {
+            match job_table {
+                Some(table) => self.apply_job(path, job, table)?,
+                None if self.job_names.is_empty() => {
+                    return Err(format!("Config file {} defines no jobs, expected a [job.{}] table.", path.display(), job));
+                }
+                None => {
+                    return Err(format!(
+                        "Config file {} has no job '{}', expected one of: {}.",
+                        path.display(), job, self.job_names.join(", "),
+                    ));
+                }
+            }
+        }
+
+        self.file = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Set the paths and options of the selected job from its table.
+    fn apply_job(&mut self, path: &Path, job: &str, table: toml::Table) -> Result<(), String> {
+        let mut paths: Vec<Option<PathBuf>> = vec![None; JOB_PATHS.len()];
+        for entry in table.entries {
+            match JOB_PATHS.iter().position(|&key| key == entry.key) {
+                Some(i) => match entry.value {
+                    toml::Value::String(s) => paths[i] = Some(PathBuf::from(s)),
+                    _ => {
+                        return Err(format!(
+                            "In config file {}, line {}: Expected a string for {}.",
+                            path.display(), entry.line, entry.key,
+                        ));
+                    }
+                },
+                None => self.apply_entry(path, entry)?,
+            }
+        }
+        let mut result = Vec::with_capacity(JOB_PATHS.len());
+        for (key, path_opt) in JOB_PATHS.iter().zip(paths) {
+            match path_opt {
+                Some(p) => result.push(p),
+                None => {
+                    return Err(format!(
+                        "Job '{}' in config file {}, line {}, does not set {}.",
+                        job, path.display(), table.line, key,
+                    ));
+                }
+            }
+        }
+        self.job = Some(job.to_string());
+        self.job_paths = Some(result);
+        Ok(())
+    }
+
+    /// Set the option for one key of a config file table.
+    fn apply_entry(&mut self, path: &Path, entry: toml::Entry) -> Result<(), String> {
+        let line = entry.line;
+        let spec = match find_option(&entry.key) {
+            Some(spec) => spec,
+            None => {
+                warn!(
+                    "Ignoring unknown key '{}' in {}, line {}.",
+                    entry.key, path.display(), entry.line,
+                );
+                return Ok(())
+            }
+        };
+        let values = match entry.value {
+            toml::Value::Array(values) if spec.is_list => values,
+            toml::Value::Array(..) => {
+                return Err(format!(
+                    "In config file {}, line {}: Expected a single value for {}, not an array.",
+                    path.display(), entry.line, entry.key,
+                ));
+            }
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                toml::Value::Array(..) => {
+                    return Err(format!(
+                        "In config file {}, line {}: Expected a string for {}, not a nested array.",
+                        path.display(), entry.line, entry.key,
+                    ));
+                }
+            };
+            let source = Source::File(path.to_path_buf(), line);
+            self.set_from(spec, &value, source).map_err(|e| {
+                format!("In config file {}, line {}: {}", path.display(), line, e)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Resolve the configuration for this process from its arguments.
+    ///
+    /// The config file is read from the path passed with `--config`, or the
+    /// `BTRFS_SNAPSYNC_CONFIG` environment variable, or the default location, in
+    /// that order. If an explicitly passed file does not exist, that is an error.
+    pub fn load(args: &Args) -> Result<Config, String> {
+        let explicit_path = args
+            .config
+            .clone()
+            .or_else(|| env::var_os("BTRFS_SNAPSYNC_CONFIG").map(PathBuf::from));
+        let path = match explicit_path {
+            Some(path) => Some(path),
+            None => default_config_path().filter(|path| path.is_file()),
+        };
+        let file = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+                Some((path, contents))
+            }
+            None => None,
+        };
+
+        let layers = Layers {
+            file: file.as_ref().map(|&(ref path, ref contents)| (path.as_path(), &contents[..])),
+            env: &|name| env::var_os(name),
+            flags: &args.flags,
+            job: args.job.as_ref().map(|job| &job[..]),
+        };
+        Config::resolve(&layers)
+    }
+
+    /// Return the resolved options as a JSON object, keyed by flag name.
+    pub fn to_json(&self) -> Json {
+        fn convert(value: toml::Value) -> Json {
+            match value {
+                toml::Value::String(s) => Json::String(s),
+                toml::Value::Integer(i) => Json::Int(i),
+                toml::Value::Boolean(b) => Json::Bool(b),
+                toml::Value::Array(values) => Json::Array(values.into_iter().map(convert).collect()),
+            }
+        }
+        Json::object(OPTIONS.iter().map(|spec| (spec.flag, convert(self.get(spec.flag)))).collect())
+    }
+
+    /// Print the resolved configuration in config file format, with sources.
+    pub fn print(&self) {
+        match self.file {
+            Some(ref path) => println!("# Config file: {}", path.display()),
+            None => println!("# Config file: none"),
+        }
+        if !self.job_names.is_empty() {
+            println!("# Jobs: {}", self.job_names.join(", "));
+        }
+        if let (Some(job), Some(paths)) = (&self.job, &self.job_paths) {
+            println!("# Job: {}", job);
+            for (key, path) in JOB_PATHS.iter().zip(paths) {
+                println!("#   {} = {}", key, toml::Value::String(path.display().to_string()));
+            }
+        }
+        for spec in OPTIONS {
+            println!("{} = {}  # {}", spec.flag, self.get(spec.flag), self.source(spec.flag));
+        }
+    }
+}
+
+/// Return `$XDG_CONFIG_HOME/btrfs-snapsync/config.toml`, or with `~/.config`
+/// if `XDG_CONFIG_HOME` is not set.
+pub fn default_config_path() -> Option<PathBuf> {
+    let mut path = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut home = PathBuf::from(env::var_os("HOME")?);
+            home.push(".config");
+            home
+        }
+    };
+    path.push("btrfs-snapsync");
+    path.push("config.toml");
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolve a config from the layers, with the environment from a list.
+    fn resolve(file: Option<&str>, env: &[(&str, &str)], flags: &[(&str, &str)]) -> Config {
+        let env_vars: Vec<(String, OsString)> = env.iter().map(|&(k, v)| (k.to_string(), OsString::from(v))).collect();
+        let lookup = |name: &str| env_vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+        let flags: Vec<(String, String)> = flags.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        let layers = Layers {
+            file: file.map(|contents| (Path::new("test.toml"), contents)),
+            env: &lookup,
+            flags: &flags,
+            job: None,
+        };
+        Config::resolve(&layers).expect("The config is valid.")
+    }
+
+    #[test]
+    fn defaults_apply_without_layers() {
+        let config = resolve(None, &[], &[]);
+        assert_eq!(config.jobs, 1);
+        assert_eq!(config.source("jobs"), &Source::Default);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = resolve(Some("jobs = 2\n"), &[], &[]);
+        assert_eq!(config.jobs, 2);
+        assert_eq!(config.source("jobs"), &Source::File(PathBuf::from("test.toml"), 1));
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let config = resolve(Some("jobs = 2\n"), &[("BTRFS_SNAPSYNC_JOBS", "3")], &[]);
+        assert_eq!(config.jobs, 3);
+        assert_eq!(config.source("jobs"), &Source::Env("BTRFS_SNAPSYNC_JOBS"));
+    }
+
+    #[test]
+    fn flags_override_env_and_file() {
+        let config = resolve(Some("jobs = 2\n"), &[("BTRFS_SNAPSYNC_JOBS", "3")], &[("jobs", "4")]);
+        assert_eq!(config.jobs, 4);
+        assert_eq!(config.source("jobs"), &Source::Flag);
+    }
+
+    #[test]
+    fn env_without_prefix_is_ignored() {
+        let config = resolve(Some("jobs = 2\n"), &[("SNAPSYNC_JOBS", "3")], &[]);
+        assert_eq!(config.jobs, 2);
+    }
+
+    #[test]
+    fn list_layer_replaces_lower_list() {
+        let file = "exclude = [\"*.tmp\", \"cache/\"]\n";
+        assert_eq!(resolve(Some(file), &[], &[]).exclude, vec!["*.tmp", "cache/"]);
+        let config = resolve(Some(file), &[("BTRFS_SNAPSYNC_EXCLUDE", "*.bak:*.swp")], &[]);
+        assert_eq!(config.exclude, vec!["*.bak", "*.swp"]);
+        let config = resolve(Some(file), &[("BTRFS_SNAPSYNC_EXCLUDE", "*.bak")], &[("exclude", "*.o")]);
+        assert_eq!(config.exclude, vec!["*.o"]);
+    }
+}
@@ -0,0 +1,117 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Finding the base and target snapshots to sync, from their names.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// A snapshot, as a subdirectory of a directory of snapshots.
+struct Snapshot {
+    name: OsString,
+    /// The digits in the name, e.g. 20200601 for "home.2020-06-01".
+    timestamp: Vec<u8>,
+}
+
+impl Snapshot {
+    fn new(name: OsString) -> Snapshot {
+        let timestamp = name.as_bytes().iter().cloned().filter(u8::is_ascii_digit).collect();
+        Snapshot { name, timestamp }
+    }
+
+    /// The key to order snapshots by, oldest first.
+    ///
+    /// Timestamps compare as numbers, so snapshot 10 comes after snapshot 9,
+    /// names without digits compare by name.
+    fn sort_key(&self) -> (usize, &[u8], &[u8]) {
+        (self.timestamp.len(), &self.timestamp, self.name.as_bytes())
+    }
+}
+
+/// The four paths to sync the newest source snapshot.
+pub struct Pair {
+    pub src_base: PathBuf,
+    pub src_target: PathBuf,
+    pub dst_base: PathBuf,
+    pub dst_target: PathBuf,
+}
+
+pub enum Discovery {
+    /// The newest source snapshot that is not in the destination, and the
+    /// newest one before it that is.
+    Found(Pair),
+    /// The newest source snapshot is in the destination already.
+    UpToDate(OsString),
+    /// No source snapshot is in the destination, so there is no base.
+    NoCommon,
+}
+
+/// List the subdirectories of the directory, oldest snapshot first.
+fn list_snapshots(dir: &Path) -> io::Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            snapshots.push(Snapshot::new(entry.file_name()));
+        }
+    }
+    snapshots.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    Ok(snapshots)
+}
+
+/// Return the destination snapshot that corresponds to the source snapshot.
+///
+/// A snapshot with the same name is preferred. Otherwise, a snapshot with the
+/// same timestamp matches, if there is exactly one, so "home.2020-06-01" in
+/// the source can match "mirror-home.20200601" in the destination.
+fn find_counterpart<'a>(src: &Snapshot, dsts: &'a [Snapshot]) -> Option<&'a Snapshot> {
+    if let Some(dst) = dsts.iter().find(|dst| dst.name == src.name) {
+        return Some(dst);
+    }
+    if src.timestamp.is_empty() {
+        return None;
+    }
+    let mut same_time = dsts.iter().filter(|dst| dst.timestamp == src.timestamp);
+    match (same_time.next(), same_time.next()) {
+        (Some(dst), None) => Some(dst),
+        _ => None,
+    }
+}
+
+/// Pair the snapshots in the two directories, and find what to sync next.
+///
+/// The destination target does not need to exist yet, it gets the name of
+/// the source target.
+pub fn discover(dir_src: &Path, dir_dst: &Path) -> io::Result<Discovery> {
+    let srcs = list_snapshots(dir_src)?;
+    let dsts = list_snapshots(dir_dst)?;
+
+    let newest = match srcs.last() {
+        Some(newest) => newest,
+        None => return Ok(Discovery::NoCommon),
+    };
+    if find_counterpart(newest, &dsts).is_some() {
+        return Ok(Discovery::UpToDate(newest.name.clone()));
+    }
+    let base = srcs
+        .iter()
+        .rev()
+        .filter_map(|src| find_counterpart(src, &dsts).map(|dst| (src, dst)))
+        .next();
+    match base {
+        Some((src_base, dst_base)) => Ok(Discovery::Found(Pair {
+            src_base: dir_src.join(&src_base.name),
+            src_target: dir_src.join(&newest.name),
+            dst_base: dir_dst.join(&dst_base.name),
+            dst_target: dir_dst.join(&newest.name),
+        })),
+        None => Ok(Discovery::NoCommon),
+    }
+}
@@ -0,0 +1,180 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Diagnosing the environment, for the doctor command: the kernel, the mount
+//! options, and which ioctls work.
+
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// The kernel that added FICLONE, as the generic version of the btrfs ioctl.
+pub const MIN_KERNEL: (u32, u32) = (4, 5);
+
+/// Counts the findings while printing them, one per line.
+#[derive(Default)]
+pub struct Report {
+    pub num_problems: usize,
+    pub num_warnings: usize,
+}
+
+impl Report {
+    pub fn ok(&mut self, finding: &str) {
+        println!("ok    {}", finding);
+    }
+
+    /// Something that harms sharing, but does not stop a run.
+    pub fn warn(&mut self, finding: &str) {
+        self.num_warnings += 1;
+        println!("warn  {}", finding);
+    }
+
+    /// Something that makes runs fail.
+    pub fn fail(&mut self, finding: &str) {
+        self.num_problems += 1;
+        println!("fail  {}", finding);
+    }
+}
+
+/// Return the release of the running kernel, like `uname -r`.
+pub fn kernel_release() -> io::Result<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Ok(release.to_string_lossy().into_owned())
+}
+
+/// Return the major and minor version of a release like `5.10.0-8-amd64`.
+pub fn parse_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// The mount that a path is on.
+pub struct Mount {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    /// The options of the mount point and of the filesystem, like `ro` and
+    /// `autodefrag`, without their values.
+    pub options: Vec<String>,
+}
+
+/// Undo the octal escapes of spaces and other special characters in mountinfo.
+fn unescape(field: &str) -> String {
+    let mut result = Vec::new();
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes.get(i + 1..i + 4).and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok());
+        match code {
+            Some(b) if bytes[i] == b'\\' => {
+                result.push(b);
+                i += 4;
+            }
+            _ => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Return the mount that the path is on, from /proc/self/mountinfo.
+pub fn find_mount(path: &Path) -> io::Result<Option<Mount>> {
+    let path = fs::canonicalize(path)?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mut best: Option<Mount> = None;
+    for line in mountinfo.lines() {
+        // The fields are: id, parent id, device, root, mount point, mount
+        // options, optional fields, a separator "-", filesystem type, source,
+        // and filesystem options.
+        let fields: Vec<&str> = line.split(' ').collect();
+        let separator = match fields.iter().position(|&f| f == "-") {
+            Some(i) if i >= 6 && fields.len() >= i + 4 => i,
+            _ => continue,
+        };
+        let mount_point = PathBuf::from(unescape(fields[4]));
+        if !path.starts_with(&mount_point) {
+            continue;
+        }
+        // Later mounts over the same point hide earlier ones, so on equal
+        // length the last one wins.
+        let depth = mount_point.components().count();
+        if best.as_ref().map_or(false, |b| b.mount_point.components().count() > depth) {
+            continue;
+        }
+        let options = fields[5]
+            .split(',')
+            .chain(fields[separator + 3].split(','))
+            .map(|option| option.split('=').next().unwrap_or(option).to_string())
+            .collect();
+        best = Some(Mount { mount_point, fs_type: fields[separator + 1].to_string(), options });
+    }
+    Ok(best)
+}
+
+// _IOWR(0x94, 54, struct file_dedupe_range).
+const FIDEDUPERANGE: libc::c_ulong = 0xc0189436;
+
+/// `struct file_dedupe_range`, with room for one `struct file_dedupe_range_info`.
+#[repr(C)]
+struct DedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// Dedupe the data of two unnamed temporary files with the same contents in
+/// the directory, and return the result of the dedupe.
+///
+/// The outer error is for when we could not set up the probe.
+pub fn probe_dedupe(dir: &Path) -> io::Result<io::Result<()>> {
+    let open_unnamed = || {
+        fs::OpenOptions::new().read(true).write(true).mode(0o600).custom_flags(libc::O_TMPFILE).open(dir)
+    };
+    let mut src = open_unnamed()?;
+    let mut dst = open_unnamed()?;
+    let data = [0x5a; 4096];
+    src.write_all(&data)?;
+    dst.write_all(&data)?;
+    let mut args = DedupeRange {
+        src_offset: 0,
+        src_length: data.len() as u64,
+        dest_count: 1,
+        reserved1: 0,
+        reserved2: 0,
+        dest_fd: i64::from(dst.as_raw_fd()),
+        dest_offset: 0,
+        bytes_deduped: 0,
+        status: 0,
+        reserved: 0,
+    };
+    if unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut args as *mut DedupeRange) } == -1 {
+        return Ok(Err(io::Error::last_os_error()));
+    }
+    // The status is 0 for success, or a negative errno for this destination.
+    match args.status {
+        0 => Ok(Ok(())),
+        status if status < 0 => Ok(Err(io::Error::from_raw_os_error(-status))),
+        _ => Ok(Err(io::Error::new(io::ErrorKind::Other, "The kernel found the data to differ."))),
+    }
+}
@@ -0,0 +1,204 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Detection of destination files that already share extents with their source.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::beneath::Root;
+
+/// One extent of a file, as reported by FIEMAP.
+#[derive(Debug, Eq, PartialEq)]
+struct Extent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    /// Whether the filesystem reports that the extent is shared with another file.
+    shared: bool,
+}
+
+/// The header of `struct fiemap`, followed by `fm_extent_count` extents.
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+/// `struct fiemap_extent`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+/// `struct fiemap` with room for a fixed number of extents.
+#[repr(C)]
+struct FiemapBuffer {
+    header: Fiemap,
+    extents: [FiemapExtent; 64],
+}
+
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+/// Extents with these flags have no meaningful physical offset to compare.
+const FIEMAP_EXTENT_NO_PHYSICAL: u32 = FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_DATA_INLINE;
+
+/// Return the extents of the file, in order of logical offset.
+///
+/// Fails with `InvalidData` if an extent has no physical location yet.
+fn extents(file: &fs::File) -> io::Result<Vec<Extent>> {
+    // _IOWR('f', 11, struct fiemap), obtained like FICLONE in main.rs.
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020660b;
+    let mut result = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut buffer: FiemapBuffer = unsafe { std::mem::zeroed() };
+        buffer.header.fm_start = start;
+        buffer.header.fm_length = u64::max_value() - start;
+        buffer.header.fm_flags = FIEMAP_FLAG_SYNC;
+        buffer.header.fm_extent_count = buffer.extents.len() as u32;
+        let status = unsafe {
+            libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut buffer as *mut FiemapBuffer)
+        };
+        if status == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mapped = &buffer.extents[..buffer.header.fm_mapped_extents as usize];
+        for extent in mapped {
+            if extent.fe_flags & FIEMAP_EXTENT_NO_PHYSICAL != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Extent has no physical location."));
+            }
+            result.push(Extent {
+                logical: extent.fe_logical,
+                physical: extent.fe_physical,
+                length: extent.fe_length,
+                shared: extent.fe_flags & FIEMAP_EXTENT_SHARED != 0,
+            });
+        }
+        match mapped.last() {
+            Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.fe_logical + last.fe_length;
+            }
+            _ => return Ok(result),
+        }
+    }
+}
+
+/// Return whether the extents of dst are exactly those of src, and shared.
+fn is_shared_copy(src_extents: &[Extent], dst_extents: &[Extent]) -> bool {
+    !dst_extents.is_empty() && dst_extents.iter().all(|extent| extent.shared) && src_extents == dst_extents
+}
+
+/// Return whether dst already is a reflinked copy of src, for a src of `len` bytes.
+///
+/// This is a conservative check, it only returns true when confident. First
+/// a cheap pre-check: dst must exist with the same size as src, and its first
+/// extent must be shared. Only then do we confirm that both files map to
+/// exactly the same physical extents. Any error along the way means we are
+/// not confident, and the clone should proceed.
+pub fn already_shared(src: &fs::File, len: u64, target: &Root, dst: &Path) -> bool {
+    let meta = match target.symlink_metadata(dst) {
+        Ok(meta) => meta,
+        Err(..) => return false,
+    };
+    if !meta.is_file() || meta.len() != len || len == 0 {
+        return false;
+    }
+    let f_dst = match target.open_file(dst, libc::O_RDONLY | libc::O_NOFOLLOW, 0) {
+        Ok(f) => f,
+        Err(..) => return false,
+    };
+    // Btrfs counts shared extents in full in st_blocks, so the flag is the
+    // only cheap sign of sharing. Most files are not shared, and for those
+    // we don't need the extents of the source.
+    let dst_extents = match extents(&f_dst) {
+        Ok(dst_extents) => dst_extents,
+        Err(..) => return false,
+    };
+    if !dst_extents.first().map_or(false, |extent| extent.shared) {
+        return false;
+    }
+    match extents(src) {
+        Ok(src_extents) => is_shared_copy(&src_extents, &dst_extents),
+        Err(..) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn extent(logical: u64, physical: u64, length: u64, shared: bool) -> Extent {
+        Extent { logical, physical, length, shared }
+    }
+
+    #[test]
+    fn is_shared_copy_needs_the_same_shared_extents() {
+        let src = vec![extent(0, 8192, 4096, true), extent(4096, 65536, 4096, true)];
+        let same = vec![extent(0, 8192, 4096, true), extent(4096, 65536, 4096, true)];
+        assert!(is_shared_copy(&src, &same));
+        // Shared, but with some other file.
+        let elsewhere = vec![extent(0, 8192, 4096, true), extent(4096, 73728, 4096, true)];
+        assert!(!is_shared_copy(&src, &elsewhere));
+        let prefix = vec![extent(0, 8192, 4096, true)];
+        assert!(!is_shared_copy(&src, &prefix));
+        assert!(!is_shared_copy(&[], &[]));
+        // The same physical extents without the flag are not trusted.
+        let unshared = vec![extent(0, 8192, 4096, false), extent(4096, 65536, 4096, false)];
+        assert!(!is_shared_copy(&unshared, &unshared));
+    }
+
+    /// Create an empty directory for the test, replacing any leftover one.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("reflink-diff-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create test directory.");
+        dir
+    }
+
+    #[test]
+    fn already_shared_is_false_for_a_copy_and_true_for_a_clone() {
+        let dir = test_dir("already-shared");
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+        fs::File::create(dir.join("src")).unwrap().write_all(&data).unwrap();
+        fs::File::create(dir.join("copy")).unwrap().write_all(&data).unwrap();
+        fs::File::create(dir.join("short")).unwrap().write_all(&data[1..]).unwrap();
+
+        let root = Root::open(&dir).unwrap();
+        let src = fs::File::open(dir.join("src")).unwrap();
+        let len = data.len() as u64;
+        assert!(!already_shared(&src, len, &root, Path::new("copy")));
+        assert!(!already_shared(&src, len, &root, Path::new("short")));
+        assert!(!already_shared(&src, len, &root, Path::new("missing")));
+
+        let clone = fs::File::create(dir.join("clone")).unwrap();
+        match crate::clone_file(&src, &clone) {
+            Ok(()) => assert!(already_shared(&src, len, &root, Path::new("clone"))),
+            Err(err) => eprintln!("Skipping the clone, the temporary directory does not support reflinks: {}", err),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
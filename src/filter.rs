@@ -0,0 +1,538 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Decide which paths in the scanned trees to skip.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::glob::Glob;
+use crate::regex::Regex;
+
+pub struct Filter {
+    excludes: Vec<Glob>,
+    /// If not empty, only paths that match, or are inside a directory that
+    /// matches, are scanned.
+    includes: Vec<Glob>,
+    exclude_regexes: Vec<Regex>,
+    /// If not empty, only files whose path matches are scanned, in addition
+    /// to those included by `includes`.
+    include_regexes: Vec<Regex>,
+    /// Rules from the ignore files passed with `--ignore-file`.
+    ignore_rules: Vec<IgnoreRule>,
+    skip_cache_dirs: bool,
+    skip_hidden: bool,
+    /// Extensions in lowercase. If `only_ext` is not empty, files without
+    /// one of those extensions are skipped.
+    only_ext: Vec<String>,
+    skip_ext: Vec<String>,
+    /// Only files with an mtime in `[newer_than, older_than)` are scanned.
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+}
+
+/// Return the number of days since the Unix epoch of a date in the proleptic
+/// Gregorian calendar, see http://howardhinnant.github.io/date_algorithms.html.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * mp + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Return the number of days in the month, 1 through 12, of the given year.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse `YYYY-MM-DD`, optionally followed by `THH:MM` or `THH:MM:SS`, and
+/// an optional `Z`, as a time in UTC.
+fn parse_date_time(value: &str) -> Option<SystemTime> {
+    let value = value.trim_end_matches('Z');
+    let (date, time) = match value.find(|c| c == 'T' || c == ' ') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => (value, "00:00:00"),
+    };
+    let date: Vec<i64> = date.split('-').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<i64> = time.split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let (year, month, day) = match date[..] {
+        [year, month, day] if (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day) => {
+            (year, month, day)
+        }
+        _ => return None,
+    };
+    let (hour, minute, second) = match time[..] {
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return None,
+    };
+    if hour > 23 || minute > 59 || second > 60 || hour < 0 || minute < 0 || second < 0 {
+        return None
+    }
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second;
+    if secs >= 0 {
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(-secs as u64))
+    }
+}
+
+/// Parse a duration before `now`, like `90m` or `7d`, or a date and time.
+fn parse_time_bound(flag: &str, value: &str, now: SystemTime) -> Result<SystemTime, String> {
+    let unit_secs = match value.chars().last() {
+        Some('s') => Some(1),
+        Some('m') => Some(60),
+        Some('h') => Some(3600),
+        Some('d') => Some(86_400),
+        Some('w') => Some(7 * 86_400),
+        _ => None,
+    };
+    let ago = unit_secs.and_then(|unit| {
+        let n: u64 = value[..value.len() - 1].parse().ok()?;
+        now.checked_sub(Duration::from_secs(n.checked_mul(unit)?))
+    });
+    ago.or_else(|| parse_date_time(value)).ok_or_else(|| format!(
+        "Invalid value for --{}: '{}', expected a duration like 7d, or a date like 2020-05-01.", flag, value,
+    ))
+}
+
+/// Return the extension of the file name in lowercase, or the empty string.
+fn extension(rel_path: &Path) -> String {
+    rel_path.extension().map_or(String::new(), |ext| ext.to_string_lossy().to_lowercase())
+}
+
+/// Return whether the file name starts with a dot.
+fn is_hidden(rel_path: &Path) -> bool {
+    rel_path.file_name().map_or(false, |name| name.as_bytes().starts_with(b"."))
+}
+
+/// The start of a `CACHEDIR.TAG` file, see https://bford.info/cachedir/.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Return whether the directory contains a valid `CACHEDIR.TAG`.
+fn has_cachedir_tag(dir: &Path) -> bool {
+    let mut signature = [0_u8; 43];
+    match fs::File::open(dir.join("CACHEDIR.TAG")) {
+        Ok(mut file) => file.read_exact(&mut signature).is_ok() && &signature[..] == CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+/// The name of the ignore files that are honored inside the scanned trees.
+const IGNORE_FILE_NAME: &str = ".snapsyncignore";
+
+/// A line of an ignore file.
+struct IgnoreRule {
+    glob: Glob,
+    /// Whether the line starts with `!`, and re-includes what it matches.
+    negated: bool,
+}
+
+/// Parse an ignore file in gitignore format.
+///
+/// Returns the rules, and the errors for lines that could not be parsed,
+/// with line numbers.
+fn parse_ignore_file(contents: &str) -> (Vec<IgnoreRule>, Vec<(usize, String)>) {
+    let mut rules = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let (negated, pattern) = if line.starts_with('!') { (true, &line[1..]) } else { (false, line) };
+        match Glob::new(pattern) {
+            Ok(glob) => rules.push(IgnoreRule { glob, negated }),
+            Err(e) => errors.push((i + 1, e)),
+        }
+    }
+    (rules, errors)
+}
+
+/// The rules of an ignore file inside the tree.
+struct IgnoreFile {
+    /// Depth of the directory that contains the file, relative to the root.
+    depth: usize,
+    /// The directory that contains the file, relative to the root.
+    dir: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+/// Read the ignore file in a directory, if it has one.
+///
+/// An ignore file that cannot be read or parsed is reported, but does not
+/// fail the scan; at worst it causes more files to be scanned.
+fn read_ignore_file(dir: &Path) -> Vec<IgnoreRule> {
+    let path = dir.join(IGNORE_FILE_NAME);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            warn!("Failed to read ignore file {:?}: {}", path, e);
+            return Vec::new()
+        }
+    };
+    let (rules, errors) = parse_ignore_file(&contents);
+    for (line, error) in errors {
+        warn!("Ignoring line {} of ignore file {:?}: {}", line, path, error);
+    }
+    rules
+}
+
+/// Tracks the ignore files that apply to the current position of a walk.
+pub struct Ignores<'a> {
+    global: &'a [IgnoreRule],
+    /// The ignore files of the ancestors of the current entry, root first.
+    stack: Vec<IgnoreFile>,
+}
+
+impl<'a> Ignores<'a> {
+    /// Start a walk of the tree at `root`, with its own ignore file loaded.
+    pub fn new(filter: &'a Filter, root: &Path) -> Ignores<'a> {
+        let root_file = IgnoreFile { depth: 0, dir: PathBuf::new(), rules: read_ignore_file(root) };
+        Ignores { global: &filter.ignore_rules, stack: vec![root_file] }
+    }
+
+    /// Return whether the entry is ignored.
+    ///
+    /// This must be called for entries in the order of a depth-first walk.
+    /// When a directory is not ignored, its ignore file is loaded, to apply to
+    /// the entries inside it. As in gitignore, the last matching rule wins,
+    /// and rules in deeper directories come after rules in their ancestors.
+    pub fn check(&mut self, full_path: &Path, rel_path: &Path, depth: usize, is_dir: bool) -> bool {
+        // Drop the ignore files of directories that the walk has left.
+        while self.stack.last().map_or(false, |file| file.depth >= depth) {
+            self.stack.pop();
+        }
+
+        let mut ignored = false;
+        for rule in self.global {
+            if rule.glob.matches(rel_path, is_dir) {
+                ignored = !rule.negated;
+            }
+        }
+        for file in &self.stack {
+            let path_in_dir = rel_path.strip_prefix(&file.dir).expect("Ignore files are of ancestors.");
+            for rule in &file.rules {
+                if rule.glob.matches(path_in_dir, is_dir) {
+                    ignored = !rule.negated;
+                }
+            }
+        }
+
+        if is_dir && !ignored {
+            let rules = read_ignore_file(full_path);
+            if !rules.is_empty() {
+                self.stack.push(IgnoreFile { depth, dir: rel_path.to_path_buf(), rules });
+            }
+        }
+        ignored
+    }
+}
+
+/// Read patterns from a file, one per line, ignoring blank lines and `#` comments.
+fn read_patterns(path: &Path) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read patterns from {}: {}", path.display(), e))?;
+    let patterns = contents
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect();
+    Ok(patterns)
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Glob>, String> {
+    patterns.iter().map(|p| Glob::new(p)).collect()
+}
+
+fn compile_regexes(flag: &str, patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).map_err(|e| format!("Invalid regex for --{}: '{}': {}", flag, p, e)))
+        .collect()
+}
+
+/// Return the string that regexes are matched against: the relative path,
+/// with a trailing slash for directories.
+fn regex_subject(rel_path: &Path, is_dir: bool) -> String {
+    let mut subject = rel_path.to_string_lossy().into_owned();
+    if is_dir {
+        subject.push('/');
+    }
+    subject
+}
+
+impl Filter {
+    pub fn from_config(config: &Config) -> Result<Filter, String> {
+        let now = SystemTime::now();
+        let mut patterns = config.exclude.clone();
+        for path in &config.exclude_from {
+            patterns.extend(read_patterns(path)?);
+        }
+        let mut includes = compile(&config.include)?;
+        for path in &config.files_from {
+            includes.extend(read_patterns(path)?.iter().map(|p| Glob::literal(p)));
+        }
+        let mut ignore_rules = Vec::new();
+        for path in &config.ignore_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read ignore file {}: {}", path.display(), e))?;
+            let (rules, errors) = parse_ignore_file(&contents);
+            if let Some(&(line, ref error)) = errors.first() {
+                return Err(format!("In ignore file {}, line {}: {}", path.display(), line, error));
+            }
+            ignore_rules.extend(rules);
+        }
+        Ok(Filter {
+            excludes: compile(&patterns)?,
+            includes,
+            exclude_regexes: compile_regexes("exclude-regex", &config.exclude_regex)?,
+            include_regexes: compile_regexes("filter-regex", &config.filter_regex)?,
+            ignore_rules,
+            skip_cache_dirs: !config.include_cache_dirs,
+            skip_hidden: config.no_hidden,
+            only_ext: config.only_ext.clone(),
+            skip_ext: config.skip_ext.clone(),
+            newer_than: match config.newer_than {
+                Some(ref value) => Some(parse_time_bound("newer-than", value, now)?),
+                None => None,
+            },
+            older_than: match config.older_than {
+                Some(ref value) => Some(parse_time_bound("older-than", value, now)?),
+                None => None,
+            },
+        })
+    }
+
+    fn is_included(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if self.includes.is_empty() && self.include_regexes.is_empty() {
+            return true
+        }
+        // A regex can match a path below any directory, so with include
+        // regexes, every directory must be entered.
+        if is_dir && !self.include_regexes.is_empty() {
+            return true
+        }
+        if self.include_regexes.iter().any(|regex| regex.is_match(&regex_subject(rel_path, is_dir))) {
+            return true
+        }
+        // Everything inside an included directory is included too. Other
+        // directories are only entered if they may contain included paths.
+        let inside_included_dir = rel_path
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| self.includes.iter().any(|glob| glob.matches(dir, true)));
+        inside_included_dir || self.includes.iter().any(|glob| {
+            glob.matches(rel_path, is_dir) || (is_dir && glob.may_match_inside(rel_path))
+        })
+    }
+
+    /// Return whether a file with the given mtime should be scanned.
+    pub fn is_in_mtime_window(&self, mtime: SystemTime) -> bool {
+        self.newer_than.map_or(true, |t| mtime >= t) && self.older_than.map_or(true, |t| mtime < t)
+    }
+
+    /// Return whether to skip the directory because it is marked as a cache.
+    pub fn is_cache_dir(&self, dir: &Path) -> bool {
+        let is_cache = self.skip_cache_dirs && has_cachedir_tag(dir);
+        if is_cache {
+            debug!("Skipping cache directory {:?}.", dir);
+        }
+        is_cache
+    }
+
+    /// Return whether to skip the file because of its extension.
+    fn is_excluded_ext(&self, rel_path: &Path) -> bool {
+        if self.only_ext.is_empty() && self.skip_ext.is_empty() {
+            return false
+        }
+        let ext = extension(rel_path);
+        (!self.only_ext.is_empty() && !self.only_ext.contains(&ext)) || self.skip_ext.contains(&ext)
+    }
+
+    /// Return whether to skip the path, relative to the root of the tree.
+    ///
+    /// When a directory is skipped, so is everything inside it.
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        (self.skip_hidden && is_hidden(rel_path))
+            || (!is_dir && self.is_excluded_ext(rel_path))
+            || self.excludes.iter().any(|glob| glob.matches(rel_path, is_dir))
+            || self.exclude_regexes.iter().any(|regex| regex.is_match(&regex_subject(rel_path, is_dir)))
+            || !self.is_included(rel_path, is_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the time that is the given number of seconds after the epoch.
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn days_from_civil_counts_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1970, 1, 2), 1);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(1900, 1, 1), -25_567);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2020, 1, 1), 18_262);
+        assert_eq!(days_from_civil(2020, 2, 29), 18_321);
+        assert_eq!(days_from_civil(2020, 3, 1), 18_322);
+        assert_eq!(days_from_civil(2021, 1, 1), 18_628);
+    }
+
+    #[test]
+    fn parse_date_time_accepts_dates_and_times() {
+        let may_first = 18_383 * 86_400;
+        assert_eq!(parse_date_time("2020-05-01"), Some(at(may_first)));
+        assert_eq!(parse_date_time("2020-05-01T12:30"), Some(at(may_first + 45_000)));
+        assert_eq!(parse_date_time("2020-05-01 12:30:15"), Some(at(may_first + 45_015)));
+        assert_eq!(parse_date_time("2020-05-01T12:30:15Z"), Some(at(may_first + 45_015)));
+        assert_eq!(parse_date_time("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(parse_date_time("1969-12-31T23:59:59"), UNIX_EPOCH.checked_sub(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn parse_date_time_rejects_days_past_the_end_of_the_month() {
+        assert!(parse_date_time("2020-02-29").is_some());
+        assert!(parse_date_time("2000-02-29").is_some());
+        assert!(parse_date_time("2020-01-31").is_some());
+        assert!(parse_date_time("2020-04-30").is_some());
+        assert_eq!(parse_date_time("2020-02-30"), None);
+        assert_eq!(parse_date_time("2020-02-31"), None);
+        assert_eq!(parse_date_time("2019-02-29"), None);
+        assert_eq!(parse_date_time("1900-02-29"), None);
+        assert_eq!(parse_date_time("2020-04-31"), None);
+        assert_eq!(parse_date_time("2020-11-31"), None);
+        assert_eq!(parse_date_time("2020-01-32"), None);
+        assert_eq!(parse_date_time("2020-01-00"), None);
+    }
+
+    #[test]
+    fn parse_date_time_rejects_malformed_values() {
+        assert_eq!(parse_date_time("2020-13-01"), None);
+        assert_eq!(parse_date_time("2020-00-01"), None);
+        assert_eq!(parse_date_time("2020-05"), None);
+        assert_eq!(parse_date_time("2020-05-01T24:00"), None);
+        assert_eq!(parse_date_time("2020-05-01T12:60"), None);
+        assert_eq!(parse_date_time("2020-05-01T12"), None);
+        assert_eq!(parse_date_time("yesterday"), None);
+    }
+
+    #[test]
+    fn parse_time_bound_accepts_duration_suffixes() {
+        let now = at(10_000_000);
+        assert_eq!(parse_time_bound("newer-than", "90s", now), Ok(at(10_000_000 - 90)));
+        assert_eq!(parse_time_bound("newer-than", "90m", now), Ok(at(10_000_000 - 90 * 60)));
+        assert_eq!(parse_time_bound("newer-than", "3h", now), Ok(at(10_000_000 - 3 * 3600)));
+        assert_eq!(parse_time_bound("newer-than", "7d", now), Ok(at(10_000_000 - 7 * 86_400)));
+        assert_eq!(parse_time_bound("newer-than", "2w", now), Ok(at(10_000_000 - 14 * 86_400)));
+        assert_eq!(parse_time_bound("newer-than", "0d", now), Ok(now));
+        assert_eq!(parse_time_bound("older-than", "2020-05-01", now), Ok(at(18_383 * 86_400)));
+    }
+
+    #[test]
+    fn parse_time_bound_rejects_invalid_durations() {
+        let now = at(10_000_000);
+        let expected = "Invalid value for --older-than: '5y', expected a duration like 7d, or a date like 2020-05-01.";
+        assert_eq!(parse_time_bound("older-than", "5y", now), Err(expected.to_string()));
+        for value in &["d", "-1d", "1.5h", "7 d", "", "99999999999999999999s", "99999999999999w"] {
+            assert!(parse_time_bound("newer-than", value, now).is_err(), "Accepted {:?}", value);
+        }
+    }
+
+    /// A filter that has no rules of its own.
+    fn empty_filter() -> Filter {
+        Filter {
+            excludes: Vec::new(),
+            includes: Vec::new(),
+            exclude_regexes: Vec::new(),
+            include_regexes: Vec::new(),
+            ignore_rules: Vec::new(),
+            skip_cache_dirs: false,
+            skip_hidden: false,
+            only_ext: Vec::new(),
+            skip_ext: Vec::new(),
+            newer_than: None,
+            older_than: None,
+        }
+    }
+
+    /// Create an empty directory for the test, replacing any leftover one.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("reflink-diff-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create test directory.");
+        dir
+    }
+
+    #[test]
+    fn ignore_file_in_a_subdirectory_can_negate_a_parent_rule() {
+        let root = test_dir("ignore-negated");
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "*.log\n").unwrap();
+        fs::write(root.join("sub").join(IGNORE_FILE_NAME), "# Keep this one.\n!keep.log\n").unwrap();
+
+        let filter = empty_filter();
+        let mut ignores = Ignores::new(&filter, &root);
+        let mut check = |rel_path: &str, depth: usize, is_dir: bool| {
+            ignores.check(&root.join(rel_path), Path::new(rel_path), depth, is_dir)
+        };
+
+        // Entries in the order of a depth-first walk.
+        assert!(check("keep.log", 1, false));
+        assert!(!check("sub", 1, true));
+        assert!(!check("sub/keep.log", 2, false));
+        assert!(check("sub/other.log", 2, false));
+        assert!(!check("sub/deeper", 2, true));
+        assert!(!check("sub/deeper/keep.log", 3, false));
+        assert!(check("sub/deeper/other.log", 3, false));
+        // After leaving the subdirectory, its rules no longer apply.
+        assert!(!check("z", 1, true));
+        assert!(check("z/keep.log", 2, false));
+        assert!(!check("z.txt", 1, false));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn last_matching_ignore_rule_wins() {
+        let (rules, errors) = parse_ignore_file("*.log\n!keep.log\nkeep.log\n\n!a.log\n[x\n");
+        assert_eq!(rules.len(), 4);
+        assert_eq!(errors, vec![(6, "Unterminated character class in pattern '[x'.".to_string())]);
+
+        let root = test_dir("ignore-last-wins");
+        let mut filter = empty_filter();
+        filter.ignore_rules = rules;
+        let mut ignores = Ignores::new(&filter, &root);
+        assert!(ignores.check(&root.join("keep.log"), Path::new("keep.log"), 1, false));
+        assert!(!ignores.check(&root.join("a.log"), Path::new("a.log"), 1, false));
+        assert!(ignores.check(&root.join("b.log"), Path::new("b.log"), 1, false));
+        assert!(!ignores.check(&root.join("b.txt"), Path::new("b.txt"), 1, false));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Glob patterns for matching paths relative to a scanned tree.
+//!
+//! The syntax follows gitignore: `*` matches anything except a slash, `?`
+//! matches one character except a slash, `[a-z]` matches a character class
+//! (negated with `!` or `^`), and `**` matches across directories. A pattern
+//! that contains a slash, other than a trailing one, is matched against the
+//! full relative path, otherwise it is matched against the file name only. A
+//! trailing slash makes the pattern match directories only. A backslash
+//! escapes the next character.
+
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+enum Token {
+    Char(char),
+    /// `?`, any character except a slash.
+    Any,
+    /// `*`, any sequence of characters except slashes.
+    Star,
+    /// `**` not followed by a slash, any sequence of characters.
+    DoubleStar,
+    /// `**/`, zero or more complete directory components.
+    Dirs,
+    /// `[...]`, a character class with inclusive ranges.
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+#[derive(Clone, Debug)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    /// Whether to match the full relative path, rather than the file name.
+    /// Anchored patterns can only match below a directory that matches their
+    /// leading components, which lets the scan skip the other directories.
+    anchored: bool,
+    /// Whether the pattern only matches directories.
+    dir_only: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, String> {
+        let dir_only = pattern.ends_with('/') && pattern.len() > 1;
+        let pattern = if dir_only { &pattern[..pattern.len() - 1] } else { pattern };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars().peekable();
+        while let Some(ch) = chars.next() {
+            let token = match ch {
+                '\\' => match chars.next() {
+                    Some(escaped) => Token::Char(escaped),
+                    None => return Err(format!("Pattern '{}' ends in a backslash.", pattern)),
+                },
+                '?' => Token::Any,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        Token::Dirs
+                    } else {
+                        Token::DoubleStar
+                    }
+                }
+                '*' => Token::Star,
+                '[' => {
+                    let negated = match chars.peek() {
+                        Some(&'!') | Some(&'^') => { chars.next(); true }
+                        _ => false,
+                    };
+                    let mut ranges = Vec::new();
+                    let mut first = true;
+                    loop {
+                        let lo = match chars.next() {
+                            Some(']') if !first => break,
+                            Some(c) => c,
+                            None => return Err(format!("Unterminated character class in pattern '{}'.", pattern)),
+                        };
+                        first = false;
+                        let hi = if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => { ranges.push((lo, lo)); ranges.push(('-', '-')); break }
+                                Some(c) => c,
+                                None => return Err(format!("Unterminated character class in pattern '{}'.", pattern)),
+                            }
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+
+        Ok(Glob { tokens, anchored, dir_only })
+    }
+
+    /// Build a pattern that matches exactly the given relative path.
+    pub fn literal(rel_path: &str) -> Glob {
+        let rel_path = rel_path.trim_start_matches("./").trim_matches('/');
+        Glob {
+            tokens: rel_path.chars().map(Token::Char).collect(),
+            anchored: true,
+            dir_only: false,
+        }
+    }
+
+    /// Return whether the pattern matches the path, relative to the tree root.
+    pub fn matches(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false
+        }
+        let path = rel_path.to_string_lossy();
+        let subject = if self.anchored {
+            &path[..]
+        } else {
+            match path.rfind('/') {
+                Some(i) => &path[i + 1..],
+                None => &path[..],
+            }
+        };
+        let chars: Vec<char> = subject.chars().collect();
+        match_tokens(&self.tokens, &chars)
+    }
+
+    /// Return whether the pattern could match a path strictly inside the
+    /// directory, relative to the tree root.
+    pub fn may_match_inside(&self, rel_dir: &Path) -> bool {
+        if !self.anchored {
+            return true
+        }
+        let segments: Vec<&[Token]> = self.tokens.split(|t| match *t {
+            Token::Char('/') => true,
+            _ => false,
+        }).collect();
+        for (i, component) in rel_dir.iter().enumerate() {
+            let segment = match segments.get(i) {
+                Some(segment) => segment,
+                None => return false,
+            };
+            let crosses_dirs = segment.iter().any(|t| match *t {
+                Token::DoubleStar | Token::Dirs => true,
+                _ => false,
+            });
+            if crosses_dirs {
+                return true
+            }
+            // The last segment must be left for the path inside the directory.
+            if i + 1 == segments.len() {
+                return false
+            }
+            let chars: Vec<char> = component.to_string_lossy().chars().collect();
+            if !match_tokens(segment, &chars) {
+                return false
+            }
+        }
+        true
+    }
+}
+
+fn match_tokens(tokens: &[Token], chars: &[char]) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return chars.is_empty(),
+    };
+    match *token {
+        Token::Char(c) => chars.first() == Some(&c) && match_tokens(rest, &chars[1..]),
+        Token::Any => match chars.first() {
+            Some(&c) if c != '/' => match_tokens(rest, &chars[1..]),
+            _ => false,
+        },
+        Token::Class { negated, ref ranges } => match chars.first() {
+            Some(&c) if c != '/' => {
+                let in_class = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                in_class != negated && match_tokens(rest, &chars[1..])
+            }
+            _ => false,
+        },
+        Token::Star => {
+            // Try every length of the run of non-slash characters.
+            let max = chars.iter().position(|&c| c == '/').unwrap_or_else(|| chars.len());
+            (0..=max).any(|n| match_tokens(rest, &chars[n..]))
+        }
+        Token::DoubleStar => (0..=chars.len()).any(|n| match_tokens(rest, &chars[n..])),
+        Token::Dirs => {
+            // Zero directories, or any prefix that ends right after a slash.
+            match_tokens(rest, chars)
+                || (0..chars.len()).any(|i| chars[i] == '/' && match_tokens(rest, &chars[i + 1..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compile the pattern, which must be valid.
+    fn glob(pattern: &str) -> Glob {
+        Glob::new(pattern).expect("The pattern is valid.")
+    }
+
+    /// Match a file (not a directory) against the pattern.
+    fn matches_file(pattern: &str, rel_path: &str) -> bool {
+        glob(pattern).matches(Path::new(rel_path), false)
+    }
+
+    fn may_match_inside(pattern: &str, rel_dir: &str) -> bool {
+        glob(pattern).may_match_inside(Path::new(rel_dir))
+    }
+
+    #[test]
+    fn unanchored_patterns_match_the_file_name() {
+        assert!(matches_file("*.tmp", "a.tmp"));
+        assert!(matches_file("*.tmp", "x/y/a.tmp"));
+        assert!(!matches_file("*.tmp", "a.tmp/b"));
+        assert!(matches_file("a?c", "x/abc"));
+        assert!(!matches_file("a?c", "ac"));
+        assert!(matches_file("a*", "b/a"));
+        assert!(!matches_file("a*", "a/b"));
+    }
+
+    #[test]
+    fn anchored_patterns_match_the_full_path() {
+        assert!(matches_file("x/*.tmp", "x/a.tmp"));
+        assert!(!matches_file("x/*.tmp", "y/x/a.tmp"));
+        assert!(!matches_file("x/*.tmp", "x/y/a.tmp"));
+        assert!(matches_file("/a.tmp", "a.tmp"));
+        assert!(!matches_file("/a.tmp", "x/a.tmp"));
+        assert!(!matches_file("x?y", "x/y"));
+        assert!(!matches_file("x*y", "x/y"));
+    }
+
+    #[test]
+    fn double_star_slash_matches_zero_or_more_directories() {
+        assert!(matches_file("**/a", "a"));
+        assert!(matches_file("**/a", "x/a"));
+        assert!(matches_file("**/a", "x/y/a"));
+        assert!(!matches_file("**/a", "xa"));
+        assert!(matches_file("x/**/a", "x/a"));
+        assert!(matches_file("x/**/a", "x/y/z/a"));
+        assert!(!matches_file("x/**/a", "xa"));
+        assert!(!matches_file("x/**/a", "y/x/a"));
+        assert!(matches_file("x/**", "x/y/z"));
+        assert!(matches_file("/x**", "x/y"));
+        assert!(!matches_file("/x*", "x/y"));
+    }
+
+    #[test]
+    fn trailing_slash_matches_directories_only() {
+        let pattern = glob("build/");
+        assert!(pattern.matches(Path::new("build"), true));
+        assert!(pattern.matches(Path::new("x/build"), true));
+        assert!(!pattern.matches(Path::new("build"), false));
+        let pattern = glob("x/build/");
+        assert!(pattern.matches(Path::new("x/build"), true));
+        assert!(!pattern.matches(Path::new("y/x/build"), true));
+        // Without the slash, the pattern matches both.
+        assert!(glob("build").matches(Path::new("build"), false));
+        assert!(glob("build").matches(Path::new("build"), true));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches_file("[a-c]x", "bx"));
+        assert!(!matches_file("[a-c]x", "dx"));
+        assert!(matches_file("[!a-c]x", "dx"));
+        assert!(matches_file("[^a-c]x", "dx"));
+        assert!(!matches_file("[!a-c]x", "ax"));
+        assert!(matches_file("[]]", "]"));
+        assert!(matches_file("[-a]", "-"));
+        assert!(!matches_file("x[!y]z", "x/z"));
+    }
+
+    #[test]
+    fn trailing_dash_in_a_class_is_literal() {
+        assert!(matches_file("[a-]", "a"));
+        assert!(matches_file("[a-]", "-"));
+        assert!(!matches_file("[a-]", "b"));
+        assert!(!matches_file("[!a-]", "a"));
+        assert!(!matches_file("[!a-]", "-"));
+        assert!(matches_file("[!a-]", "b"));
+    }
+
+    #[test]
+    fn backslash_escapes_the_next_character() {
+        assert!(matches_file("\\*", "*"));
+        assert!(!matches_file("\\*", "a"));
+        assert!(matches_file("a\\?", "a?"));
+        assert!(!matches_file("a\\?", "ab"));
+        assert!(matches_file("\\[a]", "[a]"));
+        assert!(matches_file("a\\/b", "a/b"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert_eq!(Glob::new("a\\").unwrap_err(), "Pattern 'a\\' ends in a backslash.");
+        assert_eq!(Glob::new("[ab").unwrap_err(), "Unterminated character class in pattern '[ab'.");
+        assert_eq!(Glob::new("[a-").unwrap_err(), "Unterminated character class in pattern '[a-'.");
+    }
+
+    #[test]
+    fn literal_matches_exactly_the_path() {
+        let pattern = Glob::literal("./x/[a]*/");
+        assert!(pattern.matches(Path::new("x/[a]*"), false));
+        assert!(pattern.matches(Path::new("x/[a]*"), true));
+        assert!(!pattern.matches(Path::new("x/ab"), false));
+        assert!(pattern.may_match_inside(Path::new("x")));
+        assert!(!pattern.may_match_inside(Path::new("y")));
+    }
+
+    #[test]
+    fn may_match_inside_prunes_directories() {
+        assert!(may_match_inside("*.tmp", "anything"));
+        assert!(may_match_inside("x/y/*.tmp", "x"));
+        assert!(may_match_inside("x/y/*.tmp", "x/y"));
+        assert!(!may_match_inside("x/y/*.tmp", "x/y/z"));
+        assert!(!may_match_inside("x/y/*.tmp", "z"));
+        assert!(!may_match_inside("x/y/*.tmp", "x/z"));
+        assert!(may_match_inside("x*/y", "xa"));
+        assert!(may_match_inside("x/a", "x"));
+        assert!(!may_match_inside("x/a", "x/a"));
+        assert!(!may_match_inside("/a", "a"));
+        assert!(may_match_inside("x/**/a", "x"));
+        assert!(may_match_inside("x/**/a", "x/y/z"));
+        assert!(!may_match_inside("x/**/a", "y"));
+        assert!(may_match_inside("x/**", "x/y"));
+        assert!(may_match_inside("**/a", "y/z"));
+    }
+
+    #[test]
+    fn may_match_inside_splits_on_an_escaped_slash() {
+        // A path component cannot contain a slash, so an escaped slash
+        // separates components just like an unescaped one.
+        assert!(may_match_inside("x\\/y/*.tmp", "x"));
+        assert!(may_match_inside("x\\/y/*.tmp", "x/y"));
+        assert!(!may_match_inside("x\\/y/*.tmp", "y"));
+        assert!(!may_match_inside("x\\/y", "x/y"));
+    }
+}
@@ -0,0 +1,97 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Running the commands of --pre-hook, --post-hook, and --notify-cmd.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::json::Json;
+
+/// What a hook is told about the run, through environment variables.
+pub struct HookEnv<'a> {
+    /// The paths of the run, the first destination pair of several is used.
+    pub paths: &'a [PathBuf],
+    pub dry_run: bool,
+    pub job: Option<&'a str>,
+}
+
+impl<'a> HookEnv<'a> {
+    fn vars(&self, hook: &str) -> Vec<(&'static str, OsString)> {
+        let mut vars = vec![
+            ("BTRFS_SNAPSYNC_HOOK", OsString::from(hook)),
+            ("BTRFS_SNAPSYNC_COMMAND", OsString::from(if self.dry_run { "dry-run" } else { "apply" })),
+        ];
+        let names = ["BTRFS_SNAPSYNC_SRC_BASE", "BTRFS_SNAPSYNC_SRC_TARGET", "BTRFS_SNAPSYNC_DST_BASE", "BTRFS_SNAPSYNC_DST_TARGET"];
+        vars.extend(names.iter().zip(self.paths).map(|(&name, path)| (name, path.as_os_str().to_os_string())));
+        if let Some(job) = self.job {
+            vars.push(("BTRFS_SNAPSYNC_JOB", OsString::from(job)));
+        }
+        vars
+    }
+}
+
+/// Return a duplicate of stderr, to send the output of commands to.
+fn stderr() -> io::Result<fs::File> {
+    match unsafe { libc::dup(libc::STDERR_FILENO) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { fs::File::from_raw_fd(fd) }),
+    }
+}
+
+/// Return an error that describes how the command failed.
+fn failed(what: &str, status: ExitStatus) -> io::Error {
+    let reason = match status.code() {
+        Some(code) => format!("exit status {}", code),
+        None => "a signal".to_string(),
+    };
+    io::Error::new(io::ErrorKind::Other, format!("The {} failed with {}.", what, reason))
+}
+
+/// Run the hook command with `sh -c`, and return an error if it fails.
+///
+/// The output of the hook goes to stderr, so stdout has only the plan.
+pub fn run_hook(hook: &str, command: &str, env: &HookEnv, extra_vars: &[(&str, OsString)]) -> io::Result<()> {
+    debug!("Running {}-hook: {}", hook, command);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.vars(hook))
+        .envs(extra_vars.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(stderr()?))
+        .status()
+        .map_err(|err| io::Error::new(err.kind(), format!("Failed to run {}-hook: {}", hook, err)))?;
+    if status.success() { Ok(()) } else { Err(failed(&format!("{}-hook", hook), status)) }
+}
+
+/// Run the --notify-cmd with `sh -c`, with the summary of the run on stdin.
+pub fn notify(command: &str, summary: &Json) -> io::Result<()> {
+    debug!("Running notify command: {}", command);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(stderr()?))
+        .spawn()
+        .map_err(|err| io::Error::new(err.kind(), format!("Failed to run notify command: {}", err)))?;
+    {
+        let mut stdin = child.stdin.take().expect("Stdin is piped.");
+        // A command that does not read its input is fine, so ignore a broken pipe.
+        match writeln!(stdin, "{}", summary) {
+            Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+            other => other?,
+        }
+    }
+    let status = child.wait()?;
+    if status.success() { Ok(()) } else { Err(failed("notify command", status)) }
+}
@@ -0,0 +1,59 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Asking for confirmation of every operation, for --interactive.
+
+use std::io;
+use std::io::Write;
+
+const HELP: &str = "\
+y - apply this one
+n - skip this one
+a - apply this one and all remaining ones
+q - skip this one and all remaining ones
+? - print this help";
+
+/// Asks on stderr, and reads the answers from stdin.
+#[derive(Default)]
+pub struct Prompt {
+    /// Set after answering "a", skip the question and apply everything.
+    all: bool,
+    /// Set after answering "q", or at the end of stdin, skip everything.
+    quit: bool,
+}
+
+impl Prompt {
+    /// Return whether the user quit, so the remaining operations are skipped.
+    pub fn has_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Ask whether to apply the operation, and return whether to apply it.
+    pub fn confirm(&mut self, operation: &str) -> io::Result<bool> {
+        loop {
+            if self.all { return Ok(true) }
+            if self.quit { return Ok(false) }
+
+            eprint!("{} [y,n,a,q,?] ", operation);
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer)? == 0 {
+                // End the prompt line, which did not get a newline from input.
+                eprintln!();
+                self.quit = true;
+                continue
+            }
+            match answer.trim() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                "a" | "all" => self.all = true,
+                "q" | "quit" => self.quit = true,
+                _ => eprintln!("{}", HELP),
+            }
+        }
+    }
+}
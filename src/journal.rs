@@ -0,0 +1,124 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Recording the copies that completed, so --resume can continue an apply
+//! that was interrupted.
+//!
+//! A journal is a file of NUL-terminated records. The first one is the
+//! src-target that the copies came from, the others are the destinations that
+//! completed, relative to dst-target. Every record is appended with a single
+//! write, so after a crash only the last one can be torn, and without its NUL
+//! we ignore it.
+
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Return the file name of the journal of dst-target.
+fn file_name(dir_target_dst: &Path) -> io::Result<String> {
+    // Not canonicalized, because dst-target need not exist yet, and the
+    // journal must have the same name in the next run.
+    let absolute = env::current_dir()?.join(dir_target_dst);
+    // Escape the path like systemd-escape --path does, so the name is
+    // readable, and different paths get different names.
+    let mut name = String::new();
+    for &b in absolute.as_os_str().as_bytes().iter().skip_while(|&&b| b == b'/') {
+        match b {
+            b'/' => name.push('-'),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' => name.push(b as char),
+            _ => name.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    name.push_str(".journal");
+    Ok(name)
+}
+
+fn to_record(path: &Path) -> Vec<u8> {
+    let mut record = path.as_os_str().as_bytes().to_vec();
+    record.push(0);
+    record
+}
+
+/// Read the complete records of the journal, or none if there is no journal.
+fn read_records(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut records: Vec<PathBuf> = data.split(|&b| b == 0).map(|r| PathBuf::from(OsStr::from_bytes(r))).collect();
+    // What follows the last NUL is empty, or a torn record.
+    records.pop();
+    Ok(records)
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    /// The destinations that an earlier run completed.
+    done: HashSet<PathBuf>,
+}
+
+impl Journal {
+    /// Open the journal in the directory of the copies from src-target to dst-target.
+    ///
+    /// With resume, continue the journal of an earlier run, otherwise start a
+    /// new one.
+    pub fn open(dir: &Path, dir_target_src: &Path, dir_target_dst: &Path, resume: bool) -> io::Result<Journal> {
+        let path = dir.join(file_name(dir_target_dst)?);
+        let with_path = |err: io::Error| io::Error::new(err.kind(), format!("Failed to open journal {:?}: {}", path, err));
+        fs::create_dir_all(dir).map_err(with_path)?;
+
+        let records = if resume { read_records(&path).map_err(with_path)? } else { Vec::new() };
+        let mut records = records.into_iter();
+        match records.next() {
+            Some(ref src) if src == dir_target_src => {
+                let file = fs::OpenOptions::new().append(true).open(&path).map_err(with_path)?;
+                let done = records.collect();
+                return Ok(Journal { path, file, done });
+            }
+            Some(src) => warn!(
+                "The journal {:?} is of copies from {:?}, not from {:?}. Starting a new one.",
+                path, src, dir_target_src,
+            ),
+            None if resume => info!("There is no journal of an earlier run at {:?}, nothing to resume.", path),
+            None => {}
+        }
+
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).map_err(with_path)?;
+        file.write_all(&to_record(dir_target_src)).map_err(with_path)?;
+        Ok(Journal { path, file, done: HashSet::new() })
+    }
+
+    /// Return how many copies an earlier run completed.
+    pub fn num_done(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Return whether an earlier run completed the copy to dst, relative to dst-target.
+    pub fn is_done(&self, dst: &Path) -> bool {
+        self.done.contains(dst)
+    }
+
+    /// Record that the copy to dst, relative to dst-target, completed.
+    pub fn record(&mut self, dst: &Path) -> io::Result<()> {
+        self.file.write_all(&to_record(dst)).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to write to journal {:?}: {}", self.path, err))
+        })
+    }
+
+    /// Remove the journal, when the run completed every copy.
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.path).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to remove journal {:?}: {}", self.path, err))
+        })
+    }
+}
@@ -0,0 +1,123 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A minimal JSON serializer for machine-readable output.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+pub enum Json {
+    Bool(bool),
+    Int(i64),
+    /// A number, written as `null` if it is not finite.
+    Float(f64),
+    String(String),
+    /// A path or other OS string, which need not be valid UTF-8.
+    OsString(OsString),
+    Array(Vec<Json>),
+    /// Key-value pairs, serialized in the order given.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn path(path: &Path) -> Json {
+        Json::OsString(path.as_os_str().to_os_string())
+    }
+
+    pub fn str(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    pub fn uint(n: u64) -> Json {
+        // Counts and sizes that do not fit in an i64 do not occur in practice.
+        Json::Int(n as i64)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    for ch in s.chars() {
+        match ch {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Write an OS string as the contents of a JSON string.
+///
+/// Bytes that are not valid UTF-8 are written as lone surrogates U+DC80 to
+/// U+DCFF, the encoding that Python calls "surrogateescape". This way every
+/// path can be represented, and e.g. Python's `os.fsencode` recovers the
+/// original bytes.
+fn write_os_str(f: &mut fmt::Formatter, s: &OsStr) -> fmt::Result {
+    let mut bytes = s.as_bytes();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => return write_str(f, valid),
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                write_str(f, std::str::from_utf8(valid).expect("Prefix is valid UTF-8."))?;
+                let num_invalid = err.error_len().unwrap_or_else(|| rest.len());
+                for &b in &rest[..num_invalid] {
+                    write!(f, "\\u{:04x}", 0xdc00 | b as u32)?;
+                }
+                bytes = &rest[num_invalid..];
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Float(x) if x.is_finite() => write!(f, "{}", x),
+            Json::Float(..) => write!(f, "null"),
+            Json::String(ref s) => {
+                write!(f, "\"")?;
+                write_str(f, s)?;
+                write!(f, "\"")
+            }
+            Json::OsString(ref s) => {
+                write!(f, "\"")?;
+                write_os_str(f, s)?;
+                write!(f, "\"")
+            }
+            Json::Array(ref values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref pairs) => {
+                write!(f, "{{")?;
+                for (i, &(ref k, ref v)) in pairs.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "\"")?;
+                    write_str(f, k)?;
+                    write!(f, "\":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
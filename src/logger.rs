@@ -0,0 +1,151 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A minimal backend for the `log` crate that writes diagnostics to stderr.
+//!
+//! The level is configured like env_logger does it, with `RUST_LOG`, which
+//! `--log-level` overrides, and can be changed with `-v` and `-q` on the
+//! command line.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct Logger {
+    /// Whether to color the level prefixes, decided once at startup.
+    color: AtomicBool,
+    /// Whether to prefix every line with the time.
+    timestamps: AtomicBool,
+}
+
+static LOGGER: Logger = Logger {
+    color: AtomicBool::new(false),
+    timestamps: AtomicBool::new(false),
+};
+
+/// The level to use when `RUST_LOG` and `-v` are absent.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+        let (prefix, color) = match record.level() {
+            Level::Error => ("Error: ", "\x1b[31m"),
+            Level::Warn => ("Warning: ", "\x1b[33m"),
+            Level::Info => ("", ""),
+            Level::Debug => ("Debug: ", "\x1b[2m"),
+            Level::Trace => ("Trace: ", "\x1b[2m"),
+        };
+        let time = if self.timestamps.load(Ordering::Relaxed) {
+            format!("{} ", format_timestamp(SystemTime::now()))
+        } else {
+            String::new()
+        };
+        if self.color.load(Ordering::Relaxed) && !prefix.is_empty() {
+            eprintln!("{}{}{}\x1b[0m{}", time, color, prefix, record.args());
+        } else {
+            eprintln!("{}{}{}", time, prefix, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Format the time as an ISO 8601 timestamp in UTC, with milliseconds.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert days since the epoch into a date in the proleptic Gregorian
+    // calendar, see http://howardhinnant.github.io/date_algorithms.html.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// Parse a `RUST_LOG` value into the level for this program.
+///
+/// The value is a comma-separated list of directives, which are either a
+/// level, or `target=level`. Because only this program logs, we only look at
+/// the directives without target, or with a target inside this crate.
+fn parse_filter(spec: &str) -> Option<LevelFilter> {
+    // The first component of the module path is the crate name.
+    let crate_name = module_path!().split("::").next().expect("Module path is not empty.");
+    let mut result = None;
+    for directive in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+        let level = match directive.find('=') {
+            None => directive.parse().ok(),
+            Some(i) if directive[..i].starts_with(crate_name) => directive[i + 1..].parse().ok(),
+            Some(..) => None,
+        };
+        result = level.or(result);
+    }
+    result
+}
+
+/// Install the logger, with the level from `RUST_LOG` or the default.
+///
+/// This should happen before anything logs, so call it first thing in main.
+pub fn init() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|spec| parse_filter(&spec))
+        .unwrap_or(DEFAULT_LEVEL);
+    log::set_logger(&LOGGER).expect("The logger is only installed once.");
+    log::set_max_level(level);
+}
+
+/// Enable or disable colored level prefixes.
+pub fn set_color(color: bool) {
+    LOGGER.color.store(color, Ordering::Relaxed);
+}
+
+/// Enable or disable timestamps at the start of every line.
+pub fn set_timestamps(timestamps: bool) {
+    LOGGER.timestamps.store(timestamps, Ordering::Relaxed);
+}
+
+/// Set the level from `--log-level`, which overrides `RUST_LOG`.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Apply the number of times `-v` and `-q` were passed, which overrides `RUST_LOG`.
+pub fn set_verbosity(verbose: usize, quiet: usize) {
+    match (verbose, quiet) {
+        (0, 0) => {}
+        (0, 1) => log::set_max_level(LevelFilter::Warn),
+        (0, _) => log::set_max_level(LevelFilter::Error),
+        (1, _) => log::set_max_level(LevelFilter::Debug),
+        (_, _) => log::set_max_level(LevelFilter::Trace),
+    }
+}
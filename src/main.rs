@@ -6,29 +6,116 @@
 // A copy of the License has been included in the root of the repository.
 
 extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate walkdir;
 
-use std::collections::HashMap;
+mod beneath;
+mod btrbk;
+mod btrfs;
+mod cli;
+mod completions;
+mod config;
+mod discover;
+mod doctor;
+mod extents;
+mod filter;
+mod glob;
+mod hooks;
+mod interactive;
+mod journal;
+mod json;
+mod logger;
+mod metrics;
+mod output;
+mod progress;
+mod regex;
+mod retry;
+mod sandbox;
+mod schedule;
+mod signal;
+mod snapper;
+mod summary;
+mod systemd;
+mod toml;
+mod undo;
+mod watch;
+mod xattr;
+
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::env;
 use std::fs;
-use std::ffi::OsString;
+use std::ffi::{CString, OsString};
+use std::hash::Hash;
 use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use beneath::Root;
+use cli::Command;
+use config::{Backend, Config, Fallback, OutputFormat, PathMap};
+use discover::Discovery;
+use filter::{Filter, Ignores};
+use interactive::Prompt;
+use journal::Journal;
+use json::Json;
+use metrics::Metrics;
+use output::{Kind, Printer};
+use progress::Progress;
+use retry::Retry;
+use sandbox::Access;
+use schedule::Schedule;
+use summary::{Reason, Unresolved};
+use undo::UndoLog;
 
-#[derive(Eq, Ord, Debug, Hash, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Eq, Ord, Debug, Hash, PartialEq, PartialOrd)]
 struct FileInfo {
     len: u64,
     mtime: SystemTime,
 }
 
-#[derive(Eq, Ord, Debug, PartialEq, PartialOrd)]
+/// How a target file was matched up with a base file.
+#[derive(Clone, Copy, Eq, Ord, Debug, PartialEq, PartialOrd)]
+enum Match {
+    Inode,
+    SizeMtime,
+    SizeExtension,
+    Name,
+}
+
+impl Match {
+    fn name(self) -> &'static str {
+        match self {
+            Match::Inode => "inode",
+            Match::SizeMtime => "size-mtime",
+            Match::SizeExtension => "size-extension",
+            Match::Name => "name",
+        }
+    }
+}
+
+#[derive(Clone, Eq, Ord, Debug, PartialEq, PartialOrd)]
 struct CopyFile {
     src: PathBuf,
     dst: PathBuf,
+    /// Other files in the base that matched, to use if `src` is unavailable.
+    alt_srcs: Vec<PathBuf>,
+    /// Whether `dst` is a symlink in src-target, that we materialize as a
+    /// file with the contents of its target.
+    from_symlink: bool,
+    /// Size and mtime of the target file.
+    info: FileInfo,
+    /// The heuristic that matched `dst` to `src`.
+    matched_by: Match,
 }
 
 /// All files found recursively, grouped by mtime, size, and file path leaf.
@@ -38,17 +125,50 @@ struct DirScan {
     entries_size_mtime: HashMap<FileInfo, Vec<PathBuf>>,
     entries_size: HashMap<u64, Vec<PathBuf>>,
     entries_name: HashMap<OsString, Vec<PathBuf>>,
+    /// When the other tree is a snapshot of the same subvolume, the files by
+    /// inode number, otherwise empty.
+    entries_ino: HashMap<u64, Vec<PathBuf>>,
+    /// Number of regular files found, including ones too small to link up.
+    num_files: usize,
+    /// Symlinks that were recorded as the file they point to.
+    followed_symlinks: HashSet<PathBuf>,
+    /// With --preserve-symlinks, all symlinks, with the target they contain.
+    symlinks: HashMap<PathBuf, PathBuf>,
 }
 
 impl DirScan {
-    /// Search for something similar to the given file.
+    /// Add the entries of the scan of another tree, with their paths under `dir`.
+    fn merge(&mut self, other: DirScan, dir: &Path) {
+        fn extend<K: Eq + Hash>(into: &mut HashMap<K, Vec<PathBuf>>, from: HashMap<K, Vec<PathBuf>>, dir: &Path) {
+            for (key, paths) in from {
+                let entry = into.entry(key).or_insert_with(Vec::new);
+                entry.extend(paths.into_iter().map(|p| dir.join(p)));
+                entry.sort();
+            }
+        }
+        extend(&mut self.entries_size_mtime, other.entries_size_mtime, dir);
+        extend(&mut self.entries_size, other.entries_size, dir);
+        extend(&mut self.entries_name, other.entries_name, dir);
+        // Inode numbers only identify files within one pair of trees, so the
+        // trees of a --subvolume do not match by inode.
+        self.num_files += other.num_files;
+        self.followed_symlinks.extend(other.followed_symlinks.into_iter().map(|p| dir.join(p)));
+        self.symlinks.extend(other.symlinks.into_iter().map(|(p, target)| (dir.join(p), target)));
+    }
+
+    /// Search for something similar to the given file, which has inode `ino`
+    /// if the trees share inode numbers.
     ///
-    /// * Prefer a match on both mtime and size.
+    /// * Prefer the same inode, that is the same file, even if it changed.
+    /// * If that is not possible, match on both mtime and size.
     /// * If that is not possible, match on size only, if the extension matches.
     /// * If that is not possible, match on file name (excluding path to it).
-    fn get(&self, path: &Path, info: &FileInfo) -> Option<&[PathBuf]> {
+    fn get(&self, path: &Path, info: &FileInfo, ino: Option<u64>) -> Option<(&[PathBuf], Match)> {
+        if let Some(paths) = ino.and_then(|ino| self.entries_ino.get(&ino)) {
+            return Some((&paths[..], Match::Inode));
+        }
         if let Some(paths) = self.entries_size_mtime.get(info) {
-            return Some(&paths[..]);
+            return Some((&paths[..], Match::SizeMtime));
         }
         if let Some(paths) = self.entries_size.get(&info.len) {
             // If the size matches, confirm that the extension matches too,
@@ -57,53 +177,163 @@ impl DirScan {
             // because we only need one source to copy.
             for (i, matched_path) in paths.iter().enumerate() {
                 if path.extension() == matched_path.extension() {
-                    return Some(&paths[i..i+1]);
+                    return Some((&paths[i..i+1], Match::SizeExtension));
                 }
             }
         }
         if let Some(fname) = path.file_name() {
             if let Some(paths) = self.entries_name.get(fname) {
-                return Some(&paths[..]);
+                return Some((&paths[..], Match::Name));
             }
         }
         None
     }
 }
 
-fn scan_dir<P: AsRef<Path>>(dir_path: P) -> io::Result<DirScan> {
+/// Resolve a symlink to the regular file it points to.
+///
+/// Returns none if the symlink does not resolve to a regular file inside the
+/// tree at `root`, which must be canonical. Resolving fails on loops.
+fn resolve_symlink_target(path: &Path, root: &Path) -> Option<fs::Metadata> {
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(err) => {
+            warn!("Not following symlink {:?}: {}", path, err);
+            return None
+        }
+    };
+    if !target.starts_with(root) {
+        warn!("Not following symlink {:?}, its target {:?} is outside the tree.", path, target);
+        return None
+    }
+    match fs::metadata(&target) {
+        Ok(meta) if meta.is_file() => Some(meta),
+        _ => None,
+    }
+}
+
+/// Return the path of a scanned entry relative to the root of the scan.
+///
+/// Walkdir only yields entries under the root, but if it ever does not, that
+/// is reported as an error, so the entry can be skipped instead of crashing
+/// the run.
+fn relative_path<'a>(path: &'a Path, root: &Path) -> io::Result<&'a Path> {
+    path.strip_prefix(root).map_err(|_| {
+        let msg = format!("Entry {:?} is not inside the scanned directory {:?}.", path, root);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })
+}
+
+/// Scan the tree, and with `record_inodes` also group the files by inode.
+fn scan_dir<P: AsRef<Path>>(
+    dir_path: P,
+    config: &Config,
+    filter: &Filter,
+    progress: &mut Progress,
+    record_inodes: bool,
+) -> io::Result<DirScan> {
     let mut entries_size_mtime: HashMap<FileInfo, Vec<PathBuf>> = HashMap::new();
     let mut entries_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
     let mut entries_name: HashMap<OsString, Vec<PathBuf>> = HashMap::new();
+    let mut entries_ino: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut num_files = 0;
+    let mut followed_symlinks = HashSet::new();
+    let mut symlinks = HashMap::new();
+
+    // With --copy-symlink-targets, symlinks to files inside the tree are
+    // recorded as if they were the file they point to.
+    let symlink_root = if config.copy_symlink_targets {
+        Some(fs::canonicalize(&dir_path)?)
+    } else {
+        None
+    };
 
+    // Excluded, cache, and ignored directories are not descended into at all.
+    let mut ignores = Ignores::new(filter, dir_path.as_ref());
     let wd = walkdir::WalkDir::new(&dir_path)
         .max_open(128)
-        .same_file_system(true);
+        .same_file_system(true)
+        .into_iter()
+        .filter_entry(|entry| {
+            let rel_path = match relative_path(entry.path(), dir_path.as_ref()) {
+                Ok(rel_path) => rel_path,
+                Err(err) => {
+                    warn!("{} Skipping it.", err);
+                    return false
+                }
+            };
+            let is_dir = entry.file_type().is_dir();
+            if entry.depth() == 0 {
+                return true
+            }
+            if filter.is_excluded(rel_path, is_dir) || (is_dir && filter.is_cache_dir(entry.path())) {
+                return false
+            }
+            !ignores.check(entry.path(), rel_path, entry.depth(), is_dir)
+        });
 
     for entry_opt in wd {
         let entry = entry_opt?;
-        let meta = entry.metadata()?;
+        if progress.is_due() {
+            info!("Scanning {:?}: {} files so far, at {:?}.", dir_path.as_ref(), num_files, entry.path());
+        }
+        let mut meta = entry.metadata()?;
+        let rel_path = match relative_path(entry.path(), dir_path.as_ref()) {
+            Ok(rel_path) => rel_path,
+            Err(err) => {
+                warn!("{} Skipping it.", err);
+                continue
+            }
+        };
+
+        if entry.file_type().is_symlink() && config.preserve_symlinks {
+            symlinks.insert(rel_path.to_path_buf(), fs::read_link(entry.path())?);
+            continue
+        }
+
+        if entry.file_type().is_symlink() {
+            let target_meta = symlink_root
+                .as_ref()
+                .and_then(|root| resolve_symlink_target(entry.path(), root));
+            match target_meta {
+                Some(target_meta) => {
+                    meta = target_meta;
+                    followed_symlinks.insert(rel_path.to_path_buf());
+                }
+                None => continue,
+            }
+        }
 
         if !meta.is_file() { continue }
+        num_files += 1;
 
+        // Do not bother linking up small files, they are more likely to produce
+        // false positive than to result in substantial space savings. Skip
+        // them before recording anything, as trees can have millions of them.
         let len = meta.len();
+        if len < config.min_size || config.max_size.map_or(false, |max| len > max) {
+            continue
+        }
+
         let mtime = meta.modified()?;
+        if !filter.is_in_mtime_window(mtime) {
+            continue
+        }
         let file_info = FileInfo { len, mtime };
-        let full_path = entry.into_path();
-        let rel_path = match full_path.strip_prefix(&dir_path) {
-            Ok(p) => p.to_path_buf(),
-            Err(e) => panic!("Dir entry is not inside root? {:?}", e),
-        };
-        let fname = match full_path.file_name() {
+        // Only the root of the scan has no file name relative to the root,
+        // when it is a file instead of a directory.
+        let fname = match rel_path.file_name() {
             Some(name) => name.to_os_string(),
-            None => panic!("Expected file in directory to have a file name."),
+            None => {
+                warn!("File {:?} has no name inside the scanned directory, skipping it.", entry.path());
+                continue
+            }
         };
+        let rel_path = rel_path.to_path_buf();
 
-        // Do not bother linking up small files, they are more likely to produce
-        // false positive than to result in substantial space savings.
-        if len < 8192 {
-            continue
+        if record_inodes {
+            entries_ino.entry(meta.ino()).or_insert_with(Vec::new).push(rel_path.clone());
         }
-
         match entries_size_mtime.entry(file_info) {
             Entry::Occupied(mut e) => { e.get_mut().push(rel_path.clone()); }
             Entry::Vacant(e) => { e.insert(vec![rel_path.clone()]); }
@@ -122,11 +352,16 @@ fn scan_dir<P: AsRef<Path>>(dir_path: P) -> io::Result<DirScan> {
     for (_, ref mut v) in entries_size_mtime.iter_mut() { v.sort(); }
     for (_, ref mut v) in entries_size.iter_mut() { v.sort(); }
     for (_, ref mut v) in entries_name.iter_mut() { v.sort(); }
+    for (_, ref mut v) in entries_ino.iter_mut() { v.sort(); }
 
     let result = DirScan {
         entries_size_mtime,
         entries_size,
         entries_name,
+        entries_ino,
+        num_files,
+        followed_symlinks,
+        symlinks,
     };
 
     Ok(result)
@@ -135,14 +370,16 @@ fn scan_dir<P: AsRef<Path>>(dir_path: P) -> io::Result<DirScan> {
 /// Detect potentially moved files, and emit a copy operation for each.
 fn diff(base: &DirScan, mut target: DirScan) -> io::Result<Vec<CopyFile>> {
     let mut copies = Vec::new();
+    let inodes: HashMap<&Path, u64> =
+        target.entries_ino.iter().flat_map(|(&ino, paths)| paths.iter().map(move |p| (p.as_path(), ino))).collect();
 
     for (info, mut paths) in target.entries_size_mtime.drain() {
         for path in paths.drain(..) {
-            match base.get(&path, &info) {
+            match base.get(&path, &info, inodes.get(path.as_path()).cloned()) {
                 None => {
-                    println!("Target without base: {:?}", path);
+                    info!("Target without base: {:?}", path);
                 },
-                Some(ref base_paths) => {
+                Some((base_paths, matched_by)) => {
                     if base_paths.contains(&path) {
                         // Already there with the same size and mtime, we
                         // assume that the file has not changed.
@@ -156,7 +393,11 @@ fn diff(base: &DirScan, mut target: DirScan) -> io::Result<Vec<CopyFile>> {
                         // was wrong.
                         let copy = CopyFile {
                             src: base_paths[0].clone(),
+                            from_symlink: target.followed_symlinks.contains(&path),
                             dst: path,
+                            alt_srcs: base_paths[1..].to_vec(),
+                            info,
+                            matched_by,
                         };
                         copies.push(copy);
                     }
@@ -170,6 +411,27 @@ fn diff(base: &DirScan, mut target: DirScan) -> io::Result<Vec<CopyFile>> {
     Ok(copies)
 }
 
+/// An operation that recreates a symlink from src-target in dst-target.
+#[derive(Clone, Eq, Ord, Debug, PartialEq, PartialOrd)]
+struct CreateSymlink {
+    /// Path of the symlink, relative to the tree.
+    path: PathBuf,
+    /// The target, exactly as stored in the symlink.
+    target: PathBuf,
+}
+
+/// Emit an operation for every symlink that is new or changed in the target.
+fn diff_symlinks(base: &DirScan, target: &DirScan) -> Vec<CreateSymlink> {
+    let mut result: Vec<CreateSymlink> = target
+        .symlinks
+        .iter()
+        .filter(|&(path, link_target)| base.symlinks.get(path) != Some(link_target))
+        .map(|(path, link_target)| CreateSymlink { path: path.clone(), target: link_target.clone() })
+        .collect();
+    result.sort();
+    result
+}
+
 /// Call the FICLONE ioctl to make dst a reflinked copy of src.
 fn clone_file(src: &fs::File, dst: &fs::File) -> io::Result<()> {
     // Not documented in "man ioctl_list", and in the header the constant is
@@ -197,79 +459,3441 @@ fn clone_file(src: &fs::File, dst: &fs::File) -> io::Result<()> {
     }
 }
 
-fn clone_paths(src: PathBuf, dst: PathBuf) -> io::Result<()> {
-    let parent = dst
-        .parent()
-        .expect("Destination should be in a subdirectory, so it has a parent.");
-    fs::create_dir_all(parent)?;
-    let f_src = fs::File::open(src)?;
-    let f_dst = fs::File::create(dst)?;
-    clone_file(&f_src, &f_dst)
+/// The argument of the FICLONERANGE ioctl, `struct file_clone_range`.
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+/// Call the FICLONERANGE ioctl to reflink a range of src into dst.
+///
+/// The ioctl either clones the full range or fails, it does not report
+/// partial progress.
+fn clone_range(src: &fs::File, dst: &fs::File, src_offset: u64, len: u64, dst_offset: u64) -> io::Result<()> {
+    // _IOW(0x94, 13, struct file_clone_range), obtained like FICLONE above.
+    const FICLONERANGE: libc::c_ulong = 0x4020940d;
+    let range = FileCloneRange {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+    let result = unsafe {
+        libc::ioctl(
+            dst.as_raw_fd(),
+            FICLONERANGE,
+            &range as *const FileCloneRange,
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Chunk size for `clone_range_all`, a multiple of any block size.
+const CLONE_CHUNK_SIZE: u64 = 1 << 30;
+
+/// Return the block size of the filesystem that the file is on.
+fn fs_block_size(file: &fs::File) -> io::Result<u64> {
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::fstatfs(file.as_raw_fd(), &mut buf) };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(buf.f_bsize as u64),
+    }
+}
+
+/// Shrink the range of `len` bytes at `offset` to a range that FICLONERANGE accepts.
+///
+/// The offset and length must be multiples of the block size, except that a
+/// range that ends at the end of the source file, of size `file_len`, may end
+/// there. Returns the offset and length of the largest such range inside the
+/// given one, the length is zero if not even one block fits.
+fn align_clone_range(offset: u64, len: u64, file_len: u64, block_size: u64) -> (u64, u64) {
+    let end = offset + len;
+    let aligned_start = (offset + block_size - 1) / block_size * block_size;
+    let aligned_end = if end == file_len { end } else { end / block_size * block_size };
+    if aligned_end <= aligned_start {
+        (offset, 0)
+    } else {
+        (aligned_start, aligned_end - aligned_start)
+    }
+}
+
+/// Copy `len` bytes at `offset` from src to dst at the same offset, through userspace.
+fn copy_range(src: &fs::File, dst: &fs::File, offset: u64, len: u64) -> io::Result<()> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(buffer.len() as u64) as usize;
+        let n_read = match src.read_at(&mut buffer[..n], offset + done) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Source is shorter than expected.")),
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        dst.write_all_at(&buffer[..n_read], offset + done)?;
+        done += n_read as u64;
+    }
+    Ok(())
+}
+
+/// Reflink `len` bytes at `offset` of src into dst at the same offset.
+///
+/// The block-aligned part of the range is reflinked in chunks of `chunk_size`,
+/// advancing by the bytes cloned, so a multi-gigabyte file does not keep the
+/// inodes locked for the entire duration. The unaligned head and tail of the
+/// range are copied normally. The chunk size must be a multiple of the block size.
+fn clone_range_all(src: &fs::File, dst: &fs::File, offset: u64, len: u64, chunk_size: u64) -> io::Result<()> {
+    let file_len = src.metadata()?.len();
+    let block_size = fs_block_size(dst)?;
+    let (aligned_offset, aligned_len) = align_clone_range(offset, len, file_len, block_size);
+
+    // Copy the head first, so the clones never start beyond the end of dst.
+    copy_range(src, dst, offset, aligned_offset - offset)?;
+
+    let mut done = 0;
+    while done < aligned_len {
+        let n = (aligned_len - done).min(chunk_size);
+        let pos = aligned_offset + done;
+        match clone_range(src, dst, pos, n, pos) {
+            Ok(()) => done += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    let tail_offset = aligned_offset + aligned_len;
+    copy_range(src, dst, tail_offset, offset + len - tail_offset)
+}
+
+/// Copy `len` bytes from src to dst with copy_file_range, entirely in the kernel.
+///
+/// The syscall may copy fewer bytes than requested, so we call it until
+/// everything is copied. If the source turns out to be shorter than `len`,
+/// we stop at its end. Returns the number of bytes copied.
+fn copy_file_range_all(src: &fs::File, dst: &fs::File, len: u64) -> io::Result<u64> {
+    let mut off_src: libc::loff_t = 0;
+    let mut off_dst: libc::loff_t = 0;
+    let mut copied = 0;
+    while copied < len {
+        // The kernel copies at most about 2 GiB per call anyway, so ask for
+        // one chunk at a time, which also fits in the ssize_t return value.
+        let count = (len - copied).min(CLONE_CHUNK_SIZE) as libc::size_t;
+        // The libc crate does not expose a wrapper for copy_file_range in the
+        // version we use, so make the syscall directly.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                src.as_raw_fd(),
+                &mut off_src as *mut libc::loff_t,
+                dst.as_raw_fd(),
+                &mut off_dst as *mut libc::loff_t,
+                count,
+                0 as libc::c_uint,
+            )
+        };
+        match result {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue }
+                return Err(err);
+            }
+            0 => break,
+            n => copied += n as u64,
+        }
+    }
+    Ok(copied)
+}
+
+/// Return the directory that contains the destination.
+///
+/// Destinations are inside dst-target, so they always have one, but a path
+/// without one is reported rather than assumed away.
+fn parent_dir(dst: &Path) -> io::Result<&Path> {
+    dst.parent().ok_or_else(|| {
+        let msg = format!("Destination {:?} has no parent directory.", dst);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })
+}
+
+/// Check that creating dst stays inside root, the canonical dst-target.
+///
+/// A directory leading up to dst can be a symlink, which the source snapshot
+/// may have put there, and which we would follow to create files anywhere.
+fn check_beneath(root: &Path, dst: &Path) -> io::Result<()> {
+    let parent = parent_dir(dst)?;
+    // The directories that are missing get created below one that exists.
+    let existing = parent
+        .ancestors()
+        .find(|p| p.as_os_str().is_empty() || fs::symlink_metadata(p).is_ok())
+        .unwrap_or(parent);
+    let resolved = fs::canonicalize(if existing.as_os_str().is_empty() { Path::new(".") } else { existing })?;
+    if resolved.starts_with(root) {
+        return Ok(());
+    }
+    let msg = format!(
+        "Refusing to create {:?}, because {:?} resolves to {:?}, outside of dst-target {:?}.",
+        dst, existing, resolved, root,
+    );
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+}
+
+/// Return the name of the destination in its directory.
+///
+/// A path that does not end in a name is returned whole, for `Root` to reject.
+fn file_name(dst: &Path) -> &Path {
+    Path::new(dst.file_name().unwrap_or_else(|| dst.as_os_str()))
+}
+
+/// A new destination file, that only appears in the tree after `persist`.
+///
+/// The file is created with O_TMPFILE, so until it is complete it has no
+/// name, and an interrupted run leaves no empty or partial files behind.
+struct Destination {
+    file: fs::File,
+    /// The directory of the destination, and its name in there.
+    dir: Root,
+    name: PathBuf,
+    /// False when the filesystem has no O_TMPFILE, and the file was created
+    /// at its path right away.
+    is_unnamed: bool,
+}
+
+/// Distinguishes the temporary names of destinations that workers persist
+/// at the same time.
+static NEXT_TMP_NAME: AtomicUsize = AtomicUsize::new(0);
+
+impl Destination {
+    /// Give the file its name, replacing whatever was at the path, and
+    /// return the file.
+    fn persist(self) -> io::Result<fs::File> {
+        if !self.is_unnamed {
+            return Ok(self.file);
+        }
+        // Linking fails when the name exists, so link the file under a
+        // temporary name, and rename that over the destination.
+        let tmp_name = format!(".reflink-diff-{}-{}.tmp", process::id(), NEXT_TMP_NAME.fetch_add(1, Ordering::SeqCst));
+        let tmp_name = Path::new(&tmp_name);
+        self.dir.link_unnamed(&self.file, tmp_name)?;
+        if let Err(err) = self.dir.rename(tmp_name, &self.name) {
+            let _ = self.dir.remove_file(tmp_name);
+            return Err(err);
+        }
+        Ok(self.file)
+    }
+}
+
+/// Create the destination file, and the directories leading up to it.
+///
+/// The destination replaces whatever is at its path when it is persisted. If
+/// that is a symlink, for example because we materialize the target of a
+/// symlink, the symlink is replaced rather than written through.
+///
+/// The path is relative to dst-target, and everything is created beneath it.
+fn create_destination(target: &Root, dst: &Path, retry: Retry) -> io::Result<Destination> {
+    let parent = parent_dir(dst)?;
+    let name = file_name(dst).to_path_buf();
+    let dir = retry.run(&target.path().join(parent), || target.create_dir_all(parent))?;
+    // Like fs::File::create, with the same mode before the umask.
+    match dir.open_file(Path::new(""), libc::O_RDWR | libc::O_TMPFILE, 0o666) {
+        Ok(file) => return Ok(Destination { file, dir, name, is_unnamed: true }),
+        // Older kernels and some filesystems do not support O_TMPFILE.
+        Err(ref err) if matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::EISDIR) | Some(libc::EINVAL)) => {}
+        Err(err) => return Err(err),
+    }
+    match dir.symlink_metadata(&name) {
+        Ok(ref meta) if meta.file_type().is_symlink() => dir.remove_file(&name)?,
+        _ => {}
+    }
+    let file = dir.open_file(&name, libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW, 0o666)?;
+    Ok(Destination { file, dir, name, is_unnamed: false })
+}
+
+/// Copy the data of src into dst after the filesystem refused to clone it.
+///
+/// Tries copy_file_range first, and a buffered copy when that is not supported
+/// either, e.g. across filesystems on older kernels. Returns the number of
+/// bytes copied.
+fn fallback_copy(mut src: &fs::File, mut dst: &fs::File, len: u64) -> io::Result<u64> {
+    // A range clone may have cloned some chunks before it failed.
+    dst.set_len(0)?;
+    match copy_file_range_all(src, dst, len) {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOSYS) || Reason::of_error(err) != Reason::Other => {
+            debug!("copy_file_range failed ({}), falling back to a buffered copy.", err);
+            dst.set_len(0)?;
+            io::copy(&mut src, &mut dst)
+        }
+        result => result,
+    }
+}
+
+// _IOR('f', 1, long) and _IOW('f', 2, long), although the kernel uses an int.
+const FS_IOC_GETFLAGS: libc::c_ulong = 0x80086601;
+const FS_IOC_SETFLAGS: libc::c_ulong = 0x40086602;
+
+const FS_COMPR_FL: libc::c_int = 0x4;
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+const FS_APPEND_FL: libc::c_int = 0x20;
+const FS_NODUMP_FL: libc::c_int = 0x40;
+const FS_NOATIME_FL: libc::c_int = 0x80;
+const FS_NOCOMP_FL: libc::c_int = 0x400;
+const FS_NOCOW_FL: libc::c_int = 0x800000;
+
+/// The file attributes that we copy to an empty destination, before the data.
+///
+/// Btrfs only applies nocow to empty files, and refuses to clone between a
+/// nocow and a cow file, so nocow must be set before the clone.
+const FLAGS_BEFORE_DATA: libc::c_int = FS_COMPR_FL | FS_NODUMP_FL | FS_NOATIME_FL | FS_NOCOMP_FL | FS_NOCOW_FL;
+
+/// The file attributes that we copy after everything else, because they
+/// forbid writing to the file, or changing its metadata.
+const FLAGS_AFTER_DATA: libc::c_int = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+/// Return the file attributes, as `lsattr` shows them.
+fn get_flags(file: &fs::File) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    check_os_result(unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS, &mut flags) })?;
+    Ok(flags)
+}
+
+/// Return whether dst is nocow and the source is not, then btrfs refuses to
+/// reflink with EINVAL. An empty file inherits nocow from its directory.
+fn is_nocow_mismatch(flags_src: libc::c_int, f_dst: &fs::File) -> bool {
+    flags_src & FS_NOCOW_FL == 0 && get_flags(f_dst).map_or(false, |flags| flags & FS_NOCOW_FL != 0)
+}
+
+/// Add the file attributes of the source that are in `mask` to dst.
+///
+/// Does nothing when the source has none of them, so filesystems without file
+/// attributes only fail when there is something to copy.
+fn copy_flags(flags_src: libc::c_int, f_dst: &fs::File, mask: libc::c_int) -> io::Result<()> {
+    if flags_src & mask == 0 {
+        return Ok(());
+    }
+    let mut flags = get_flags(f_dst)? | (flags_src & mask);
+    check_os_result(unsafe { libc::ioctl(f_dst.as_raw_fd(), FS_IOC_SETFLAGS, &mut flags) })
+}
+
+fn check_os_result(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Give dst the ownership, permissions, and times of the source, and with
+/// `xattrs` also its extended attributes.
+///
+/// `fs::File::create` makes a file owned by us with mode 0644, and the time
+/// of now. Rsync would fix that, but with a different mtime it may rewrite the
+/// data that we just shared, and with the right metadata already in place,
+/// its metadata pass has nothing left to do for these files.
+fn copy_metadata(meta_src: &fs::Metadata, f_src: &fs::File, f_dst: &fs::File, xattrs: bool) -> io::Result<()> {
+    let fd = f_dst.as_raw_fd();
+    // Only root can give files away. Do it before the chmod, because a chown
+    // clears the setuid and setgid bits.
+    if unsafe { libc::geteuid() } == 0 {
+        check_os_result(unsafe { libc::fchown(fd, meta_src.uid(), meta_src.gid()) })?;
+    }
+    check_os_result(unsafe { libc::fchmod(fd, meta_src.mode() & 0o7777) })?;
+    if xattrs {
+        xattr::copy_all(f_src, f_dst)?;
+    }
+    let times = [
+        libc::timespec { tv_sec: meta_src.atime() as libc::time_t, tv_nsec: meta_src.atime_nsec() as libc::c_long },
+        libc::timespec { tv_sec: meta_src.mtime() as libc::time_t, tv_nsec: meta_src.mtime_nsec() as libc::c_long },
+    ];
+    check_os_result(unsafe { libc::futimens(fd, times.as_ptr()) })
+}
+
+/// Return whether dst already is a file with the size and mtime of the source.
+///
+/// That is what an earlier run leaves behind, for example one that was
+/// interrupted, or what rsync leaves behind, so there is nothing left to do.
+fn is_up_to_date(meta_src: &fs::Metadata, target: &Root, dst: &Path) -> bool {
+    match target.symlink_metadata(dst) {
+        Ok(meta_dst) => {
+            meta_dst.is_file()
+                && meta_dst.len() == meta_src.len()
+                && meta_dst.mtime() == meta_src.mtime()
+                && meta_dst.mtime_nsec() == meta_src.mtime_nsec()
+        }
+        Err(..) => false,
+    }
+}
+
+/// Return the outcome of a clone that we should not make, or none to make it.
+fn skip_clone(
+    src: &ResolvedSource,
+    f_src: &fs::File,
+    meta_src: &fs::Metadata,
+    target: &Root,
+    dst: &Path,
+    options: CloneOptions,
+) -> Option<Outcome> {
+    // Something may have written to the source since we resolved it, we would
+    // clone different data than the heuristic matched then.
+    if meta_src.modified().ok() != Some(src.info.mtime) || meta_src.len() != src.info.len {
+        return Some(Outcome::SourceChanged);
+    }
+    let len = meta_src.len();
+    // If the destination is the source, creating it would truncate the source.
+    if let Ok(meta_dst) = target.metadata(dst) {
+        if meta_dst.dev() == meta_src.dev() && meta_dst.ino() == meta_src.ino() {
+            return Some(Outcome::SameInode(len));
+        }
+    }
+    // Don't truncate a destination that is already a reflinked copy, e.g.
+    // from a previous run, that would only destroy the existing sharing.
+    if options.backend != Backend::CopyFileRange && extents::already_shared(f_src, len, target, dst) {
+        return Some(Outcome::AlreadyShared(len));
+    }
+    if is_up_to_date(meta_src, target, dst) {
+        return Some(Outcome::UpToDate(len));
+    }
+    refuse_to_clobber(target, dst, options)
+}
+
+/// Return the outcome for a destination that already has data, unless --force
+/// allows us to truncate it.
+fn refuse_to_clobber(target: &Root, dst: &Path, options: CloneOptions) -> Option<Outcome> {
+    if options.force {
+        return None;
+    }
+    match target.symlink_metadata(dst) {
+        Ok(ref meta) if meta.is_file() && meta.len() > 0 => Some(Outcome::DestinationExists(meta.len())),
+        _ => None,
+    }
+}
+
+/// How `clone_paths` creates the destinations, from the config.
+#[derive(Clone, Copy)]
+struct CloneOptions {
+    backend: Backend,
+    /// Copy the data when the filesystem refuses to reflink, for --fallback-copy.
+    fallback_copy: bool,
+    retry: Retry,
+    /// Copy the extended attributes too, for --xattrs.
+    xattrs: bool,
+    /// Overwrite destinations that have data, for --force.
+    force: bool,
+}
+
+impl CloneOptions {
+    fn from_config(config: &Config) -> CloneOptions {
+        CloneOptions {
+            backend: config.backend,
+            fallback_copy: config.fallback_copy,
+            retry: Retry::from_config(config),
+            xattrs: config.xattrs,
+            force: config.force,
+        }
+    }
+}
+
+/// The destination trees, that the copies read from and write to.
+struct Trees {
+    base: Root,
+    target: Root,
+    /// The snapshots of the other-bases fallback.
+    other_bases: Vec<Root>,
 }
 
-const USAGE: &'static str = r#"btrfs-snapsync: Replay likely moves as reflink copies.
+impl Trees {
+    fn open(base: Root, dir_target_dst: &Path, other_bases: Vec<Root>) -> io::Result<Trees> {
+        Ok(Trees { base, target: Root::open(dir_target_dst)?, other_bases })
+    }
 
-Usage:
-    reflink-diff apply   <src-base> <src-target> <dst-base> <dst-target>
-    reflink-diff dry-run <src-base> <src-target> <dst-base> <dst-target>
+    /// Return the tree that the source is in.
+    fn root_of(&self, src: &ResolvedSource) -> &Root {
+        match (src.via, src.other_base) {
+            (Some(Fallback::DstTarget), _) => &self.target,
+            (_, Some(i)) => &self.other_bases[i],
+            _ => &self.base,
+        }
+    }
+}
 
-Diffs the file hierarchy from src-base to src-target, and detects
-potential moves, based on files having the same mtime and size.
+/// Make dst a copy of src using the configured backend, and return the outcome.
+///
+/// With the reflink backends, dst shares its extents with src. With the
+/// copy_file_range backend, the kernel copies the data without passing it
+/// through userspace, although some filesystems share extents anyway. With
+/// --fallback-copy, files that the filesystem refuses to reflink are copied.
+///
+/// The source is opened before anything is created in the destination, so
+/// when the source is unavailable, the destination is left untouched. Both
+/// are opened beneath their trees, and dst is relative to dst-target.
+fn clone_paths(src: &ResolvedSource, trees: &Trees, rel_dst: &Path, options: CloneOptions) -> Outcome {
+    let backend = options.backend;
+    let dst = trees.target.path().join(rel_dst);
+    debug!("Opening source {:?}.", src.path);
+    let f_src = match trees.root_of(src).open_file(&src.rel, libc::O_RDONLY, 0) {
+        Ok(f) => f,
+        Err(ref err) if is_missing(err) => return Outcome::SourceMissing,
+        Err(err) => return Outcome::SourceFailed(err),
+    };
+    let meta_src = match f_src.metadata() {
+        Ok(meta) => meta,
+        Err(err) => return Outcome::SourceFailed(err),
+    };
+    if let Some(outcome) = skip_clone(src, &f_src, &meta_src, &trees.target, rel_dst, options) {
+        return outcome;
+    }
+    let len = meta_src.len();
+    debug!("Creating destination {:?}.", dst);
+    let destination = match create_destination(&trees.target, rel_dst, options.retry) {
+        Ok(destination) => destination,
+        Err(err) => return Outcome::DestinationFailed(err),
+    };
+    let f_dst = &destination.file;
+    // Without file attributes on the source filesystem there are none to copy.
+    let flags_src = get_flags(&f_src).unwrap_or(0);
+    if let Err(err) = copy_flags(flags_src, &f_dst, FLAGS_BEFORE_DATA) {
+        warn!("Failed to copy the file attributes of the source to {:?}: {}", dst, err);
+    }
+    if let Err(err) = xattr::copy_compression(&f_src, &f_dst) {
+        warn!("Failed to copy the compression property of the source to {:?}: {}", dst, err);
+    }
+    trace!("Cloning {} bytes with the {} backend.", len, backend.name());
+    let result = options.retry.run(&dst, || match backend {
+        Backend::Reflink => clone_file(&f_src, &f_dst).map(|()| Outcome::Cloned(len)),
+        Backend::ReflinkRange => clone_range_all(&f_src, &f_dst, 0, len, CLONE_CHUNK_SIZE).map(|()| Outcome::Cloned(len)),
+        Backend::CopyFileRange => copy_file_range_all(&f_src, &f_dst, len).map(Outcome::RangeCopied),
+    });
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(ref err) if options.fallback_copy && backend != Backend::CopyFileRange && matches!(Reason::of_error(err), Reason::Unsupported | Reason::CrossDevice) => {
+            debug!("Reflinking {:?} failed ({}), copying it instead.", dst, err);
+            match fallback_copy(&f_src, &f_dst, len) {
+                Ok(len) => Outcome::FallbackCopied(len),
+                Err(err) => Outcome::DestinationFailed(err),
+            }
+        }
+        Err(ref err) if backend != Backend::CopyFileRange && err.raw_os_error() == Some(libc::EINVAL) && is_nocow_mismatch(flags_src, f_dst) => {
+            Outcome::NocowDestination
+        }
+        Err(err) if backend != Backend::CopyFileRange && Reason::of_error(&err) == Reason::Unsupported => {
+            Outcome::CloneUnsupported(err)
+        }
+        Err(err) => Outcome::DestinationFailed(err),
+    };
+    if !matches!(outcome, Outcome::Cloned(..) | Outcome::RangeCopied(..) | Outcome::FallbackCopied(..)) {
+        // Without data, the unnamed destination goes away when it is closed.
+        return outcome;
+    }
+    // The data is in place, so a failure here only costs rsync work.
+    if let Err(err) = copy_metadata(&meta_src, &f_src, f_dst, options.xattrs) {
+        warn!("Failed to copy the metadata of the source to {:?}: {}", dst, err);
+    }
+    let f_dst = match destination.persist() {
+        Ok(f_dst) => f_dst,
+        Err(err) => return Outcome::DestinationFailed(err),
+    };
+    // Changing these needs CAP_LINUX_IMMUTABLE, and afterwards we could not
+    // change or rename the file any more, so they go last.
+    if let Err(err) = copy_flags(flags_src, &f_dst, FLAGS_AFTER_DATA) {
+        warn!("Failed to make {:?} immutable or append-only like its source: {}", dst, err);
+    }
+    outcome
+}
 
-For every detected move, create a reflink:
-  * With as source, the base file, but in the destination tree.
-  * With as target, the target file, but in the destination tree.
+/// Return the held src-target tree that the path is in, the deepest one for
+/// a --subvolume, and the path relative to it.
+fn src_target_root<'a>(sources: &'a [Root], path: &'a Path) -> Option<(&'a Root, &'a Path)> {
+    // The first source is src-base, the others are src-target and those of the subvolumes.
+    sources[1..]
+        .iter()
+        .filter_map(|root| path.strip_prefix(root.path()).ok().map(|rel| (root, rel)))
+        .max_by_key(|&(root, _)| root.path().components().count())
+}
 
-In other words, this diffs src-base..src-target and replays that diff on
-top of dst-base.
+/// Make dst a plain copy of src, and return the size of the file.
+///
+/// Unlike `clone_paths`, this reads all data, and the copy shares nothing.
+/// The source is opened beneath its src-target tree, dst is relative to
+/// dst-target.
+fn copy_paths(src: &Path, sources: &[Root], target: &Root, rel_dst: &Path, options: CloneOptions) -> Outcome {
+    let dst = target.path().join(rel_dst);
+    debug!("Copying {:?} to {:?}.", src, dst);
+    let (root, rel_src) = match src_target_root(sources, src) {
+        Some(found) => found,
+        None => {
+            let msg = format!("{:?} is not in src-target.", src);
+            return Outcome::SourceFailed(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let mut f_src = match root.open_file(rel_src, libc::O_RDONLY, 0) {
+        Ok(f) => f,
+        Err(ref err) if is_missing(err) => return Outcome::SourceMissing,
+        Err(err) => return Outcome::SourceFailed(err),
+    };
+    let meta_src = match f_src.metadata() {
+        Ok(ref meta_src) if is_up_to_date(meta_src, target, rel_dst) => return Outcome::UpToDate(meta_src.len()),
+        Ok(meta_src) => meta_src,
+        Err(err) => return Outcome::SourceFailed(err),
+    };
+    if let Some(outcome) = refuse_to_clobber(target, rel_dst, options) {
+        return outcome;
+    }
+    let mut destination = match create_destination(target, rel_dst, options.retry) {
+        Ok(destination) => destination,
+        Err(err) => return Outcome::DestinationFailed(err),
+    };
+    // We can't tell from io::copy whether reading or writing failed, so
+    // attribute errors to the destination, which is the more likely culprit.
+    let len = match io::copy(&mut f_src, &mut destination.file) {
+        Ok(len) => len,
+        Err(err) => return Outcome::DestinationFailed(err),
+    };
+    // Without the mtime of the source, the next run and rsync would see a
+    // changed file, and copy it again.
+    if let Err(err) = copy_metadata(&meta_src, &f_src, &destination.file, options.xattrs) {
+        warn!("Failed to copy the metadata of the source to {:?}: {}", dst, err);
+    }
+    match destination.persist() {
+        Ok(..) => Outcome::Copied(len),
+        Err(err) => Outcome::DestinationFailed(err),
+    }
+}
 
-In "apply" mode the reflinks are created. In "dry-run" mode, we print
-which reflinks would be created.
+/// A source for a copy that exists in the destination trees.
+struct ResolvedSource {
+    /// Full path of the source file.
+    path: PathBuf,
+    /// The path relative to dst-base, or to dst-target for that fallback.
+    rel: PathBuf,
+    /// The fallback that found this source, or none for the primary source.
+    via: Option<Fallback>,
+    /// For the other-bases fallback, the index of the snapshot.
+    other_base: Option<usize>,
+    /// Size and mtime of the source when we resolved it.
+    info: FileInfo,
+}
 
-This is only a heuristic, but it sets up reflink sharing where possible,
-and rsync can later fix everything up (metadata, changed files, new and
-deleted files, etc.). When using rsync by itself, it would try to copy
-the file, destroying potential sharing.
-"#;
+/// Return whether opening a path failed because it is not there, also when
+/// one of its directories was replaced by a file since the scan.
+fn is_missing(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::NotFound || err.raw_os_error() == Some(libc::ENOTDIR)
+}
 
-fn main() -> io::Result<()> {
-    if env::args().len() < 6 {
-        println!("{}", USAGE);
-        process::exit(1);
+/// Return the size and mtime of the file, or none if it is not a file.
+fn file_info(path: &Path) -> Option<FileInfo> {
+    match fs::metadata(path) {
+        Ok(ref meta) if meta.is_file() => meta.modified().ok().map(|mtime| FileInfo { len: meta.len(), mtime }),
+        _ => None,
     }
+}
 
-    let dry_run = match &env::args().nth(1).unwrap()[..] {
-        "dry-run" => true,
-        "apply" => false,
-        _ => {
-            println!("{}", USAGE);
-            process::exit(1);
+/// Find a source for the copy that exists in the destination trees.
+///
+/// The destination base is not necessarily a perfect mirror of the source
+/// base, so the file that the diff chose may be missing there. In that case we
+/// try the fallbacks in order. Files in dst-target that are the destination of
+/// some copy in the plan are never used, because they may be overwritten.
+fn resolve_source(
+    dir_base_dst: &Path,
+    dir_target_dst: &Path,
+    copy: &CopyFile,
+    fallbacks: &[Fallback],
+    other_bases: &[PathBuf],
+    destinations: &HashSet<&Path>,
+) -> Option<ResolvedSource> {
+    if let Some(info) = file_info(&dir_base_dst.join(&copy.src)) {
+        return Some(ResolvedSource {
+            path: dir_base_dst.join(&copy.src),
+            rel: copy.src.clone(),
+            via: None,
+            other_base: None,
+            info,
+        });
+    }
+    let own_and_alternatives = || Some(&copy.src).into_iter().chain(copy.alt_srcs.iter());
+    for &fallback in fallbacks {
+        let trees: Vec<(Option<usize>, &Path, Vec<&PathBuf>)> = match fallback {
+            Fallback::Alternatives => vec![(None, dir_base_dst, copy.alt_srcs.iter().collect())],
+            Fallback::DstTarget => vec![(
+                None,
+                dir_target_dst,
+                own_and_alternatives().filter(|p| !destinations.contains(p.as_path())).collect(),
+            )],
+            Fallback::OtherBases => other_bases
+                .iter()
+                .enumerate()
+                .map(|(i, dir)| (Some(i), dir.as_path(), own_and_alternatives().collect()))
+                .collect(),
+        };
+        for (other_base, dir, candidates) in trees {
+            for candidate in candidates {
+                let path = dir.join(candidate);
+                if let Some(info) = file_info(&path) {
+                    return Some(ResolvedSource {
+                        path,
+                        rel: candidate.clone(),
+                        via: Some(fallback),
+                        other_base,
+                        info,
+                    });
+                }
+            }
         }
+    }
+    None
+}
+
+/// A copy, together with the source that will be used for it.
+struct Planned {
+    copy: CopyFile,
+    /// The source found by the pre-flight check, if any.
+    src: Option<ResolvedSource>,
+    /// With --copy-missing-from-source, when there is no source to reflink
+    /// from, the file in src-target to copy the data from instead.
+    copy_from: Option<PathBuf>,
+}
+
+/// Resolve the source for every copy, before anything is written.
+///
+/// Reports all copies that have no source up front, so the user can fix
+/// dst-base before the run mutates anything. With `strict`, a missing source
+/// is an error, otherwise those copies are skipped later, or copied from
+/// src-target with `copy_missing_from_source`.
+fn preflight(
+    copies: Vec<CopyFile>,
+    dir_base_src: &Path,
+    dir_target_src: &Path,
+    dir_base_dst: &Path,
+    dir_target_dst: &Path,
+    config: &Config,
+) -> io::Result<Vec<Planned>> {
+    // The diff is in the layout of the source trees, resolve it in the layout
+    // of the destination, but keep the source path to copy missing data from.
+    let paths_base_src: Vec<PathBuf> = copies.iter().map(|c| c.src.clone()).collect();
+    let paths_target_src: Vec<PathBuf> = copies.iter().map(|c| c.dst.clone()).collect();
+    let copies: Vec<CopyFile> = copies.into_iter().map(|c| map_copy(c, &config.maps)).collect();
+    let sources: Vec<Option<ResolvedSource>> = {
+        let destinations: HashSet<&Path> = copies.iter().map(|c| c.dst.as_path()).collect();
+        copies
+            .iter()
+            .map(|copy| {
+                resolve_source(dir_base_dst, dir_target_dst, copy, &config.fallback, &config.other_bases, &destinations)
+            })
+            .collect()
+    };
+    let plan: Vec<Planned> = copies
+        .into_iter()
+        .zip(sources)
+        .zip(paths_target_src)
+        .map(|((copy, src), path_target_src)| {
+            let copy_from = match src {
+                None if config.copy_missing_from_source => Some(src_target_path(dir_target_src, &path_target_src, config)),
+                _ => None,
+            };
+            Planned { copy, src, copy_from }
+        })
+        .collect();
+    check_mirror(&plan, &paths_base_src, dir_base_src, config)?;
+
+    let num_missing = plan.iter().filter(|p| p.src.is_none()).count();
+    if num_missing == 0 {
+        return Ok(plan);
+    }
+
+    let mut message = format!("{} of {} moves have no source in dst-base {:?}:", num_missing, plan.len(), dir_base_dst);
+    for planned in plan.iter().filter(|p| p.src.is_none()) {
+        message.push_str(&format!("\n  {:?} -> {:?}", planned.copy.src, planned.copy.dst));
+    }
+    if config.strict && !config.copy_missing_from_source {
+        error!("{}", message);
+    } else {
+        warn!("{}", message);
+    }
+
+    if config.copy_missing_from_source {
+        info!("These files will be copied from src-target instead, without sharing.");
+        Ok(plan)
+    } else if config.strict {
+        let msg = format!("{} sources missing in dst-base, aborting because of --strict.", num_missing);
+        Err(io::Error::new(io::ErrorKind::NotFound, msg))
+    } else {
+        info!("These moves will be skipped.");
+        Ok(plan)
+    }
+}
+
+/// Refuse to apply a plan where more moves than --max-missing have no source,
+/// which suggests that the wrong dst-base was passed, or a filter is off.
+fn check_max_missing(plan: &[Planned], dir_base_dst: &Path, config: &Config) -> io::Result<()> {
+    let max = match config.max_missing {
+        Some(max) => max,
+        None => return Ok(()),
     };
+    let num_missing = plan.iter().filter(|p| p.src.is_none()).count();
+    if !max.is_exceeded(num_missing, plan.len()) {
+        return Ok(());
+    }
+    let msg = format!(
+        "{} of {} moves have no source in dst-base {:?}, more than --max-missing {}. \
+        Is it the right snapshot? Refusing to apply.",
+        num_missing,
+        plan.len(),
+        dir_base_dst,
+        max,
+    );
+    Err(io::Error::new(io::ErrorKind::NotFound, msg))
+}
+
+/// How many of the sources in dst-base we compare with src-base.
+const MIRROR_SAMPLE_SIZE: usize = 64;
 
-    let dir_base_src = env::args().nth(2).unwrap();
-    let dir_target_src = env::args().nth(3).unwrap();
+/// Spot-check that dst-base mirrors src-base: a sample of the sources that
+/// were found at their own path should have the size there that they have in
+/// src-base. When the mirror diverged, or the wrong directory was passed, the
+/// clones would get the wrong data.
+///
+/// Warns about the sources that differ, with --strict aborts.
+fn check_mirror(plan: &[Planned], paths_base_src: &[PathBuf], dir_base_src: &Path, config: &Config) -> io::Result<()> {
+    let primary: Vec<(&ResolvedSource, &PathBuf)> = plan
+        .iter()
+        .zip(paths_base_src)
+        .filter_map(|(planned, path)| planned.src.as_ref().filter(|src| src.via.is_none()).map(|src| (src, path)))
+        .collect();
+    // Spread the sample over the plan, rather than take its first sources.
+    let step = (primary.len() / MIRROR_SAMPLE_SIZE).max(1);
+    let sample: Vec<_> = primary.into_iter().step_by(step).take(MIRROR_SAMPLE_SIZE).collect();
+    let mut message = String::new();
+    let mut num_differ = 0;
+    for &(src, path) in &sample {
+        // A file that src-base lost since the scan we can not compare.
+        match file_info(&dir_base_src.join(path)) {
+            Some(info) if info.len != src.info.len => {
+                message.push_str(&format!(
+                    "\n  {:?}: {} bytes in src-base, {} bytes in dst-base",
+                    path, info.len, src.info.len,
+                ));
+                num_differ += 1;
+            }
+            _ => {}
+        }
+    }
+    if num_differ == 0 {
+        return Ok(());
+    }
+    let message = format!(
+        "{} of {} sampled sources in dst-base have a different size than in src-base {:?}, \
+        dst-base may not mirror it, and clones from it would get the wrong data:{}",
+        num_differ,
+        sample.len(),
+        dir_base_src,
+        message,
+    );
+    if !config.strict {
+        warn!("{}", message);
+        return Ok(());
+    }
+    error!("{}", message);
+    let msg = format!("{} sources differ between src-base and dst-base, aborting because of --strict.", num_differ);
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
 
-    let dir_base_dst = PathBuf::from(env::args().nth(4).unwrap());
-    let dir_target_dst = PathBuf::from(env::args().nth(5).unwrap());
+/// Log how many of the sources used were found by which fallback.
+fn print_source_breakdown<'a, I: Iterator<Item = &'a ResolvedSource>>(sources: I) {
+    let (mut num_primary, mut num_alternative, mut num_dst_target, mut num_other) = (0, 0, 0, 0);
+    for src in sources {
+        match src.via {
+            None => num_primary += 1,
+            Some(Fallback::Alternatives) => num_alternative += 1,
+            Some(Fallback::DstTarget) => num_dst_target += 1,
+            Some(Fallback::OtherBases) => num_other += 1,
+        }
+    }
+    if num_alternative + num_dst_target + num_other > 0 {
+        info!(
+            "Sources: {} at their own path in dst-base, {} alternatives in dst-base, {} from dst-target, \
+            {} from other bases.",
+            num_primary, num_alternative, num_dst_target, num_other,
+        );
+    }
+}
 
-    let entries_base = scan_dir(&dir_base_src)?;
-    let entries_target = scan_dir(&dir_target_src)?;
+/// What happened when applying one copy.
+enum Outcome {
+    /// The reflink was created, for a file of this many bytes.
+    Cloned(u64),
+    /// The destination is the same inode as the source, for a file of this
+    /// many bytes, so there is nothing to do.
+    SameInode(u64),
+    /// The destination already shares all extents with the source, for a file
+    /// of this many bytes, so we left it alone.
+    AlreadyShared(u64),
+    /// The destination already has the size and mtime of the source, for a
+    /// file of this many bytes, so we assume that an earlier run did the copy.
+    UpToDate(u64),
+    /// The data was copied in the kernel with copy_file_range, this many bytes.
+    RangeCopied(u64),
+    /// There was no source to reflink, so the data was copied from src-target.
+    Copied(u64),
+    /// The filesystem refused to reflink the source, so with --fallback-copy
+    /// its data was copied instead, this many bytes.
+    FallbackCopied(u64),
+    /// The filesystem does not support reflinking this file, e.g. a swapfile,
+    /// a nodatacow file, or an encrypted directory, so we skipped it.
+    ///
+    /// Like a missing source, this does not stop the run; rsync copies it.
+    CloneUnsupported(io::Error),
+    /// The destination inherited the nocow attribute from its directory, and
+    /// the source is not nocow, so btrfs refused to reflink it, and without
+    /// --fallback-copy we skipped it.
+    NocowDestination,
+    /// The destination already has data, this many bytes, and without --force
+    /// we do not truncate it, so we skipped the copy.
+    DestinationExists(u64),
+    /// The size or mtime of the source changed since the pre-flight check,
+    /// so we skipped the copy rather than clone data that may not match.
+    SourceChanged,
+    /// None of the candidate sources exist in dst-base, so we skipped the copy.
+    ///
+    /// This is a limitation of dst-base not mirroring src-base, rather than an
+    /// error, so it does not stop the run.
+    SourceMissing,
+    /// The source exists in dst-base, but opening it failed.
+    SourceFailed(io::Error),
+    /// Creating the destination file, or the reflink into it, failed.
+    DestinationFailed(io::Error),
+}
 
-    let copies = diff(&entries_base, entries_target)?;
+impl Outcome {
+    fn error(&self) -> Option<&io::Error> {
+        match *self {
+            Outcome::SourceFailed(ref err) => Some(err),
+            Outcome::DestinationFailed(ref err) => Some(err),
+            _ => None,
+        }
+    }
 
-    for copy in copies.iter() {
-        if dry_run {
-            println!("{:?} -> {:?}", copy.src, copy.dst);
-        } else {
-            let mut src_path = dir_base_dst.clone();
-            let mut dst_path = dir_target_dst.clone();
-            src_path.push(&copy.src);
-            dst_path.push(&copy.dst);
-            println!("{:?} -> {:?}", src_path, dst_path);
-            clone_paths(src_path, dst_path)?;
+    /// Why the move could not be completed, or none if it was completed.
+    fn unresolved_reason(&self) -> Option<Reason> {
+        match *self {
+            Outcome::Cloned(..) | Outcome::SameInode(..) | Outcome::AlreadyShared(..) | Outcome::RangeCopied(..) => None,
+            Outcome::UpToDate(..) => None,
+            Outcome::Copied(..) | Outcome::FallbackCopied(..) => None,
+            Outcome::CloneUnsupported(..) => Some(Reason::Unsupported),
+            Outcome::NocowDestination => Some(Reason::NocowDestination),
+            Outcome::DestinationExists(..) => Some(Reason::DestinationExists),
+            Outcome::SourceChanged => Some(Reason::SourceChanged),
+            Outcome::SourceMissing => Some(Reason::SourceMissing),
+            Outcome::SourceFailed(ref err) => Some(Reason::of_error(err)),
+            Outcome::DestinationFailed(ref err) => Some(Reason::of_error(err)),
         }
     }
 
-    Ok(())
+    /// Whether the destination now has the data of its resolved source.
+    fn shares_source(&self) -> bool {
+        match *self {
+            Outcome::Cloned(..) | Outcome::SameInode(..) | Outcome::AlreadyShared(..) | Outcome::RangeCopied(..) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A copy that failed, for the report.
+struct Failure {
+    src: PathBuf,
+    dst: PathBuf,
+    /// Which side failed, `source` or `destination`.
+    side: &'static str,
+    reason: Reason,
+    kind: io::ErrorKind,
+    errno: Option<i32>,
+    message: String,
+}
+
+impl Failure {
+    fn new(src: &Path, dst: &Path, side: &'static str, err: &io::Error) -> Failure {
+        Failure {
+            src: src.to_path_buf(),
+            dst: dst.to_path_buf(),
+            side,
+            reason: Reason::of_error(err),
+            kind: err.kind(),
+            errno: err.raw_os_error(),
+            message: err.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        let mut pairs = vec![
+            ("src", Json::path(&self.src)),
+            ("dst", Json::path(&self.dst)),
+            ("side", Json::str(self.side)),
+            ("reason", Json::str(self.reason.name())),
+            ("error", Json::str(&self.message)),
+        ];
+        // Errors that did not come from a system call have no errno.
+        if let Some(errno) = self.errno {
+            pairs.push(("errno", Json::Int(errno as i64)));
+        }
+        Json::object(pairs)
+    }
+}
+
+/// Log every failed copy with its error, for --keep-going, where there can be
+/// many of them among the other output.
+fn print_failures(failures: &[Failure]) {
+    if failures.is_empty() {
+        return
+    }
+    let mut message = format!("Failed copies ({}):", failures.len());
+    for failure in failures {
+        message.push_str(&format!("\n  {:?}: {} error: {}", failure.dst, failure.side, failure.message));
+    }
+    error!("{}", message);
+}
+
+/// The outcome of applying one copy, kept until it is its turn to be printed.
+struct Applied {
+    src: PathBuf,
+    dst: PathBuf,
+    outcome: Outcome,
+    /// The paths that did not exist before the copy, for --undo-log.
+    created: Vec<PathBuf>,
+}
+
+/// Counters for the apply phase, safe to update from multiple workers.
+///
+/// Every outcome has its own file counter, and the number of files done is
+/// their sum, so the file counts of a tally always add up.
+#[derive(Default)]
+struct Counters {
+    files_reflinked: AtomicUsize,
+    bytes_reflinked: AtomicU64,
+    files_same_inode: AtomicUsize,
+    files_already_shared: AtomicUsize,
+    files_up_to_date: AtomicUsize,
+    files_range_copied: AtomicUsize,
+    bytes_range_copied: AtomicU64,
+    files_copied: AtomicUsize,
+    bytes_copied: AtomicU64,
+    files_fallback_copied: AtomicUsize,
+    bytes_fallback_copied: AtomicU64,
+    files_unsupported: AtomicUsize,
+    files_nocow: AtomicUsize,
+    files_exist: AtomicUsize,
+    sources_changed: AtomicUsize,
+    sources_missing: AtomicUsize,
+    source_failures: AtomicUsize,
+    destination_failures: AtomicUsize,
+}
+
+/// A snapshot of the counters at one point in time.
+#[derive(Clone, Copy, Debug)]
+struct Tally {
+    files_done: usize,
+    files_reflinked: usize,
+    bytes_reflinked: u64,
+    files_same_inode: usize,
+    files_already_shared: usize,
+    files_up_to_date: usize,
+    files_range_copied: usize,
+    bytes_range_copied: u64,
+    files_copied: usize,
+    bytes_copied: u64,
+    files_fallback_copied: usize,
+    bytes_fallback_copied: u64,
+    files_unsupported: usize,
+    files_nocow: usize,
+    files_exist: usize,
+    sources_changed: usize,
+    sources_missing: usize,
+    source_failures: usize,
+    destination_failures: usize,
+}
+
+impl Tally {
+    fn failures(&self) -> usize {
+        self.source_failures + self.destination_failures
+    }
+
+    /// The number of files that got new data, by reflinking or copying.
+    fn files_changed(&self) -> usize {
+        self.files_reflinked + self.files_range_copied + self.files_copied + self.files_fallback_copied
+    }
+
+    /// The number of bytes that were reflinked or copied.
+    fn bytes_changed(&self) -> u64 {
+        self.bytes_reflinked + self.bytes_range_copied + self.bytes_copied + self.bytes_fallback_copied
+    }
+
+    /// Return whether the files reflinked or copied so far reach --limit or --limit-bytes.
+    fn reaches_limit(&self, limit: Option<usize>, limit_bytes: Option<u64>) -> bool {
+        limit.map_or(false, |n| self.files_changed() >= n) || limit_bytes.map_or(false, |n| self.bytes_changed() >= n)
+    }
+}
+
+impl Counters {
+    /// Record the outcome of one copy.
+    fn record(&self, outcome: &Outcome) {
+        match *outcome {
+            Outcome::Cloned(len) => {
+                self.files_reflinked.fetch_add(1, Ordering::SeqCst);
+                self.bytes_reflinked.fetch_add(len, Ordering::SeqCst);
+            }
+            Outcome::SameInode(..) => { self.files_same_inode.fetch_add(1, Ordering::SeqCst); }
+            Outcome::AlreadyShared(..) => { self.files_already_shared.fetch_add(1, Ordering::SeqCst); }
+            Outcome::UpToDate(..) => { self.files_up_to_date.fetch_add(1, Ordering::SeqCst); }
+            Outcome::RangeCopied(len) => {
+                self.files_range_copied.fetch_add(1, Ordering::SeqCst);
+                self.bytes_range_copied.fetch_add(len, Ordering::SeqCst);
+            }
+            Outcome::Copied(len) => {
+                self.files_copied.fetch_add(1, Ordering::SeqCst);
+                self.bytes_copied.fetch_add(len, Ordering::SeqCst);
+            }
+            Outcome::FallbackCopied(len) => {
+                self.files_fallback_copied.fetch_add(1, Ordering::SeqCst);
+                self.bytes_fallback_copied.fetch_add(len, Ordering::SeqCst);
+            }
+            Outcome::CloneUnsupported(..) => { self.files_unsupported.fetch_add(1, Ordering::SeqCst); }
+            Outcome::NocowDestination => { self.files_nocow.fetch_add(1, Ordering::SeqCst); }
+            Outcome::DestinationExists(..) => { self.files_exist.fetch_add(1, Ordering::SeqCst); }
+            Outcome::SourceChanged => { self.sources_changed.fetch_add(1, Ordering::SeqCst); }
+            Outcome::SourceMissing => { self.sources_missing.fetch_add(1, Ordering::SeqCst); }
+            Outcome::SourceFailed(..) => { self.source_failures.fetch_add(1, Ordering::SeqCst); }
+            Outcome::DestinationFailed(..) => { self.destination_failures.fetch_add(1, Ordering::SeqCst); }
+        }
+    }
+
+    /// Read the counters. Every counter is loaded on its own, so a copy that
+    /// completes during the read may or may not be in the tally, and its bytes
+    /// may be missing while its file is counted. The file counts are consistent
+    /// with each other, because the number of files done is computed from them.
+    fn tally(&self) -> Tally {
+        let mut tally = Tally {
+            files_done: 0,
+            files_reflinked: self.files_reflinked.load(Ordering::SeqCst),
+            bytes_reflinked: self.bytes_reflinked.load(Ordering::SeqCst),
+            files_same_inode: self.files_same_inode.load(Ordering::SeqCst),
+            files_already_shared: self.files_already_shared.load(Ordering::SeqCst),
+            files_up_to_date: self.files_up_to_date.load(Ordering::SeqCst),
+            files_range_copied: self.files_range_copied.load(Ordering::SeqCst),
+            bytes_range_copied: self.bytes_range_copied.load(Ordering::SeqCst),
+            files_copied: self.files_copied.load(Ordering::SeqCst),
+            bytes_copied: self.bytes_copied.load(Ordering::SeqCst),
+            files_fallback_copied: self.files_fallback_copied.load(Ordering::SeqCst),
+            bytes_fallback_copied: self.bytes_fallback_copied.load(Ordering::SeqCst),
+            files_unsupported: self.files_unsupported.load(Ordering::SeqCst),
+            files_nocow: self.files_nocow.load(Ordering::SeqCst),
+            files_exist: self.files_exist.load(Ordering::SeqCst),
+            sources_changed: self.sources_changed.load(Ordering::SeqCst),
+            sources_missing: self.sources_missing.load(Ordering::SeqCst),
+            source_failures: self.source_failures.load(Ordering::SeqCst),
+            destination_failures: self.destination_failures.load(Ordering::SeqCst),
+        };
+        tally.files_done = tally.files_reflinked
+            + tally.files_same_inode
+            + tally.files_already_shared
+            + tally.files_up_to_date
+            + tally.files_range_copied
+            + tally.files_copied
+            + tally.files_fallback_copied
+            + tally.files_unsupported
+            + tally.files_nocow
+            + tally.files_exist
+            + tally.sources_changed
+            + tally.sources_missing
+            + tally.failures();
+        tally
+    }
+}
+
+fn percentage(n: usize, total: usize) -> f64 {
+    if total == 0 { 100.0 } else { 100.0 * n as f64 / total as f64 }
+}
+
+/// Warn if only a small fraction of the moves can be reflinked.
+///
+/// We want to set up as much sharing as possible before rsync runs, so when
+/// that is only possible for few moves, dst-base is likely not a good mirror
+/// of src-base, or the wrong base was passed.
+fn warn_if_poor_mirror(num_shared: usize, num_moves: usize) {
+    if percentage(num_shared, num_moves) < 50.0 {
+        warn!(
+            "Only {} of {} moves could be satisfied from dst-base, \
+            it may not be a good mirror of src-base.",
+            num_shared, num_moves,
+        );
+    }
+}
+
+/// Return the counts of the tally as JSON object members.
+fn tally_json(tally: &Tally) -> Vec<(&'static str, Json)> {
+    vec![
+        ("reflinked", Json::uint(tally.files_reflinked as u64)),
+        ("bytes_reflinked", Json::uint(tally.bytes_reflinked)),
+        ("same_inode", Json::uint(tally.files_same_inode as u64)),
+        ("already_shared", Json::uint(tally.files_already_shared as u64)),
+        ("up_to_date", Json::uint(tally.files_up_to_date as u64)),
+        ("range_copied", Json::uint(tally.files_range_copied as u64)),
+        ("bytes_range_copied", Json::uint(tally.bytes_range_copied)),
+        ("copied", Json::uint(tally.files_copied as u64)),
+        ("bytes_copied", Json::uint(tally.bytes_copied)),
+        ("fallback_copied", Json::uint(tally.files_fallback_copied as u64)),
+        ("bytes_fallback_copied", Json::uint(tally.bytes_fallback_copied)),
+        ("skipped", Json::uint(tally.sources_missing as u64)),
+        ("unsupported", Json::uint(tally.files_unsupported as u64)),
+        ("nocow", Json::uint(tally.files_nocow as u64)),
+        ("exists", Json::uint(tally.files_exist as u64)),
+        ("source_changed", Json::uint(tally.sources_changed as u64)),
+        ("failed", Json::uint(tally.failures() as u64)),
+    ]
+}
+
+/// Quote a CSV field if needed, as in RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write every detected move as a CSV row, with the heuristic that matched it.
+fn write_csv(path: &Path, copies: &[CopyFile]) -> io::Result<()> {
+    let mut csv = String::from("src,dst,size,mtime,heuristic\n");
+    for copy in copies {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&copy.src.to_string_lossy()),
+            csv_field(&copy.dst.to_string_lossy()),
+            copy.info.len,
+            logger::format_timestamp(copy.info.mtime),
+            copy.matched_by.name(),
+        ));
+    }
+    fs::write(path, csv)
+}
+
+/// Write the stats of an apply run as a JSON object to the given file.
+fn write_stats_json(
+    path: &Path,
+    num_copies: usize,
+    tally: &Tally,
+    num_symlink_targets: usize,
+    num_symlinks: usize,
+    unresolved: &Unresolved,
+) -> io::Result<()> {
+    let mut pairs = vec![
+        ("moves", Json::uint(num_copies as u64)),
+        ("symlink_targets", Json::uint(num_symlink_targets as u64)),
+        ("symlinks_created", Json::uint(num_symlinks as u64)),
+    ];
+    pairs.extend(tally_json(tally));
+    pairs.push(("unresolved", unresolved.to_json()));
+    fs::write(path, format!("{}\n", Json::object(pairs)))
+}
+
+/// Write the report of the run: the arguments and resolved options, the
+/// duration of every phase, the counts, and in apply mode every failure.
+fn write_report(
+    path: &Path,
+    args: &cli::Args,
+    config: &Config,
+    num_scanned: (usize, usize),
+    num_moves: usize,
+    applied: Option<(&Tally, &[Failure], &io::Result<()>)>,
+    durations: &PhaseDurations,
+) -> io::Result<()> {
+    let phases = durations.0.iter().map(|&(phase, d)| (phase, Json::Float(d.as_secs_f64()))).collect();
+    let finished = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut counts = vec![
+        ("files_scanned_src_base", Json::uint(num_scanned.0 as u64)),
+        ("files_scanned_src_target", Json::uint(num_scanned.1 as u64)),
+        ("moves", Json::uint(num_moves as u64)),
+    ];
+    let mut pairs = vec![
+        ("version", Json::str(env!("CARGO_PKG_VERSION"))),
+        ("finished_unix_seconds", Json::uint(finished)),
+        ("command", Json::str(if args.command == Some(Command::Apply) { "apply" } else { "dry-run" })),
+        ("paths", Json::Array(args.paths.iter().map(|p| Json::path(p)).collect())),
+        ("config", config.to_json()),
+        ("phase_seconds", Json::object(phases)),
+    ];
+    match applied {
+        Some((tally, failures, result)) => {
+            counts.extend(tally_json(tally));
+            pairs.push(("counts", Json::object(counts)));
+            pairs.push(("failures", Json::Array(failures.iter().map(Failure::to_json).collect())));
+            let error = match *result {
+                Ok(()) => Json::str(""),
+                Err(ref err) => Json::str(&err.to_string()),
+            };
+            pairs.push(("error", error));
+        }
+        None => pairs.push(("counts", Json::object(counts))),
+    }
+    fs::write(path, format!("{}\n", Json::object(pairs)))
+}
+
+/// Recreate the symlink in dst-target, return whether it had to be created.
+///
+/// A symlink that is already there with the right target is left alone, and
+/// one with a different target is replaced. Anything else at the path is
+/// not ours to remove, so that is an error.
+fn create_symlink(link: &CreateSymlink, target: &Root, root: &Path, retry: Retry) -> io::Result<bool> {
+    let path = target.path().join(&link.path);
+    check_beneath(root, &path)?;
+    let parent = parent_dir(&link.path)?;
+    let name = file_name(&link.path);
+    let dir = retry.run(&target.path().join(parent), || target.create_dir_all(parent))?;
+    match dir.symlink_metadata(name) {
+        Ok(ref meta) if meta.file_type().is_symlink() => {
+            if dir.read_link(name)? == link.target {
+                return Ok(false)
+            }
+            dir.remove_file(name)?;
+        }
+        Ok(..) => {
+            let msg = format!("{:?} exists and is not a symlink.", path);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    dir.symlink(&link.target, name)?;
+    Ok(true)
+}
+
+/// Recreate all symlinks, and return how many were created.
+///
+/// Stops at the first failure, like applying the copies does.
+fn apply_symlinks(
+    symlinks: &[CreateSymlink],
+    target: &Root,
+    printer: &Printer,
+    retry: Retry,
+    undo_log: &mut Option<UndoLog>,
+) -> io::Result<usize> {
+    let mut num_created = 0;
+    let dir_target_dst = target.path();
+    let root = fs::canonicalize(dir_target_dst)?;
+    for link in symlinks {
+        let created = if undo_log.is_some() { undo::missing_paths(&dir_target_dst.join(&link.path)) } else { Vec::new() };
+        let result = create_symlink(link, target, &root, retry);
+        record_undo(undo_log, &created);
+        match result {
+            Ok(true) => {
+                printer.print(Kind::Link, Some(&link.target), &dir_target_dst.join(&link.path), None);
+                num_created += 1;
+            }
+            Ok(false) => {}
+            Err(err) => {
+                error!("Failed to create symlink {:?}: {}", dir_target_dst.join(&link.path), err);
+                return Err(err);
+            }
+        }
+    }
+    Ok(num_created)
+}
+
+/// Log how far applying is, and the oldest copy in progress, if a progress
+/// line is due.
+fn print_apply_progress(progress: &mut Progress, num_copies: usize, tally: &Tally, current: Option<&Path>) {
+    if !progress.is_due() {
+        return
+    }
+    let bytes = tally.bytes_changed();
+    let eta = match progress.eta(tally.files_done, num_copies) {
+        Some(eta) => format!(", about {} remaining", progress::format_duration(eta)),
+        None => String::new(),
+    };
+    let current = current.map_or(String::new(), |path| format!(", at {:?}", path));
+    info!(
+        "Applied {} of {} moves ({:.1}%, {} bytes){}{}.",
+        tally.files_done,
+        num_copies,
+        percentage(tally.files_done, num_copies),
+        bytes,
+        eta,
+        current,
+    );
+}
+
+/// Log the final counts of an apply run.
+fn print_tally(backend: Backend, num_copies: usize, tally: &Tally, cloned_sources: Vec<&ResolvedSource>) {
+    let num_cloned = tally.files_reflinked;
+    match backend {
+        Backend::Reflink | Backend::ReflinkRange => info!(
+            "Reflinked {} of {} files ({:.1}%, {} bytes).",
+            num_cloned,
+            num_copies,
+            percentage(num_cloned, num_copies),
+            tally.bytes_reflinked,
+        ),
+        Backend::CopyFileRange => info!(
+            "Copied {} of {} files with copy_file_range ({:.1}%, {} bytes).",
+            tally.files_range_copied,
+            num_copies,
+            percentage(tally.files_range_copied, num_copies),
+            tally.bytes_range_copied,
+        ),
+    }
+    print_source_breakdown(cloned_sources.into_iter());
+    if tally.files_same_inode > 0 {
+        info!(
+            "Skipped {} files whose destination is the same inode as the source.",
+            tally.files_same_inode,
+        );
+    }
+    if tally.files_already_shared > 0 {
+        info!(
+            "Left {} files alone that already share their extents with the source.",
+            tally.files_already_shared,
+        );
+    }
+    if tally.files_up_to_date > 0 {
+        info!(
+            "Skipped {} files that already have the size and mtime of their source.",
+            tally.files_up_to_date,
+        );
+    }
+    if tally.files_copied > 0 {
+        info!(
+            "Copied {} files ({} bytes) from src-target, because their source is not present in dst-base.",
+            tally.files_copied, tally.bytes_copied,
+        );
+    }
+    if tally.files_fallback_copied > 0 {
+        info!(
+            "Copied {} files ({} bytes) instead, because the filesystem refused to reflink them.",
+            tally.files_fallback_copied, tally.bytes_fallback_copied,
+        );
+    }
+}
+
+/// Print the outcome of one copy, and return the failure, if it failed.
+fn print_applied(applied: &Applied, planned: &Planned, printer: &Printer) -> Option<Failure> {
+    let (src, dst) = (Some(applied.src.as_path()), applied.dst.as_path());
+    match applied.outcome {
+        Outcome::Cloned(..) => printer.print(Kind::Clone, src, dst, None),
+        Outcome::SameInode(..) => printer.print(Kind::Skip, src, dst, Some("same inode, skipped")),
+        Outcome::AlreadyShared(..) => printer.print(Kind::Skip, src, dst, Some("already shared")),
+        Outcome::UpToDate(..) => printer.print(Kind::Skip, src, dst, Some("already done")),
+        Outcome::RangeCopied(..) => printer.print(Kind::KernelCopy, src, dst, Some("copy_file_range")),
+        Outcome::Copied(..) => printer.print(Kind::Copy, src, dst, Some("copied")),
+        Outcome::FallbackCopied(..) => printer.print(Kind::Copy, src, dst, Some("copied, reflink refused")),
+        // Copies without source were already reported by the
+        // pre-flight check, only report sources that vanished since.
+        Outcome::SourceMissing if planned.src.is_none() && planned.copy_from.is_none() => {
+            printer.print(Kind::Missing, src, dst, None);
+        }
+        Outcome::SourceMissing => {
+            warn!(
+                "Source {:?} not present in dst-base, skipping {:?}.",
+                applied.src, applied.dst,
+            );
+            printer.print(Kind::Missing, src, dst, None);
+        }
+        Outcome::CloneUnsupported(ref err) => {
+            warn!("Cannot reflink {:?} to {:?}, skipping it: {}", applied.src, applied.dst, err);
+            printer.print(Kind::Skip, src, dst, Some("reflink not supported"));
+        }
+        Outcome::NocowDestination => {
+            warn!(
+                "Cannot reflink {:?} to {:?}, skipping it: the destination has the nocow attribute \
+                of its directory, and the source does not. Remove it with chattr -C on the directory \
+                in dst-target, or copy such files with --fallback-copy.",
+                applied.src, applied.dst,
+            );
+            printer.print(Kind::Skip, src, dst, Some("destination is nocow"));
+        }
+        Outcome::DestinationExists(..) => printer.print(Kind::Skip, src, dst, Some("destination has data, skipped")),
+        Outcome::SourceChanged => {
+            warn!("Source {:?} of {:?} changed since the scan, skipping it.", applied.src, applied.dst);
+            printer.print(Kind::Skip, src, dst, Some("source changed"));
+        }
+        Outcome::SourceFailed(ref err) => {
+            error!("Failed to open source {:?} for {:?}: {}", applied.src, applied.dst, err);
+            printer.print(Kind::Error, src, dst, Some(&err.to_string()));
+            return Some(Failure::new(&applied.src, &applied.dst, "source", err));
+        }
+        Outcome::DestinationFailed(ref err) => {
+            error!("Failed to write destination {:?} from {:?}: {}", applied.dst, applied.src, err);
+            printer.print(Kind::Error, src, dst, Some(&err.to_string()));
+            return Some(Failure::new(&applied.src, &applied.dst, "destination", err));
+        }
+    }
+    None
+}
+
+/// Take the lock of dst-target, so no other run applies to it at the same
+/// time, and wait up to --lock-wait for a run that holds it.
+///
+/// We lock the directory itself rather than a lock file in it, so the mirror
+/// does not get an extra file. The lock is released when the file is closed.
+fn lock_destination(dir_target_dst: &Path, config: &Config) -> io::Result<fs::File> {
+    // How often to retry, flock cannot wait with a timeout.
+    const POLL: Duration = Duration::from_millis(500);
+
+    if let Err(err) = fs::create_dir_all(dir_target_dst) {
+        return Err(io::Error::new(err.kind(), format!("Failed to create dst-target {:?}: {}", dir_target_dst, err)));
+    }
+    let dir = fs::File::open(dir_target_dst)?;
+    let deadline = Instant::now() + config.lock_wait;
+    let mut is_waiting = false;
+    loop {
+        if unsafe { libc::flock(dir.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(dir);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(io::Error::new(err.kind(), format!("Failed to lock {:?}: {}", dir_target_dst, err)));
+        }
+        if Instant::now() >= deadline {
+            let msg = if config.lock_wait == Duration::from_secs(0) {
+                format!("Another run is applying to {:?}. Pass --lock-wait to wait for it to finish.", dir_target_dst)
+            } else {
+                let waited = progress::format_duration(config.lock_wait);
+                format!("Another run is still applying to {:?} after waiting {}.", dir_target_dst, waited)
+            };
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+        }
+        if !is_waiting {
+            info!("Another run is applying to {:?}, waiting for it to finish.", dir_target_dst);
+            is_waiting = true;
+        }
+        thread::sleep(POLL);
+    }
+}
+
+fn to_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Path {:?} contains a NUL byte.", path)))
+}
+
+/// Check whether the effective user may access the path with the mode of access(2).
+fn check_access(path: &Path, mode: libc::c_int) -> io::Result<()> {
+    // Check with the effective ids rather than the real ones, the libc crate
+    // lacks the constant.
+    const AT_EACCESS: libc::c_int = 0x200;
+    let path_c = to_cstring(if path.as_os_str().is_empty() { Path::new(".") } else { path })?;
+    check_os_result(unsafe { libc::faccessat(libc::AT_FDCWD, path_c.as_ptr(), mode, AT_EACCESS) })
+}
+
+/// Check that we can read the source trees and dst-base, and in apply mode
+/// write dst-target, before the scan.
+///
+/// Reports every problem at once, rather than failing at the first file that
+/// we cannot access, maybe hours into the run.
+fn check_permissions(args: &cli::Args, config: &Config, dry_run: bool) -> io::Result<()> {
+    let mut problems = Vec::new();
+    let mut check = |what: &str, path: &Path, mode: libc::c_int| {
+        if let Err(err) = check_access(path, mode) {
+            problems.push(format!("{} {:?}: {}", what, path, err));
+        }
+    };
+    let read = libc::R_OK | libc::X_OK;
+    check("Cannot read src-base", &args.paths[0], read);
+    check("Cannot read src-target", &args.paths[1], read);
+    for subvolume in &config.subvolumes {
+        check("Cannot read src-base", &subvolume.src_base, read);
+        check("Cannot read src-target", &subvolume.src_target, read);
+    }
+    for pair in args.paths[2..].chunks(2) {
+        check("Cannot read dst-base", &pair[0], read);
+        if dry_run {
+            continue;
+        }
+        // When dst-target does not exist yet, we must be able to create it.
+        let dir = pair[1].ancestors().find(|p| p.as_os_str().is_empty() || p.exists()).unwrap_or(&pair[1]);
+        check("Cannot write dst-target in", dir, libc::W_OK | libc::X_OK);
+    }
+    if !problems.is_empty() {
+        let msg = format!("Cannot access the trees, nothing was changed:\n  {}", problems.join("\n  "));
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    if !dry_run && config.xattrs && unsafe { libc::geteuid() } != 0 {
+        warn!(
+            "Not running as root, so copies get our ownership rather than that of their source, \
+            and security.* attributes may fail to copy with --xattrs."
+        );
+    }
+    Ok(())
+}
+
+/// Clone between two unnamed temporary files in the directory, so the probe
+/// leaves nothing behind, and return the result of the clone.
+///
+/// The outer error is for when we could not set up the probe.
+fn probe_reflink(dir: &Path) -> io::Result<io::Result<()>> {
+    let open_unnamed = || {
+        fs::OpenOptions::new().read(true).write(true).mode(0o600).custom_flags(libc::O_TMPFILE).open(dir)
+    };
+    let mut src = open_unnamed()?;
+    src.write_all(&[0; 4096])?;
+    let dst = open_unnamed()?;
+    Ok(clone_file(&src, &dst))
+}
+
+/// Warn when dst-target has the nocow attribute, which new files inherit, so
+/// they can not be reflinks of sources that are not nocow.
+///
+/// The probe does not catch this, because it clones between two new files.
+fn warn_if_nocow(dir_target_dst: &Path, config: &Config) {
+    let is_nocow = fs::File::open(dir_target_dst)
+        .and_then(|dir| get_flags(&dir))
+        .map_or(false, |flags| flags & FS_NOCOW_FL != 0);
+    if !is_nocow {
+        return;
+    }
+    warn!(
+        "dst-target {:?} has the nocow attribute (chattr +C), so the files created in it are nocow too, \
+        and reflinks from sources that are not nocow fail. {}",
+        dir_target_dst,
+        if config.fallback_copy {
+            "Those are copied instead, because of --fallback-copy."
+        } else {
+            "Remove the attribute with chattr -C, or copy those files with --fallback-copy."
+        },
+    );
+}
+
+/// Check that the filesystem of dst-target supports reflinks, before the
+/// scan, which can take long, rather than fail every copy after it.
+///
+/// With --fallback-copy the copies still work, so we only warn.
+fn check_reflink_support(dir_target_dst: &Path, config: &Config) -> io::Result<()> {
+    if config.backend == Backend::CopyFileRange {
+        return Ok(());
+    }
+    warn_if_nocow(dir_target_dst, config);
+    let err = match probe_reflink(dir_target_dst) {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(err)) if Reason::of_error(&err) == Reason::Unsupported => err,
+        Ok(Err(err)) | Err(err) => {
+            // E.g. dst-target does not exist yet, then apply finds out.
+            debug!("Could not check whether {:?} supports reflinks: {}", dir_target_dst, err);
+            return Ok(());
+        }
+    };
+    if config.fallback_copy {
+        warn!("The filesystem of {:?} does not support reflinks ({}), every file will be copied.", dir_target_dst, err);
+        return Ok(());
+    }
+    let msg = format!(
+        "The filesystem of dst-target {:?} does not support reflinks: {}. \
+        Pass --fallback-copy or --backend copy-file-range to copy the data instead.",
+        dir_target_dst, err,
+    );
+    Err(io::Error::new(err.kind(), msg))
+}
+
+/// The metadata that a clone or copy adds at most, one btrfs tree block for
+/// the inode, its name, and its extent items. Most files need less.
+const METADATA_PER_FILE: u64 = 16 * 1024;
+
+/// When less than this fraction of the filesystem would be left, we warn.
+const LOW_SPACE_FRACTION: f64 = 0.05;
+
+fn format_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Estimate the space that applying the plan needs, and abort before anything
+/// is written when dst-target does not have it, rather than fail halfway with
+/// ENOSPC. Warns when little space would be left.
+///
+/// A clone needs only metadata, but a copy needs the data too, with
+/// --copy-missing-from-source, or when dst-target does not support reflinks.
+/// On btrfs, the free space that statvfs reports is an estimate too, so this
+/// catches a full filesystem, not every ENOSPC.
+fn check_free_space(plan: &[Planned], dir_target_dst: &Path, config: &Config) -> io::Result<()> {
+    if config.no_space_check || plan.is_empty() {
+        return Ok(());
+    }
+    let reflinks_work = matches!(probe_reflink(dir_target_dst), Ok(Ok(())));
+    let needed: u64 = plan
+        .iter()
+        .map(|planned| match (&planned.src, &planned.copy_from) {
+            (Some(..), _) if reflinks_work => METADATA_PER_FILE,
+            (Some(..), _) | (None, Some(..)) => METADATA_PER_FILE + planned.copy.info.len,
+            (None, None) => 0,
+        })
+        .sum();
+
+    let dir = fs::File::open(dir_target_dst)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check_os_result(unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut stat) })?;
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    let size = stat.f_blocks as u64 * stat.f_frsize as u64;
+
+    if needed > available {
+        let msg = format!(
+            "The plan needs an estimated {} on dst-target {:?}, but only {} is available, nothing was changed. \
+            Free up space, or pass --no-space-check to apply anyway.",
+            format_mib(needed),
+            dir_target_dst,
+            format_mib(available),
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    let left = available - needed;
+    if (left as f64) < size as f64 * LOW_SPACE_FRACTION {
+        warn!(
+            "After applying, an estimated {} of {} would be left on dst-target {:?}.",
+            format_mib(left),
+            format_mib(size),
+            dir_target_dst,
+        );
+    } else {
+        debug!("The plan needs an estimated {}, {} is available.", format_mib(needed), format_mib(available));
+    }
+    Ok(())
+}
+
+/// Makes a subvolume read-only again when it is dropped, after --make-writable
+/// made it writable for the run, also when the run fails.
+struct RestoreReadOnly(PathBuf);
+
+impl Drop for RestoreReadOnly {
+    fn drop(&mut self) {
+        info!("Making subvolume {:?} read-only again.", self.0);
+        if let Err(err) = btrfs::set_read_only(&self.0, true) {
+            error!("Failed to make subvolume {:?} read-only again: {}", self.0, err);
+        }
+    }
+}
+
+/// Check that dst-target is writable, before every copy fails with EROFS.
+///
+/// With --make-writable, a read-only subvolume is made writable until the
+/// returned guard is dropped. A read-only mount we cannot fix.
+fn check_writable(dir_target_dst: &Path, config: &Config) -> io::Result<Option<RestoreReadOnly>> {
+    let dir = fs::File::open(dir_target_dst)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check_os_result(unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut stat) })?;
+    if stat.f_flag & libc::ST_RDONLY != 0 {
+        let msg = format!("dst-target {:?} is on a filesystem that is mounted read-only.", dir_target_dst);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    if !btrfs::is_btrfs(dir_target_dst)? || !btrfs::is_read_only(dir_target_dst)? {
+        return Ok(None);
+    }
+    if !config.make_writable {
+        let msg = format!(
+            "dst-target {:?} is a read-only subvolume. Pass --make-writable to make it writable for the run.",
+            dir_target_dst,
+        );
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    info!("Making subvolume {:?} writable for the run.", dir_target_dst);
+    btrfs::set_read_only(dir_target_dst, false)?;
+    Ok(Some(RestoreReadOnly(dir_target_dst.to_path_buf())))
+}
+
+/// Check that dst-base and dst-target are on the same filesystem.
+///
+/// Reflinks cannot cross filesystems, so otherwise every clone would fail with
+/// EXDEV, and we would only find out at the first one, after the whole run up
+/// to there. With --fallback-copy the copies still work, so we only warn.
+fn check_same_filesystem(dir_base_dst: &Path, dir_target_dst: &Path, config: &Config) -> io::Result<()> {
+    if config.backend == Backend::CopyFileRange {
+        return Ok(());
+    }
+    let fs_base = btrfs::filesystem_id(dir_base_dst)?;
+    // Without dst-target yet, there is nothing to check, it is created below.
+    let fs_target = match btrfs::filesystem_id(dir_target_dst) {
+        Ok(id) => id,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if fs_base == fs_target {
+        return Ok(());
+    }
+    let msg = format!(
+        "dst-base {:?} and dst-target {:?} are on different filesystems, \
+        reflink impossible across filesystems (EXDEV).",
+        dir_base_dst, dir_target_dst,
+    );
+    if config.fallback_copy {
+        warn!("{} Copying all files instead, because of --fallback-copy.", msg);
+        return Ok(());
+    }
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+/// Create the reflinks for all planned copies, using `config.jobs` workers.
+///
+/// Output is printed in plan order, no matter in which order the workers
+/// finish, so the log is the same for any number of jobs. After a failure no
+/// new copies are started, but copies already in progress run to completion,
+/// unless `config.keep_going` is set. At the end, the moves that could not be
+/// completed are summarized on stderr.
+/// Returns the final counts and the failures, also when applying failed.
+fn apply(
+    plan: Vec<Planned>,
+    symlinks: &[CreateSymlink],
+    trees: Trees,
+    sources: Arc<Vec<Root>>,
+    mut journal: Option<Journal>,
+    mut undo_log: Option<UndoLog>,
+    config: &Config,
+) -> (Tally, Vec<Failure>, io::Result<()>) {
+    let jobs = config.jobs;
+    let record_created = undo_log.is_some();
+    let _catch = signal::Catch::install();
+    let options = CloneOptions::from_config(config);
+    let keep_going = config.keep_going;
+    let (limit, limit_bytes) = (config.limit, config.limit_bytes);
+    let num_copies = plan.len();
+    let width = output::column_width(plan.iter().filter_map(|p| p.src.as_ref().map(|s| s.path.as_path())));
+    let printer = Printer::from_config(config, width, "apply");
+    let plan = Arc::new(plan);
+    // When dst-target can not be resolved, every destination is refused.
+    let root = Arc::new(fs::canonicalize(trees.target.path()).unwrap_or_else(|_| trees.target.path().to_path_buf()));
+    let trees = Arc::new(trees);
+    let next_index = Arc::new(AtomicUsize::new(0));
+    let failed = Arc::new(AtomicBool::new(false));
+    let counters = Arc::new(Counters::default());
+    let (sender, receiver) = mpsc::channel();
+
+    let workers: Vec<_> = (0..jobs).map(|_| {
+        let plan = plan.clone();
+        let trees = trees.clone();
+        let sources = sources.clone();
+        let root = root.clone();
+        let next_index = next_index.clone();
+        let failed = failed.clone();
+        let counters = counters.clone();
+        let sender = sender.clone();
+        thread::spawn(move || loop {
+            if failed.load(Ordering::SeqCst) { break }
+            if signal::received().is_some() { break }
+            if counters.tally().reaches_limit(limit, limit_bytes) { break }
+            let i = next_index.fetch_add(1, Ordering::SeqCst);
+            if i >= plan.len() { break }
+
+            let planned = &plan[i];
+            let dst_path = trees.target.path().join(&planned.copy.dst);
+            let created = if record_created { undo::missing_paths(&dst_path) } else { Vec::new() };
+            let (src_path, outcome) = match (check_beneath(&root, &dst_path), &planned.src, &planned.copy_from) {
+                (Err(err), _, _) => (planned.copy.src.clone(), Outcome::DestinationFailed(err)),
+                (Ok(()), Some(src), _) => {
+                    let outcome = clone_paths(src, &trees, &planned.copy.dst, options);
+                    (src.path.clone(), outcome)
+                }
+                (Ok(()), None, Some(copy_from)) => {
+                    let outcome = copy_paths(copy_from, &sources, &trees.target, &planned.copy.dst, options);
+                    (copy_from.clone(), outcome)
+                }
+                (Ok(()), None, None) => (planned.copy.src.clone(), Outcome::SourceMissing),
+            };
+            if outcome.error().is_some() && !keep_going {
+                failed.store(true, Ordering::SeqCst);
+            }
+            counters.record(&outcome);
+            let applied = Applied { src: src_path, dst: dst_path, outcome, created };
+            if sender.send((i, applied)).is_err() { break }
+        })
+    }).collect();
+
+    // Drop our own sender, so the receiver ends when all workers are done.
+    drop(sender);
+
+    // The workers take copies in increasing order, so the copies that were
+    // started always form a prefix of the plan, and we can print everything
+    // that completed without gaps.
+    let mut pending: Vec<Option<Applied>> = (0..num_copies).map(|_| None).collect();
+    let mut next_print = 0;
+    let mut first_error = None;
+    let mut failures = Vec::new();
+    let mut cloned_sources = Vec::new();
+    let mut unresolved = Unresolved::new();
+    let mut num_symlink_targets = 0;
+    let mut progress = Progress::new(config.progress);
+    // The oldest copy that is in progress, for the progress lines.
+    let current = |next_print: usize| plan.get(next_print).map(|p| trees.target.path().join(&p.copy.dst));
+    let roots: Vec<&Root> = sources.iter().chain(vec![&trees.base, &trees.target]).chain(&trees.other_bases).collect();
+    // Set when one of the trees was deleted or replaced, then we stop.
+    let mut vanished = None;
+    loop {
+        stop_if_vanished(&roots, &mut vanished, &failed);
+        // Wake up for progress lines, also when a single copy takes long.
+        let (i, applied) = match receiver.recv_timeout(progress.time_until_due()) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                print_apply_progress(&mut progress, num_copies, &counters.tally(), current(next_print).as_deref());
+                continue
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        print_apply_progress(&mut progress, num_copies, &counters.tally(), current(next_print).as_deref());
+        pending[i] = Some(applied);
+        while let Some(applied) = pending.get_mut(next_print).and_then(Option::take) {
+            match applied.outcome.unresolved_reason() {
+                Some(reason) => unresolved.add(reason, &plan[next_print].copy.dst),
+                None if plan[next_print].copy.from_symlink => num_symlink_targets += 1,
+                None => {}
+            }
+            record_completed(&mut journal, &applied, &plan[next_print]);
+            record_undo(&mut undo_log, &applied.created);
+            if let Some(failure) = print_applied(&applied, &plan[next_print], &printer) {
+                first_error = first_error.or(Some(failure.kind));
+                failures.push(failure);
+            }
+            if applied.outcome.shares_source() {
+                cloned_sources.extend(plan[next_print].src.as_ref());
+            }
+            next_print += 1;
+        }
+    }
+
+    for worker in workers {
+        worker.join().expect("Apply worker panicked.");
+    }
+
+    // All workers have finished, so this snapshot is final.
+    let tally = counters.tally();
+
+    // Symlinks are cheap to create, so we do that here, after the copies.
+    let interrupted = signal::received();
+    let mut num_symlinks = 0;
+    if (first_error.is_none() || keep_going) && interrupted.is_none() && vanished.is_none() {
+        match apply_symlinks(symlinks, &trees.target, &printer, options.retry, &mut undo_log) {
+            Ok(n) => num_symlinks = n,
+            Err(err) => first_error = first_error.or_else(|| Some(err.kind())),
+        }
+    }
+
+    // Without a failure, or with --keep-going, the workers only stop early at
+    // --limit or --limit-bytes, or on a signal.
+    let stopped_early = (first_error.is_none() || keep_going) && next_print < num_copies;
+    let num_left = add_not_started(&plan[next_print..], stopped_early, &mut unresolved);
+    print_tally(options.backend, num_copies, &tally, cloned_sources);
+    if num_symlink_targets > 0 {
+        info!("Materialized {} symlink targets as files.", num_symlink_targets);
+    }
+    if num_symlinks > 0 {
+        info!("Created {} symlinks.", num_symlinks);
+    }
+    unresolved.print();
+    if keep_going {
+        print_failures(&failures);
+    }
+    match interrupted {
+        Some(signo) => warn!(
+            "Interrupted by {}, stopped after the copies in progress, {} moves are left for the next run.",
+            signal::name(signo), num_left,
+        ),
+        None if num_left > 0 => info!("Reached the limit, {} moves are left for the next run.", num_left),
+        None => {}
+    }
+    finish_journal(journal, first_error.is_none() && interrupted.is_none() && vanished.is_none() && num_left == 0);
+    // After a failure the run stops early, and the fraction is meaningless.
+    if first_error.is_none() && !stopped_early {
+        // Fallback copies also found their source in dst-base.
+        let num_found = tally.files_reflinked + tally.files_range_copied + tally.files_fallback_copied + tally.files_up_to_date;
+        warn_if_poor_mirror(num_found + tally.files_same_inode + tally.files_already_shared, num_copies);
+    }
+
+    if config.sync {
+        if let Err(err) = sync_filesystem(trees.target.path()) {
+            return (tally, failures, Err(err));
+        }
+    }
+
+    if let Some(ref path) = config.stats_json {
+        if let Err(err) = write_stats_json(path, num_copies, &tally, num_symlink_targets, num_symlinks, &unresolved) {
+            return (tally, failures, Err(err));
+        }
+    }
+
+    let result = match vanished {
+        Some(err) => Err(err),
+        None => apply_result(first_error, interrupted, &tally, num_copies),
+    };
+    (tally, failures, result)
+}
+
+/// Tell the workers to stop when one of the trees vanished, once.
+fn stop_if_vanished(roots: &[&Root], vanished: &mut Option<io::Error>, failed: &AtomicBool) {
+    if vanished.is_some() {
+        return;
+    }
+    if let Err(err) = check_unchanged(roots) {
+        failed.store(true, Ordering::SeqCst);
+        *vanished = Some(err);
+    }
+}
+
+/// Return an error when one of the trees was deleted or replaced since we
+/// opened it, e.g. by a tool that rotates snapshots, so we stop rather than
+/// mix the files of two snapshots, or clone from a snapshot that is gone.
+fn check_unchanged(roots: &[&Root]) -> io::Result<()> {
+    roots.iter().try_for_each(|root| root.check_unchanged())
+}
+
+/// Return the error of an apply that stopped after a failure or a signal.
+fn apply_result(
+    first_error: Option<io::ErrorKind>,
+    interrupted: Option<libc::c_int>,
+    tally: &Tally,
+    num_copies: usize,
+) -> io::Result<()> {
+    match (first_error, interrupted) {
+        (None, None) => Ok(()),
+        (None, Some(signo)) => {
+            Err(io::Error::new(io::ErrorKind::Interrupted, format!("Interrupted by {}.", signal::name(signo))))
+        }
+        (Some(kind), _) => {
+            Err(io::Error::new(kind, format!("{} of {} copies failed.", tally.failures(), num_copies)))
+        }
+    }
+}
+
+/// Record the copy in the journal, if there is one, when it completed.
+///
+/// When the journal cannot be written, we stop journaling, but continue the
+/// run. Without a record, a resumed run only redoes the copy.
+fn record_completed(journal: &mut Option<Journal>, applied: &Applied, planned: &Planned) {
+    if applied.outcome.error().is_some() || applied.outcome.unresolved_reason().is_some() {
+        return;
+    }
+    if let Some(Err(err)) = journal.as_mut().map(|j| j.record(&planned.copy.dst)) {
+        warn!("{} Not journaling the remaining copies.", err);
+        *journal = None;
+    }
+}
+
+/// Record the paths that a copy or symlink created in the undo log, if there is one.
+///
+/// When the log cannot be written, we stop logging, but continue the run.
+fn record_undo(undo_log: &mut Option<UndoLog>, created: &[PathBuf]) {
+    if let Some(Err(err)) = undo_log.as_ref().map(|log| log.record_created(created)) {
+        error!("{} Rollback will not remove the remaining files.", err);
+        *undo_log = None;
+    }
+}
+
+/// Remove the journal, if there is one, when the run is complete and leaves
+/// nothing to resume.
+fn finish_journal(journal: Option<Journal>, is_complete: bool) {
+    if let Some(journal) = journal.filter(|_| is_complete) {
+        if let Err(err) = journal.remove() {
+            warn!("{}", err);
+        }
+    }
+}
+
+/// Drop the copies that the journal records as completed by an earlier run.
+fn skip_completed(plan: Vec<Planned>, journal: &Journal) -> Vec<Planned> {
+    let num_planned = plan.len();
+    let plan: Vec<Planned> = plan.into_iter().filter(|p| !journal.is_done(&p.copy.dst)).collect();
+    info!("Resuming, skipping {} moves that an earlier run completed.", num_planned - plan.len());
+    plan
+}
+
+/// Flush the filesystem that the directory is on to disk, for --sync.
+///
+/// One syncfs at the end is much cheaper than an fsync of every file and
+/// directory, and dst-base and dst-target are on the same filesystem.
+fn sync_filesystem(dir: &Path) -> io::Result<()> {
+    info!("Syncing the filesystem of {:?}.", dir);
+    let file = fs::File::open(dir)?;
+    // Like copy_file_range, the libc crate does not expose syncfs.
+    let result = unsafe { libc::syscall(libc::SYS_syncfs, file.as_raw_fd()) };
+    check_os_result(result as libc::c_int)
+        .map_err(|err| io::Error::new(err.kind(), format!("Failed to sync the filesystem of {:?}: {}", dir, err)))
+}
+
+/// Record the copies that apply did not start, and return how many of them
+/// are left for the next run because the run stopped at the limit, or on a
+/// signal.
+///
+/// The copies without source would not have been completed anyway, so we can
+/// still report why.
+fn add_not_started(not_started: &[Planned], stopped_early: bool, unresolved: &mut Unresolved) -> usize {
+    let mut num_left = 0;
+    for planned in not_started {
+        if planned.src.is_none() && planned.copy_from.is_none() {
+            unresolved.add(Reason::SourceMissing, &planned.copy.dst);
+        } else if stopped_early {
+            num_left += 1;
+        } else {
+            unresolved.not_attempted += 1;
+        }
+    }
+    num_left
+}
+
+/// How long every phase of the run took, in order.
+#[derive(Clone)]
+struct PhaseDurations(Vec<(&'static str, Duration)>);
+
+impl PhaseDurations {
+    /// Record the time since `start` for the phase, and return the current instant.
+    fn end_phase(&mut self, phase: &'static str, start: Instant) -> Instant {
+        let now = Instant::now();
+        self.0.push((phase, now - start));
+        now
+    }
+}
+
+/// Write metrics about the run for the Prometheus node_exporter textfile collector.
+fn write_metrics(
+    path: &Path,
+    num_scanned: (usize, usize),
+    num_moves: usize,
+    tally: Option<&Tally>,
+    durations: &PhaseDurations,
+) -> io::Result<()> {
+    let mut metrics = Metrics::new();
+    metrics.gauge(
+        "files_scanned",
+        "Number of regular files found in the source trees.",
+        Some("tree"),
+        &[("src-base", num_scanned.0 as f64), ("src-target", num_scanned.1 as f64)],
+    );
+    metrics.gauge("moves_detected", "Number of likely moves found by the diff.", None, &[("", num_moves as f64)]);
+    if let Some(tally) = tally {
+        metrics.gauge("files_reflinked", "Number of moves replayed as a reflink.", None, &[("", tally.files_reflinked as f64)]);
+        metrics.gauge("bytes_reflinked", "Total size of the files reflinked.", None, &[("", tally.bytes_reflinked as f64)]);
+        metrics.gauge("files_copied", "Number of moves replayed as a copy.", None, &[("", (tally.files_range_copied + tally.files_copied + tally.files_fallback_copied) as f64)]);
+        metrics.gauge("moves_skipped", "Number of moves skipped because the source is missing.", None, &[("", tally.sources_missing as f64)]);
+        metrics.gauge("failures", "Number of moves that failed.", None, &[("", tally.failures() as f64)]);
+    }
+    let phases: Vec<(&str, f64)> = durations.0.iter().map(|&(phase, d)| (phase, d.as_secs_f64())).collect();
+    metrics.gauge("phase_duration_seconds", "Wall time spent in every phase of the run.", Some("phase"), &phases);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+    metrics.gauge("last_run_timestamp_seconds", "Unix time at which the run finished.", None, &[("", now)]);
+    metrics.write(path)
+}
+
+/// Log the counts of the whole run as one block, at the end of the run.
+fn print_summary(num_scanned: (usize, usize), num_moves: usize, tally: Option<&Tally>, durations: &PhaseDurations) {
+    let total: Duration = durations.0.iter().map(|&(_, d)| d).sum();
+    let mut message = format!(
+        "Summary:\n  files scanned: {} in src-base, {} in src-target\n  moves detected: {}",
+        num_scanned.0, num_scanned.1, num_moves,
+    );
+    if let Some(tally) = tally {
+        message.push_str(&format!(
+            "\n  reflinked: {} files, {} bytes now shared", tally.files_reflinked, tally.bytes_reflinked,
+        ));
+        if tally.files_range_copied > 0 {
+            message.push_str(&format!(
+                "\n  copied with copy_file_range: {} files, {} bytes",
+                tally.files_range_copied, tally.bytes_range_copied,
+            ));
+        }
+        if tally.files_copied > 0 {
+            message.push_str(&format!(
+                "\n  copied from src-target: {} files, {} bytes", tally.files_copied, tally.bytes_copied,
+            ));
+        }
+        if tally.files_fallback_copied > 0 {
+            message.push_str(&format!(
+                "\n  copied, reflink refused: {} files, {} bytes",
+                tally.files_fallback_copied, tally.bytes_fallback_copied,
+            ));
+        }
+        message.push_str(&format!(
+            "\n  skipped, already shared: {}", tally.files_same_inode + tally.files_already_shared,
+        ));
+        if tally.files_up_to_date > 0 {
+            message.push_str(&format!("\n  skipped, already done: {}", tally.files_up_to_date));
+        }
+        message.push_str(&format!("\n  skipped, source missing: {}", tally.sources_missing));
+        if tally.files_unsupported > 0 {
+            message.push_str(&format!("\n  skipped, reflink not supported: {}", tally.files_unsupported));
+        }
+        if tally.files_nocow > 0 {
+            message.push_str(&format!("\n  skipped, destination is nocow: {}", tally.files_nocow));
+        }
+        if tally.files_exist > 0 {
+            message.push_str(&format!("\n  skipped, destination has data: {}", tally.files_exist));
+        }
+        if tally.sources_changed > 0 {
+            message.push_str(&format!("\n  skipped, source changed: {}", tally.sources_changed));
+        }
+        message.push_str(&format!(
+            "\n  errors: {}",
+            tally.failures(),
+        ));
+    }
+    message.push_str(&format!("\n  time: {:.1}s", total.as_secs_f64()));
+    info!("{}", message);
+}
+
+/// Print the plan for dry-run mode, and what applying it would do.
+fn print_plan(plan: &[Planned], symlinks: &[CreateSymlink], dir_target_dst: &Path, config: &Config) {
+    // Print the same full paths that apply would use, so they can be checked.
+    let sources = plan.iter().map(|p| match (&p.src, &p.copy_from) {
+        (Some(src), _) => src.path.as_path(),
+        (None, Some(copy_from)) => copy_from.as_path(),
+        (None, None) => p.copy.src.as_path(),
+    });
+    let width = output::column_width(sources.chain(symlinks.iter().map(|link| link.path.as_path())));
+    let printer = Printer::from_config(config, width, "plan");
+    for planned in plan.iter() {
+        let dst = dir_target_dst.join(&planned.copy.dst);
+        match (&planned.src, &planned.copy_from) {
+            (Some(src), _) if src.via == Some(Fallback::DstTarget) => {
+                printer.print(Kind::Clone, Some(&src.path), &dst, Some("source in dst-target"));
+            }
+            (Some(src), _) if src.via == Some(Fallback::OtherBases) => {
+                printer.print(Kind::Clone, Some(&src.path), &dst, Some("source in other base"));
+            }
+            (Some(src), _) => printer.print(Kind::Clone, Some(&src.path), &dst, None),
+            (None, Some(copy_from)) => printer.print(Kind::Copy, Some(copy_from), &dst, Some("copy from src-target")),
+            (None, None) => printer.print(Kind::Missing, Some(&planned.copy.src), &dst, None),
+        }
+    }
+    let num_resolved = plan.iter().filter(|p| p.src.is_some()).count();
+    info!(
+        "Would reflink {} of {} files ({:.1}%).",
+        num_resolved,
+        plan.len(),
+        percentage(num_resolved, plan.len()),
+    );
+    // Files that already share extents are counted too, dry-run does not
+    // compare extents, so this is an upper bound.
+    let bytes_shared: u64 = plan.iter().filter(|p| p.src.is_some()).map(|p| p.copy.info.len).sum();
+    info!("Would share up to {} bytes with dst-base, that rsync would otherwise copy.", bytes_shared);
+    print_source_breakdown(plan.iter().filter_map(|p| p.src.as_ref()));
+    let num_copy_from = plan.iter().filter(|p| p.copy_from.is_some()).count();
+    if num_copy_from > 0 {
+        info!("Would copy {} files from src-target.", num_copy_from);
+    }
+    let num_symlink_targets = plan
+        .iter()
+        .filter(|p| p.copy.from_symlink && (p.src.is_some() || p.copy_from.is_some()))
+        .count();
+    if num_symlink_targets > 0 {
+        info!("Would materialize {} symlink targets as files.", num_symlink_targets);
+    }
+    for link in symlinks {
+        printer.print(Kind::Link, Some(&link.target), &dir_target_dst.join(&link.path), None);
+    }
+    if !symlinks.is_empty() {
+        info!("Would create {} symlinks, unless they are already present.", symlinks.len());
+    }
+    warn_if_poor_mirror(num_resolved, plan.len());
+}
+
+/// Ask for every copy and symlink whether to apply it, and drop the ones
+/// that the user declined. Copies without source are kept, they will be
+/// reported as such.
+fn confirm_plan(
+    plan: Vec<Planned>,
+    symlinks: Vec<CreateSymlink>,
+    dir_target_dst: &Path,
+) -> io::Result<(Vec<Planned>, Vec<CreateSymlink>)> {
+    let mut prompt = Prompt::default();
+    let mut confirmed = Vec::with_capacity(plan.len());
+    for planned in plan {
+        let dst = dir_target_dst.join(&planned.copy.dst);
+        let question = match (&planned.src, &planned.copy_from) {
+            (Some(src), _) => format!("Reflink {:?} -> {:?}?", src.path, dst),
+            (None, Some(copy_from)) => format!("Copy {:?} -> {:?}?", copy_from, dst),
+            (None, None) => {
+                confirmed.push(planned);
+                continue
+            }
+        };
+        if prompt.confirm(&question)? {
+            confirmed.push(planned);
+        }
+    }
+    let mut confirmed_symlinks = Vec::with_capacity(symlinks.len());
+    for link in symlinks {
+        let question = format!("Create symlink {:?} -> {:?}?", dir_target_dst.join(&link.path), link.target);
+        if prompt.confirm(&question)? {
+            confirmed_symlinks.push(link);
+        }
+    }
+    if prompt.has_quit() {
+        info!("Quit, skipping the remaining operations.");
+    }
+    Ok((confirmed, confirmed_symlinks))
+}
+
+// Exit codes, documented in the usage text.
+const EXIT_NOTHING_TO_DO: i32 = 0;
+const EXIT_APPLIED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_FATAL: i32 = 3;
+const EXIT_FAILED: i32 = 4;
+const EXIT_MISSING: i32 = 5;
+
+/// Return the more severe of two exit codes, to combine the codes of jobs.
+fn most_severe(a: i32, b: i32) -> i32 {
+    let rank = |code| [EXIT_NOTHING_TO_DO, EXIT_APPLIED, EXIT_MISSING, EXIT_FAILED, EXIT_FATAL]
+        .iter()
+        .position(|&c| c == code)
+        .unwrap_or(0);
+    if rank(b) > rank(a) { b } else { a }
+}
+
+/// Pick the exit code for a run that was not aborted, the most severe one that applies.
+fn exit_code(num_failed: usize, num_missing: usize, num_changed: usize, config: &Config) -> i32 {
+    if num_failed > 0 {
+        EXIT_FAILED
+    } else if num_missing > 0 && config.fail_on_missing {
+        EXIT_MISSING
+    } else if num_changed > 0 {
+        EXIT_APPLIED
+    } else {
+        EXIT_NOTHING_TO_DO
+    }
+}
+
+/// Print the completion script for the one shell named in the arguments.
+fn print_completions(args: &[PathBuf]) -> io::Result<i32> {
+    let shell = match args {
+        [shell] => shell.to_string_lossy(),
+        _ => exit_usage_error(&format!(
+            "Expected a shell, one of: {}, but got {} arguments.",
+            completions::SHELLS.join(", "),
+            args.len(),
+        )),
+    };
+    match completions::generate(&shell) {
+        Ok(script) => print!("{}", script),
+        Err(msg) => exit_usage_error(&msg),
+    }
+    Ok(EXIT_NOTHING_TO_DO)
+}
+
+/// Remove what the runs that wrote the undo log created.
+fn run_rollback(args: &[PathBuf]) -> io::Result<i32> {
+    let path = match args {
+        [path] => path,
+        _ => exit_usage_error(&format!("Expected one undo log to roll back, but got {} paths.", args.len())),
+    };
+    let rollback = undo::rollback(path)?;
+    info!("Removed {} paths, kept {} that changed since the run.", rollback.num_removed, rollback.num_kept);
+    if rollback.num_failed > 0 {
+        error!("Failed to remove {} paths, keeping the undo log {:?} to retry.", rollback.num_failed, path);
+        Ok(EXIT_FAILED)
+    } else if rollback.num_removed > 0 {
+        Ok(EXIT_APPLIED)
+    } else {
+        Ok(EXIT_NOTHING_TO_DO)
+    }
+}
+
+/// Print the paths of the next sync between two directories of snapshots.
+fn print_discovery(args: &[PathBuf], config: &Config) -> io::Result<i32> {
+    let (dir_src, dir_dst) = match args {
+        [src, dst] => (src, dst),
+        _ => exit_usage_error(&format!("Expected <src-snapshots> <dst-snapshots>, but got {} paths.", args.len())),
+    };
+    let pair = match discover::discover(dir_src, dir_dst)? {
+        Discovery::Found(pair) => pair,
+        Discovery::UpToDate(name) => {
+            info!("The newest snapshot {:?} is in {:?} already, there is nothing to sync.", name, dir_dst);
+            return Ok(EXIT_NOTHING_TO_DO);
+        }
+        Discovery::NoCommon => {
+            error!("None of the snapshots in {:?} has a match in {:?}, so there is no base.", dir_src, dir_dst);
+            return Ok(EXIT_FATAL);
+        }
+    };
+    let terminator: &[u8] = if config.output == OutputFormat::Nul { b"\0" } else { b"\n" };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in &[&pair.src_base, &pair.src_target, &pair.dst_base, &pair.dst_target] {
+        out.write_all(path.as_os_str().as_bytes())?;
+        out.write_all(terminator)?;
+    }
+    Ok(EXIT_NOTHING_TO_DO)
+}
+
+/// Sync the newest snapshot of the source directory, if there is a new one
+/// that is read-only. Return whether a snapshot is waiting to become read-only.
+fn sync_new_snapshot(args: &cli::Args, config: &Config, dir_src: &Path, dir_dst: &Path) -> io::Result<bool> {
+    let pair = match discover::discover(dir_src, dir_dst)? {
+        Discovery::Found(pair) => pair,
+        Discovery::UpToDate(..) => return Ok(false),
+        Discovery::NoCommon => {
+            warn!("None of the snapshots in {:?} has a match in {:?}, so there is no base.", dir_src, dir_dst);
+            return Ok(false);
+        }
+    };
+    if !btrfs::is_read_only(&pair.src_target)? {
+        debug!("Snapshot {:?} is not read-only yet.", pair.src_target);
+        return Ok(true);
+    }
+    info!("Creating snapshot {:?} of {:?}.", pair.dst_target, pair.dst_base);
+    btrfs::create_snapshot(&pair.dst_base, &pair.dst_target)?;
+    let mut sync_args = args.clone();
+    sync_args.command = Some(Command::Apply);
+    sync_args.paths = vec![pair.src_base, pair.src_target, pair.dst_base, pair.dst_target];
+    match run_job(sync_args, config, false) {
+        Ok(code) => info!("Synced with exit status {}.", code),
+        Err(err) => error!("Sync aborted: {}", err),
+    }
+    Ok(false)
+}
+
+/// Watch the source snapshot directory, and sync every new snapshot, forever.
+fn run_daemon(args: cli::Args, config: &Config) -> io::Result<i32> {
+    // Snapshot tools may create a few entries in a row, wait for them to finish.
+    const SETTLE: Duration = Duration::from_secs(5);
+    // How often to check a new snapshot that is not read-only yet, in case
+    // making it read-only does not trigger an event.
+    const RECHECK: Duration = Duration::from_secs(60);
+
+    let (dir_src, dir_dst) = match &args.paths[..] {
+        [src, dst] => (src.clone(), dst.clone()),
+        _ => exit_usage_error(&format!("Expected <src-snapshots> <dst-snapshots>, but got {} paths.", args.paths.len())),
+    };
+    if let Some(schedule) = Schedule::from_config(config) {
+        return run_scheduled(&args, config, &dir_src, &dir_dst, schedule);
+    }
+    let watcher = watch::Watcher::new(&dir_src)?;
+    info!("Watching {:?} for new snapshots.", dir_src);
+    loop {
+        // Check once before waiting, to catch up on snapshots made while we
+        // were not running.
+        let is_pending = sync_new_snapshot(&args, config, &dir_src, &dir_dst).unwrap_or_else(|err| {
+            error!("Failed to sync the newest snapshot in {:?}: {}", dir_src, err);
+            false
+        });
+        if signal::received().is_some() {
+            return Ok(EXIT_NOTHING_TO_DO);
+        }
+        if watcher.wait(if is_pending { Some(RECHECK) } else { None })? {
+            thread::sleep(SETTLE);
+            watcher.drain()?;
+        }
+    }
+}
+
+/// Sync the newest snapshot at the times of the schedule, forever.
+///
+/// Syncs run one at a time. When a sync takes so long that the next
+/// scheduled times pass, those are skipped rather than run late.
+fn run_scheduled(args: &cli::Args, config: &Config, dir_src: &Path, dir_dst: &Path, mut schedule: Schedule) -> io::Result<i32> {
+    let mut next = schedule.next_after(SystemTime::now());
+    loop {
+        let at = next + schedule.jitter();
+        info!("Next sync of {:?} at {}.", dir_src, logger::format_timestamp(at));
+        if let Ok(wait) = at.duration_since(SystemTime::now()) {
+            thread::sleep(wait);
+        }
+        // A snapshot that is not read-only yet is checked again at the next
+        // scheduled time, so we can ignore whether one is pending.
+        if let Err(err) = sync_new_snapshot(args, config, dir_src, dir_dst) {
+            error!("Failed to sync the newest snapshot in {:?}: {}", dir_src, err);
+        }
+        if signal::received().is_some() {
+            return Ok(EXIT_NOTHING_TO_DO);
+        }
+        let now = SystemTime::now();
+        let mut num_skipped = 0;
+        next = schedule.next_after(next);
+        while next <= now {
+            num_skipped += 1;
+            next = schedule.next_after(next);
+        }
+        if num_skipped > 0 {
+            warn!("Skipped {} scheduled syncs, because the previous sync was still running.", num_skipped);
+        }
+    }
+}
+
+/// Check that the kernel has the ioctls that we need.
+fn diagnose_kernel(report: &mut doctor::Report) {
+    let release = match doctor::kernel_release() {
+        Ok(release) => release,
+        Err(err) => return report.fail(&format!("Could not determine the kernel version: {}", err)),
+    };
+    match doctor::parse_version(&release) {
+        Some(version) if version < doctor::MIN_KERNEL => report.fail(&format!(
+            "Linux {} lacks FICLONE, it needs {}.{} or later, or use --backend copy-file-range.",
+            release, doctor::MIN_KERNEL.0, doctor::MIN_KERNEL.1,
+        )),
+        Some(..) => report.ok(&format!("Linux {} has FICLONE and FIDEDUPERANGE.", release)),
+        None => report.warn(&format!("Could not parse the kernel version {:?}.", release)),
+    }
+}
+
+/// Check the mount options that affect sharing.
+fn diagnose_mount(path: &Path, report: &mut doctor::Report) {
+    let mount = match doctor::find_mount(path) {
+        Ok(Some(mount)) => mount,
+        Ok(None) => return report.warn(&format!("{:?} is not below any mount point in /proc/self/mountinfo.", path)),
+        Err(err) => return report.warn(&format!("Could not read the mount options of {:?}: {}", path, err)),
+    };
+    let has = |option: &str| mount.options.iter().any(|o| o == option);
+    let at = format!("{:?} is on {} mounted at {:?}", path, mount.fs_type, mount.mount_point);
+    if has("ro") {
+        report.warn(&format!("{} read-only, so apply can not write to it.", at));
+    }
+    if has("autodefrag") {
+        report.warn(&format!(
+            "{} with autodefrag, which unshares the extents of reflinks when it defragments them. \
+            Remount without it to keep the sharing.",
+            at,
+        ));
+    }
+    if has("nodatacow") {
+        report.warn(&format!(
+            "{} with nodatacow, new files on it can not share extents with the sources. \
+            Remount without it, or chattr -C the dst-target directory.",
+            at,
+        ));
+    }
+    if !has("ro") && !has("autodefrag") && !has("nodatacow") {
+        report.ok(&format!("{}, its options do not affect sharing.", at));
+    }
+}
+
+/// Check that FICLONE and FIDEDUPERANGE work in the directory.
+fn diagnose_ioctls(dir: &Path, report: &mut doctor::Report) {
+    if check_access(dir, libc::W_OK | libc::X_OK).is_err() {
+        return report.warn(&format!("{:?} is not writable for us, skipping the reflink checks.", dir));
+    }
+    match probe_reflink(dir) {
+        Ok(Ok(())) => report.ok(&format!("{:?} supports reflinks with FICLONE.", dir)),
+        Ok(Err(err)) => report.fail(&format!(
+            "FICLONE fails in {:?}: {}. Use --fallback-copy or --backend copy-file-range.",
+            dir, err,
+        )),
+        Err(err) => report.warn(&format!("Could not check FICLONE in {:?}: {}", dir, err)),
+    }
+    match doctor::probe_dedupe(dir) {
+        Ok(Ok(())) => report.ok(&format!("{:?} supports deduplication with FIDEDUPERANGE.", dir)),
+        Ok(Err(err)) => report.warn(&format!(
+            "FIDEDUPERANGE fails in {:?}: {}. Offline deduplication, e.g. with duperemove, will not work.",
+            dir, err,
+        )),
+        Err(err) => report.warn(&format!("Could not check FIDEDUPERANGE in {:?}: {}", dir, err)),
+    }
+}
+
+/// Check one path of the trees to mirror.
+fn diagnose_path(path: &Path, report: &mut doctor::Report) {
+    match btrfs::is_btrfs(path) {
+        Ok(true) => match (btrfs::is_subvolume(path), btrfs::is_read_only(path)) {
+            (Ok(true), Ok(true)) => report.ok(&format!("{:?} is a read-only btrfs subvolume.", path)),
+            (Ok(true), _) => report.ok(&format!("{:?} is a writable btrfs subvolume.", path)),
+            (Ok(false), _) => report.warn(&format!(
+                "{:?} is on btrfs, but not the root of a subvolume, so chain and daemon can not snapshot it.",
+                path,
+            )),
+            (Err(err), _) => report.warn(&format!("Could not check whether {:?} is a subvolume: {}", path, err)),
+        },
+        Ok(false) => report.warn(&format!(
+            "{:?} is not on btrfs, list, chain, daemon, and --make-writable do not work for it.",
+            path,
+        )),
+        Err(err) => return report.fail(&format!("Can not open {:?}: {}", path, err)),
+    }
+    diagnose_mount(path, report);
+    if path.is_dir() {
+        diagnose_ioctls(path, report);
+    }
+}
+
+/// Print what in the environment stops reflinks or sharing, for the paths,
+/// or for the paths of --job.
+fn run_doctor(args: &[PathBuf], config: &Config) -> io::Result<i32> {
+    let paths = match (args, &config.job_paths) {
+        ([], Some(paths)) => &paths[..],
+        _ => args,
+    };
+    let mut report = doctor::Report::default();
+    diagnose_kernel(&mut report);
+    for path in paths {
+        diagnose_path(path, &mut report);
+    }
+    info!("Found {} problems and {} warnings.", report.num_problems, report.num_warnings);
+    Ok(if report.num_problems > 0 { EXIT_FAILED } else { EXIT_NOTHING_TO_DO })
+}
+
+/// Print the subvolumes below the path, and the suggested pairs to mirror.
+fn print_subvolumes(args: &[PathBuf]) -> io::Result<i32> {
+    let dir = match args {
+        [dir] => dir,
+        _ => exit_usage_error(&format!("Expected one path to list subvolumes of, but got {}.", args.len())),
+    };
+    let subvolumes = btrfs::list_subvolumes(dir)?;
+    if subvolumes.is_empty() {
+        info!("There are no subvolumes below {:?}.", dir);
+        return Ok(EXIT_NOTHING_TO_DO);
+    }
+    println!("{:>8} {:>10} {:<24} {:<2} path", "id", "generation", "created", "ro");
+    for info in &subvolumes {
+        println!(
+            "{:>8} {:>10} {:<24} {:<2} {}",
+            info.id,
+            info.generation,
+            info.otime.map_or("-".to_string(), logger::format_timestamp),
+            if info.read_only { "ro" } else { "-" },
+            info.path.display(),
+        );
+    }
+    let pairs = btrfs::suggest_pairs(&subvolumes);
+    if !pairs.is_empty() {
+        println!("\nSuggested <src-base> <src-target> pairs, the two newest snapshots of a subvolume:");
+        for (base, target) in pairs {
+            println!("  {:?} {:?}", base.path, target.path);
+        }
+    }
+    Ok(EXIT_NOTHING_TO_DO)
+}
+
+/// Return the directories that the output files of the config go into, and
+/// the journal directory, which is created if needed.
+fn output_dirs(config: &Config) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let output_files = [&config.stats_json, &config.report, &config.metrics_file, &config.csv, &config.undo_log];
+    for file in output_files.iter().filter_map(|f| f.as_ref()) {
+        let dir = file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        let dir = fs::canonicalize(dir).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to resolve the directory of {:?}: {}", file, err))
+        })?;
+        dirs.push(dir);
+    }
+    if let Some(ref dir) = config.journal_dir {
+        fs::create_dir_all(dir)?;
+        dirs.push(fs::canonicalize(dir)?);
+    }
+    Ok(dirs)
+}
+
+/// Write a systemd service and timer that run the job nightly.
+fn install_systemd(args: &cli::Args) -> io::Result<i32> {
+    let (job, unit_dir) = match &args.paths[..] {
+        [job] => (job.to_string_lossy(), Path::new(systemd::UNIT_DIR)),
+        [job, dir] => (job.to_string_lossy(), dir.as_path()),
+        _ => exit_usage_error(&format!("Expected <job> [<unit-dir>], but got {} arguments.", args.paths.len())),
+    };
+    let mut job_args = args.clone();
+    job_args.job = Some(job.to_string());
+    let config = Config::load(&job_args).unwrap_or_else(|msg| exit_usage_error(&msg));
+    let (config_file, job_paths) = match (config.file.as_ref(), config.job_paths.as_ref()) {
+        (Some(file), Some(paths)) => (fs::canonicalize(file)?, paths),
+        _ => exit_usage_error(&format!("Job '{}' must define its paths in the config file to run unattended.", job)),
+    };
+
+    // The service does not run in this directory, so all paths must be absolute.
+    let mut write_paths = Vec::new();
+    for dst_target in job_paths[2..].iter().skip(1).step_by(2) {
+        let path = fs::canonicalize(dst_target).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to resolve dst-target {:?}: {}", dst_target, err))
+        })?;
+        write_paths.push(path);
+    }
+    write_paths.extend(output_dirs(&config)?);
+    write_paths.sort();
+    write_paths.dedup();
+
+    let mut exec_args = vec![format!("--config={}", config_file.to_string_lossy())];
+    exec_args.extend(args.flags.iter().map(|(flag, value)| format!("--{}={}", flag, value)));
+    exec_args.extend((0..args.verbose).map(|_| "--verbose".to_string()));
+    exec_args.extend((0..args.quiet).map(|_| "--quiet".to_string()));
+    let exe = env::current_exe()?;
+    let service = systemd::Service { job: &job, exe: &exe, args: &exec_args, write_paths: &write_paths };
+
+    let name = systemd::unit_name(&job);
+    for (suffix, contents) in &[("service", service.service_unit()), ("timer", service.timer_unit())] {
+        let path = unit_dir.join(format!("{}.{}", name, suffix));
+        fs::write(&path, contents).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to write {:?}: {}", path, err))
+        })?;
+        info!("Wrote {:?}.", path);
+    }
+    info!("Enable the timer with 'systemctl daemon-reload && systemctl enable --now {}.timer'.", name);
+    Ok(EXIT_NOTHING_TO_DO)
+}
+
+/// Report an error in the arguments, and exit.
+fn exit_usage_error(msg: &str) -> ! {
+    error!("{}", msg);
+    eprintln!("Run 'reflink-diff --help' for usage.");
+    process::exit(EXIT_USAGE);
+}
+
+fn main() {
+    let code = match run() {
+        Ok(code) => code,
+        Err(err) => {
+            error!("{}", err);
+            EXIT_FATAL
+        }
+    };
+    // The exit code of an interrupted run says so, also when it failed otherwise.
+    process::exit(signal::received().map_or(code, signal::exit_code));
+}
+
+/// Run the command, and return the exit code.
+fn run() -> io::Result<i32> {
+    // Install the logger before loading the config, so config warnings show.
+    logger::init();
+    let args = match cli::parse_args(env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => exit_usage_error(&msg),
+    };
+    if args.help {
+        print!("{}", cli::USAGE);
+        return Ok(EXIT_NOTHING_TO_DO);
+    }
+    if args.version {
+        println!("reflink-diff {}", env!("CARGO_PKG_VERSION"));
+        return Ok(EXIT_NOTHING_TO_DO);
+    }
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(msg) => exit_usage_error(&msg),
+    };
+    if let Some(level) = config.log_level {
+        logger::set_level(level);
+    }
+    logger::set_verbosity(args.verbose, args.quiet);
+    // Diagnostics go to stderr, so that is the stream that must be a terminal.
+    let is_tty = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+    logger::set_color(config.color.resolve(env::var_os("NO_COLOR"), is_tty));
+    logger::set_timestamps(config.timestamps);
+    signal::catch_status_requests();
+
+    if args.show_config {
+        config.print();
+        return Ok(EXIT_NOTHING_TO_DO);
+    }
+
+    if config.sandbox && matches!(args.command, Some(Command::Chain) | Some(Command::Daemon)) {
+        exit_usage_error("--sandbox restricts the process for good, so it works for a single sync, not for chain or daemon.");
+    }
+
+    match args.command {
+        Some(Command::DryRun) if config.btrbk.is_some() => run_btrbk(args, &config, true),
+        Some(Command::Apply) if config.btrbk.is_some() => run_btrbk(args, &config, false),
+        Some(Command::DryRun) => run_job(args, &config, true),
+        Some(Command::Apply) => run_job(args, &config, false),
+        Some(Command::Run) => run_jobs(&args),
+        Some(Command::Chain) => run_chain(args, &config),
+        Some(Command::Discover) => print_discovery(&args.paths, &config),
+        Some(Command::Daemon) => run_daemon(args, &config),
+        Some(Command::List) => print_subvolumes(&args.paths),
+        Some(Command::InstallSystemd) => install_systemd(&args),
+        Some(Command::Completions) => print_completions(&args.paths),
+        Some(Command::Rollback) => run_rollback(&args.paths),
+        Some(Command::Doctor) => run_doctor(&args.paths, &config),
+        None => exit_usage_error("No command given, expected 'apply', 'dry-run', 'run', or 'chain'."),
+    }
+}
+
+/// Run the configured jobs named in the arguments, or all of them with
+/// `--all`, in apply mode, one after another. Return the most severe exit
+/// code, after running all jobs, also when one of them failed.
+fn run_jobs(args: &cli::Args) -> io::Result<i32> {
+    let names: Vec<String> = args.paths.iter().map(|p| p.to_string_lossy().into_owned()).collect();
+    let names = match (args.all, names.is_empty()) {
+        (true, true) => Config::load(args).map(|config| config.job_names).unwrap_or_else(|msg| exit_usage_error(&msg)),
+        (true, false) => exit_usage_error("Jobs cannot be named with --all, it runs all of them."),
+        (false, true) => exit_usage_error("No job given, expected 'run <job>...' or 'run --all'."),
+        (false, false) => names,
+    };
+    if names.is_empty() {
+        exit_usage_error("The config file defines no jobs, expected [job.<name>] tables.");
+    }
+
+    // Load the config of every job before running any, so a typo in the last
+    // job does not fail the run half way.
+    let mut jobs = Vec::with_capacity(names.len());
+    for name in &names {
+        let mut job_args = args.clone();
+        job_args.command = Some(Command::Apply);
+        job_args.job = Some(name.clone());
+        job_args.paths.clear();
+        match Config::load(&job_args) {
+            Ok(config) => jobs.push((job_args, config)),
+            Err(msg) => exit_usage_error(&msg),
+        }
+    }
+
+    let mut code = EXIT_NOTHING_TO_DO;
+    let mut num_failed = 0;
+    for (job_args, config) in jobs {
+        let name = config.job.clone().expect("Job configs have a job.");
+        info!("Running job '{}'.", name);
+        let job_code = match run_job(job_args, &config, false) {
+            Ok(job_code) => job_code,
+            Err(err) => {
+                error!("Job '{}' aborted: {}", name, err);
+                EXIT_FATAL
+            }
+        };
+        if signal::received().is_some() {
+            return Ok(job_code);
+        }
+        if job_code != EXIT_NOTHING_TO_DO && job_code != EXIT_APPLIED {
+            num_failed += 1;
+        }
+        code = most_severe(code, job_code);
+    }
+    info!("Ran {} jobs, {} of them with errors.", names.len(), num_failed);
+    Ok(code)
+}
+
+/// Sync the newest snapshot pair of every subvolume and target in the btrbk
+/// config, one after another, and return the most severe exit code.
+fn run_btrbk(args: cli::Args, config: &Config, dry_run: bool) -> io::Result<i32> {
+    let path = config.btrbk.as_ref().expect("Only called with --btrbk.");
+    if !args.paths.is_empty() || config.job_paths.is_some() || config.snapper.is_some() {
+        exit_usage_error("--btrbk takes the paths from the btrbk config, they cannot be passed as well.");
+    }
+    let subvolumes = btrbk::load_config(path)?;
+    let mut code = EXIT_NOTHING_TO_DO;
+    let mut num_synced = 0;
+    for subvolume in &subvolumes {
+        for target in &subvolume.targets {
+            let paths = match btrbk::find_pair(subvolume, target)? {
+                Some(paths) => paths,
+                None => {
+                    warn!(
+                        "Skipping btrbk snapshots {:?} in {:?}, there are not two of them in {:?} yet.",
+                        subvolume.snapshot_name, subvolume.snapshot_dir, target,
+                    );
+                    continue;
+                }
+            };
+            info!("Syncing {:?} with base {:?}.", paths[3], paths[2]);
+            let mut pair_args = args.clone();
+            pair_args.paths = paths.to_vec();
+            let pair_code = run_job(pair_args, config, dry_run).unwrap_or_else(|err| {
+                error!("Syncing {:?} aborted: {}", paths[3], err);
+                EXIT_FATAL
+            });
+            code = most_severe(code, pair_code);
+            num_synced += 1;
+        }
+    }
+    if num_synced == 0 {
+        error!("Found no snapshot pairs to sync in btrbk config {:?}.", path);
+        return Ok(most_severe(code, EXIT_FATAL));
+    }
+    Ok(code)
+}
+
+/// Return the snapshots of a chain as (source, destination) pairs, in order.
+///
+/// Takes either the two directories that hold the snapshots, which are then
+/// paired by name, or the pairs themselves.
+fn chain_snapshots(paths: &[PathBuf]) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    if paths.len() > 2 {
+        if paths.len() % 2 != 0 {
+            exit_usage_error(&format!("Expected <src-snapshot> <dst-snapshot> pairs, but got {} paths.", paths.len()));
+        }
+        return Ok(paths.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect());
+    }
+    let (dir_src, dir_dst) = match paths {
+        [src, dst] => (src, dst),
+        _ => exit_usage_error("Expected <src-snapshots> <dst-snapshots>, or <src-snapshot> <dst-snapshot> pairs."),
+    };
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir_src)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name());
+        }
+    }
+    // Sort by the raw bytes, so the order does not depend on the locale.
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    let mut snapshots = Vec::with_capacity(names.len());
+    for name in names {
+        let dst = dir_dst.join(&name);
+        if dst.is_dir() {
+            snapshots.push((dir_src.join(&name), dst));
+        } else {
+            debug!("Skipping snapshot {:?}, it has no counterpart in {:?}.", name, dir_dst);
+        }
+    }
+    Ok(snapshots)
+}
+
+/// Replay every consecutive pair of snapshots onto the destination snapshots,
+/// in apply mode, and stop at the first step that fails.
+fn run_chain(args: cli::Args, config: &Config) -> io::Result<i32> {
+    if config.job_paths.is_some() {
+        exit_usage_error("The chain command takes its snapshots as paths, it cannot be used with a --job that defines paths.");
+    }
+    let snapshots = chain_snapshots(&args.paths)?;
+    if snapshots.len() < 2 {
+        exit_usage_error(&format!(
+            "A chain needs at least two snapshots that exist on both sides, but found {}.",
+            snapshots.len(),
+        ));
+    }
+
+    let num_steps = snapshots.len() - 1;
+    let mut code = EXIT_NOTHING_TO_DO;
+    for (i, pair) in snapshots.windows(2).enumerate() {
+        let ((src_base, dst_base), (src_target, dst_target)) = (&pair[0], &pair[1]);
+        info!("Step {} of {}: replaying {:?} -> {:?} on {:?}.", i + 1, num_steps, src_base, src_target, dst_target);
+        let mut step_args = args.clone();
+        step_args.command = Some(Command::Apply);
+        step_args.paths = vec![src_base.clone(), src_target.clone(), dst_base.clone(), dst_target.clone()];
+        // What dst-base lacks, an earlier snapshot may still have.
+        let mut step_config = config.clone();
+        step_config.other_bases.extend(snapshots[..i].iter().rev().map(|(_, dst)| dst.clone()));
+        let step_code = run_job(step_args, &step_config, false).unwrap_or_else(|err| {
+            error!("Step {} aborted: {}", i + 1, err);
+            EXIT_FATAL
+        });
+        code = most_severe(code, step_code);
+        if step_code == EXIT_FATAL || step_code == EXIT_FAILED {
+            error!("Stopping the chain after step {} of {}, later steps build on it.", i + 1, num_steps);
+            return Ok(code);
+        }
+    }
+    info!("Replayed {} steps.", num_steps);
+    Ok(code)
+}
+
+/// Run one mirror job, with the paths from the arguments or the config.
+fn run_job(mut args: cli::Args, config: &Config, dry_run: bool) -> io::Result<i32> {
+    if let Some(ref paths) = config.job_paths {
+        if !args.paths.is_empty() {
+            exit_usage_error("Paths cannot be passed with --job, the job defines them.");
+        }
+        args.paths = paths.clone();
+    }
+    if let Some(ref snapper_config) = config.snapper {
+        if config.job_paths.is_some() {
+            exit_usage_error("--snapper cannot be used with a --job that defines src-base and src-target.");
+        }
+        let (base, target) = snapper::last_two(snapper_config)?;
+        info!(
+            "Using snapper snapshots {} ({}) and {} ({}) of config '{}'.",
+            base.number, base.date.as_ref().map_or("no date", |d| d), target.number,
+            target.date.as_ref().map_or("no date", |d| d), snapper_config,
+        );
+        args.paths.splice(0..0, vec![base.path, target.path]);
+    }
+    if args.paths.len() < 4 || args.paths.len() % 2 != 0 {
+        exit_usage_error(&format!(
+            "Expected 4 paths, <src-base> <src-target> <dst-base> <dst-target>, \
+            optionally followed by more <dst-base> <dst-target> pairs, but got {}.",
+            args.paths.len(),
+        ));
+    }
+
+    // Check this before the scan, which may take a long time.
+    if config.interactive && !dry_run && unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+        exit_usage_error("--interactive needs a terminal on stdin to ask for confirmation.");
+    }
+    if config.resume && config.journal_dir.is_none() {
+        exit_usage_error("--resume needs --journal-dir to find the journal of the earlier run.");
+    }
+    if config.sandbox && (config.post_hook.is_some() || config.notify_cmd.is_some()) {
+        exit_usage_error("--sandbox can not be combined with --post-hook or --notify-cmd, the sandbox would not let them run.");
+    }
+
+    let started = SystemTime::now();
+    let result = run_with_hooks(&args, config, dry_run);
+    if let Some(ref command) = config.notify_cmd {
+        let summary = notify_summary(&args, config, dry_run, started, &result);
+        if let Err(err) = hooks::notify(command, &summary) {
+            error!("{}", err);
+        }
+    }
+    result
+}
+
+/// Return the name of an exit code, for --notify-cmd.
+fn exit_code_name(code: i32) -> &'static str {
+    match code {
+        EXIT_NOTHING_TO_DO => "nothing-to-do",
+        EXIT_APPLIED => "applied",
+        EXIT_FAILED => "failed",
+        EXIT_MISSING => "missing",
+        _ => "aborted",
+    }
+}
+
+/// Return the name of this machine, to tell apart notifications of several.
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    match unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } {
+        0 => {
+            let len = buffer.iter().position(|&b| b == 0).unwrap_or_else(|| buffer.len());
+            String::from_utf8_lossy(&buffer[..len]).into_owned()
+        }
+        _ => String::new(),
+    }
+}
+
+/// The JSON object that --notify-cmd gets on stdin.
+fn notify_summary(args: &cli::Args, config: &Config, dry_run: bool, started: SystemTime, result: &io::Result<i32>) -> Json {
+    let code = result.as_ref().map_or(EXIT_FATAL, |&code| code);
+    let mut pairs = vec![
+        ("status", Json::str(exit_code_name(code))),
+        ("exit_status", Json::Int(i64::from(code))),
+    ];
+    if let Err(ref err) = result {
+        pairs.push(("error", Json::String(err.to_string())));
+    }
+    pairs.push(("command", Json::str(if dry_run { "dry-run" } else { "apply" })));
+    if let Some(ref job) = config.job {
+        pairs.push(("job", Json::str(job)));
+    }
+    pairs.push(("host", Json::String(hostname())));
+    pairs.push(("started", Json::String(logger::format_timestamp(started))));
+    let elapsed = started.elapsed().unwrap_or_default();
+    pairs.push(("duration_seconds", Json::Float(elapsed.as_secs_f64())));
+    pairs.push(("paths", Json::Array(args.paths.iter().map(|p| Json::path(p)).collect())));
+    Json::object(pairs)
+}
+
+/// Run the pre-hook, the diff and replay, and the post-hook.
+fn run_with_hooks(args: &cli::Args, config: &Config, dry_run: bool) -> io::Result<i32> {
+    let hook_env = hooks::HookEnv { paths: &args.paths, dry_run, job: config.job.as_ref().map(|j| &j[..]) };
+    if let Some(ref command) = config.pre_hook {
+        hooks::run_hook("pre", command, &hook_env, &[])?;
+    }
+    let result = diff_and_replay(args, config, dry_run);
+    let command = match config.post_hook {
+        Some(ref command) => command,
+        None => return result,
+    };
+    let mut vars = vec![("BTRFS_SNAPSYNC_EXIT_STATUS", OsString::from(result.as_ref().map_or(EXIT_FATAL, |&c| c).to_string()))];
+    if let Err(ref err) = result {
+        vars.push(("BTRFS_SNAPSYNC_ERROR", OsString::from(err.to_string())));
+    }
+    match hooks::run_hook("post", command, &hook_env, &vars) {
+        Ok(()) => result,
+        Err(err) => {
+            error!("{}", err);
+            Ok(most_severe(result.unwrap_or(EXIT_FATAL), EXIT_FAILED))
+        }
+    }
+}
+
+/// Scan and diff the source trees, and replay the diff on every destination pair.
+fn diff_and_replay(args: &cli::Args, config: &Config, dry_run: bool) -> io::Result<i32> {
+    let dir_base_src = &args.paths[0];
+    let dir_target_src = &args.paths[1];
+    let mut durations = PhaseDurations(Vec::new());
+    let start = Instant::now();
+    let filter = match Filter::from_config(&config) {
+        Ok(filter) => filter,
+        Err(msg) => exit_usage_error(&msg),
+    };
+    check_permissions(args, config, dry_run)?;
+    if !dry_run {
+        for dir_target_dst in args.paths[2..].iter().skip(1).step_by(2) {
+            check_reflink_support(dir_target_dst, config)?;
+        }
+    }
+    if config.sandbox {
+        enter_sandbox(args, config, dry_run)?;
+    }
+    // Held for the whole run, to notice when a snapshot is swapped under us.
+    let mut sources = vec![Root::open(dir_base_src)?, Root::open(dir_target_src)?];
+    for subvolume in &config.subvolumes {
+        sources.push(Root::open(&subvolume.src_target)?);
+    }
+    let (entries_base, entries_target) = scan_sources(dir_base_src, dir_target_src, config, &filter)?;
+    let num_scanned = (entries_base.num_files, entries_target.num_files);
+    let start = durations.end_phase("scan", start);
+
+    let symlinks = diff_symlinks(&entries_base, &entries_target)
+        .into_iter()
+        .map(|link| CreateSymlink { path: map_path(&link.path, &config.maps), ..link })
+        .collect();
+    let copies = diff(&entries_base, entries_target)?;
+    if let Some(ref path) = config.csv {
+        write_csv(path, &copies)?;
+    }
+    durations.end_phase("diff", start);
+    check_unchanged(&sources.iter().collect::<Vec<_>>())?;
+    let diffed = Diffed { copies, symlinks, num_scanned, durations, sources: Arc::new(sources) };
+
+    // The diff of the source trees is the same for every destination pair, so
+    // we compute it once, and replay it on the pairs one after another.
+    let destinations = &args.paths[2..];
+    if destinations.len() == 2 {
+        return replay(args, config, dry_run, &diffed, dir_target_src, &destinations[0], &destinations[1]);
+    }
+    let mut code = EXIT_NOTHING_TO_DO;
+    for pair in destinations.chunks(2) {
+        info!("Replaying on dst-base {:?} and dst-target {:?}.", pair[0], pair[1]);
+        let pair_code = match replay(args, config, dry_run, &diffed, dir_target_src, &pair[0], &pair[1]) {
+            Ok(pair_code) => pair_code,
+            Err(err) => {
+                error!("Replaying on {:?} aborted: {}", pair[1], err);
+                EXIT_FATAL
+            }
+        };
+        if signal::received().is_some() {
+            return Ok(pair_code);
+        }
+        code = most_severe(code, pair_code);
+    }
+    Ok(code)
+}
+
+/// Restrict the process with Landlock to reading the source trees and the
+/// dst-bases, and to writing the dst-targets and the output files, for
+/// --sandbox. In dry-run mode the dst-targets are only read.
+fn enter_sandbox(args: &cli::Args, config: &Config, dry_run: bool) -> io::Result<()> {
+    if sandbox::is_restricted() {
+        let msg = "--sandbox restricts the process for good, so it can not run a second sync, like run with several jobs does.";
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    let mut ruleset = sandbox::Ruleset::new()?;
+    let subvolumes = config.subvolumes.iter().flat_map(|sub| vec![&sub.src_base, &sub.src_target]);
+    let dst_bases = args.paths[2..].iter().step_by(2).chain(&config.other_bases);
+    for dir in args.paths[..2].iter().chain(subvolumes).chain(dst_bases) {
+        ruleset.allow(dir, Access::Read)?;
+    }
+    for dir_target_dst in args.paths[2..].iter().skip(1).step_by(2) {
+        if !dry_run {
+            // The sandbox needs the directory, apply would create it anyway.
+            fs::create_dir_all(dir_target_dst)?;
+            ruleset.allow(dir_target_dst, Access::Write)?;
+        } else if dir_target_dst.is_dir() {
+            ruleset.allow(dir_target_dst, Access::Read)?;
+        }
+    }
+    for dir in output_dirs(config)? {
+        ruleset.allow(&dir, Access::Write)?;
+    }
+    debug!("Entering the sandbox, with Landlock ABI version {}.", ruleset.abi_version);
+    ruleset.restrict_self()
+}
+
+/// Scan src-base and src-target, and the trees of every --subvolume, merged
+/// into one scan per side, with the subvolumes under their directories.
+fn scan_sources(
+    dir_base_src: &Path,
+    dir_target_src: &Path,
+    config: &Config,
+    filter: &Filter,
+) -> io::Result<(DirScan, DirScan)> {
+    let mut progress = Progress::new(config.progress);
+    let scan_printer = Printer::from_config(config, 0, "scan");
+    let match_inodes = match btrfs::share_inode_numbers(dir_base_src, dir_target_src) {
+        Ok(share) => share,
+        Err(err) => {
+            debug!("Could not tell whether src-base and src-target share inode numbers: {}", err);
+            false
+        }
+    };
+    if match_inodes {
+        info!("src-base and src-target are snapshots of the same subvolume, matching files by inode first.");
+    }
+    let mut entries_base = scan_dir(dir_base_src, config, filter, &mut progress, match_inodes)?;
+    scan_printer.print_scan("src-base", dir_base_src, entries_base.num_files);
+    let mut entries_target = scan_dir(dir_target_src, config, filter, &mut progress, match_inodes)?;
+    scan_printer.print_scan("src-target", dir_target_src, entries_target.num_files);
+    for subvolume in &config.subvolumes {
+        let base = scan_dir(&subvolume.src_base, config, filter, &mut progress, false)?;
+        scan_printer.print_scan("src-base", &subvolume.src_base, base.num_files);
+        entries_base.merge(base, &subvolume.dir);
+        let target = scan_dir(&subvolume.src_target, config, filter, &mut progress, false)?;
+        scan_printer.print_scan("src-target", &subvolume.src_target, target.num_files);
+        entries_target.merge(target, &subvolume.dir);
+    }
+    Ok((entries_base, entries_target))
+}
+
+/// Return the path of a file in src-target, or in the src-target of the
+/// --subvolume that it is in, from its path relative to dst-target.
+fn src_target_path(dir_target_src: &Path, rel_path: &Path, config: &Config) -> PathBuf {
+    // With nested subvolume directories, the deepest one is the one we want.
+    config
+        .subvolumes
+        .iter()
+        .filter_map(|sub| rel_path.strip_prefix(&sub.dir).ok().map(|rest| (sub, rest)))
+        .max_by_key(|&(sub, _)| sub.dir.components().count())
+        .map_or_else(|| dir_target_src.join(rel_path), |(sub, rest)| sub.src_target.join(rest))
+}
+
+/// Rewrite a path relative to the source trees with the --map rule with the
+/// longest matching prefix, or return it unchanged if no rule matches.
+fn map_path(path: &Path, maps: &[PathMap]) -> PathBuf {
+    // On a tie max_by_key picks the last rule, reverse so the first one wins.
+    maps.iter()
+        .rev()
+        .filter(|map| path.starts_with(&map.src_prefix))
+        .max_by_key(|map| map.src_prefix.components().count())
+        .and_then(|map| map.apply(path))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Rewrite the paths of the copy from the source layout to the destination layout.
+fn map_copy(copy: CopyFile, maps: &[PathMap]) -> CopyFile {
+    if maps.is_empty() {
+        return copy;
+    }
+    CopyFile {
+        src: map_path(&copy.src, maps),
+        dst: map_path(&copy.dst, maps),
+        alt_srcs: copy.alt_srcs.iter().map(|p| map_path(p, maps)).collect(),
+        ..copy
+    }
+}
+
+/// The result of diffing the source trees, to replay on destinations.
+struct Diffed {
+    copies: Vec<CopyFile>,
+    symlinks: Vec<CreateSymlink>,
+    num_scanned: (usize, usize),
+    /// The durations of the scan and diff.
+    durations: PhaseDurations,
+    /// src-base, src-target, and the src-target of every --subvolume, opened
+    /// before the scan. Copies from src-target open their source through these.
+    sources: Arc<Vec<Root>>,
+}
+
+/// Replay the diff on one pair of destination trees, and return the exit code.
+fn replay(
+    args: &cli::Args,
+    config: &Config,
+    dry_run: bool,
+    diffed: &Diffed,
+    dir_target_src: &Path,
+    dir_base_dst: &Path,
+    dir_target_dst: &Path,
+) -> io::Result<i32> {
+    let num_scanned = diffed.num_scanned;
+    let symlinks = diffed.symlinks.clone();
+    let mut durations = diffed.durations.clone();
+    let start = Instant::now();
+    let base = Root::open(dir_base_dst)?;
+    let other_bases = config.other_bases.iter().map(|dir| Root::open(dir)).collect::<io::Result<Vec<_>>>()?;
+    let dir_base_src = &args.paths[0];
+    let plan = preflight(diffed.copies.clone(), dir_base_src, dir_target_src, dir_base_dst, dir_target_dst, config)?;
+    check_unchanged(&diffed.sources.iter().chain(Some(&base)).chain(&other_bases).collect::<Vec<_>>())?;
+    let start = durations.end_phase("preflight", start);
+    let num_moves = plan.len();
+
+    if dry_run {
+        if let Err(err) = check_max_missing(&plan, dir_base_dst, config) {
+            warn!("{}", err);
+        }
+        print_plan(&plan, &symlinks, dir_target_dst, config);
+        print_summary(num_scanned, num_moves, None, &durations);
+        if let Some(ref path) = config.metrics_file {
+            write_metrics(path, num_scanned, num_moves, None, &durations)?;
+        }
+        if let Some(ref path) = config.report {
+            write_report(path, args, config, num_scanned, num_moves, None, &durations)?;
+        }
+        let num_missing = plan.iter().filter(|p| p.src.is_none() && p.copy_from.is_none()).count();
+        let num_changed = plan.len() - num_missing + symlinks.len();
+        Ok(exit_code(0, num_missing, num_changed, config))
+    } else {
+        check_max_missing(&plan, dir_base_dst, config)?;
+        let _lock = lock_destination(dir_target_dst, config)?;
+        let _restore_read_only = check_writable(dir_target_dst, config)?;
+        check_same_filesystem(dir_base_dst, dir_target_dst, config)?;
+        let journal = match config.journal_dir {
+            Some(ref dir) => Some(Journal::open(dir, dir_target_src, dir_target_dst, config.resume)?),
+            None => None,
+        };
+        let plan = match journal {
+            Some(ref journal) if journal.num_done() > 0 => skip_completed(plan, journal),
+            _ => plan,
+        };
+        let undo_log = match config.undo_log {
+            Some(ref path) => Some(UndoLog::open(path)?),
+            None => None,
+        };
+        let (plan, symlinks) = if config.interactive {
+            confirm_plan(plan, symlinks, dir_target_dst)?
+        } else {
+            (plan, symlinks)
+        };
+        check_free_space(&plan, dir_target_dst, config)?;
+        let trees = Trees::open(base, dir_target_dst, other_bases)?;
+        let (tally, failures, result) = apply(plan, &symlinks, trees, diffed.sources.clone(), journal, undo_log, config);
+        durations.end_phase("apply", start);
+        print_summary(num_scanned, num_moves, Some(&tally), &durations);
+        if let Some(ref path) = config.metrics_file {
+            write_metrics(path, num_scanned, num_moves, Some(&tally), &durations)?;
+        }
+        if let Some(ref path) = config.report {
+            let applied = Some((&tally, &failures[..], &result));
+            write_report(path, args, config, num_scanned, num_moves, applied, &durations)?;
+        }
+        // Failed copies have their own exit code, any other error aborted the run.
+        match result {
+            Err(err) if failures.is_empty() => return Err(err),
+            Err(err) => error!("{}", err),
+            Ok(()) => {}
+        }
+        let num_changed = tally.files_changed();
+        Ok(exit_code(failures.len(), tally.sources_missing, num_changed, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_clone_range_keeps_an_aligned_range() {
+        assert_eq!(align_clone_range(4096, 8192, 65536, 4096), (4096, 8192));
+    }
+
+    #[test]
+    fn align_clone_range_shrinks_an_unaligned_head_and_tail() {
+        assert_eq!(align_clone_range(100, 10000, 65536, 4096), (4096, 4096));
+    }
+
+    #[test]
+    fn align_clone_range_may_end_at_the_end_of_the_file() {
+        assert_eq!(align_clone_range(100, 9900, 10000, 4096), (4096, 5904));
+        assert_eq!(align_clone_range(0, 10000, 10000, 4096), (0, 10000));
+    }
+
+    #[test]
+    fn align_clone_range_is_empty_when_no_block_fits() {
+        assert_eq!(align_clone_range(100, 1000, 65536, 4096), (100, 0));
+        assert_eq!(align_clone_range(4000, 4000, 65536, 4096), (4000, 0));
+    }
+
+    /// Return an unnamed file in the temporary directory, with the data.
+    fn temp_file(data: &[u8]) -> fs::File {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .open(std::env::temp_dir())
+            .expect("Can create a temporary file.");
+        file.write_all(data).unwrap();
+        file
+    }
+
+    fn read_all(file: &fs::File) -> Vec<u8> {
+        let mut data = vec![0; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut data, 0).unwrap();
+        data
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    /// A file for `scan`: path, size, mtime in seconds, and inode number.
+    type ScanFile<'a> = (&'a str, u64, u64, Option<u64>);
+
+    /// Build a scan of the files, as `scan_dir` would, if the trees share
+    /// inode numbers the files have one.
+    fn scan(files: &[ScanFile]) -> DirScan {
+        let mut scan = DirScan {
+            entries_size_mtime: HashMap::new(),
+            entries_size: HashMap::new(),
+            entries_name: HashMap::new(),
+            entries_ino: HashMap::new(),
+            num_files: files.len(),
+            followed_symlinks: HashSet::new(),
+            symlinks: HashMap::new(),
+        };
+        for &(path, len, mtime, ino) in files {
+            let path = PathBuf::from(path);
+            let info = FileInfo { len, mtime: UNIX_EPOCH + Duration::from_secs(mtime) };
+            scan.entries_size_mtime.entry(info).or_insert_with(Vec::new).push(path.clone());
+            scan.entries_size.entry(len).or_insert_with(Vec::new).push(path.clone());
+            let name = path.file_name().unwrap().to_os_string();
+            scan.entries_name.entry(name).or_insert_with(Vec::new).push(path.clone());
+            if let Some(ino) = ino {
+                scan.entries_ino.entry(ino).or_insert_with(Vec::new).push(path);
+            }
+        }
+        scan
+    }
+
+    /// Return the source, destination, and heuristic of every copy.
+    fn diff_moves(base: &[ScanFile], target: &[ScanFile]) -> Vec<(String, String, Match)> {
+        diff(&scan(base), scan(target))
+            .unwrap()
+            .into_iter()
+            .map(|copy| (copy.src.display().to_string(), copy.dst.display().to_string(), copy.matched_by))
+            .collect()
+    }
+
+    fn moved(src: &str, dst: &str, matched_by: Match) -> (String, String, Match) {
+        (src.to_string(), dst.to_string(), matched_by)
+    }
+
+    #[test]
+    fn diff_matches_a_moved_file_by_inode_before_size_and_mtime() {
+        // Both base files have the size and mtime of the target, but only one
+        // is the same file.
+        let base = [("a/x.bin", 100, 1, Some(10)), ("b/y.bin", 100, 1, Some(11))];
+        let target = [("c/z.bin", 100, 1, Some(11))];
+        assert_eq!(diff_moves(&base, &target), vec![moved("b/y.bin", "c/z.bin", Match::Inode)]);
+    }
+
+    #[test]
+    fn diff_matches_a_moved_and_modified_file_by_inode() {
+        // Another file has the new size and mtime, but the inode is the same file.
+        let base = [("a/x.bin", 100, 1, Some(10)), ("d/other.bin", 200, 2, Some(12))];
+        let target = [("c/x.bin", 200, 2, Some(10))];
+        assert_eq!(diff_moves(&base, &target), vec![moved("a/x.bin", "c/x.bin", Match::Inode)]);
+    }
+
+    #[test]
+    fn diff_falls_back_to_size_and_mtime_without_inodes() {
+        let base = [("a/x.bin", 100, 1, None), ("b/y.bin", 100, 1, None)];
+        let target = [("c/z.bin", 100, 1, None)];
+        let copies = diff(&scan(&base), scan(&target)).unwrap();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].src, PathBuf::from("a/x.bin"));
+        assert_eq!(copies[0].alt_srcs, vec![PathBuf::from("b/y.bin")]);
+        assert_eq!(copies[0].matched_by, Match::SizeMtime);
+        // A new inode in the target does not match, so size and mtime do.
+        let target = [("c/z.bin", 100, 1, Some(99))];
+        assert_eq!(diff_moves(&base, &target), vec![moved("a/x.bin", "c/z.bin", Match::SizeMtime)]);
+    }
+
+    #[test]
+    fn diff_skips_a_file_at_the_same_path() {
+        // Modified in place: the same inode at the same path needs no copy.
+        let base = [("a/x.bin", 100, 1, Some(10)), ("b/y.bin", 200, 2, Some(11))];
+        let target = [("a/x.bin", 200, 2, Some(10))];
+        assert_eq!(diff_moves(&base, &target), vec![]);
+        // Unchanged, also without inodes.
+        let base = [("a/x.bin", 100, 1, None)];
+        assert_eq!(diff_moves(&base, &[("a/x.bin", 100, 1, None)]), vec![]);
+    }
+
+    #[test]
+    fn copy_range_copies_a_range_of_several_buffers() {
+        let data = test_data(200 * 1024 + 17);
+        let src = temp_file(&data);
+        let dst = temp_file(&[]);
+        copy_range(&src, &dst, 0, data.len() as u64).unwrap();
+        assert_eq!(read_all(&dst), data);
+        copy_range(&src, &dst, 70000, 1000).unwrap();
+        assert_eq!(read_all(&dst), data);
+    }
+
+    #[test]
+    fn clone_range_all_clones_in_chunks() {
+        let src = temp_file(&[]);
+        let block_size = fs_block_size(&src).unwrap();
+        let data = test_data(block_size as usize * 5 + 100);
+        (&src).write_all(&data).unwrap();
+        let dst = temp_file(&[]);
+        // The chunk is a single block, so this takes several clones.
+        match clone_range_all(&src, &dst, 0, data.len() as u64, block_size) {
+            Ok(()) => assert_eq!(read_all(&dst), data),
+            Err(ref err) if Reason::of_error(err) == Reason::Unsupported => {
+                eprintln!("Skipping, the temporary directory does not support reflinks: {}", err);
+            }
+            Err(err) => panic!("Clone failed: {}", err),
+        }
+    }
 }
@@ -0,0 +1,52 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Run metrics in the Prometheus text format, for the node_exporter textfile collector.
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Prefix for all metric names.
+const PREFIX: &str = "btrfs_snapsync_";
+
+pub struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { text: String::new() }
+    }
+
+    /// Add a gauge with one sample for every (label value, value) pair.
+    ///
+    /// If `label` is none, there should be a single sample, without labels.
+    pub fn gauge(&mut self, name: &str, help: &str, label: Option<&str>, samples: &[(&str, f64)]) {
+        // Writing to a string cannot fail.
+        writeln!(self.text, "# HELP {}{} {}", PREFIX, name, help).unwrap();
+        writeln!(self.text, "# TYPE {}{} gauge", PREFIX, name).unwrap();
+        for &(label_value, value) in samples {
+            match label {
+                Some(label) => writeln!(self.text, "{}{}{{{}=\"{}\"}} {}", PREFIX, name, label, label_value, value),
+                None => writeln!(self.text, "{}{} {}", PREFIX, name, value),
+            }.unwrap();
+        }
+    }
+
+    /// Write the metrics to the file.
+    ///
+    /// The collector may read the file at any time, so we write to a temporary
+    /// file next to it, and rename that into place.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, &self.text)?;
+        fs::rename(&tmp_path, path)
+    }
+}
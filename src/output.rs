@@ -0,0 +1,198 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Printing of the plan and the applied moves to stdout.
+
+use std::env;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::config::{Config, OutputFormat};
+use crate::json::Json;
+
+/// What happens, or happened, to a destination path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// Share extents with the source.
+    Clone,
+    /// Copy from the source in the kernel, with copy_file_range(2).
+    KernelCopy,
+    /// Copy from src-target, without sharing.
+    Copy,
+    /// Nothing to do, the destination already shares the source data.
+    Skip,
+    /// There is no source to create the destination from.
+    Missing,
+    /// Creating the destination failed.
+    Error,
+    /// Create a symlink; the source is the symlink target.
+    Link,
+}
+
+impl Kind {
+    /// Return the name of the operation in the JSON format.
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Clone => "clone",
+            Kind::KernelCopy => "copy-file-range",
+            Kind::Copy => "copy",
+            Kind::Skip => "skip",
+            Kind::Missing => "missing",
+            Kind::Error => "error",
+            Kind::Link => "symlink",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Kind::Clone => "CLONE",
+            Kind::KernelCopy | Kind::Copy => "COPY",
+            Kind::Skip => "SKIP",
+            Kind::Missing => "MISSING",
+            Kind::Error => "ERROR",
+            Kind::Link => "LINK",
+        }
+    }
+
+    /// Return the ANSI color code for the tag.
+    fn color(self) -> &'static str {
+        match self {
+            Kind::Clone => "32",
+            Kind::KernelCopy | Kind::Copy => "36",
+            Kind::Skip => "2",
+            Kind::Missing => "33",
+            Kind::Error => "31",
+            Kind::Link => "34",
+        }
+    }
+}
+
+/// Source columns wider than this are not padded, to keep lines readable.
+const MAX_COLUMN_WIDTH: usize = 60;
+
+/// Return the width of the source column that fits all of the paths.
+pub fn column_width<'a, I: Iterator<Item = &'a Path>>(paths: I) -> usize {
+    paths
+        .map(|p| p.to_string_lossy().chars().count())
+        .filter(|&n| n <= MAX_COLUMN_WIDTH)
+        .max()
+        .unwrap_or(0)
+}
+
+pub struct Printer {
+    format: OutputFormat,
+    color: bool,
+    /// Width of the source column in the human format.
+    width: usize,
+    /// Event name in the JSON format: `plan` for dry runs, `apply` otherwise.
+    event: &'static str,
+}
+
+impl Printer {
+    /// Create a printer in the configured format, which colors the human
+    /// format by the same rules as diagnostics, but for stdout.
+    pub fn from_config(config: &Config, width: usize, event: &'static str) -> Printer {
+        let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        Printer {
+            format: config.output,
+            color: config.color.resolve(env::var_os("NO_COLOR"), is_tty),
+            width,
+            event,
+        }
+    }
+
+    /// Report the number of files found in a tree. Only the JSON format
+    /// prints this, the other formats only print operations.
+    pub fn print_scan(&self, tree: &str, root: &Path, num_files: usize) {
+        if self.format == OutputFormat::Json {
+            let pairs = vec![
+                ("event", Json::str("scan")),
+                ("tree", Json::str(tree)),
+                ("root", Json::path(root)),
+                ("files", Json::uint(num_files as u64)),
+            ];
+            println!("{}", Json::object(pairs));
+        }
+    }
+
+    /// Print one operation on `dst`, from `src` if it has one.
+    ///
+    /// The plain format only lists the operations that create something;
+    /// missing sources and errors are reported on stderr instead.
+    pub fn print(&self, kind: Kind, src: Option<&Path>, dst: &Path, note: Option<&str>) {
+        match self.format {
+            OutputFormat::Plain => print_plain(kind, src, dst, note),
+            OutputFormat::Human => self.print_human(kind, src, dst, note),
+            OutputFormat::Json => self.print_json(kind, src, dst, note),
+            OutputFormat::Nul => print_nul(kind, src, dst),
+        }
+    }
+
+    fn print_json(&self, kind: Kind, src: Option<&Path>, dst: &Path, note: Option<&str>) {
+        let mut pairs = vec![("event", Json::str(self.event)), ("op", Json::str(kind.name()))];
+        match (kind, src) {
+            (Kind::Link, Some(target)) => pairs.push(("target", Json::path(target))),
+            (_, Some(src)) => pairs.push(("src", Json::path(src))),
+            (_, None) => {}
+        }
+        pairs.push(("dst", Json::path(dst)));
+        match (kind, note) {
+            (Kind::Error, Some(note)) => pairs.push(("error", Json::str(note))),
+            (_, Some(note)) => pairs.push(("note", Json::str(note))),
+            (_, None) => {}
+        }
+        println!("{}", Json::object(pairs));
+    }
+
+    fn print_human(&self, kind: Kind, src: Option<&Path>, dst: &Path, note: Option<&str>) {
+        let tag = if self.color {
+            format!("\x1b[{}m{:<7}\x1b[0m", kind.color(), kind.tag())
+        } else {
+            format!("{:<7}", kind.tag())
+        };
+        let note = match note {
+            Some(note) if self.color => format!("  \x1b[2m({})\x1b[0m", note),
+            Some(note) => format!("  ({})", note),
+            None => String::new(),
+        };
+        // Symlinks read like in `ls -l`, with the target after the arrow.
+        let (left, right) = match (kind, src) {
+            (Kind::Link, Some(target)) => (dst.display().to_string(), target.display().to_string()),
+            (_, Some(src)) => (src.display().to_string(), dst.display().to_string()),
+            (_, None) => ("-".to_string(), dst.display().to_string()),
+        };
+        println!("{} {:<width$}  -> {}{}", tag, left, right, note, width = self.width);
+    }
+}
+
+/// Print the source, or nothing if there is none, and the destination, each
+/// terminated by a NUL byte, so every operation is exactly two fields.
+fn print_nul(kind: Kind, src: Option<&Path>, dst: &Path) {
+    if kind == Kind::Missing || kind == Kind::Error {
+        return
+    }
+    let mut record = Vec::new();
+    record.extend_from_slice(src.map_or(&b""[..], |p| p.as_os_str().as_bytes()));
+    record.push(0);
+    record.extend_from_slice(dst.as_os_str().as_bytes());
+    record.push(0);
+    // Like println!, fail loudly if stdout is gone.
+    io::stdout().write_all(&record).expect("Failed to write to stdout.");
+}
+
+fn print_plain(kind: Kind, src: Option<&Path>, dst: &Path, note: Option<&str>) {
+    let note = note.map(|n| format!(" ({})", n)).unwrap_or_default();
+    match (kind, src) {
+        (Kind::Missing, _) | (Kind::Error, _) => {}
+        (Kind::Link, Some(target)) => println!("{:?} (symlink to {:?})", dst, target),
+        (Kind::Copy, Some(src)) => println!("{:?} => {:?}{}", src, dst, note),
+        (_, Some(src)) => println!("{:?} -> {:?}{}", src, dst, note),
+        (_, None) => println!("{:?}{}", dst, note),
+    }
+}
@@ -0,0 +1,79 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Periodic progress lines for long scans and apply runs, and on SIGUSR1.
+
+use std::time::{Duration, Instant};
+
+use crate::signal;
+
+/// Time between two progress lines.
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait at most before checking for a SIGUSR1, which does not
+/// wake up a thread that blocks.
+const STATUS_POLL: Duration = Duration::from_secs(1);
+
+pub struct Progress {
+    enabled: bool,
+    start: Instant,
+    last: Instant,
+}
+
+impl Progress {
+    pub fn new(enabled: bool) -> Progress {
+        let now = Instant::now();
+        Progress { enabled, start: now, last: now }
+    }
+
+    /// Return whether a progress line is due, or was requested with SIGUSR1,
+    /// and if so, reset the timer.
+    pub fn is_due(&mut self) -> bool {
+        let now = Instant::now();
+        if signal::take_status_request() {
+            self.last = now;
+            return true
+        }
+        if !self.enabled {
+            return false
+        }
+        if now.duration_since(self.last) < INTERVAL {
+            return false
+        }
+        self.last = now;
+        true
+    }
+
+    /// Return how long to wait at most before the next progress line may be due.
+    pub fn time_until_due(&self) -> Duration {
+        if self.enabled {
+            INTERVAL.checked_sub(self.last.elapsed()).unwrap_or_default().min(STATUS_POLL)
+        } else {
+            STATUS_POLL
+        }
+    }
+
+    /// Estimate the time remaining, assuming the remaining items take as long
+    /// on average as the items done so far.
+    pub fn eta(&self, done: usize, total: usize) -> Option<Duration> {
+        if done == 0 || done > total {
+            return None
+        }
+        let per_item = self.start.elapsed().as_secs_f64() / done as f64;
+        Some(Duration::from_secs_f64(per_item * (total - done) as f64))
+    }
+}
+
+/// Format a duration like `1h02m`, `3m05s`, or `12s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
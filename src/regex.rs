@@ -0,0 +1,377 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A backtracking matcher for the subset of regular expressions that path
+//! filters need.
+//!
+//! Supported are literals, `.`, character classes with ranges and negation,
+//! the escapes `\d`, `\w`, `\s` and their negations, the anchors `^` and `$`,
+//! groups, alternation, and the greedy quantifiers `*`, `+`, `?`, and `{m,n}`.
+//! Not supported are backreferences, lookaround, lazy quantifiers, and
+//! capture groups; a group only groups. Matching is unanchored, like
+//! `grep -E`, unless the pattern uses `^` or `$`.
+
+#[derive(Clone, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+            ClassItem::Digit(positive) => c.is_ascii_digit() == positive,
+            ClassItem::Word(positive) => (c.is_alphanumeric() || c == '_') == positive,
+            ClassItem::Space(positive) => c.is_whitespace() == positive,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class { negated: bool, items: Vec<ClassItem> },
+    Start,
+    End,
+    /// A group with one sequence per alternative.
+    Group(Vec<Vec<Node>>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize> },
+}
+
+#[derive(Clone, Debug)]
+pub struct Regex {
+    alternatives: Vec<Vec<Node>>,
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+impl<'a> Parser<'a> {
+    fn parse_alternatives(&mut self) -> Result<Vec<Vec<Node>>> {
+        let mut alternatives = vec![self.parse_sequence()?];
+        while self.chars.peek() == Some(&'|') {
+            self.chars.next();
+            alternatives.push(self.parse_sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+        while let Some(&ch) = self.chars.peek() {
+            if ch == '|' || ch == ')' {
+                break
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_quantifier(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node> {
+        let ch = self.chars.next().expect("Caller peeked a character.");
+        let node = match ch {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let alternatives = self.parse_alternatives()?;
+                if self.chars.next() != Some(')') {
+                    return Err("Unclosed group, expected ')'.".to_string());
+                }
+                Node::Group(alternatives)
+            }
+            '[' => self.parse_class()?,
+            '\\' => match self.parse_escape()? {
+                ClassItem::Range(c, _) => Node::Char(c),
+                item => Node::Class { negated: false, items: vec![item] },
+            },
+            '*' | '+' | '?' | '{' => return Err(format!("Nothing to repeat before '{}'.", ch)),
+            c => Node::Char(c),
+        };
+        Ok(node)
+    }
+
+    fn parse_escape(&mut self) -> Result<ClassItem> {
+        let item = match self.chars.next() {
+            Some('d') => ClassItem::Digit(true),
+            Some('D') => ClassItem::Digit(false),
+            Some('w') => ClassItem::Word(true),
+            Some('W') => ClassItem::Word(false),
+            Some('s') => ClassItem::Space(true),
+            Some('S') => ClassItem::Space(false),
+            Some('n') => ClassItem::Range('\n', '\n'),
+            Some('t') => ClassItem::Range('\t', '\t'),
+            Some(c) if c.is_ascii_alphanumeric() => return Err(format!("Unsupported escape '\\{}'.", c)),
+            Some(c) => ClassItem::Range(c, c),
+            None => return Err("Pattern ends in a backslash.".to_string()),
+        };
+        Ok(item)
+    }
+
+    fn parse_class(&mut self) -> Result<Node> {
+        let negated = self.chars.peek() == Some(&'^');
+        if negated {
+            self.chars.next();
+        }
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let lo = match self.chars.next() {
+                Some(']') if !first => break,
+                Some('\\') => match self.parse_escape()? {
+                    ClassItem::Range(c, _) => c,
+                    item => { items.push(item); first = false; continue }
+                },
+                Some(c) => c,
+                None => return Err("Unclosed character class, expected ']'.".to_string()),
+            };
+            first = false;
+            let mut lookahead = self.chars.clone();
+            let is_range = lookahead.next() == Some('-') && lookahead.peek().map_or(false, |&c| c != ']');
+            if is_range {
+                self.chars.next();
+                let hi = match self.chars.next() {
+                    Some('\\') => match self.parse_escape()? {
+                        ClassItem::Range(c, _) => c,
+                        _ => return Err("Invalid range in character class.".to_string()),
+                    },
+                    Some(c) => c,
+                    None => return Err("Unclosed character class, expected ']'.".to_string()),
+                };
+                if hi < lo {
+                    return Err(format!("Invalid range '{}-{}' in character class.", lo, hi));
+                }
+                items.push(ClassItem::Range(lo, hi));
+            } else {
+                items.push(ClassItem::Range(lo, lo));
+            }
+        }
+        Ok(Node::Class { negated, items })
+    }
+
+    fn parse_number(&mut self) -> Option<usize> {
+        let mut digits = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_digit() { break }
+            digits.push(c);
+            self.chars.next();
+        }
+        digits.parse().ok()
+    }
+
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node> {
+        let (min, max) = match self.chars.peek() {
+            Some(&'*') => (0, None),
+            Some(&'+') => (1, None),
+            Some(&'?') => (0, Some(1)),
+            Some(&'{') => {
+                self.chars.next();
+                let min = self.parse_number().ok_or_else(|| "Expected a number after '{'.".to_string())?;
+                let max = match self.chars.next() {
+                    Some('}') => Some(min),
+                    Some(',') => {
+                        let max = self.parse_number();
+                        if self.chars.next() != Some('}') {
+                            return Err("Unclosed repetition, expected '}'.".to_string());
+                        }
+                        max
+                    }
+                    _ => return Err("Unclosed repetition, expected '}'.".to_string()),
+                };
+                if let Some(max) = max.filter(|&max| max < min) {
+                    return Err(format!("Invalid repetition {{{},{}}}.", min, max));
+                }
+                return self.parse_quantifier(Node::Repeat { node: Box::new(atom), min, max });
+            }
+            _ => return Ok(atom),
+        };
+        self.chars.next();
+        self.parse_quantifier(Node::Repeat { node: Box::new(atom), min, max })
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex> {
+        let mut parser = Parser { chars: pattern.chars().peekable() };
+        let alternatives = parser.parse_alternatives()?;
+        if parser.chars.next().is_some() {
+            return Err("Unmatched ')'.".to_string());
+        }
+        Ok(Regex { alternatives })
+    }
+
+    /// Return whether the pattern matches anywhere in the subject.
+    pub fn is_match(&self, subject: &str) -> bool {
+        let chars: Vec<char> = subject.chars().collect();
+        let group = [Node::Group(self.alternatives.clone())];
+        (0..=chars.len()).any(|start| match_seq(&group, &chars, start, &mut |_| true))
+    }
+}
+
+/// Match the nodes at `pos`, then call `k` with the end position, and
+/// backtrack into the nodes while `k` returns false.
+fn match_seq(nodes: &[Node], input: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    let (node, rest) = match nodes.split_first() {
+        Some(split) => split,
+        None => return k(pos),
+    };
+    let matches_char = |f: &dyn Fn(char) -> bool| input.get(pos).map_or(false, |&c| f(c));
+    match *node {
+        Node::Char(c) => matches_char(&|x| x == c) && match_seq(rest, input, pos + 1, k),
+        Node::Any => matches_char(&|_| true) && match_seq(rest, input, pos + 1, k),
+        Node::Class { negated, ref items } => {
+            matches_char(&|x| items.iter().any(|item| item.matches(x)) != negated)
+                && match_seq(rest, input, pos + 1, k)
+        }
+        Node::Start => pos == 0 && match_seq(rest, input, pos, k),
+        Node::End => pos == input.len() && match_seq(rest, input, pos, k),
+        Node::Group(ref alternatives) => alternatives
+            .iter()
+            .any(|alt| match_seq(alt, input, pos, &mut |p| match_seq(rest, input, p, k))),
+        Node::Repeat { ref node, min, max } => match_repeat(node, min, max, 0, rest, input, pos, k),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    rest: &[Node],
+    input: &[char],
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    // Greedy: first try one more repetition, then try to stop here. A
+    // repetition that consumes nothing would loop forever, so it counts
+    // as a match only while the minimum is not yet reached.
+    let can_repeat = max.map_or(true, |max| count < max);
+    let more = can_repeat && match_seq(std::slice::from_ref(node), input, pos, &mut |p| {
+        (p != pos || count < min) && match_repeat(node, min, max, count + 1, rest, input, p, k)
+    });
+    more || (count >= min && match_seq(rest, input, pos, k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compile the pattern, which must be valid, and match it against the subject.
+    fn is_match(pattern: &str, subject: &str) -> bool {
+        Regex::new(pattern).expect("The pattern is valid.").is_match(subject)
+    }
+
+    /// Return the error for a pattern that must be invalid.
+    fn error(pattern: &str) -> String {
+        Regex::new(pattern).expect_err("The pattern is invalid.")
+    }
+
+    #[test]
+    fn matching_is_unanchored_without_anchors() {
+        assert!(is_match("b/c", "a/b/c/d"));
+        assert!(is_match("", "anything"));
+        assert!(!is_match("x", "a/b/c"));
+    }
+
+    #[test]
+    fn anchors_match_only_at_the_ends() {
+        assert!(is_match("^a/b", "a/b/c"));
+        assert!(!is_match("^b/c", "a/b/c"));
+        assert!(is_match("b/c$", "a/b/c"));
+        assert!(!is_match("a/b$", "a/b/c"));
+        assert!(is_match("^a/b/c$", "a/b/c"));
+        assert!(is_match("^$", ""));
+        assert!(!is_match("^$", "a"));
+        assert!(!is_match("a^b", "a^b"));
+    }
+
+    #[test]
+    fn alternation_inside_groups_backtracks_into_the_rest() {
+        assert!(is_match("^(foo|foobar)baz$", "foobarbaz"));
+        assert!(is_match("^(a|b)+$", "abba"));
+        assert!(!is_match("^(a|b)+$", "abca"));
+        assert!(is_match("^x(|y)z$", "xz"));
+        assert!(is_match("^x(|y)z$", "xyz"));
+        assert!(is_match("^ab|cd$", "abx"));
+        assert!(!is_match("^(ab|cd)$", "abx"));
+    }
+
+    #[test]
+    fn bounded_and_open_repetitions() {
+        assert!(!is_match("^a{2,}$", "a"));
+        assert!(is_match("^a{2,}$", "aa"));
+        assert!(is_match("^a{2,}$", "aaaaaa"));
+        assert!(is_match("^a{2,3}$", "aaa"));
+        assert!(!is_match("^a{2,3}$", "aaaa"));
+        assert!(is_match("^a{2}$", "aa"));
+        assert!(!is_match("^a{2}$", "aaa"));
+        assert!(is_match("^(ab){2,}c$", "ababc"));
+        assert!(is_match("^a{0,}$", ""));
+    }
+
+    #[test]
+    fn repeated_empty_matches_terminate() {
+        assert!(is_match("^(a*)*$", ""));
+        assert!(is_match("^(a*)*$", "aaaa"));
+        assert!(!is_match("^(a*)*$", "aaab"));
+        assert!(is_match("^(a*)+b$", "b"));
+        assert!(is_match("^(a?){3}$", "a"));
+        assert!(is_match("^(a*){2,}$", ""));
+    }
+
+    #[test]
+    fn escapes_inside_classes() {
+        assert!(is_match("^[\\]]$", "]"));
+        assert!(is_match("^[a\\-z]$", "-"));
+        assert!(!is_match("^[a\\-z]$", "b"));
+        assert!(is_match("^[\\d_]+$", "12_3"));
+        assert!(!is_match("^[\\d_]+$", "12a"));
+        assert!(is_match("^[^\\s]+$", "a/b"));
+        assert!(!is_match("^[^\\s]+$", "a b"));
+        assert!(is_match("^[\\\\]$", "\\"));
+        assert!(is_match("^[]a]+$", "]a]"));
+        assert!(is_match("^[a-]$", "-"));
+        assert!(is_match("^[.]$", "."));
+        assert!(!is_match("^[.]$", "x"));
+    }
+
+    #[test]
+    fn escapes_outside_classes() {
+        assert!(is_match("^a\\.b$", "a.b"));
+        assert!(!is_match("^a\\.b$", "axb"));
+        assert!(is_match("^\\w+\\s\\d$", "file_1 2"));
+        assert!(is_match("^\\(\\)$", "()"));
+        assert!(is_match("^a\\tb$", "a\tb"));
+    }
+
+    #[test]
+    fn errors_describe_the_problem() {
+        assert_eq!(error("(ab"), "Unclosed group, expected ')'.");
+        assert_eq!(error("ab)"), "Unmatched ')'.");
+        assert_eq!(error("*a"), "Nothing to repeat before '*'.");
+        assert_eq!(error("a|+"), "Nothing to repeat before '+'.");
+        assert_eq!(error("a\\"), "Pattern ends in a backslash.");
+        assert_eq!(error("\\q"), "Unsupported escape '\\q'.");
+        assert_eq!(error("[ab"), "Unclosed character class, expected ']'.");
+        assert_eq!(error("[a-"), "Unclosed character class, expected ']'.");
+        assert_eq!(error("[z-a]"), "Invalid range 'z-a' in character class.");
+        assert_eq!(error("[a-\\d]"), "Invalid range in character class.");
+        assert_eq!(error("a{"), "Expected a number after '{'.");
+        assert_eq!(error("a{2"), "Unclosed repetition, expected '}'.");
+        assert_eq!(error("a{2,3"), "Unclosed repetition, expected '}'.");
+        assert_eq!(error("a{3,2}"), "Invalid repetition {3,2}.");
+    }
+}
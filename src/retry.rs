@@ -0,0 +1,59 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Retrying operations that failed with a transient error, for --retries.
+
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::progress;
+
+/// Whether the error is one that often goes away by itself on a loaded
+/// system, so the operation is worth retrying.
+fn is_transient(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EBUSY) | Some(libc::ENOMEM) | Some(libc::EAGAIN) => true,
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Retry {
+    /// How many times to retry after the first attempt.
+    count: u32,
+    /// The delay before the first retry, it doubles for every next one.
+    backoff: Duration,
+}
+
+impl Retry {
+    pub fn from_config(config: &Config) -> Retry {
+        Retry { count: config.retries, backoff: config.retry_backoff }
+    }
+
+    /// Run the operation on the path, and run it again while it fails with a
+    /// transient error, until the retries are exhausted.
+    pub fn run<T, F: FnMut() -> io::Result<T>>(self, path: &Path, mut operation: F) -> io::Result<T> {
+        let mut delay = self.backoff;
+        for attempt in 1..=self.count {
+            match operation() {
+                Err(ref err) if is_transient(err) => {
+                    warn!(
+                        "{:?}: {}, retrying in {} ({} of {}).",
+                        path, err, progress::format_duration(delay), attempt, self.count,
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+        operation()
+    }
+}
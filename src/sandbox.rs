@@ -0,0 +1,181 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Restricting the process to the trees with Landlock, for --sandbox.
+//!
+//! A Landlock ruleset lists the directories that the process may still
+//! access, and how. Once it is applied, it can not be lifted, and the threads
+//! that we start later inherit it. The kernel only enforces the access rights
+//! that its version of Landlock knows, the others we drop from the rules.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// The libc crate lacks Landlock, the numbers are the same on every architecture.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_MAKE_SYM: u64 = 1 << 12;
+/// Since ABI version 2, linking and renaming into another directory.
+const ACCESS_REFER: u64 = 1 << 13;
+/// Since ABI version 3.
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+
+const ACCESS_V1: u64 = ACCESS_EXECUTE
+    | ACCESS_WRITE_FILE
+    | ACCESS_READ_FILE
+    | ACCESS_READ_DIR
+    | ACCESS_REMOVE_DIR
+    | ACCESS_REMOVE_FILE
+    | ACCESS_MAKE_CHAR
+    | ACCESS_MAKE_DIR
+    | ACCESS_MAKE_REG
+    | ACCESS_MAKE_SOCK
+    | ACCESS_MAKE_FIFO
+    | ACCESS_MAKE_BLOCK
+    | ACCESS_MAKE_SYM;
+
+/// `struct landlock_ruleset_attr`, as of ABI version 1.
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`, which the kernel declares packed.
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// What a rule allows beneath its directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// Reading files and listing directories, for the trees we copy from.
+    Read,
+    /// Also creating files, directories, and symlinks, writing and truncating
+    /// files, and removing files, for dst-target and the output files.
+    Write,
+}
+
+impl Access {
+    fn rights(self) -> u64 {
+        let read = ACCESS_READ_FILE | ACCESS_READ_DIR;
+        match self {
+            Access::Read => read,
+            Access::Write => {
+                read | ACCESS_WRITE_FILE
+                    | ACCESS_REMOVE_FILE
+                    | ACCESS_MAKE_DIR
+                    | ACCESS_MAKE_REG
+                    | ACCESS_MAKE_SYM
+                    | ACCESS_TRUNCATE
+            }
+        }
+    }
+}
+
+/// Set after the first ruleset was applied.
+static RESTRICTED: AtomicBool = AtomicBool::new(false);
+
+/// Return whether the process is restricted already.
+pub fn is_restricted() -> bool {
+    RESTRICTED.load(Ordering::SeqCst)
+}
+
+pub struct Ruleset {
+    fd: fs::File,
+    /// The access rights that the kernel enforces.
+    handled: u64,
+    pub abi_version: u32,
+}
+
+impl Ruleset {
+    /// Create a ruleset that denies everything that the kernel can enforce.
+    pub fn new() -> io::Result<Ruleset> {
+        let version = unsafe {
+            libc::syscall(SYS_LANDLOCK_CREATE_RULESET, ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+        };
+        if version < 0 {
+            let err = io::Error::last_os_error();
+            let msg = match err.raw_os_error() {
+                Some(libc::ENOSYS) => "--sandbox needs Landlock, which needs Linux 5.13 or later.".to_string(),
+                Some(libc::EOPNOTSUPP) => {
+                    "--sandbox needs Landlock, which is disabled, add landlock to the lsm= boot parameter.".to_string()
+                }
+                _ => format!("Failed to check for Landlock: {}", err),
+            };
+            return Err(io::Error::new(err.kind(), msg));
+        }
+        let abi_version = version as u32;
+        let mut handled = ACCESS_V1;
+        if abi_version >= 2 {
+            handled |= ACCESS_REFER;
+        }
+        if abi_version >= 3 {
+            handled |= ACCESS_TRUNCATE;
+        }
+        let attr = RulesetAttr { handled_access_fs: handled };
+        let fd = unsafe {
+            libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, mem::size_of::<RulesetAttr>(), 0)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { fs::File::from_raw_fd(fd as libc::c_int) };
+        Ok(Ruleset { fd, handled, abi_version })
+    }
+
+    /// Allow the access to everything beneath the directory.
+    pub fn allow(&mut self, dir: &Path, access: Access) -> io::Result<()> {
+        let with_path = |err: io::Error| io::Error::new(err.kind(), format!("Failed to add {:?} to the sandbox: {}", dir, err));
+        let parent = fs::OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(dir).map_err(with_path)?;
+        let attr = PathBeneathAttr { allowed_access: access.rights() & self.handled, parent_fd: parent.as_raw_fd() };
+        let result = unsafe {
+            libc::syscall(SYS_LANDLOCK_ADD_RULE, self.fd.as_raw_fd(), LANDLOCK_RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0)
+        };
+        if result < 0 {
+            return Err(with_path(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Restrict the process to the rules, for good.
+    pub fn restrict_self(self) -> io::Result<()> {
+        // Without CAP_SYS_ADMIN, Landlock needs this, so a restricted process
+        // can not gain privileges by running a setuid program.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, self.fd.as_raw_fd(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        RESTRICTED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! When the daemon syncs, with --every and --at.
+
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, TimeOfDay};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+pub struct Schedule {
+    /// Sync at every multiple of this interval since the Unix epoch, so
+    /// `--every 1h` syncs on the hour.
+    every: Option<u64>,
+    at: Vec<TimeOfDay>,
+    jitter: Duration,
+    /// State of the xorshift generator for the jitter.
+    rng: u64,
+}
+
+impl Schedule {
+    /// Return the schedule of the config, or None if it has no schedule.
+    pub fn from_config(config: &Config) -> Option<Schedule> {
+        if config.every.is_none() && config.at.is_empty() {
+            return None;
+        }
+        // The jitter only needs to differ between machines and runs, not be
+        // unpredictable, so the time and pid are a good enough seed.
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        Some(Schedule {
+            every: config.every.map(|d| d.as_secs().max(1)),
+            at: config.at.clone(),
+            jitter: config.jitter,
+            rng: (u64::from(nanos) << 32 | u64::from(process::id())) | 1,
+        })
+    }
+
+    /// Return the first scheduled time strictly after `after`, without jitter.
+    pub fn next_after(&self, after: SystemTime) -> SystemTime {
+        let secs = after.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let by_interval = self.every.map(|every| (secs / every + 1) * every);
+        let day_start = secs - secs % SECS_PER_DAY;
+        let by_time = self
+            .at
+            .iter()
+            .map(|t| {
+                let today = day_start + u64::from(t.0);
+                if today > secs { today } else { today + SECS_PER_DAY }
+            })
+            .min();
+        let next = match (by_interval, by_time) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => unreachable!("A schedule has an interval or times."),
+        };
+        UNIX_EPOCH + Duration::from_secs(next)
+    }
+
+    /// Return a random delay between zero and the jitter.
+    pub fn jitter(&mut self) -> Duration {
+        let max_ms = self.jitter.as_millis() as u64;
+        if max_ms == 0 {
+            return Duration::from_secs(0);
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        Duration::from_millis(self.rng % (max_ms + 1))
+    }
+}
@@ -0,0 +1,103 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Stopping apply cleanly on SIGINT and SIGTERM, and printing progress on
+//! SIGUSR1.
+//!
+//! While apply runs, SIGINT and SIGTERM only set a flag, so the workers finish
+//! the copies in progress and start no new ones, and we still print the
+//! summary and write the journal and reports. A second signal kills us as
+//! usual, in case a copy hangs.
+//!
+//! SIGUSR1 asks for a progress line, like it does for dd. It is caught for
+//! the whole run, because by default it would kill us.
+
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// The signal that we received, or 0 if there was none.
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a SIGUSR1 arrived that we did not print progress for yet.
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(signo: libc::c_int) {
+    // Storing to an atomic is one of the few things that are safe in a handler.
+    RECEIVED.store(signo as usize, Ordering::SeqCst);
+}
+
+extern "C" fn handle_status_request(_signo: libc::c_int) {
+    STATUS_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install the function as the handler of the signal.
+fn install(signo: libc::c_int, handler: extern "C" fn(libc::c_int), flags: libc::c_int) {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = flags;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signo, &action, ptr::null_mut());
+    }
+}
+
+/// Catch SIGUSR1 from now on, for `take_status_request`.
+pub fn catch_status_requests() {
+    install(libc::SIGUSR1, handle_status_request, libc::SA_RESTART);
+}
+
+/// Return whether a SIGUSR1 asked for progress since the last call.
+pub fn take_status_request() -> bool {
+    STATUS_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Catches the signals until it is dropped, then they kill us again.
+pub struct Catch;
+
+impl Catch {
+    pub fn install() -> Catch {
+        for &signo in &SIGNALS {
+            // Restart interrupted syscalls, so copies in progress do not fail
+            // with EINTR. The handler resets after the first signal.
+            install(signo, handle, libc::SA_RESTART | libc::SA_RESETHAND);
+        }
+        Catch
+    }
+}
+
+impl Drop for Catch {
+    fn drop(&mut self) {
+        for &signo in &SIGNALS {
+            unsafe { libc::signal(signo, libc::SIG_DFL) };
+        }
+    }
+}
+
+/// Return the signal that interrupted the run, if there was one.
+pub fn received() -> Option<libc::c_int> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signo => Some(signo as libc::c_int),
+    }
+}
+
+pub fn name(signo: libc::c_int) -> &'static str {
+    match signo {
+        libc::SIGINT => "SIGINT",
+        libc::SIGTERM => "SIGTERM",
+        _ => "a signal",
+    }
+}
+
+/// Return the exit code of a run that the signal interrupted, the one that a
+/// shell reports for a process that the signal killed.
+pub fn exit_code(signo: libc::c_int) -> i32 {
+    128 + signo
+}
@@ -0,0 +1,107 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Finding the snapshots of a snapper config, for --snapper.
+//!
+//! Snapper keeps the snapshots of a subvolume in `<subvolume>/.snapshots`,
+//! as `<number>/snapshot`, with the metadata in `<number>/info.xml`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where snapper keeps its configs, one file per config.
+const CONFIGS_DIR: &str = "/etc/snapper/configs";
+
+/// A snapshot, with the metadata from its info.xml.
+pub struct Snapshot {
+    pub number: u64,
+    /// The time of the snapshot as snapper wrote it, in UTC.
+    pub date: Option<String>,
+    /// The path of the snapshot subvolume itself.
+    pub path: PathBuf,
+}
+
+fn error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+/// Return the value of a `KEY="value"` line in a snapper config.
+fn config_value(contents: &str, key: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let line = line.trim();
+        let eq = line.find('=')?;
+        if line[..eq].trim() != key {
+            return None;
+        }
+        let value = line[eq + 1..].trim();
+        Some(value.trim_matches(|c| c == '"' || c == '\'').to_string())
+    })
+}
+
+/// Return the text of the first `<tag>` element, enough for snapper's info.xml.
+fn xml_element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let len = xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..start + len].trim())
+}
+
+/// Return the subvolume of the snapper config, which is a config name, or a
+/// path to a config file when it contains a slash.
+fn config_subvolume(config: &str) -> io::Result<PathBuf> {
+    let config_path = if config.contains('/') {
+        PathBuf::from(config)
+    } else {
+        Path::new(CONFIGS_DIR).join(config)
+    };
+    let contents = fs::read_to_string(&config_path)
+        .map_err(|err| error(format!("Failed to read snapper config {:?}: {}", config_path, err)))?;
+    match config_value(&contents, "SUBVOLUME") {
+        Some(subvolume) => Ok(PathBuf::from(subvolume)),
+        None => Err(error(format!("Snapper config {:?} does not set SUBVOLUME.", config_path))),
+    }
+}
+
+/// List the snapshots of the snapper config, oldest first.
+pub fn list_snapshots(config: &str) -> io::Result<Vec<Snapshot>> {
+    let dir = config_subvolume(config)?.join(".snapshots");
+    let mut snapshots = Vec::new();
+    let entries = fs::read_dir(&dir)
+        .map_err(|err| error(format!("Failed to read snapper snapshots in {:?}: {}", dir, err)))?;
+    for entry in entries {
+        let entry = entry?;
+        // Snapper names the directories after the snapshot number, skip anything else.
+        let number = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+            Some(number) => number,
+            None => continue,
+        };
+        let path = entry.path().join("snapshot");
+        if !path.is_dir() {
+            continue;
+        }
+        let info = fs::read_to_string(entry.path().join("info.xml")).unwrap_or_default();
+        let date = xml_element(&info, "date").map(|date| date.to_string());
+        if let Some(info_number) = xml_element(&info, "num") {
+            if info_number.parse() != Ok(number) {
+                warn!("Snapper snapshot {:?} has number {} in its info.xml.", entry.path(), info_number);
+            }
+        }
+        snapshots.push(Snapshot { number, date, path });
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.number);
+    Ok(snapshots)
+}
+
+/// Return the two newest snapshots of the snapper config, as base and target.
+pub fn last_two(config: &str) -> io::Result<(Snapshot, Snapshot)> {
+    let mut snapshots = list_snapshots(config)?;
+    match (snapshots.pop(), snapshots.pop()) {
+        (Some(target), Some(base)) => Ok((base, target)),
+        _ => Err(error(format!("Snapper config '{}' needs at least two snapshots, to use as base and target.", config))),
+    }
+}
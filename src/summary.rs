@@ -0,0 +1,155 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Grouping of the moves that could not be completed, for the end of a run.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::json::Json;
+
+/// How many example paths to keep for every reason.
+const NUM_EXAMPLES: usize = 3;
+
+/// Why a move could not be completed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reason {
+    SourceMissing,
+    DestinationExists,
+    SourceChanged,
+    CrossDevice,
+    Unsupported,
+    NocowDestination,
+    PermissionDenied,
+    Other,
+}
+
+const REASONS: &[Reason] = &[
+    Reason::SourceMissing,
+    Reason::DestinationExists,
+    Reason::SourceChanged,
+    Reason::CrossDevice,
+    Reason::Unsupported,
+    Reason::NocowDestination,
+    Reason::PermissionDenied,
+    Reason::Other,
+];
+
+impl Reason {
+    /// Classify the error that made a move fail.
+    pub fn of_error(err: &io::Error) -> Reason {
+        match err.raw_os_error() {
+            Some(libc::EXDEV) => Reason::CrossDevice,
+            // FICLONE fails with EOPNOTSUPP on filesystems without reflink
+            // support, older kernels return EINVAL or ENOTTY instead.
+            Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ENOTTY) => Reason::Unsupported,
+            Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) => Reason::PermissionDenied,
+            _ => Reason::Other,
+        }
+    }
+
+    /// A short identifier, for the structured stats.
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::SourceMissing => "source-missing",
+            Reason::DestinationExists => "destination-exists",
+            Reason::SourceChanged => "source-changed",
+            Reason::CrossDevice => "cross-device",
+            Reason::Unsupported => "unsupported-fs",
+            Reason::NocowDestination => "nocow-destination",
+            Reason::PermissionDenied => "permission-denied",
+            Reason::Other => "other",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Reason::SourceMissing => "source missing in dst-base",
+            Reason::DestinationExists => "destination has data, not overwritten without --force",
+            Reason::SourceChanged => "source changed since the scan",
+            Reason::CrossDevice => "source and destination are on different filesystems",
+            Reason::Unsupported => "filesystem does not support reflinks",
+            Reason::NocowDestination => "destination directory is nocow (chattr +C), the source is not",
+            Reason::PermissionDenied => "permission denied",
+            Reason::Other => "other errors",
+        }
+    }
+}
+
+/// Counts and example paths of moves that could not be completed, by reason.
+pub struct Unresolved {
+    /// For every reason in `REASONS`, the count and up to `NUM_EXAMPLES` paths.
+    groups: Vec<(usize, Vec<PathBuf>)>,
+    /// Moves that were not started because the run stopped after a failure.
+    pub not_attempted: usize,
+}
+
+impl Unresolved {
+    pub fn new() -> Unresolved {
+        Unresolved {
+            groups: REASONS.iter().map(|_| (0, Vec::new())).collect(),
+            not_attempted: 0,
+        }
+    }
+
+    /// Record a move that could not be completed, by its destination path.
+    pub fn add(&mut self, reason: Reason, path: &Path) {
+        let i = REASONS.iter().position(|&r| r == reason).expect("All reasons are in REASONS.");
+        let (ref mut count, ref mut examples) = self.groups[i];
+        *count += 1;
+        if examples.len() < NUM_EXAMPLES {
+            examples.push(path.to_path_buf());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.not_attempted == 0 && self.groups.iter().all(|&(count, _)| count == 0)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Reason, usize, &[PathBuf])> {
+        REASONS
+            .iter()
+            .zip(self.groups.iter())
+            .filter(|&(_, &(count, _))| count > 0)
+            .map(|(&reason, &(count, ref examples))| (reason, count, &examples[..]))
+    }
+
+    /// Log the summary as a warning, if there is anything to report.
+    pub fn print(&self) {
+        if self.is_empty() {
+            return
+        }
+        let mut message = "Moves that could not be completed:".to_string();
+        for (reason, count, examples) in self.iter() {
+            message.push_str(&format!("\n  {} ({}):", reason.description(), count));
+            for path in examples {
+                message.push_str(&format!("\n    {:?}", path));
+            }
+            if count > examples.len() {
+                message.push_str(&format!("\n    ... and {} more", count - examples.len()));
+            }
+        }
+        if self.not_attempted > 0 {
+            message.push_str(&format!(
+                "\n  not attempted, because the run stopped after a failure ({})", self.not_attempted,
+            ));
+        }
+        warn!("{}", message);
+    }
+
+    pub fn to_json(&self) -> Json {
+        let groups = self.iter().map(|(reason, count, examples)| Json::object(vec![
+            ("reason", Json::str(reason.name())),
+            ("count", Json::uint(count as u64)),
+            ("examples", Json::Array(examples.iter().map(|p| Json::path(p)).collect())),
+        ]));
+        Json::object(vec![
+            ("by_reason", Json::Array(groups.collect())),
+            ("not_attempted", Json::uint(self.not_attempted as u64)),
+        ])
+    }
+}
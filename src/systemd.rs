@@ -0,0 +1,116 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Generating a systemd service and timer that run a job, for install-systemd.
+
+use std::path::{Path, PathBuf};
+
+/// Where units for the local system go.
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Return the name of the units for the job, without the .service or .timer suffix.
+pub fn unit_name(job: &str) -> String {
+    // Systemd allows only some characters in unit names, escape the others
+    // like systemd-escape does.
+    let mut name = String::from("reflink-diff-");
+    for b in job.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b'-' => name.push(b as char),
+            _ => name.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    name
+}
+
+/// Quote a word for a unit file, where `%` starts a specifier, and in
+/// ExecStart `$` starts a variable.
+fn quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%").replace('$', "$$");
+    let needs_quotes = escaped.is_empty() || escaped.chars().any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';');
+    if !needs_quotes {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// What the service runs, and what it may write to.
+pub struct Service<'a> {
+    pub job: &'a str,
+    /// The absolute path of the binary.
+    pub exe: &'a Path,
+    /// The arguments before the command, e.g. `--config` and its path.
+    pub args: &'a [String],
+    /// The paths that the job writes to, all others are read-only.
+    pub write_paths: &'a [PathBuf],
+}
+
+impl<'a> Service<'a> {
+    /// Format the .service unit.
+    pub fn service_unit(&self) -> String {
+        let mut exec_start = quote(&self.exe.to_string_lossy());
+        for arg in self.args.iter().map(|a| &a[..]).chain(["run", self.job].iter().cloned()) {
+            exec_start.push(' ');
+            exec_start.push_str(&quote(arg));
+        }
+        let write_paths: Vec<String> = self.write_paths.iter().map(|p| quote(&p.to_string_lossy())).collect();
+        format!(
+            r#"# Generated by "reflink-diff install-systemd {job}".
+
+[Unit]
+Description=Replay moves as reflinks for mirror job {job}
+After=local-fs.target
+
+[Service]
+Type=oneshot
+ExecStart={exec_start}
+# Exit status 1 means that files were reflinked, which is a success.
+SuccessExitStatus=1
+Nice=10
+IOSchedulingClass=idle
+
+# The job only needs to write to the destination trees and its output files.
+ProtectSystem=strict
+ReadWritePaths={write_paths}
+ProtectHome=read-only
+PrivateTmp=yes
+PrivateNetwork=yes
+NoNewPrivileges=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+RestrictRealtime=yes
+RestrictNamespaces=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+"#,
+            job = self.job.replace('%', "%%"),
+            exec_start = exec_start,
+            write_paths = write_paths.join(" "),
+        )
+    }
+
+    /// Format the .timer unit, that starts the service nightly.
+    pub fn timer_unit(&self) -> String {
+        format!(
+            r#"# Generated by "reflink-diff install-systemd {job}".
+
+[Unit]
+Description=Nightly run of reflink-diff mirror job {job}
+
+[Timer]
+OnCalendar=daily
+RandomizedDelaySec=1h
+# Catch up on a run that was missed while the machine was off.
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"#,
+            job = self.job.replace('%', "%%"),
+        )
+    }
+}
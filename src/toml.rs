@@ -0,0 +1,477 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A parser for the subset of TOML that the config file needs.
+//!
+//! Supported are tables, bare and quoted keys, strings (basic and literal),
+//! integers, booleans, and arrays of those. Not supported are floats, dates,
+//! inline tables, arrays of tables, multi-line strings, and dotted keys.
+
+use std::fmt;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    /// Format the value as TOML.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::String(ref s) => {
+                write!(f, "\"")?;
+                for ch in s.chars() {
+                    match ch {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        c if c.is_control() => write!(f, "\\u{:04X}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(ref values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// A key-value pair, with the line it was defined on, for error reporting.
+#[derive(Debug)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// A `[table]` section. The table before the first header has an empty name.
+#[derive(Debug)]
+pub struct Table {
+    pub name: Vec<String>,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: String) -> Result<T> {
+        Err(ParseError { line: self.line, message })
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.chars.next();
+        if ch == Some('\n') { self.line += 1; }
+        ch
+    }
+
+    /// Skip spaces and tabs, but not newlines.
+    fn skip_blank(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.bump();
+        }
+    }
+
+    /// Skip a comment, if there is one, up to but excluding the newline.
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while let Some(ch) = self.peek() {
+                if ch == '\n' { break }
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, newlines, and comments, as allowed inside arrays.
+    fn skip_all(&mut self) {
+        loop {
+            self.skip_blank();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => { self.bump(); }
+                _ => return,
+            }
+        }
+    }
+
+    /// Expect the end of the line, allowing trailing whitespace and a comment.
+    fn expect_line_end(&mut self) -> Result<()> {
+        self.skip_blank();
+        self.skip_comment();
+        match self.peek() {
+            None => Ok(()),
+            Some('\r') | Some('\n') => { self.bump(); Ok(()) }
+            Some(ch) => self.error(format!("Expected end of line, found '{}'.", ch.escape_debug())),
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string(),
+            Some('\'') => self.parse_literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(ch) = self.peek() {
+                    if ch.is_ascii_alphanumeric() || ch == '_' || ch == '-' {
+                        key.push(ch);
+                        self.bump();
+                    } else {
+                        break
+                    }
+                }
+                if key.is_empty() {
+                    return self.error("Expected a key.".to_string());
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    /// Report an unterminated string on its own line, not on the next one.
+    fn unterminated<T>(&self, line: usize) -> Result<T> {
+        Err(ParseError { line, message: "Unterminated string.".to_string() })
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String> {
+        assert_eq!(self.bump(), Some('"'));
+        let line = self.line;
+        let mut result = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.unterminated(line),
+                Some('"') => return Ok(result),
+                Some('\\') => match self.bump() {
+                    Some('"') => result.push('"'),
+                    Some('\\') => result.push('\\'),
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some('r') => result.push('\r'),
+                    Some('u') => {
+                        let mut code = 0;
+                        for _ in 0..4 {
+                            match self.bump().and_then(|ch| ch.to_digit(16)) {
+                                Some(d) => code = code * 16 + d,
+                                None => return self.error("Invalid \\u escape.".to_string()),
+                            }
+                        }
+                        match std::char::from_u32(code) {
+                            Some(ch) => result.push(ch),
+                            None => return self.error("Invalid \\u escape.".to_string()),
+                        }
+                    }
+                    None | Some('\n') => return self.unterminated(line),
+                    Some(ch) => return self.error(format!("Unsupported escape '\\{}'.", ch)),
+                },
+                Some(ch) => result.push(ch),
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String> {
+        assert_eq!(self.bump(), Some('\''));
+        let line = self.line;
+        let mut result = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.unterminated(line),
+                Some('\'') => return Ok(result),
+                Some(ch) => result.push(ch),
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string().map(Value::String),
+            Some('\'') => self.parse_literal_string().map(Value::String),
+            Some('[') => self.parse_array(),
+            Some(ch) if ch.is_ascii_alphanumeric() || ch == '-' || ch == '+' => {
+                let mut word = String::new();
+                while let Some(ch) = self.peek() {
+                    if ch.is_ascii_alphanumeric() || ch == '-' || ch == '+' || ch == '_' || ch == '.' || ch == ':' {
+                        word.push(ch);
+                        self.bump();
+                    } else {
+                        break
+                    }
+                }
+                // An underscore may only separate two digits, as in `1_000`.
+                let digits: Vec<char> = word.chars().collect();
+                let underscores_ok = digits.iter().enumerate().all(|(i, &ch)| {
+                    ch != '_' || (i > 0 && digits[i - 1].is_ascii_digit()
+                        && digits.get(i + 1).map_or(false, |c| c.is_ascii_digit()))
+                });
+                match &word[..] {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => match word.replace('_', "").parse() {
+                        Ok(i) if underscores_ok => Ok(Value::Integer(i)),
+                        _ => self.error(format!("Unsupported value '{}'.", word)),
+                    },
+                }
+            }
+            Some(ch) => self.error(format!("Expected a value, found '{}'.", ch.escape_debug())),
+            None => self.error("Expected a value.".to_string()),
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value> {
+        assert_eq!(self.bump(), Some('['));
+        let mut values = Vec::new();
+        loop {
+            self.skip_all();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.parse_value()?);
+            self.skip_all();
+            match self.bump() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return self.error("Expected ',' or ']' in array.".to_string()),
+            }
+        }
+    }
+
+    fn parse_table_header(&mut self) -> Result<Vec<String>> {
+        assert_eq!(self.bump(), Some('['));
+        if self.peek() == Some('[') {
+            return self.error("Arrays of tables are not supported.".to_string());
+        }
+        let mut name = Vec::new();
+        loop {
+            self.skip_blank();
+            name.push(self.parse_key()?);
+            self.skip_blank();
+            match self.bump() {
+                Some('.') => continue,
+                Some(']') => return Ok(name),
+                _ => return self.error("Expected '.' or ']' in table header.".to_string()),
+            }
+        }
+    }
+}
+
+/// Parse a document into its tables, in the order in which they appear.
+pub fn parse(input: &str) -> Result<Vec<Table>> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+    let mut tables = vec![Table { name: Vec::new(), line: 1, entries: Vec::new() }];
+
+    loop {
+        parser.skip_all();
+        match parser.peek() {
+            None => break,
+            Some('[') => {
+                let line = parser.line;
+                let name = parser.parse_table_header()?;
+                if tables.iter().any(|t| t.name == name) {
+                    return parser.error(format!("Table [{}] is defined twice.", name.join(".")));
+                }
+                tables.push(Table { name, line, entries: Vec::new() });
+            }
+            Some(_) => {
+                let line = parser.line;
+                let key = parser.parse_key()?;
+                parser.skip_blank();
+                if parser.bump() != Some('=') {
+                    return parser.error(format!("Expected '=' after key '{}'.", key));
+                }
+                parser.skip_blank();
+                let value = parser.parse_value()?;
+                let table = tables.last_mut().expect("There is always a root table.");
+                if table.entries.iter().any(|e| e.key == key) {
+                    // Report the line of the key, the value may span lines.
+                    let message = format!("Key '{}' is defined twice.", key);
+                    return Err(ParseError { line, message });
+                }
+                table.entries.push(Entry { key, value, line });
+            }
+        }
+        parser.expect_line_end()?;
+    }
+
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a document that must be valid.
+    fn parse_ok(input: &str) -> Vec<Table> {
+        parse(input).unwrap_or_else(|err| panic!("Failed to parse {:?}: {}", input, err))
+    }
+
+    /// Parse a document that must be invalid, and return the formatted error.
+    fn parse_err(input: &str) -> String {
+        match parse(input) {
+            Ok(_) => panic!("Parsed invalid document {:?}.", input),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// Parse a document with a single `key = value` line, and return the value.
+    fn parse_value(value: &str) -> Value {
+        let mut tables = parse_ok(&format!("key = {}\n", value));
+        assert_eq!(tables.len(), 1);
+        let mut entries = tables.remove(0).entries;
+        assert_eq!(entries.len(), 1);
+        entries.remove(0).value
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn tables_and_keys_keep_their_order_and_lines() {
+        let tables = parse_ok("a = 1\n\n# Comment.\n[x]\nb = 'two'\n[x.\"y z\"]\n  c = true # Trailing.\n");
+        assert_eq!(tables.len(), 3);
+        assert_eq!(tables[0].name, Vec::<String>::new());
+        assert_eq!(tables[0].entries[0].key, "a");
+        assert_eq!(tables[0].entries[0].line, 1);
+        assert_eq!(tables[1].name, vec!["x"]);
+        assert_eq!(tables[1].line, 4);
+        assert_eq!(tables[1].entries[0].value, string("two"));
+        assert_eq!(tables[1].entries[0].line, 5);
+        assert_eq!(tables[2].name, vec!["x", "y z"]);
+        assert_eq!(tables[2].line, 6);
+        assert_eq!(tables[2].entries[0].value, Value::Boolean(true));
+        assert_eq!(tables[2].entries[0].line, 7);
+    }
+
+    #[test]
+    fn duplicate_keys_and_tables_are_rejected() {
+        assert_eq!(parse_err("a = 1\nb = 2\na = 3\n"), "line 3: Key 'a' is defined twice.");
+        assert_eq!(parse_err("a = 1\n\"a\" = 2\n"), "line 2: Key 'a' is defined twice.");
+        assert_eq!(parse_err("[x]\na = 1\n[y]\n[x]\n"), "line 4: Table [x] is defined twice.");
+        assert_eq!(parse_err("[x.y]\n[x . y]\n"), "line 2: Table [x.y] is defined twice.");
+        // The same key in different tables is fine.
+        assert_eq!(parse_ok("a = 1\n[x]\na = 2\n").len(), 2);
+    }
+
+    #[test]
+    fn duplicate_key_is_reported_on_the_line_of_the_key() {
+        assert_eq!(parse_err("a = 1\na = [\n  1,\n  2,\n]\n"), "line 2: Key 'a' is defined twice.");
+    }
+
+    #[test]
+    fn multi_line_arrays_allow_comments_and_trailing_commas() {
+        let tables = parse_ok("a = [\n  1, # One.\n  # Nothing here.\n  2,\r\n  [3, 'x'],\n]\nb = 4\n");
+        let a = &tables[0].entries[0];
+        assert_eq!(a.line, 1);
+        assert_eq!(
+            a.value,
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Array(vec![Value::Integer(3), string("x")]),
+            ])
+        );
+        assert_eq!(tables[0].entries[1].line, 7);
+        assert_eq!(parse_value("[]"), Value::Array(Vec::new()));
+        assert_eq!(parse_value("[ ]"), Value::Array(Vec::new()));
+    }
+
+    #[test]
+    fn strings_support_escapes() {
+        assert_eq!(parse_value(r#""a\"b\\c\nd\te\rf""#), string("a\"b\\c\nd\te\rf"));
+        assert_eq!(parse_value(r#""\u00e9\u0041\u20AC""#), string("éA€"));
+        assert_eq!(parse_value(r"'C:\no\escapes'"), string(r"C:\no\escapes"));
+        assert_eq!(parse_err(r#"a = "\u00g0""#), "line 1: Invalid \\u escape.");
+        assert_eq!(parse_err(r#"a = "\u12""#), "line 1: Invalid \\u escape.");
+        assert_eq!(parse_err(r#"a = "\uD800""#), "line 1: Invalid \\u escape.");
+        assert_eq!(parse_err(r#"a = "\x""#), "line 1: Unsupported escape '\\x'.");
+        assert_eq!(parse_err("a = \"open\nb = 1\n"), "line 1: Unterminated string.");
+        assert_eq!(parse_err("a = 1\nb = 'open\n"), "line 2: Unterminated string.");
+        assert_eq!(parse_err("a = \"open\\\n\""), "line 1: Unterminated string.");
+    }
+
+    #[test]
+    fn integers_allow_underscores_between_digits() {
+        assert_eq!(parse_value("1_000_000"), Value::Integer(1_000_000));
+        assert_eq!(parse_value("-1_0"), Value::Integer(-10));
+        assert_eq!(parse_value("+7"), Value::Integer(7));
+        assert_eq!(parse_err("a = 1_"), "line 1: Unsupported value '1_'.");
+        assert_eq!(parse_err("a = 1__0"), "line 1: Unsupported value '1__0'.");
+        assert_eq!(parse_err("a = -_1"), "line 1: Unsupported value '-_1'.");
+        assert_eq!(parse_err("a = 1.5"), "line 1: Unsupported value '1.5'.");
+        assert_eq!(parse_err("a = 99999999999999999999"), "line 1: Unsupported value '99999999999999999999'.");
+    }
+
+    #[test]
+    fn errors_report_the_line() {
+        assert_eq!(parse_err("a = 1\n\nb 2\n"), "line 3: Expected '=' after key 'b'.");
+        assert_eq!(parse_err("a = 1 2\n"), "line 1: Expected end of line, found '2'.");
+        assert_eq!(parse_err("a = [\n1,\n2\n3]\n"), "line 4: Expected ',' or ']' in array.");
+        assert_eq!(parse_err("# Comment.\n[[x]]\n"), "line 2: Arrays of tables are not supported.");
+        assert_eq!(parse_err("[x\n"), "line 2: Expected '.' or ']' in table header.");
+        assert_eq!(parse_err("a =\n"), "line 1: Expected a value, found '\\n'.");
+        assert_eq!(parse_err("a = "), "line 1: Expected a value.");
+        assert_eq!(parse_err("= 1\n"), "line 1: Expected a key.");
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_value() {
+        let values = vec![
+            string(""),
+            string("plain"),
+            string("quote \" backslash \\ newline \n tab \t return \r"),
+            string("bell \u{7} delete \u{7f} é €"),
+            Value::Integer(0),
+            Value::Integer(-42),
+            Value::Integer(std::i64::MIN),
+            Value::Integer(std::i64::MAX),
+            Value::Boolean(true),
+            Value::Boolean(false),
+            Value::Array(Vec::new()),
+            Value::Array(vec![string("a"), Value::Integer(1), Value::Array(vec![Value::Boolean(false)])]),
+        ];
+        for value in values {
+            assert_eq!(parse_value(&value.to_string()), value, "Round trip of {}", value);
+        }
+    }
+}
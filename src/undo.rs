@@ -0,0 +1,271 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Recording the paths that apply created, for --undo-log, and removing them
+//! again, for the rollback command.
+//!
+//! The log is a file of NUL-terminated records, every record is a type byte,
+//! `f` for a file, `l` for a symlink, or `d` for a directory, followed by the
+//! absolute path. For files and symlinks, the device, inode, size, and mtime
+//! in seconds and nanoseconds go between the two, as decimal numbers that are
+//! each followed by a space, so rollback can tell whether they changed. Paths
+//! that existed before apply wrote to them are not recorded, because removing
+//! them would not restore them.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Return the paths from dst up to the first ancestor that exists, which
+/// creating dst creates, parents first.
+pub fn missing_paths(dst: &Path) -> Vec<PathBuf> {
+    let mut missing: Vec<PathBuf> = dst
+        .ancestors()
+        .take_while(|p| !p.as_os_str().is_empty())
+        .take_while(|p| matches!(fs::symlink_metadata(p), Err(ref err) if err.kind() == io::ErrorKind::NotFound))
+        .map(Path::to_path_buf)
+        .collect();
+    missing.reverse();
+    missing
+}
+
+/// What we record of a file or symlink, to recognize it at rollback.
+#[derive(Debug, Eq, PartialEq)]
+struct Identity {
+    dev: u64,
+    ino: u64,
+    len: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Identity {
+    fn of(meta: &fs::Metadata) -> Identity {
+        Identity {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            len: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let fields = format!("{} {} {} {} {} ", self.dev, self.ino, self.len, self.mtime, self.mtime_nsec);
+        out.extend_from_slice(fields.as_bytes());
+    }
+
+    /// Parse the fields that `write` wrote.
+    fn parse(fields: &[u8]) -> Option<Identity> {
+        let fields = std::str::from_utf8(fields).ok()?;
+        match fields.split_terminator(' ').collect::<Vec<_>>()[..] {
+            [dev, ino, len, mtime, mtime_nsec] => Some(Identity {
+                dev: dev.parse().ok()?,
+                ino: ino.parse().ok()?,
+                len: len.parse().ok()?,
+                mtime: mtime.parse().ok()?,
+                mtime_nsec: mtime_nsec.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct UndoLog {
+    path: PathBuf,
+    file: fs::File,
+    /// To make the recorded paths absolute, so rollback can run anywhere.
+    current_dir: PathBuf,
+}
+
+impl UndoLog {
+    /// Open the log to append to it, earlier runs stay in it.
+    pub fn open(path: &Path) -> io::Result<UndoLog> {
+        let file = fs::OpenOptions::new().append(true).create(true).open(path).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to open undo log {:?}: {}", path, err))
+        })?;
+        Ok(UndoLog { path: path.to_path_buf(), file, current_dir: env::current_dir()? })
+    }
+
+    /// Record the paths that were missing before a copy, and exist now.
+    pub fn record_created(&self, paths: &[PathBuf]) -> io::Result<()> {
+        let mut records = Vec::new();
+        for path in paths {
+            let meta = match fs::symlink_metadata(path) {
+                Ok(meta) => meta,
+                // The copy failed before it got here.
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            if meta.is_dir() {
+                records.push(b'd');
+            } else {
+                records.push(if meta.file_type().is_symlink() { b'l' } else { b'f' });
+                Identity::of(&meta).write(&mut records);
+            }
+            records.extend_from_slice(self.current_dir.join(path).as_os_str().as_bytes());
+            records.push(0);
+        }
+        // With a single write, the records of concurrent copies do not interleave.
+        (&self.file).write_all(&records).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to write to undo log {:?}: {}", self.path, err))
+        })
+    }
+}
+
+/// What a rollback did.
+#[derive(Default)]
+pub struct Rollback {
+    pub num_removed: usize,
+    /// Paths that were changed since the run, and that we left alone.
+    pub num_kept: usize,
+    pub num_failed: usize,
+}
+
+/// Remove a path of the log, or leave it when it is not what we created.
+///
+/// A file or symlink is only removed when it is still the same inode, with
+/// the same size and mtime, otherwise it was replaced or edited since the run.
+fn remove(kind: u8, identity: Option<&Identity>, path: &Path, rollback: &mut Rollback) {
+    let unchanged = |meta: &fs::Metadata| identity == Some(&Identity::of(meta));
+    let result = match fs::symlink_metadata(path) {
+        Ok(ref meta) if kind == b'l' && meta.file_type().is_symlink() && unchanged(meta) => fs::remove_file(path),
+        Ok(ref meta) if kind == b'f' && meta.is_file() && unchanged(meta) => fs::remove_file(path),
+        Ok(ref meta) if kind == b'd' && meta.is_dir() => fs::remove_dir(path),
+        Ok(..) => {
+            warn!("{:?} is no longer what the run created, keeping it.", path);
+            rollback.num_kept += 1;
+            return;
+        }
+        // Removed already, e.g. by a rollback that failed halfway.
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => rollback.num_removed += 1,
+        Err(ref err) if kind == b'd' && err.raw_os_error() == Some(libc::ENOTEMPTY) => {
+            warn!("Directory {:?} has files that the run did not create, keeping it.", path);
+            rollback.num_kept += 1;
+        }
+        Err(err) => {
+            error!("Failed to remove {:?}: {}", path, err);
+            rollback.num_failed += 1;
+        }
+    }
+}
+
+/// Remove everything that the log records, newest first, and then the log.
+///
+/// When a removal fails, the log is kept, so the rollback can be retried.
+pub fn rollback(path: &Path) -> io::Result<Rollback> {
+    let data = fs::read(path).map_err(|err| {
+        io::Error::new(err.kind(), format!("Failed to read undo log {:?}: {}", path, err))
+    })?;
+    // What follows the last NUL is empty, or a torn record.
+    let mut records: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+    records.pop();
+
+    let mut rollback = Rollback::default();
+    let mut dirs = Vec::new();
+    for record in records.iter().rev().filter(|r| r.len() > 1) {
+        // The path is absolute, so it starts at the first slash.
+        let start = match record.iter().position(|&b| b == b'/') {
+            Some(start) => start,
+            None => {
+                warn!("Skipping a record without a path in {:?}.", path);
+                continue
+            }
+        };
+        let (kind, record_path) = (record[0], Path::new(OsStr::from_bytes(&record[start..])));
+        match kind {
+            b'f' | b'l' => remove(kind, Identity::parse(&record[1..start]).as_ref(), record_path, &mut rollback),
+            b'd' => dirs.push(record_path),
+            _ => warn!("Skipping record of unknown type '{}' in {:?}.", kind as char, path),
+        }
+    }
+    // Concurrent copies can both record a directory that they created, so
+    // remove the directories after all files, deepest first.
+    dirs.sort_by(|a, b| b.components().count().cmp(&a.components().count()).then(a.cmp(b)));
+    dirs.dedup();
+    for dir in dirs {
+        remove(b'd', None, dir, &mut rollback);
+    }
+
+    if rollback.num_failed == 0 {
+        fs::remove_file(path)?;
+    }
+    Ok(rollback)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty directory for the test, replacing any leftover one.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("reflink-diff-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create test directory.");
+        dir
+    }
+
+    #[test]
+    fn identity_round_trips() {
+        let identity = Identity { dev: 43, ino: 257, len: 10_000, mtime: -1, mtime_nsec: 999_999_999 };
+        let mut fields = Vec::new();
+        identity.write(&mut fields);
+        assert_eq!(&fields[..], &b"43 257 10000 -1 999999999 "[..]);
+        assert_eq!(Identity::parse(&fields), Some(identity));
+        assert_eq!(Identity::parse(b""), None);
+        assert_eq!(Identity::parse(b"43 257 10000 "), None);
+    }
+
+    #[test]
+    fn rollback_removes_only_what_is_unchanged() {
+        let dir = test_dir("rollback");
+        let created = dir.join("created");
+        fs::create_dir_all(created.join("sub")).unwrap();
+        for name in &["sub/unchanged", "edited", "replaced", "now-a-dir"] {
+            fs::write(created.join(name), "data").unwrap();
+        }
+        std::os::unix::fs::symlink("unchanged", created.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink("a", created.join("symlink-replaced")).unwrap();
+        let kept = ["edited", "replaced", "now-a-dir", "symlink-replaced"];
+        let paths: Vec<PathBuf> = std::iter::once(created.clone())
+            .chain(["sub", "sub/unchanged", "sub/link"].iter().chain(&kept).map(|name| created.join(name)))
+            .collect();
+        // The first path is the directory that holds them all.
+        let log_path = dir.join("undo.log");
+        UndoLog::open(&log_path).unwrap().record_created(&paths).unwrap();
+
+        fs::write(created.join("edited"), "other data").unwrap();
+        // The replacements exist before the originals go, so they get other inodes.
+        fs::write(dir.join("replacement"), "data").unwrap();
+        fs::rename(dir.join("replacement"), created.join("replaced")).unwrap();
+        std::os::unix::fs::symlink("a", dir.join("replacement")).unwrap();
+        fs::rename(dir.join("replacement"), created.join("symlink-replaced")).unwrap();
+        fs::remove_file(created.join("now-a-dir")).unwrap();
+        fs::create_dir(created.join("now-a-dir")).unwrap();
+
+        let result = rollback(&log_path).unwrap();
+        assert_eq!(result.num_removed, 3);
+        // The changed paths, and the directory that still holds them.
+        assert_eq!(result.num_kept, kept.len() + 1);
+        assert_eq!(result.num_failed, 0);
+        assert!(!created.join("sub").exists());
+        for name in &kept {
+            assert!(fs::symlink_metadata(created.join(name)).is_ok(), "{} was removed", name);
+        }
+        assert!(!log_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
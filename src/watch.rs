@@ -0,0 +1,79 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Waiting for changes to a directory with inotify, for the daemon.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// An inotify instance that watches directories for new entries.
+pub struct Watcher {
+    fd: libc::c_int,
+}
+
+impl Watcher {
+    /// Watch the directory for entries that are created, moved in, or that
+    /// change attributes, such as a snapshot that is made read-only.
+    pub fn new(dir: &Path) -> io::Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let watcher = Watcher { fd };
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains a NUL byte."))?;
+        let mask = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ATTRIB | libc::IN_ONLYDIR;
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(watcher)
+    }
+
+    /// Block until there is an event, or until the timeout passes, and return
+    /// whether there was an event. Consumes all pending events.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::max_value() as u128) as libc::c_int);
+        let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let n = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+        match n {
+            -1 => {
+                let err = io::Error::last_os_error();
+                // A signal that does not kill us, is not a reason to stop.
+                if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) }
+            }
+            0 => Ok(false),
+            _ => {
+                self.drain()?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Read and discard the pending events, we only care that there were some.
+    pub fn drain(&self) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll_fd, 1, 0) } <= 0 {
+                return Ok(());
+            }
+            let n = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if n == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
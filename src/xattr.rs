@@ -0,0 +1,115 @@
+// btrfs-mirror-subvolumes -- Mirror subvolumes between two btrfs filesystems
+// Copyright 2020 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Copying extended attributes from a source to a clone, for --xattrs.
+//!
+//! POSIX ACLs are the `system.posix_acl_*` attributes, and file capabilities
+//! and SELinux labels are in `security.*`, so copying the attributes copies
+//! those too. Btrfs exposes its per-file properties as `btrfs.*` attributes,
+//! the compression property we always copy.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// The namespaces that we copy. The `trusted.*` attributes are only visible
+/// to root, and are not about the file itself.
+const NAMESPACES: &[&[u8]] = &[b"user.", b"security.", b"system."];
+
+/// The btrfs compression property, with the NUL for the syscalls.
+const BTRFS_COMPRESSION: &[u8] = b"btrfs.compression\0";
+
+/// Call a function that fills a buffer, like getxattr, first to learn the
+/// size, then to get the data. Retries when the data grew in between.
+fn read_sized<F: Fn(*mut libc::c_void, usize) -> libc::ssize_t>(read: F) -> io::Result<Vec<u8>> {
+    loop {
+        let size = match read(std::ptr::null_mut(), 0) {
+            -1 => return Err(io::Error::last_os_error()),
+            n => n as usize,
+        };
+        let mut buffer = vec![0u8; size];
+        match read(buffer.as_mut_ptr() as *mut libc::c_void, size) {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::ERANGE) { continue }
+                return Err(err);
+            }
+            n => {
+                buffer.truncate(n as usize);
+                return Ok(buffer);
+            }
+        }
+    }
+}
+
+/// Return the value of the attribute, or None if the file does not have it.
+///
+/// The name must end in a NUL.
+fn get(fd: libc::c_int, name: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let name_ptr = name.as_ptr() as *const libc::c_char;
+    match read_sized(|buf, size| unsafe { libc::fgetxattr(fd, name_ptr, buf, size) }) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Set the attribute, whose name must end in a NUL.
+fn set(fd: libc::c_int, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let name_ptr = name.as_ptr() as *const libc::c_char;
+    match unsafe { libc::fsetxattr(fd, name_ptr, value.as_ptr() as *const libc::c_void, value.len(), 0) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            let name = String::from_utf8_lossy(&name[..name.len() - 1]);
+            Err(io::Error::new(err.kind(), format!("Failed to set {}: {}", name, err)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Copy the btrfs compression property of src to dst, if src has one.
+///
+/// This must happen before the data is written, like `chattr +c`, to
+/// compress the data that is written to dst later.
+pub fn copy_compression(src: &fs::File, dst: &fs::File) -> io::Result<()> {
+    match get(src.as_raw_fd(), BTRFS_COMPRESSION) {
+        Ok(Some(value)) => set(dst.as_raw_fd(), BTRFS_COMPRESSION, &value),
+        Ok(None) => Ok(()),
+        // Not btrfs, so there is no property.
+        Err(ref err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Copy the extended attributes of src to dst.
+///
+/// This must happen after the chown and chmod of dst, because a chown drops
+/// the file capabilities, and a chmod rewrites the access ACL.
+pub fn copy_all(src: &fs::File, dst: &fs::File) -> io::Result<()> {
+    let (fd_src, fd_dst) = (src.as_raw_fd(), dst.as_raw_fd());
+    let names = match read_sized(|buf, size| unsafe { libc::flistxattr(fd_src, buf as *mut libc::c_char, size) }) {
+        Ok(names) => names,
+        // The source filesystem has no extended attributes, so there is nothing to copy.
+        Err(ref err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    // The list is a sequence of NUL-terminated names.
+    let mut start = 0;
+    while let Some(len) = names[start..].iter().position(|&b| b == 0) {
+        // Keep the NUL, to pass the name to the syscalls.
+        let name = &names[start..start + len + 1];
+        start += len + 1;
+        if !NAMESPACES.iter().any(|ns| name.starts_with(ns)) {
+            continue;
+        }
+        // Without a value, it was removed since we listed it.
+        if let Some(value) = get(fd_src, name)? {
+            set(fd_dst, name, &value)?;
+        }
+    }
+    Ok(())
+}